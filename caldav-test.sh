@@ -0,0 +1,152 @@
+#!/usr/bin/env bash
+
+# This script checks the CalDAV server with curl, see docs/dev/testing.md.
+#
+# Usage: ./caldav-test.sh <caldav-url> <api-key> <calendar-id>
+
+set -u
+
+if [[ $# -ne 3 ]]
+then
+    echo "Usage: $0 <caldav-url> <api-key> <calendar-id>"
+    exit 1
+fi
+
+URL=$1
+API_KEY=$2
+CALENDAR=$URL/dav/calendars/$3/
+OBJECT=${CALENDAR}caldav-test-$$.ics
+
+FAILED=0
+HEADERS=$(mktemp)
+
+# Runs curl with the rest of the arguments and checks that the response's
+# status is the first argument. The response's body is kept in $BODY and its
+# headers in $HEADERS.
+request()
+{
+    local expected=$1
+    shift
+
+    BODY=$(curl -s -u ":$API_KEY" -D "$HEADERS" -w '\n%{http_code}' "$@")
+
+    local status=${BODY##*$'\n'}
+    BODY=${BODY%$'\n'*}
+
+    if [[ $status == "$expected" ]]
+    then
+        echo "ok   $expected ${*: -1}"
+    else
+        echo "FAIL expected $expected, got $status: $*"
+        echo "$BODY"
+        FAILED=1
+    fi
+}
+
+# Checks that the last response's body contains the first argument.
+expect_body()
+{
+    if [[ $BODY != *"$1"* ]]
+    then
+        echo "FAIL the response doesn't contain $1"
+        FAILED=1
+    fi
+}
+
+etag()
+{
+    grep -i '^etag:' "$HEADERS" | cut -d ' ' -f 2 | tr -d '\r'
+}
+
+EVENT="BEGIN:VCALENDAR\r
+VERSION:2.0\r
+PRODID:-//caldav-test//EN\r
+BEGIN:VEVENT\r
+UID:caldav-test-$$\r
+DTSTAMP:20200901T000000Z\r
+DTSTART:20200901T100000Z\r
+DTEND:20200901T101500Z\r
+RRULE:FREQ=DAILY;COUNT=5\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:caldav-test-$$\r
+DTSTAMP:20200901T000000Z\r
+RECURRENCE-ID:20200903T100000Z\r
+DTSTART:20200903T120000Z\r
+DTEND:20200903T121500Z\r
+END:VEVENT\r
+END:VCALENDAR\r
+"
+
+# Discovery.
+request 301 -X PROPFIND "$URL/.well-known/caldav"
+request 207 -X PROPFIND -H "Depth: 0" --data '<propfind xmlns="DAV:"><prop><current-user-principal/></prop></propfind>' "$URL/dav/"
+expect_body "/dav/principal/"
+request 207 -X PROPFIND -H "Depth: 0" --data '<propfind xmlns="DAV:"><prop><C:calendar-home-set xmlns:C="urn:ietf:params:xml:ns:caldav"/></prop></propfind>' "$URL/dav/principal/"
+expect_body "/dav/calendars/"
+request 207 -X PROPFIND -H "Depth: 1" "$URL/dav/calendars/"
+expect_body "$3"
+
+# The sync token before the event is created.
+request 207 -X REPORT --data '<sync-collection xmlns="DAV:"><sync-token/><sync-level>1</sync-level><prop><getetag/></prop></sync-collection>' "$CALENDAR"
+TOKEN=$(echo "$BODY" | sed -n 's:.*<d\:sync-token>\(.*\)</d\:sync-token>.*:\1:p')
+
+# Create.
+request 201 -X PUT -H "Content-Type: text/calendar" -H "If-None-Match: *" --data-binary "$(printf "$EVENT")" "$OBJECT"
+ETAG=$(etag)
+request 412 -X PUT -H "Content-Type: text/calendar" -H "If-None-Match: *" --data-binary "$(printf "$EVENT")" "$OBJECT"
+request 200 "$OBJECT"
+expect_body "RECURRENCE-ID:20200903T100000Z"
+
+# Find.
+request 207 -X PROPFIND -H "Depth: 1" --data '<propfind xmlns="DAV:"><prop><getetag/></prop></propfind>' "$CALENDAR"
+expect_body "caldav-test-$$.ics"
+expect_body "$ETAG"
+
+QUERY='<C:calendar-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <D:prop><D:getetag/></D:prop>
+  <C:filter><C:comp-filter name="VCALENDAR"><C:comp-filter name="VEVENT">
+    <C:time-range start="START" end="END"/>
+  </C:comp-filter></C:comp-filter></C:filter>
+</C:calendar-query>'
+
+# The fourth instance, not the first one.
+request 207 -X REPORT -H "Depth: 1" --data "$(echo "$QUERY" | sed 's/START/20200904T000000Z/; s/END/20200905T000000Z/')" "$CALENDAR"
+expect_body "caldav-test-$$.ics"
+
+# After the last instance.
+request 207 -X REPORT -H "Depth: 1" --data "$(echo "$QUERY" | sed 's/START/20200906T000000Z/; s/END/20200907T000000Z/')" "$CALENDAR"
+if [[ $BODY == *"caldav-test-$$.ics"* ]]
+then
+    echo "FAIL the event matched a range without instances"
+    FAILED=1
+fi
+
+request 207 -X REPORT -H "Depth: 1" --data "<C:calendar-multiget xmlns:D=\"DAV:\" xmlns:C=\"urn:ietf:params:xml:ns:caldav\"><D:prop><D:getetag/><C:calendar-data/></D:prop><D:href>/dav/calendars/$3/caldav-test-$$.ics</D:href><D:href>/dav/calendars/$3/missing.ics</D:href></C:calendar-multiget>" "$CALENDAR"
+expect_body "BEGIN:VCALENDAR"
+expect_body "HTTP/1.1 404 Not Found"
+
+request 207 -X REPORT --data "<sync-collection xmlns=\"DAV:\"><sync-token>$TOKEN</sync-token><sync-level>1</sync-level><prop><getetag/></prop></sync-collection>" "$CALENDAR"
+expect_body "caldav-test-$$.ics"
+
+# Replace.
+request 412 -X PUT -H "Content-Type: text/calendar" -H 'If-Match: "0"' --data-binary "$(printf "$EVENT")" "$OBJECT"
+request 204 -X PUT -H "Content-Type: text/calendar" -H "If-Match: $ETAG" --data-binary "$(printf "$EVENT")" "$OBJECT"
+ETAG=$(etag)
+
+# Delete.
+request 204 -X DELETE -H "If-Match: $ETAG" "$OBJECT"
+request 404 "$OBJECT"
+
+request 207 -X REPORT --data "<sync-collection xmlns=\"DAV:\"><sync-token>$TOKEN</sync-token><sync-level>1</sync-level><prop><getetag/></prop></sync-collection>" "$CALENDAR"
+expect_body "HTTP/1.1 404 Not Found"
+
+rm -f "$HEADERS"
+
+if [[ $FAILED -ne 0 ]]
+then
+    echo "Some checks failed."
+    exit 1
+fi
+
+echo "All checks passed."
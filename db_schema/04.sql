@@ -0,0 +1,18 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- Adds an original_start_date column to events. Child events (events with a
+-- parent_event_id) override an instance of their parent's recurrence, this column
+-- stores the date of the instance they took the place of (akin to RFC 5545's
+-- RECURRENCE-ID). Existing children are assumed to not have been moved to
+-- another date.
+
+ALTER TABLE events ADD COLUMN original_start_date date;
+
+UPDATE events SET original_start_date = start_date WHERE parent_event_id IS NOT NULL;
+
+ALTER TABLE events ADD CONSTRAINT original_start_date_iff_child CHECK ((parent_event_id IS NULL) = (original_start_date IS NULL));
+
+INSERT INTO schema_changelog (version) VALUES (4);
+
+COMMIT TRANSACTION;
@@ -0,0 +1,21 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- Creates a change_feed table to record changes that can't be figured out from
+-- the calendars and events tables themselves, like deletions. event_id is NULL
+-- for changes to the calendar itself. There are no foreign keys since the
+-- calendars and events referenced here might not exist anymore.
+
+CREATE TABLE change_feed (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    calendar_id uuid NOT NULL,
+    event_id uuid,
+    change_type TEXT NOT NULL,
+    changed_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_change_feed_calendar_id ON change_feed (calendar_id, changed_at);
+
+INSERT INTO schema_changelog (version) VALUES (5);
+
+COMMIT TRANSACTION;
@@ -0,0 +1,17 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- Adds a deleted_at column to calendars and events. Deleted calendars and events
+-- are kept as tombstones (rows with a deleted_at) so that clients syncing changes
+-- can learn about deletions. Tombstones are purged by the server once the
+-- configured retention period expires.
+
+ALTER TABLE calendars ADD COLUMN deleted_at TIMESTAMP WITHOUT TIME ZONE;
+ALTER TABLE events ADD COLUMN deleted_at TIMESTAMP WITHOUT TIME ZONE;
+
+CREATE INDEX idx_calendars_deleted_at ON calendars (deleted_at) WHERE deleted_at IS NOT NULL;
+CREATE INDEX idx_events_deleted_at ON events (deleted_at) WHERE deleted_at IS NOT NULL;
+
+INSERT INTO schema_changelog (version) VALUES (6);
+
+COMMIT TRANSACTION;
@@ -0,0 +1,142 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- Adds a monotonically increasing change sequence per calendar, used for sync
+-- tokens. Every change to an event (and every entry of the change feed) gets the
+-- next sequence number of its calendar.
+--
+-- Sequence numbers are handed out by incrementing calendars.change_seq, which
+-- locks the calendar's row until the transaction ends. So changes to the same
+-- calendar get their sequence numbers in the order they're committed, unlike
+-- NOW() timestamps, which are taken when the transaction starts.
+--
+-- calendars.purged_seq is the highest sequence number of the changes that were
+-- purged along with expired tombstones, sync tokens older than that are invalid.
+
+ALTER TABLE calendars ADD COLUMN change_seq BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE calendars ADD COLUMN purged_seq BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE events ADD COLUMN change_seq BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE change_feed ADD COLUMN seq BIGINT;
+
+
+
+-- Backfill sequence numbers without touching last_modified.
+
+ALTER TABLE events DISABLE TRIGGER update_last_modified;
+ALTER TABLE calendars DISABLE TRIGGER update_last_modified;
+
+UPDATE events SET change_seq = numbered.seq
+FROM (SELECT id, ROW_NUMBER() OVER (PARTITION BY calendar_id ORDER BY last_modified, id) AS seq FROM events) AS numbered
+WHERE events.id = numbered.id;
+
+UPDATE change_feed SET seq = numbered.seq
+FROM (
+    SELECT
+        change_feed.id,
+        COALESCE((SELECT MAX(change_seq) FROM events WHERE events.calendar_id = change_feed.calendar_id), 0)
+            + ROW_NUMBER() OVER (PARTITION BY change_feed.calendar_id ORDER BY change_feed.id) AS seq
+    FROM change_feed
+) AS numbered
+WHERE change_feed.id = numbered.id;
+
+UPDATE calendars SET change_seq = GREATEST(
+    COALESCE((SELECT MAX(change_seq) FROM events WHERE events.calendar_id = calendars.id), 0),
+    COALESCE((SELECT MAX(seq) FROM change_feed WHERE change_feed.calendar_id = calendars.id), 0)
+);
+
+ALTER TABLE events ENABLE TRIGGER update_last_modified;
+ALTER TABLE calendars ENABLE TRIGGER update_last_modified;
+
+ALTER TABLE change_feed ALTER COLUMN seq SET NOT NULL;
+
+CREATE INDEX idx_events_change_seq ON events (calendar_id, change_seq);
+CREATE INDEX idx_change_feed_seq ON change_feed (calendar_id, seq);
+
+
+
+-- Bumping the sequence numbers should not count as modifying the calendar.
+
+DROP TRIGGER IF EXISTS update_last_modified ON calendars;
+
+CREATE TRIGGER update_last_modified
+BEFORE UPDATE ON calendars
+FOR EACH ROW
+WHEN (OLD.change_seq = NEW.change_seq AND OLD.purged_seq = NEW.purged_seq)
+EXECUTE PROCEDURE update_last_modified();
+
+
+
+
+CREATE OR REPLACE FUNCTION next_change_seq(calendar uuid) RETURNS BIGINT AS $$
+DECLARE
+    seq BIGINT;
+BEGIN
+    UPDATE calendars SET change_seq = change_seq + 1 WHERE id = calendar RETURNING change_seq INTO seq;
+
+    RETURN seq;
+END;
+$$ LANGUAGE plpgsql;
+
+COMMENT ON FUNCTION next_change_seq(uuid) IS 'Returns the next change sequence number of the calendar. Locks the calendar''s row until the end of the transaction.';
+
+
+
+
+CREATE OR REPLACE FUNCTION assign_event_change_seq() RETURNS TRIGGER AS $$
+BEGIN
+    IF (TG_OP = 'INSERT' OR NEW IS DISTINCT FROM OLD) THEN
+        NEW.change_seq := next_change_seq(NEW.calendar_id);
+    END IF;
+
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+COMMENT ON FUNCTION assign_event_change_seq() IS 'Gives a changed event the next change sequence number of its calendar. Should be used in BEFORE INSERT OR UPDATE triggers.';
+
+DROP TRIGGER IF EXISTS assign_change_seq ON events;
+
+-- BEFORE triggers fire in alphabetical order, so this fires
+-- before update_last_modified.
+CREATE TRIGGER assign_change_seq
+BEFORE INSERT OR UPDATE ON events
+FOR EACH ROW EXECUTE PROCEDURE assign_event_change_seq();
+
+
+
+
+CREATE OR REPLACE FUNCTION record_event_change() RETURNS TRIGGER AS $$
+DECLARE
+    change_type TEXT;
+BEGIN
+    IF (TG_OP = 'INSERT') THEN
+        change_type := 'created';
+    ELSIF (NEW.change_seq = OLD.change_seq) THEN
+        RETURN NULL;
+    ELSIF (OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL) THEN
+        change_type := 'deleted';
+    ELSE
+        change_type := 'updated';
+    END IF;
+
+    INSERT INTO change_feed (calendar_id, event_id, change_type, seq)
+    VALUES (NEW.calendar_id, NEW.id, change_type, NEW.change_seq);
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+COMMENT ON FUNCTION record_event_change() IS 'Records a change to an event in the change feed. Should be used in AFTER INSERT OR UPDATE triggers.';
+
+DROP TRIGGER IF EXISTS record_change ON events;
+
+CREATE TRIGGER record_change
+AFTER INSERT OR UPDATE ON events
+FOR EACH ROW EXECUTE PROCEDURE record_event_change();
+
+
+
+
+INSERT INTO schema_changelog (version) VALUES (7);
+
+COMMIT TRANSACTION;
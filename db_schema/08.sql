@@ -0,0 +1,21 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- Adds a name, description, default time zone, color and owner to calendars.
+-- Time zones are IANA time zone names (e.g. America/Sao_Paulo), they're
+-- validated by the server since Postgres' list of names might differ from ours.
+-- owner_id is the id of the calendar's owner in the client's system, we
+-- don't know anything about it.
+
+ALTER TABLE calendars ADD COLUMN name TEXT NOT NULL DEFAULT '';
+ALTER TABLE calendars ADD COLUMN description TEXT;
+ALTER TABLE calendars ADD COLUMN timezone TEXT NOT NULL DEFAULT 'UTC';
+ALTER TABLE calendars ADD COLUMN color TEXT;
+ALTER TABLE calendars ADD COLUMN owner_id UUID;
+
+ALTER TABLE calendars ADD CONSTRAINT name_length CHECK (char_length(name) <= 255);
+ALTER TABLE calendars ADD CONSTRAINT color_format CHECK (color ~ '^#[0-9a-fA-F]{6}$');
+
+INSERT INTO schema_changelog (version) VALUES (8);
+
+COMMIT TRANSACTION;
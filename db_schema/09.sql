@@ -0,0 +1,44 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- Creates a tenants table. Every API key and calendar belongs to a tenant,
+-- and API keys can only access calendars (and their events) of their own tenant.
+-- Events belong to the tenant of their calendar. Tenants are also looked up by
+-- their name (e.g. the tenant of JWT users), so names are unique.
+--
+-- Existing API keys and calendars are moved to a tenant named 'default'.
+
+CREATE TABLE tenants (
+    id uuid NOT NULL PRIMARY KEY DEFAULT gen_random_uuid(),
+    name TEXT NOT NULL,
+    created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW(),
+
+    CONSTRAINT tenants_name_unique UNIQUE (name)
+);
+
+INSERT INTO tenants (name) VALUES ('default');
+
+
+
+ALTER TABLE api_keys ADD COLUMN tenant_id uuid;
+ALTER TABLE calendars ADD COLUMN tenant_id uuid;
+
+-- Don't touch the calendars' last_modified.
+ALTER TABLE calendars DISABLE TRIGGER update_last_modified;
+
+UPDATE api_keys SET tenant_id = (SELECT id FROM tenants WHERE name = 'default');
+UPDATE calendars SET tenant_id = (SELECT id FROM tenants WHERE name = 'default');
+
+ALTER TABLE calendars ENABLE TRIGGER update_last_modified;
+
+ALTER TABLE api_keys ALTER COLUMN tenant_id SET NOT NULL;
+ALTER TABLE calendars ALTER COLUMN tenant_id SET NOT NULL;
+
+ALTER TABLE api_keys ADD CONSTRAINT fk_tenant_id FOREIGN KEY (tenant_id) REFERENCES tenants(id);
+ALTER TABLE calendars ADD CONSTRAINT fk_tenant_id FOREIGN KEY (tenant_id) REFERENCES tenants(id);
+
+CREATE INDEX idx_calendars_tenant_id ON calendars (tenant_id);
+
+INSERT INTO schema_changelog (version) VALUES (9);
+
+COMMIT TRANSACTION;
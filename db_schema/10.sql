@@ -0,0 +1,26 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- Adds the columns needed to manage API keys through the API. Keys get an id
+-- that is not a secret (unlike api_key), so that they can be listed, rotated
+-- and revoked without exposing them.
+--
+-- Revoked and expired keys are kept, they just can't be used anymore.
+-- calendar_ids restricts a key to some of its tenant's calendars, NULL
+-- means the key can access all of them.
+
+ALTER TABLE api_keys ADD COLUMN id uuid NOT NULL DEFAULT gen_random_uuid();
+ALTER TABLE api_keys ADD CONSTRAINT api_keys_id_unique UNIQUE (id);
+
+ALTER TABLE api_keys ADD COLUMN label TEXT NOT NULL DEFAULT '';
+ALTER TABLE api_keys ADD COLUMN created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW();
+ALTER TABLE api_keys ADD COLUMN last_used_at TIMESTAMP WITHOUT TIME ZONE;
+ALTER TABLE api_keys ADD COLUMN expires_at TIMESTAMP WITHOUT TIME ZONE;
+ALTER TABLE api_keys ADD COLUMN revoked_at TIMESTAMP WITHOUT TIME ZONE;
+ALTER TABLE api_keys ADD COLUMN calendar_ids uuid[];
+
+CREATE INDEX idx_api_keys_tenant_id ON api_keys (tenant_id);
+
+INSERT INTO schema_changelog (version) VALUES (10);
+
+COMMIT TRANSACTION;
@@ -0,0 +1,39 @@
+BEGIN TRANSACTION;
+
+CREATE EXTENSION IF NOT EXISTS "pgcrypto";
+
+-- DESCRIPTION --
+-- Stops storing API keys in plaintext. Keys are stored as a prefix, used to
+-- look them up, plus a salted SHA-256 hash of the whole key:
+--
+--   key_hash = sha256(key_salt || key)
+--
+-- New keys look like `<prefix>.<secret>`. Existing keys (UUIDs) keep working,
+-- their prefix is the first 8 characters of the UUID and the hashed key is the
+-- UUID in its lowercase hyphenated form.
+
+ALTER TABLE api_keys ADD COLUMN key_prefix TEXT;
+ALTER TABLE api_keys ADD COLUMN key_salt BYTEA;
+ALTER TABLE api_keys ADD COLUMN key_hash BYTEA;
+
+UPDATE api_keys SET
+    key_prefix = substr(api_key::text, 1, 8),
+    key_salt = gen_random_bytes(16);
+
+UPDATE api_keys SET key_hash = digest(key_salt || convert_to(api_key::text, 'UTF8'), 'sha256');
+
+ALTER TABLE api_keys ALTER COLUMN key_prefix SET NOT NULL;
+ALTER TABLE api_keys ALTER COLUMN key_salt SET NOT NULL;
+ALTER TABLE api_keys ALTER COLUMN key_hash SET NOT NULL;
+
+-- api_key was the primary key, id takes its place.
+ALTER TABLE api_keys DROP CONSTRAINT api_keys_pkey;
+ALTER TABLE api_keys DROP COLUMN api_key;
+ALTER TABLE api_keys DROP CONSTRAINT api_keys_id_unique;
+ALTER TABLE api_keys ADD CONSTRAINT api_keys_pkey PRIMARY KEY (id);
+
+CREATE INDEX idx_api_keys_key_prefix ON api_keys (key_prefix);
+
+INSERT INTO schema_changelog (version) VALUES (11);
+
+COMMIT TRANSACTION;
@@ -0,0 +1,38 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- Per-calendar access control lists. A grant gives a principal a role on a
+-- calendar. Principals are:
+--
+--   user:    a user authenticated with a JWT, principal_id is the user's id
+--   api_key: an API key, principal_id is the key's id (not the key itself)
+--   group:   every user that has the role principal_id in the JWT's roles claim
+--
+-- Roles, from least to most permissive: reader, self-writer, writer, editor
+-- and owner, see docs/acl.md. Grants are removed when their calendar is purged.
+--
+-- events.created_by is the principal that created the event (e.g.
+-- `user:<id>`), self-writers can only change their own events. Events
+-- created before this migration have no creator.
+
+CREATE TABLE calendar_acl (
+    id uuid NOT NULL PRIMARY KEY DEFAULT gen_random_uuid(),
+    calendar_id uuid NOT NULL,
+    principal_type TEXT NOT NULL,
+    principal_id TEXT NOT NULL,
+    role TEXT NOT NULL,
+    created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW(),
+
+    CONSTRAINT fk_calendar_id FOREIGN KEY (calendar_id) REFERENCES calendars(id) ON DELETE CASCADE,
+    CONSTRAINT calendar_acl_principal_unique UNIQUE (calendar_id, principal_type, principal_id),
+    CONSTRAINT principal_type_values CHECK (principal_type IN ('user', 'api_key', 'group')),
+    CONSTRAINT role_values CHECK (role IN ('reader', 'self-writer', 'writer', 'editor', 'owner'))
+);
+
+CREATE INDEX idx_calendar_acl_principal ON calendar_acl (principal_type, principal_id);
+
+ALTER TABLE events ADD COLUMN created_by TEXT;
+
+INSERT INTO schema_changelog (version) VALUES (12);
+
+COMMIT TRANSACTION;
@@ -0,0 +1,30 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- Rate limits. API keys can have their own limit, keys without one use the
+-- server's default (see the RATE_LIMIT_* configs). Limits are token buckets
+-- that hold up to `rate_limit_burst` requests and refill at
+-- `rate_limit_per_minute`.
+--
+-- rate_limit_buckets is only used when RATE_LIMIT_STORE is `postgres`, so
+-- that instances behind a load balancer share their buckets. Buckets are
+-- keyed by `api_key:<id>` or `user:<id>`.
+
+ALTER TABLE api_keys ADD COLUMN rate_limit_per_minute INTEGER;
+ALTER TABLE api_keys ADD COLUMN rate_limit_burst INTEGER;
+
+ALTER TABLE api_keys ADD CONSTRAINT api_keys_rate_limit_check CHECK (
+    (rate_limit_per_minute IS NULL AND rate_limit_burst IS NULL)
+    OR (rate_limit_per_minute > 0 AND rate_limit_burst > 0)
+);
+
+CREATE TABLE rate_limit_buckets
+(
+    bucket_key TEXT NOT NULL PRIMARY KEY,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+);
+
+INSERT INTO schema_changelog (version) VALUES (13);
+
+COMMIT TRANSACTION;
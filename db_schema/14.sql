@@ -0,0 +1,38 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- Webhook subscriptions. A subscription watches one of its tenant's calendars
+-- and gets a POST at target_url for each change it's interested in. Its scope
+-- says what it watches:
+--
+--   calendar:        the calendar itself and all of its events
+--   event:           the event event_id
+--   event_instances: the event event_id and the events that override its
+--                    instances (its children)
+--
+-- event_types are the changes it's interested in, e.g. `event.updated`, see
+-- docs/webhooks.md. Subscriptions are removed when their calendar (or event)
+-- is purged.
+
+CREATE TABLE webhook_subscriptions (
+    id uuid NOT NULL PRIMARY KEY DEFAULT gen_random_uuid(),
+    tenant_id uuid NOT NULL,
+    calendar_id uuid NOT NULL,
+    event_id uuid,
+    scope TEXT NOT NULL,
+    target_url TEXT NOT NULL,
+    event_types TEXT[] NOT NULL,
+    created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW(),
+
+    CONSTRAINT fk_tenant_id FOREIGN KEY (tenant_id) REFERENCES tenants(id) ON DELETE CASCADE,
+    CONSTRAINT fk_calendar_id FOREIGN KEY (calendar_id) REFERENCES calendars(id) ON DELETE CASCADE,
+    CONSTRAINT fk_event_id FOREIGN KEY (event_id) REFERENCES events(id) ON DELETE CASCADE,
+    CONSTRAINT scope_values CHECK (scope IN ('calendar', 'event', 'event_instances')),
+    CONSTRAINT event_id_matches_scope CHECK ((scope = 'calendar') = (event_id IS NULL))
+);
+
+CREATE INDEX idx_webhook_subscriptions_calendar_id ON webhook_subscriptions (calendar_id);
+
+INSERT INTO schema_changelog (version) VALUES (14);
+
+COMMIT TRANSACTION;
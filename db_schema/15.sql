@@ -0,0 +1,42 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- Reliable webhook delivery. Changes are no longer POSTed right away, a
+-- delivery is queued in webhook_deliveries (in the same transaction as the
+-- change) for each interested subscription, and the delivery worker sends
+-- them. Failed deliveries are retried with exponential backoff until they
+-- run out of attempts, then they're dead:
+--
+--   pending:   waiting to be sent, at next_attempt_at
+--   delivered: the target responded with a 2xx
+--   dead:      ran out of attempts, can be redelivered manually
+--
+-- Requests are signed with the subscription's secret, see docs/webhooks.md.
+-- Existing subscriptions get a new secret, which can't be retrieved.
+
+ALTER TABLE webhook_subscriptions ADD COLUMN secret TEXT;
+UPDATE webhook_subscriptions SET secret = encode(gen_random_bytes(32), 'hex');
+ALTER TABLE webhook_subscriptions ALTER COLUMN secret SET NOT NULL;
+
+CREATE TABLE webhook_deliveries (
+    id uuid NOT NULL PRIMARY KEY,
+    subscription_id uuid NOT NULL,
+    event_type TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW(),
+    last_attempt_at TIMESTAMP WITHOUT TIME ZONE,
+    last_error TEXT,
+    created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW(),
+
+    CONSTRAINT fk_subscription_id FOREIGN KEY (subscription_id) REFERENCES webhook_subscriptions(id) ON DELETE CASCADE,
+    CONSTRAINT status_values CHECK (status IN ('pending', 'delivered', 'dead'))
+);
+
+CREATE INDEX idx_webhook_deliveries_pending ON webhook_deliveries (next_attempt_at) WHERE status = 'pending';
+CREATE INDEX idx_webhook_deliveries_subscription_id ON webhook_deliveries (subscription_id, created_at);
+
+INSERT INTO schema_changelog (version) VALUES (15);
+
+COMMIT TRANSACTION;
@@ -0,0 +1,38 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- Notifies the calendar_changes channel of every entry of the change feed, so
+-- the server can push changes to clients (see src/change_stream.rs) instead of
+-- them polling for changes. Every change to a calendar or an event is recorded
+-- in the change feed (events by the record_change trigger, calendars by the
+-- server), so this covers both.
+--
+-- Notifications are only sent when the transaction commits, in commit order,
+-- which for a single calendar is also the order of the sequence numbers (see
+-- db_schema/07.sql). The payload is a JSON object with the entry's calendar_id,
+-- event_id, change_type and seq.
+
+CREATE OR REPLACE FUNCTION notify_change() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('calendar_changes', json_build_object(
+        'calendar_id', NEW.calendar_id,
+        'event_id', NEW.event_id,
+        'change_type', NEW.change_type,
+        'seq', NEW.seq
+    )::text);
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+COMMENT ON FUNCTION notify_change() IS 'Notifies the calendar_changes channel of a change feed entry. Should be used in AFTER INSERT triggers of change_feed.';
+
+DROP TRIGGER IF EXISTS notify_change ON change_feed;
+
+CREATE TRIGGER notify_change
+AFTER INSERT ON change_feed
+FOR EACH ROW EXECUTE PROCEDURE notify_change();
+
+INSERT INTO schema_changelog (version) VALUES (16);
+
+COMMIT TRANSACTION;
@@ -0,0 +1,15 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- The UID events imported from iCalendar files had in the file (see
+-- src/ical/import.rs). Overrides of a recurring event's instances are found by
+-- their parent's UID, and exports keep the UID so apps that had the file don't
+-- get duplicates. NULL for events that weren't imported.
+
+ALTER TABLE events ADD COLUMN ical_uid TEXT;
+
+CREATE INDEX idx_events_ical_uid ON events (calendar_id, ical_uid) WHERE ical_uid IS NOT NULL;
+
+INSERT INTO schema_changelog (version) VALUES (17);
+
+COMMIT TRANSACTION;
@@ -0,0 +1,30 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- Feeds give read-only access to a calendar as an iCalendar file, without
+-- authentication, through a URL with a secret token (see src/feeds.rs). Tokens
+-- are stored like API keys (see db_schema/11.sql): a prefix to look them up
+-- plus a salted SHA-256 hash of the whole token.
+--
+-- Revoked feeds are kept, their tokens just don't work anymore.
+
+CREATE TABLE calendar_feeds (
+    id uuid NOT NULL PRIMARY KEY DEFAULT gen_random_uuid(),
+    calendar_id uuid NOT NULL,
+    label TEXT NOT NULL DEFAULT '',
+    token_prefix TEXT NOT NULL,
+    token_salt BYTEA NOT NULL,
+    token_hash BYTEA NOT NULL,
+    created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW(),
+    last_used_at TIMESTAMP WITHOUT TIME ZONE,
+    revoked_at TIMESTAMP WITHOUT TIME ZONE,
+
+    CONSTRAINT fk_calendar_id FOREIGN KEY (calendar_id) REFERENCES calendars(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_calendar_feeds_calendar_id ON calendar_feeds (calendar_id);
+CREATE INDEX idx_calendar_feeds_token_prefix ON calendar_feeds (token_prefix);
+
+INSERT INTO schema_changelog (version) VALUES (18);
+
+COMMIT TRANSACTION;
@@ -0,0 +1,16 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- The name CalDAV clients gave an event's resource when they created it (e.g.
+-- `<uid>.ics`), see src/caldav/objects.rs. Clients expect to find the resource
+-- under the name they PUT it at. NULL for events that weren't created through
+-- CalDAV, their resource is named `<id>.ics`. Only set on events that aren't
+-- children, children are part of their parent's resource.
+
+ALTER TABLE events ADD COLUMN dav_name TEXT;
+
+CREATE UNIQUE INDEX idx_events_dav_name ON events (calendar_id, dav_name) WHERE dav_name IS NOT NULL AND deleted_at IS NULL;
+
+INSERT INTO schema_changelog (version) VALUES (19);
+
+COMMIT TRANSACTION;
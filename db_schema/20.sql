@@ -0,0 +1,90 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- Scheduling (RFC 5546 iTIP), see src/scheduling/mod.rs. An event that isn't a
+-- child can have an organizer and attendees, who are sent a REQUEST when the
+-- event (or one of its children) is created or changed and a CANCEL when it's
+-- deleted or they're removed from it. Attendees answer with REPLYs, which
+-- update their participation status (partstat).
+--
+-- events.sequence is RFC 5545's SEQUENCE: it's incremented whenever the
+-- event's dates or recurrence change or it's deleted, so attendees can tell
+-- which version of the event a message is about. Attendees that were asked to
+-- reply (rsvp) have to answer again when it's incremented.
+--
+-- Messages are queued in scheduling_messages (an outbox, like
+-- webhook_deliveries, see db_schema/15.sql) in the same transaction as the
+-- change, the scheduling worker sends them through the configured transport
+-- (e.g. email, RFC 6047 iMIP).
+
+ALTER TABLE events ADD COLUMN sequence INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE events ADD COLUMN organizer TEXT;
+ALTER TABLE events ADD COLUMN organizer_name TEXT;
+
+CREATE TABLE event_attendees (
+    event_id uuid NOT NULL,
+    email TEXT NOT NULL,
+    name TEXT,
+    role TEXT NOT NULL DEFAULT 'req-participant',
+    partstat TEXT NOT NULL DEFAULT 'needs-action',
+    rsvp BOOLEAN NOT NULL DEFAULT TRUE,
+    replied_at TIMESTAMP WITHOUT TIME ZONE,
+
+    PRIMARY KEY (event_id, email),
+    CONSTRAINT fk_event_id FOREIGN KEY (event_id) REFERENCES events(id) ON DELETE CASCADE,
+    CONSTRAINT role_values CHECK (role IN ('chair', 'req-participant', 'opt-participant', 'non-participant')),
+    CONSTRAINT partstat_values CHECK (partstat IN ('needs-action', 'accepted', 'declined', 'tentative', 'delegated'))
+);
+
+CREATE TABLE scheduling_messages (
+    id uuid NOT NULL PRIMARY KEY DEFAULT gen_random_uuid(),
+    calendar_id uuid NOT NULL,
+    event_id uuid NOT NULL,
+    method TEXT NOT NULL,
+    sequence INTEGER NOT NULL,
+    organizer TEXT NOT NULL,
+    recipient TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW(),
+    last_attempt_at TIMESTAMP WITHOUT TIME ZONE,
+    last_error TEXT,
+    created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW(),
+
+    CONSTRAINT fk_calendar_id FOREIGN KEY (calendar_id) REFERENCES calendars(id) ON DELETE CASCADE,
+    CONSTRAINT fk_event_id FOREIGN KEY (event_id) REFERENCES events(id) ON DELETE CASCADE,
+    CONSTRAINT method_values CHECK (method IN ('REQUEST', 'CANCEL')),
+    CONSTRAINT status_values CHECK (status IN ('pending', 'delivered', 'dead'))
+);
+
+CREATE INDEX idx_scheduling_messages_pending ON scheduling_messages (next_attempt_at) WHERE status = 'pending';
+CREATE INDEX idx_scheduling_messages_event_id ON scheduling_messages (event_id, created_at);
+
+CREATE OR REPLACE FUNCTION increment_event_sequence() RETURNS TRIGGER AS $$
+BEGIN
+    IF (
+        (NEW.start_date, NEW.start_time, NEW.end_date, NEW.end_time, NEW.rrule, NEW.exdates, NEW.rdates, NEW.deleted_at IS NULL)
+        IS DISTINCT FROM
+        (OLD.start_date, OLD.start_time, OLD.end_date, OLD.end_time, OLD.rrule, OLD.exdates, OLD.rdates, OLD.deleted_at IS NULL)
+    ) THEN
+        NEW.sequence := OLD.sequence + 1;
+
+        UPDATE event_attendees SET partstat = 'needs-action', replied_at = NULL WHERE event_id = NEW.id AND rsvp;
+    END IF;
+
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+COMMENT ON FUNCTION increment_event_sequence() IS 'Increments the SEQUENCE of events whose dates or recurrence changed or that were deleted, and asks their attendees to reply again. Should be used in BEFORE UPDATE triggers.';
+
+DROP TRIGGER IF EXISTS increment_sequence ON events;
+
+CREATE TRIGGER increment_sequence
+BEFORE UPDATE ON events
+FOR EACH ROW EXECUTE PROCEDURE increment_event_sequence();
+
+INSERT INTO schema_changelog (version) VALUES (20);
+
+COMMIT TRANSACTION;
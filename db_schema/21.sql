@@ -0,0 +1,59 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- Adds a change sequence per tenant for its calendars, like the one of each
+-- calendar for its events (see db_schema/07.sql), so that lists of calendars have
+-- sync tokens too. Every change to a calendar gets the next sequence number of
+-- its tenant, except for the changes to the calendar's own sequence numbers,
+-- which are changes to its events.
+--
+-- tenants.purged_seq is the highest sequence number of the calendars that were
+-- purged along with expired tombstones, tokens older than that are invalid.
+
+ALTER TABLE tenants ADD COLUMN change_seq BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE tenants ADD COLUMN purged_seq BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE calendars ADD COLUMN tenant_change_seq BIGINT NOT NULL DEFAULT 0;
+
+
+
+-- Backfill sequence numbers without touching last_modified.
+
+ALTER TABLE calendars DISABLE TRIGGER update_last_modified;
+
+UPDATE calendars SET tenant_change_seq = numbered.seq
+FROM (SELECT id, ROW_NUMBER() OVER (PARTITION BY tenant_id ORDER BY last_modified, id) AS seq FROM calendars) AS numbered
+WHERE calendars.id = numbered.id;
+
+ALTER TABLE calendars ENABLE TRIGGER update_last_modified;
+
+UPDATE tenants SET change_seq = COALESCE((SELECT MAX(tenant_change_seq) FROM calendars WHERE calendars.tenant_id = tenants.id), 0);
+
+CREATE INDEX idx_calendars_tenant_change_seq ON calendars (tenant_id, tenant_change_seq);
+
+
+
+
+CREATE OR REPLACE FUNCTION assign_calendar_change_seq() RETURNS TRIGGER AS $$
+BEGIN
+    IF (TG_OP = 'INSERT' OR (NEW.change_seq = OLD.change_seq AND NEW.purged_seq = OLD.purged_seq)) THEN
+        UPDATE tenants SET change_seq = change_seq + 1 WHERE id = NEW.tenant_id RETURNING change_seq INTO NEW.tenant_change_seq;
+    END IF;
+
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+COMMENT ON FUNCTION assign_calendar_change_seq() IS 'Gives a changed calendar the next change sequence number of its tenant. Locks the tenant''s row until the end of the transaction. Should be used in BEFORE INSERT OR UPDATE triggers.';
+
+DROP TRIGGER IF EXISTS assign_change_seq ON calendars;
+
+CREATE TRIGGER assign_change_seq
+BEFORE INSERT OR UPDATE ON calendars
+FOR EACH ROW EXECUTE PROCEDURE assign_calendar_change_seq();
+
+
+
+
+INSERT INTO schema_changelog (version) VALUES (21);
+
+COMMIT TRANSACTION;
@@ -0,0 +1,20 @@
+BEGIN TRANSACTION;
+
+-- DESCRIPTION --
+-- Adds when each rate limit bucket will be full again, so that full buckets,
+-- which are the same as no bucket at all, can be deleted. Buckets of keys
+-- that stopped making requests were kept forever.
+--
+-- The limits of existing buckets aren't known here, they're kept for a day.
+
+ALTER TABLE rate_limit_buckets ADD COLUMN full_at TIMESTAMP;
+
+UPDATE rate_limit_buckets SET full_at = updated_at + INTERVAL '1 day';
+
+ALTER TABLE rate_limit_buckets ALTER COLUMN full_at SET NOT NULL;
+
+CREATE INDEX idx_rate_limit_buckets_full_at ON rate_limit_buckets (full_at);
+
+INSERT INTO schema_changelog (version) VALUES (22);
+
+COMMIT TRANSACTION;
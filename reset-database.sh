@@ -3,7 +3,8 @@
 # This script removes the dbdata volume and rebuilds the db image. This is done
 # so that you can easily update the database schema. The rebuild will copy everything
 # from db_schema into the image and when the container boots with an empty dbdata volume
-# it will execute all .sql files in db_schema.
+# it will execute all .sql files in db_schema. They're executed in lexical order, so
+# their names are zero-padded (00.sql, 01.sql, ..., 10.sql).
 
 
 read -p "This will delete the database volume, you'll lose all data in the db. Are you sure? [y/N]" -n 1 -r
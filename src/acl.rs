@@ -0,0 +1,317 @@
+//! Per-calendar access control lists, see docs/acl.md.
+//!
+//! A grant gives a principal (a user, an API key or a group of users) a
+//! role on a calendar. Routes don't use this directly, they get the role of
+//! the request's principal from the `CalendarAccess` guard, which also takes
+//! the grants of the principal's groups into account.
+
+use crate::database_error::{DatabaseError, DatabaseErrorKind};
+use crate::database_helpers::{FromRow, get_cell_from_row};
+use postgres::{Row, GenericClient};
+use chrono::NaiveDateTime;
+use uuid::Uuid;
+use std::fmt::{Display, Formatter};
+
+const GRANT_FIELDS: &str = "id, principal_type, principal_id, role, created_at";
+
+/// A principal's role on a calendar. Each role can do everything
+/// the roles before it can, so roles can be compared with `<`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum CalendarRole
+{
+    /// Can read the calendar and its events.
+    Reader,
+
+    /// Can create events and change or delete the events it created.
+    SelfWriter,
+
+    /// Can create, change and delete any of the calendar's events.
+    Writer,
+
+    /// Can also change the calendar's properties.
+    Editor,
+
+    /// Can also delete the calendar and manage its grants.
+    Owner,
+}
+
+impl CalendarRole
+{
+    pub const ALL: [CalendarRole; 5] = [
+        CalendarRole::Reader,
+        CalendarRole::SelfWriter,
+        CalendarRole::Writer,
+        CalendarRole::Editor,
+        CalendarRole::Owner,
+    ];
+
+    /// The role's name, as stored in the `role` column of `calendar_acl`.
+    pub fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            CalendarRole::Reader => "reader",
+            CalendarRole::SelfWriter => "self-writer",
+            CalendarRole::Writer => "writer",
+            CalendarRole::Editor => "editor",
+            CalendarRole::Owner => "owner",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<CalendarRole>
+    {
+        CalendarRole::ALL.iter().copied().find(|role| role.as_str() == s)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PrincipalType
+{
+    User,
+    ApiKey,
+    Group,
+}
+
+impl PrincipalType
+{
+    pub const ALL: [PrincipalType; 3] = [PrincipalType::User, PrincipalType::ApiKey, PrincipalType::Group];
+
+    /// The type's name, as stored in the `principal_type` column of `calendar_acl`.
+    pub fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            PrincipalType::User => "user",
+            PrincipalType::ApiKey => "api_key",
+            PrincipalType::Group => "group",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<PrincipalType>
+    {
+        PrincipalType::ALL.iter().copied().find(|t| t.as_str() == s)
+    }
+}
+
+/// Identifies a principal in grants. Its string form (`<type>:<id>`,
+/// e.g. `user:1234`) is what goes in `events.created_by`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PrincipalRef
+{
+    pub principal_type: PrincipalType,
+    pub principal_id: String,
+}
+
+impl PrincipalRef
+{
+    pub fn new(principal_type: PrincipalType, principal_id: impl Into<String>) -> PrincipalRef
+    {
+        PrincipalRef { principal_type, principal_id: principal_id.into() }
+    }
+}
+
+impl Display for PrincipalRef
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result
+    {
+        write!(f, "{}:{}", self.principal_type.as_str(), self.principal_id)
+    }
+}
+
+/// A grant of a role on a calendar.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct Grant
+{
+    pub id: Uuid,
+    pub principal_type: PrincipalType,
+    pub principal_id: String,
+    pub role: CalendarRole,
+
+    #[serde(with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub created_at: Option<NaiveDateTime>,
+}
+
+impl FromRow for Grant
+{
+    type SelfType = Grant;
+
+    fn from_row(row: &Row) -> Result<Self::SelfType, DatabaseError>
+    {
+        let principal_type: String = get_cell_from_row(row, "principal_type")?;
+        let role: String = get_cell_from_row(row, "role")?;
+
+        Ok(
+            Grant {
+                id: get_cell_from_row(row, "id")?,
+                principal_type: PrincipalType::from_str(&principal_type)
+                    .ok_or_else(|| DatabaseErrorKind::FailedConstraint("principal_type_values".to_owned()))?,
+                principal_id: get_cell_from_row(row, "principal_id")?,
+                role: CalendarRole::from_str(&role)
+                    .ok_or_else(|| DatabaseErrorKind::FailedConstraint("role_values".to_owned()))?,
+                created_at: Some(get_cell_from_row(row, "created_at")?),
+            }
+        )
+    }
+}
+
+/// Request body for granting a role on a calendar.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct NewGrant
+{
+    pub principal_type: PrincipalType,
+    pub principal_id: String,
+    pub role: CalendarRole,
+}
+
+/// Lists the calendar's grants.
+pub fn list_grants(db: &mut impl GenericClient, calendar_id: &Uuid) -> Result<Vec<Grant>, DatabaseError>
+{
+    let query = format!("SELECT {} FROM calendar_acl WHERE calendar_id = $1 ORDER BY created_at, id;", GRANT_FIELDS);
+
+    db.query(query.as_str(), &[calendar_id])?
+        .iter()
+        .map(|row| Grant::from_row(row))
+        .collect()
+}
+
+/// Grants a role on the calendar, replacing the principal's
+/// current grant on it if there's one.
+pub fn set_grant(db: &mut impl GenericClient, calendar_id: &Uuid, grant: &NewGrant) -> Result<Grant, DatabaseError>
+{
+    let query = format!("
+        INSERT INTO calendar_acl (calendar_id, principal_type, principal_id, role)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT ON CONSTRAINT calendar_acl_principal_unique DO UPDATE SET role = EXCLUDED.role
+        RETURNING {};
+    ", GRANT_FIELDS);
+
+    let row = db.query_one(query.as_str(), &[
+        calendar_id,
+        &grant.principal_type.as_str(),
+        &grant.principal_id,
+        &grant.role.as_str(),
+    ])?;
+
+    Grant::from_row(&row)
+}
+
+/// Removes a grant. Returns false if the calendar has no grant with that id.
+pub fn delete_grant(db: &mut impl GenericClient, calendar_id: &Uuid, grant_id: &Uuid) -> Result<bool, DatabaseError>
+{
+    let deleted = db.execute("DELETE FROM calendar_acl WHERE id = $1 AND calendar_id = $2;", &[grant_id, calendar_id])?;
+
+    Ok(deleted > 0)
+}
+
+/// The highest role any of the principals was granted on the calendar.
+pub fn granted_role(db: &mut impl GenericClient, calendar_id: &Uuid, principals: &[PrincipalRef]) -> Result<Option<CalendarRole>, DatabaseError>
+{
+    let (types, ids) = split_principals(principals);
+
+    let rows = db.query("
+        SELECT role FROM calendar_acl
+        WHERE
+            calendar_id = $1
+            AND (principal_type, principal_id) IN (SELECT * FROM unnest($2::TEXT[], $3::TEXT[]));
+    ", &[calendar_id, &types, &ids])?;
+
+    let mut highest = None;
+    for row in rows.iter()
+    {
+        let role: String = get_cell_from_row(row, "role")?;
+        let role = CalendarRole::from_str(&role).ok_or_else(|| DatabaseErrorKind::FailedConstraint("role_values".to_owned()))?;
+
+        highest = highest.max(Some(role));
+    }
+
+    Ok(highest)
+}
+
+/// The tenant's calendars any of the principals was granted a role on.
+pub fn granted_calendar_ids(db: &mut impl GenericClient, tenant_id: &Uuid, principals: &[PrincipalRef]) -> Result<Vec<Uuid>, DatabaseError>
+{
+    let (types, ids) = split_principals(principals);
+
+    let rows = db.query("
+        SELECT DISTINCT calendar_acl.calendar_id FROM calendar_acl
+        JOIN calendars ON calendars.id = calendar_acl.calendar_id
+        WHERE
+            calendars.tenant_id = $1
+            AND (principal_type, principal_id) IN (SELECT * FROM unnest($2::TEXT[], $3::TEXT[]));
+    ", &[tenant_id, &types, &ids])?;
+
+    rows.iter()
+        .map(|row| get_cell_from_row(row, "calendar_id"))
+        .collect()
+}
+
+fn split_principals(principals: &[PrincipalRef]) -> (Vec<&str>, Vec<&str>)
+{
+    principals
+        .iter()
+        .map(|p| (p.principal_type.as_str(), p.principal_id.as_str()))
+        .unzip()
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{CalendarRole, PrincipalType, PrincipalRef, NewGrant, set_grant, granted_role, granted_calendar_ids, list_grants, delete_grant};
+    use crate::test_helpers::{connect, insert_tenant, insert_calendar};
+
+    #[test]
+    fn roles_are_ordered()
+    {
+        assert!(CalendarRole::Reader < CalendarRole::SelfWriter);
+        assert!(CalendarRole::SelfWriter < CalendarRole::Writer);
+        assert!(CalendarRole::Writer < CalendarRole::Editor);
+        assert!(CalendarRole::Editor < CalendarRole::Owner);
+
+        for role in CalendarRole::ALL.iter()
+        {
+            assert_eq!(CalendarRole::from_str(role.as_str()), Some(*role));
+        }
+    }
+
+    #[test]
+    fn principal_strings()
+    {
+        assert_eq!(PrincipalRef::new(PrincipalType::User, "1234").to_string(), "user:1234");
+        assert_eq!(PrincipalRef::new(PrincipalType::ApiKey, "abc").to_string(), "api_key:abc");
+    }
+
+    #[test]
+    #[ignore]
+    fn highest_grant_wins()
+    {
+        let mut client = connect();
+        let mut db = client.transaction().unwrap();
+
+        let tenant_id = insert_tenant(&mut db);
+        let calendar_id = insert_calendar(&mut db, &tenant_id);
+
+        let user = PrincipalRef::new(PrincipalType::User, "alice");
+        let group = PrincipalRef::new(PrincipalType::Group, "staff");
+
+        assert_eq!(granted_role(&mut db, &calendar_id, &[user.clone(), group.clone()]).unwrap(), None);
+
+        set_grant(&mut db, &calendar_id, &NewGrant { principal_type: PrincipalType::User, principal_id: "alice".to_owned(), role: CalendarRole::Reader }).unwrap();
+        let group_grant = set_grant(&mut db, &calendar_id, &NewGrant { principal_type: PrincipalType::Group, principal_id: "staff".to_owned(), role: CalendarRole::Writer }).unwrap();
+
+        assert_eq!(granted_role(&mut db, &calendar_id, &[user.clone()]).unwrap(), Some(CalendarRole::Reader));
+        assert_eq!(granted_role(&mut db, &calendar_id, &[user.clone(), group.clone()]).unwrap(), Some(CalendarRole::Writer));
+        assert_eq!(granted_calendar_ids(&mut db, &tenant_id, &[user.clone()]).unwrap(), vec![calendar_id]);
+
+        // Granting again replaces the grant.
+        set_grant(&mut db, &calendar_id, &NewGrant { principal_type: PrincipalType::User, principal_id: "alice".to_owned(), role: CalendarRole::Owner }).unwrap();
+        assert_eq!(list_grants(&mut db, &calendar_id).unwrap().len(), 2);
+        assert_eq!(granted_role(&mut db, &calendar_id, &[user.clone()]).unwrap(), Some(CalendarRole::Owner));
+
+        assert!(delete_grant(&mut db, &calendar_id, &group_grant.id).unwrap());
+        assert!(!delete_grant(&mut db, &calendar_id, &group_grant.id).unwrap());
+        assert_eq!(granted_role(&mut db, &calendar_id, &[group]).unwrap(), None);
+    }
+}
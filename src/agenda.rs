@@ -0,0 +1,331 @@
+//! Calendar-wide agenda: every concrete occurrence of a calendar's events
+//! inside a date window. That is single events, instances generated from
+//! recurring events and the child events that override some of those instances,
+//! all sorted by the date-time they start at.
+//!
+//! Pagination is done with a cursor (see `AgendaCursor`) instead of an offset,
+//! so that pages stay stable even though instances are generated on the fly.
+
+use crate::connection_pool::PgsqlConn;
+use crate::database_error::DatabaseError;
+use crate::database_helpers::{FromRow, get_cell_from_row};
+use crate::event::{Event, EventPlain, ToPlain, EVENT_FIELDS};
+use chrono::{NaiveDate, NaiveDateTime, Duration};
+use uuid::Uuid;
+use itertools::Itertools;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+const CURSOR_DATE_TIME_FORMAT: &str = "%Y%m%dT%H%M%S";
+const CURSOR_DATE_FORMAT: &str = "%Y%m%d";
+
+/// Position of an occurrence in the agenda.
+///
+/// Occurrences are sorted by the date-time they start at, then by
+/// their event's id (the parent's id for generated instances) and lastly
+/// by the date the occurrence originally had in its recurrence. This makes
+/// the ordering total, so a cursor always points to exactly one place in
+/// the agenda.
+///
+/// Clients get a cursor as an opaque string, they should not try to
+/// build or parse one.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct AgendaCursor
+{
+    // Order matters here! The derived Ord compares fields in
+    // declaration order.
+    start: NaiveDateTime,
+    id: Uuid,
+    original_start_date: NaiveDate,
+}
+
+#[derive(Error, Debug)]
+#[error("Invalid agenda cursor.")]
+pub struct InvalidCursorError;
+
+impl Display for AgendaCursor
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result
+    {
+        write!(
+            f,
+            "{}.{}.{}",
+            self.start.format(CURSOR_DATE_TIME_FORMAT),
+            self.id.to_simple(),
+            self.original_start_date.format(CURSOR_DATE_FORMAT)
+        )
+    }
+}
+
+impl FromStr for AgendaCursor
+{
+    type Err = InvalidCursorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        let parts: Vec<&str> = s.split('.').collect();
+
+        if parts.len() != 3
+        {
+            return Err(InvalidCursorError);
+        }
+
+        Ok(
+            AgendaCursor {
+                start: NaiveDateTime::parse_from_str(parts[0], CURSOR_DATE_TIME_FORMAT).map_err(|_| InvalidCursorError)?,
+                id: Uuid::from_str(parts[1]).map_err(|_| InvalidCursorError)?,
+                original_start_date: NaiveDate::parse_from_str(parts[2], CURSOR_DATE_FORMAT).map_err(|_| InvalidCursorError)?,
+            }
+        )
+    }
+}
+
+/// A page of the agenda.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct AgendaPage
+{
+    pub items: Vec<EventPlain>,
+
+    /// Pass this as the `cursor` parameter to get the next page.
+    /// None if this is the last page.
+    pub next_cursor: Option<String>,
+}
+
+struct AgendaEntry
+{
+    cursor: AgendaCursor,
+    event: EventPlain,
+}
+
+/// Gets at most `max_results` occurrences of the calendar's events that happen
+/// between `since` and `until` (both inclusive) and come after `after` in the agenda.
+///
+/// An occurrence happens between `since` and `until` if any of its days
+/// falls between them, so an event that started before `since` but is still
+/// going on at `since` is part of the agenda.
+pub fn get_agenda(
+    db: &mut PgsqlConn,
+    calendar_id: Uuid,
+    since: NaiveDate,
+    until: NaiveDate,
+    after: Option<AgendaCursor>,
+    max_results: usize
+) -> Result<AgendaPage, DatabaseError>
+{
+    // Get one more entry than we need from each source so
+    // we know whether there is a next page.
+    let mut entries = get_single_entries(db, calendar_id, since, until, after, max_results + 1)?;
+    entries.extend(get_generated_entries(db, calendar_id, since, until, after, max_results + 1)?);
+
+    let mut entries = entries
+        .into_iter()
+        .sorted_by_key(|entry| entry.cursor)
+        .collect_vec();
+
+    let has_more = entries.len() > max_results;
+    entries.truncate(max_results);
+
+    let next_cursor = if has_more
+    {
+        entries.last().map(|entry| entry.cursor.to_string())
+    }
+    else
+    {
+        None
+    };
+
+    Ok(
+        AgendaPage {
+            items: entries.into_iter().map(|entry| entry.event).collect(),
+            next_cursor,
+        }
+    )
+}
+
+/// Gets non-recurring events, including children that override
+/// instances of recurring events.
+fn get_single_entries(
+    db: &mut PgsqlConn,
+    calendar_id: Uuid,
+    since: NaiveDate,
+    until: NaiveDate,
+    after: Option<AgendaCursor>,
+    max_results: usize
+) -> Result<Vec<AgendaEntry>, DatabaseError>
+{
+    // The row comparison has to match AgendaCursor's ordering.
+    let query = format!("
+        SELECT {} FROM events
+        WHERE
+            calendar_id = $1
+            AND deleted_at IS NULL
+            AND rrule IS NULL
+            AND start_date <= $3
+            AND end_date >= $2
+            AND (
+                $4::TIMESTAMP IS NULL
+                OR (start_date + COALESCE(start_time, '00:00'::TIME), id, COALESCE(original_start_date, start_date)) > ($4::TIMESTAMP, $5::UUID, $6::DATE)
+            )
+        ORDER BY start_date + COALESCE(start_time, '00:00'::TIME), id, COALESCE(original_start_date, start_date)
+        LIMIT $7;
+    ", EVENT_FIELDS);
+
+    let rows = db.query(query.as_str(), &[
+        &calendar_id,
+        &since,
+        &until,
+
+        &after.map(|c| c.start),
+        &after.map(|c| c.id),
+        &after.map(|c| c.original_start_date),
+
+        &(max_results as i64),
+    ])?;
+
+    let mut entries = vec![];
+
+    for row in rows.iter()
+    {
+        if let Event::Single(event) = Event::from_row(row)?
+        {
+            let cursor = AgendaCursor {
+                start: event.get_span().get_start_naive_date_time(),
+                id: event.get_id(),
+                original_start_date: event.get_original_start_date().unwrap_or(event.get_span().get_start_date()),
+            };
+
+            entries.push(AgendaEntry { cursor, event: event.into_plain() });
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Gets the dates of the instances that are overridden by a child event
+/// (their `original_start_date`), by recurring event.
+fn get_overridden_dates(db: &mut PgsqlConn, calendar_id: Uuid) -> Result<HashMap<Uuid, HashSet<NaiveDate>>, DatabaseError>
+{
+    let query = "
+        SELECT parent_event_id, original_start_date FROM events
+        WHERE calendar_id = $1 AND deleted_at IS NULL AND parent_event_id IS NOT NULL AND original_start_date IS NOT NULL;
+    ";
+
+    let mut overridden: HashMap<Uuid, HashSet<NaiveDate>> = HashMap::new();
+
+    for row in db.query(query, &[&calendar_id])?.iter()
+    {
+        overridden.entry(get_cell_from_row(row, "parent_event_id")?)
+            .or_default()
+            .insert(get_cell_from_row(row, "original_start_date")?);
+    }
+
+    Ok(overridden)
+}
+
+/// Gets instances generated from the recurring events of the calendar. Instances
+/// that are overridden by a child event are left out (like `EventRecurring::expand_instances`
+/// does), the children are listed by `get_single_entries`.
+fn get_generated_entries(
+    db: &mut PgsqlConn,
+    calendar_id: Uuid,
+    since: NaiveDate,
+    until: NaiveDate,
+    after: Option<AgendaCursor>,
+    max_results: usize
+) -> Result<Vec<AgendaEntry>, DatabaseError>
+{
+    let query = format!("SELECT {} FROM events WHERE calendar_id = $1 AND deleted_at IS NULL AND rrule IS NOT NULL AND start_date <= $2;", EVENT_FIELDS);
+
+    let rows = db.query(query.as_str(), &[&calendar_id, &until])?;
+    let overridden = get_overridden_dates(db, calendar_id)?;
+    let no_dates = HashSet::new();
+
+    let mut entries = vec![];
+
+    for row in rows.iter()
+    {
+        if let Event::Recurring(event) = Event::from_row(row)?
+        {
+            let overridden_dates = overridden.get(&event.get_id()).unwrap_or(&no_dates);
+
+            // Instances that start up to this many days before `since`
+            // still overlap with the window.
+            let span = event.get_span();
+            let overlap_days = Duration::days((span.get_end_date() - span.get_start_date()).num_days());
+
+            // Instances are generated in order, so the ones before the cursor's
+            // date can be skipped right away and the rest are generated lazily,
+            // only until there are enough of them.
+            let from_date = match after
+            {
+                Some(after) => std::cmp::max(since - overlap_days, after.start.date()),
+                None => since - overlap_days,
+            };
+
+            let instances = event.iter_instances(Some(from_date), Some(until))
+                .filter(|instance| !overridden_dates.contains(&instance.get_original_start_date()))
+                .filter(|instance| instance.get_span().get_start_date() <= until && instance.get_span().get_end_date() >= since)
+                .map(|instance| {
+                    AgendaEntry {
+                        cursor: AgendaCursor {
+                            start: instance.get_span().get_start_naive_date_time(),
+                            id: instance.get_parent_id(),
+                            original_start_date: instance.get_original_start_date(),
+                        },
+                        event: instance.into_plain(),
+                    }
+                })
+                .skip_while(|entry| after.map_or(false, |after| entry.cursor <= after))
+                .take(max_results);
+
+            entries.extend(instances);
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::AgendaCursor;
+    use chrono::NaiveDate;
+    use uuid::Uuid;
+    use std::str::FromStr;
+
+    #[test]
+    fn cursor_round_trip()
+    {
+        let cursor = AgendaCursor {
+            start: NaiveDate::from_ymd(2026, 10, 1).and_hms(14, 30, 0),
+            id: Uuid::from_str("cb3c0e50-7cb1-464b-8db5-af712f79a4e8").unwrap(),
+            original_start_date: NaiveDate::from_ymd(2026, 9, 30),
+        };
+
+        assert_eq!(AgendaCursor::from_str(&cursor.to_string()).unwrap(), cursor);
+    }
+
+    #[test]
+    fn cursor_rejects_garbage()
+    {
+        assert!(AgendaCursor::from_str("").is_err());
+        assert!(AgendaCursor::from_str("20261001T143000.not-a-uuid.20260930").is_err());
+        assert!(AgendaCursor::from_str("20261001T143000.cb3c0e507cb1464b8db5af712f79a4e8").is_err());
+    }
+
+    #[test]
+    fn cursor_ordering()
+    {
+        let id_a = Uuid::from_str("00000000-0000-0000-0000-000000000001").unwrap();
+        let id_b = Uuid::from_str("00000000-0000-0000-0000-000000000002").unwrap();
+        let date = NaiveDate::from_ymd(2026, 10, 1);
+
+        let earlier = AgendaCursor { start: date.and_hms(9, 0, 0), id: id_b, original_start_date: date };
+        let later = AgendaCursor { start: date.and_hms(10, 0, 0), id: id_a, original_start_date: date };
+        let same_start = AgendaCursor { start: date.and_hms(9, 0, 0), id: id_a, original_start_date: date };
+
+        assert!(earlier < later);
+        assert!(same_start < earlier);
+    }
+}
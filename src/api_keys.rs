@@ -0,0 +1,330 @@
+//! Management of API keys: creating, listing, rotating and revoking them.
+//! Checking keys sent in requests is done by the `ApiKey` request guard.
+//!
+//! The key itself (the secret clients send in the `Authorization` header) is
+//! only returned when a key is created or rotated. Everything else refers
+//! to keys by their id, which is not a secret.
+
+use crate::database_error::DatabaseError;
+use crate::database_helpers::{FromRow, get_cell_from_row};
+use crate::authentication::scopes::Scopes;
+use crate::authentication::key_hashing::{self, RandomError};
+use crate::rate_limit::RateLimit;
+use postgres::{Row, GenericClient};
+use chrono::NaiveDateTime;
+use uuid::Uuid;
+
+const API_KEY_FIELDS: &str = "id, label, scopes, created_at, last_used_at, expires_at, revoked_at, calendar_ids, rate_limit_per_minute, rate_limit_burst";
+
+/// An API key, without the key itself.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct ApiKeyInfo
+{
+    pub id: Uuid,
+    pub label: String,
+    pub scopes: Vec<String>,
+
+    #[serde(with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub created_at: Option<NaiveDateTime>,
+
+    #[serde(with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub last_used_at: Option<NaiveDateTime>,
+
+    #[serde(with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub expires_at: Option<NaiveDateTime>,
+
+    #[serde(with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub revoked_at: Option<NaiveDateTime>,
+
+    /// The calendars the key is restricted to. If None the key
+    /// can access all of its tenant's calendars.
+    pub calendar_ids: Option<Vec<Uuid>>,
+
+    /// The key's own rate limit. If None the key uses
+    /// the server's default rate limit.
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl FromRow for ApiKeyInfo
+{
+    type SelfType = ApiKeyInfo;
+
+    fn from_row(row: &Row) -> Result<Self::SelfType, DatabaseError>
+    {
+        Ok(
+            ApiKeyInfo {
+                id: get_cell_from_row(row, "id")?,
+                label: get_cell_from_row(row, "label")?,
+                scopes: get_cell_from_row(row, "scopes")?,
+                created_at: Some(get_cell_from_row(row, "created_at")?),
+                last_used_at: get_cell_from_row(row, "last_used_at")?,
+                expires_at: get_cell_from_row(row, "expires_at")?,
+                revoked_at: get_cell_from_row(row, "revoked_at")?,
+                calendar_ids: get_cell_from_row(row, "calendar_ids")?,
+                rate_limit: RateLimit::from_columns(
+                    get_cell_from_row(row, "rate_limit_per_minute")?,
+                    get_cell_from_row(row, "rate_limit_burst")?,
+                ),
+            }
+        )
+    }
+}
+
+/// An API key together with the key itself. Only returned
+/// when a key is created or rotated.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct ApiKeyWithSecret
+{
+    #[serde(flatten)]
+    pub info: ApiKeyInfo,
+
+    /// The key to send in the `Authorization` header. This is the only
+    /// time it's shown, it can't be retrieved later.
+    pub key: String,
+}
+
+/// Request body for creating an API key.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct NewApiKey
+{
+    #[serde(default)]
+    pub label: String,
+
+    pub scopes: Vec<String>,
+
+    #[serde(default, with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub expires_at: Option<NaiveDateTime>,
+
+    #[serde(default)]
+    pub calendar_ids: Option<Vec<Uuid>>,
+
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl NewApiKey
+{
+    /// Whether a key restricted to `calendar_ids` and limited to `rate_limit`
+    /// can create this key, i.e. whether this key can't reach other calendars
+    /// and can't make more requests. `default_limit` is the limit of keys
+    /// without their own, None if they aren't limited.
+    pub fn is_within(&self, calendar_ids: Option<&Vec<Uuid>>, rate_limit: Option<RateLimit>, default_limit: Option<RateLimit>) -> bool
+    {
+        let calendars_within = match (calendar_ids, &self.calendar_ids)
+        {
+            (None, _) => true,
+            (Some(allowed), Some(requested)) => requested.iter().all(|id| allowed.contains(id)),
+            (Some(_), None) => false,
+        };
+
+        let rate_limit_within = match (rate_limit.or(default_limit), self.rate_limit.or(default_limit))
+        {
+            (None, _) => true,
+            (Some(allowed), Some(requested)) => requested.per_minute <= allowed.per_minute && requested.burst <= allowed.burst,
+            (Some(_), None) => false,
+        };
+
+        calendars_within && rate_limit_within
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum NewApiKeyError
+{
+    #[error("Invalid scopes.")]
+    InvalidScopes,
+
+    #[error("Some of the calendars don't exist.")]
+    UnknownCalendars,
+
+    #[error("Invalid rate limit.")]
+    InvalidRateLimit,
+
+    #[error(transparent)]
+    Database(#[from] DatabaseError),
+
+    #[error(transparent)]
+    Random(#[from] RandomError),
+}
+
+#[derive(Error, Debug)]
+pub enum RotateApiKeyError
+{
+    #[error(transparent)]
+    Database(#[from] DatabaseError),
+
+    #[error(transparent)]
+    Random(#[from] RandomError),
+}
+
+/// Creates an API key for the tenant. The key can't be restricted to
+/// calendars of other tenants.
+pub fn create(db: &mut impl GenericClient, tenant_id: &Uuid, new_key: &NewApiKey) -> Result<ApiKeyWithSecret, NewApiKeyError>
+{
+    if new_key.scopes.is_empty() || Scopes::parse(&new_key.scopes).is_err()
+    {
+        return Err(NewApiKeyError::InvalidScopes);
+    }
+
+    if new_key.rate_limit.map_or(false, |limit| !limit.is_valid())
+    {
+        return Err(NewApiKeyError::InvalidRateLimit);
+    }
+
+    if let Some(calendar_ids) = &new_key.calendar_ids
+    {
+        let rows = db.query(
+            "SELECT COUNT(*) AS count FROM calendars WHERE tenant_id = $1 AND deleted_at IS NULL AND id = ANY($2);",
+            &[tenant_id, calendar_ids]
+        ).map_err(DatabaseError::from)?;
+
+        let count: i64 = get_cell_from_row(&rows[0], "count")?;
+
+        let mut unique_ids = calendar_ids.clone();
+        unique_ids.sort();
+        unique_ids.dedup();
+
+        if count != unique_ids.len() as i64
+        {
+            return Err(NewApiKeyError::UnknownCalendars);
+        }
+    }
+
+    let generated = key_hashing::generate()?;
+
+    let query = format!("
+        INSERT INTO api_keys (tenant_id, label, scopes, expires_at, calendar_ids, key_prefix, key_salt, key_hash, rate_limit_per_minute, rate_limit_burst)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        RETURNING {};
+    ", API_KEY_FIELDS);
+
+    let row = db.query_one(query.as_str(), &[
+        tenant_id,
+        &new_key.label,
+        &new_key.scopes,
+        &new_key.expires_at,
+        &new_key.calendar_ids,
+        &generated.prefix,
+        &generated.salt,
+        &generated.hash,
+        &new_key.rate_limit.map(|limit| limit.per_minute as i32),
+        &new_key.rate_limit.map(|limit| limit.burst as i32),
+    ]).map_err(DatabaseError::from)?;
+
+    Ok(
+        ApiKeyWithSecret {
+            info: ApiKeyInfo::from_row(&row)?,
+            key: generated.key,
+        }
+    )
+}
+
+/// Lists the tenant's API keys, including revoked and expired ones.
+pub fn list(db: &mut impl GenericClient, tenant_id: &Uuid, offset: i64, limit: i64) -> Result<Vec<ApiKeyInfo>, DatabaseError>
+{
+    let query = format!("SELECT {} FROM api_keys WHERE tenant_id = $1 ORDER BY created_at, id OFFSET $2 LIMIT $3;", API_KEY_FIELDS);
+
+    db.query(query.as_str(), &[tenant_id, &offset, &limit])?
+        .iter()
+        .map(|row| ApiKeyInfo::from_row(row))
+        .collect()
+}
+
+/// Replaces the key of one of the tenant's API keys with a new one, keeping
+/// everything else. The old key stops working right away. Returns None if
+/// the key doesn't exist or is revoked.
+pub fn rotate(db: &mut impl GenericClient, tenant_id: &Uuid, id: &Uuid) -> Result<Option<ApiKeyWithSecret>, RotateApiKeyError>
+{
+    let generated = key_hashing::generate()?;
+
+    let query = format!("
+        UPDATE api_keys SET key_prefix = $3, key_salt = $4, key_hash = $5
+        WHERE id = $1 AND tenant_id = $2 AND revoked_at IS NULL
+        RETURNING {};
+    ", API_KEY_FIELDS);
+
+    let rows = db.query(query.as_str(), &[id, tenant_id, &generated.prefix, &generated.salt, &generated.hash])
+        .map_err(DatabaseError::from)?;
+
+    if let Some(row) = rows.get(0)
+    {
+        Ok(
+            Some(
+                ApiKeyWithSecret {
+                    info: ApiKeyInfo::from_row(row)?,
+                    key: generated.key,
+                }
+            )
+        )
+    }
+    else
+    {
+        Ok(None)
+    }
+}
+
+/// Revokes one of the tenant's API keys. Returns false if the
+/// key doesn't exist or was already revoked.
+pub fn revoke(db: &mut impl GenericClient, tenant_id: &Uuid, id: &Uuid) -> Result<bool, DatabaseError>
+{
+    let updated = db.execute(
+        "UPDATE api_keys SET revoked_at = NOW() WHERE id = $1 AND tenant_id = $2 AND revoked_at IS NULL;",
+        &[id, tenant_id]
+    )?;
+
+    Ok(updated > 0)
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::NewApiKey;
+    use crate::rate_limit::RateLimit;
+    use uuid::Uuid;
+
+    fn new_key(calendar_ids: Option<Vec<Uuid>>, rate_limit: Option<RateLimit>) -> NewApiKey
+    {
+        NewApiKey {
+            label: String::new(),
+            scopes: vec!["READ".to_owned()],
+            expires_at: None,
+            calendar_ids,
+            rate_limit,
+        }
+    }
+
+    #[test]
+    fn new_keys_within_calendars()
+    {
+        let a = Uuid::new_v4();
+        let b = Uuid::new_v4();
+        let allowed = vec![a];
+
+        assert!(new_key(None, None).is_within(None, None, None));
+        assert!(new_key(Some(vec![a, b]), None).is_within(None, None, None));
+        assert!(new_key(Some(vec![a]), None).is_within(Some(&allowed), None, None));
+        assert!(!new_key(Some(vec![a, b]), None).is_within(Some(&allowed), None, None));
+        assert!(!new_key(None, None).is_within(Some(&allowed), None, None));
+    }
+
+    #[test]
+    fn new_keys_within_rate_limit()
+    {
+        let low = RateLimit { per_minute: 10, burst: 5 };
+        let high = RateLimit { per_minute: 100, burst: 5 };
+
+        assert!(new_key(None, Some(high)).is_within(None, None, None));
+        assert!(new_key(None, Some(low)).is_within(None, Some(high), None));
+        assert!(!new_key(None, Some(high)).is_within(None, Some(low), None));
+        assert!(!new_key(None, None).is_within(None, Some(low), None));
+
+        // Keys without their own limit use the default one.
+        assert!(new_key(None, None).is_within(None, Some(high), Some(low)));
+        assert!(!new_key(None, Some(high)).is_within(None, None, Some(low)));
+    }
+}
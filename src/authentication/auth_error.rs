@@ -0,0 +1,238 @@
+//! Errors of the authentication guards (`ApiKey`, `User`, `Principal` and
+//! `CalendarAccess`).
+//!
+//! Guards can't respond themselves, so they fail with the error's status and
+//! keep the error in the request's local cache (see `fail`). The catcher for
+//! that status then takes it from there and responds with it, `AuthError`
+//! implements `Responder`.
+
+use rocket::request::{Request, Outcome};
+use rocket::response::{self, Responder, Response};
+use rocket::http::{Status, ContentType, Header};
+use crate::authentication::scopes::Scope;
+use crate::acl::CalendarRole;
+use std::io::Cursor;
+
+const REALM: &str = "calendar-server";
+
+/// How the client tried to authenticate.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AuthScheme
+{
+    /// `Authorization: <API key>`, or the key as the password
+    /// of `Authorization: Basic` credentials.
+    ApiKey,
+
+    /// `Authorization: Bearer <JWT>`
+    Bearer,
+}
+
+impl AuthScheme
+{
+    fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            AuthScheme::ApiKey => "ApiKey",
+            AuthScheme::Bearer => "Bearer",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AuthError
+{
+    /// No `Authorization` header.
+    Missing,
+
+    /// The credentials are not an API key or JWT at all.
+    Malformed(AuthScheme),
+
+    /// No such API key (or it was revoked), or the JWT is invalid.
+    Unknown(AuthScheme),
+
+    Expired(AuthScheme),
+
+    MissingScope(AuthScheme, Scope),
+
+    /// The principal doesn't have this role on the calendar, see `acl`.
+    MissingRole(CalendarRole),
+
+    /// The credentials couldn't be checked, e.g. the database is down.
+    Unavailable,
+
+    /// The API key's (or user's) rate limit bucket is empty, see `rate_limit`.
+    RateLimited,
+}
+
+/// Keeps the first `AuthError` of a request, see `fail`.
+pub struct AuthFailure(pub Option<AuthError>);
+
+impl AuthError
+{
+    pub fn status(&self) -> Status
+    {
+        match self
+        {
+            AuthError::Missing
+                | AuthError::Malformed(_)
+                | AuthError::Unknown(_)
+                | AuthError::Expired(_) => Status::Unauthorized,
+            AuthError::MissingScope(_, _)
+                | AuthError::MissingRole(_) => Status::Forbidden,
+            AuthError::Unavailable => Status::ServiceUnavailable,
+            AuthError::RateLimited => Status::TooManyRequests,
+        }
+    }
+
+    fn code(&self) -> &'static str
+    {
+        match self
+        {
+            AuthError::Missing => "missing-credentials",
+            AuthError::Malformed(_) => "malformed-credentials",
+            AuthError::Unknown(_) => "unknown-credentials",
+            AuthError::Expired(_) => "expired-credentials",
+            AuthError::MissingScope(_, _) => "missing-scope",
+            AuthError::MissingRole(_) => "missing-role",
+            AuthError::Unavailable => "service-unavailable",
+            AuthError::RateLimited => "rate-limited",
+        }
+    }
+
+    fn message(&self) -> String
+    {
+        match self
+        {
+            AuthError::Missing => "The Authorization header is missing.".to_owned(),
+            AuthError::Malformed(AuthScheme::ApiKey) => "The Authorization header is not an API key.".to_owned(),
+            AuthError::Malformed(AuthScheme::Bearer) => "The bearer token is not a valid JWT.".to_owned(),
+            AuthError::Unknown(AuthScheme::ApiKey) => "The API key does not exist or was revoked.".to_owned(),
+            AuthError::Unknown(AuthScheme::Bearer) => "The bearer token was not accepted.".to_owned(),
+            AuthError::Expired(AuthScheme::ApiKey) => "The API key has expired.".to_owned(),
+            AuthError::Expired(AuthScheme::Bearer) => "The bearer token has expired.".to_owned(),
+            AuthError::MissingScope(AuthScheme::ApiKey, scope) => format!("The API key does not have the {} scope.", scope.as_str()),
+            AuthError::MissingScope(AuthScheme::Bearer, scope) => format!("Users can't use routes that require the {} scope.", scope.as_str()),
+            AuthError::MissingRole(role) => format!("This requires the {} role on the calendar.", role.as_str()),
+            AuthError::Unavailable => "Credentials can't be checked right now, try again later.".to_owned(),
+            AuthError::RateLimited => "Too many requests, see the Retry-After header for when to try again.".to_owned(),
+        }
+    }
+
+    /// The `WWW-Authenticate` challenge, see RFC 6750 section 3.
+    /// None for errors that are not about the credentials.
+    fn challenge(&self) -> Option<String>
+    {
+        let (scheme, error, scope) = match self
+        {
+            AuthError::Missing => return Some(format!("ApiKey realm=\"{0}\", Bearer realm=\"{0}\", Basic realm=\"{0}\"", REALM)),
+            AuthError::Malformed(scheme) => (scheme, "invalid_request", None),
+            AuthError::Unknown(scheme) | AuthError::Expired(scheme) => (scheme, "invalid_token", None),
+            AuthError::MissingScope(scheme, scope) => (scheme, "insufficient_scope", Some(scope)),
+            AuthError::MissingRole(_) | AuthError::Unavailable | AuthError::RateLimited => return None,
+        };
+
+        let mut challenge = format!(
+            "{} realm=\"{}\", error=\"{}\", error_description=\"{}\"",
+            scheme.as_str(),
+            REALM,
+            error,
+            self.message()
+        );
+
+        if let Some(scope) = scope
+        {
+            challenge.push_str(&format!(", scope=\"{}\"", scope.as_str()));
+        }
+
+        Some(challenge)
+    }
+
+    fn body(&self) -> serde_json::Value
+    {
+        let mut body = serde_json::json!({
+            "error": self.code(),
+            "message": self.message(),
+        });
+
+        match self
+        {
+            AuthError::MissingScope(_, scope) => body["scope"] = scope.as_str().into(),
+            AuthError::MissingRole(role) => body["role"] = role.as_str().into(),
+            _ => {},
+        }
+
+        body
+    }
+}
+
+impl<'r> Responder<'r> for AuthError
+{
+    fn respond_to(self, _request: &Request) -> response::Result<'r>
+    {
+        let mut response = Response::build();
+
+        response
+            .status(self.status())
+            .header(ContentType::JSON)
+            .sized_body(Cursor::new(self.body().to_string()));
+
+        if let Some(challenge) = self.challenge()
+        {
+            response.header(Header::new("WWW-Authenticate", challenge));
+        }
+
+        response.ok()
+    }
+}
+
+/// Fails a guard with `error`, keeping it in the request's local cache for
+/// the catchers. If the request already failed a guard, that error is kept.
+pub fn fail<S>(request: &Request, error: AuthError) -> Outcome<S, AuthError>
+{
+    request.local_cache(|| AuthFailure(Some(error)));
+    Outcome::Failure((error.status(), error))
+}
+
+/// The error a guard of the request failed with, if any.
+pub fn get_failure(request: &Request) -> Option<AuthError>
+{
+    request.local_cache(|| AuthFailure(None)).0
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{AuthError, AuthScheme};
+    use crate::authentication::scopes::Scope;
+    use rocket::http::Status;
+
+    #[test]
+    fn statuses_and_codes()
+    {
+        assert_eq!(AuthError::Missing.status(), Status::Unauthorized);
+        assert_eq!(AuthError::Expired(AuthScheme::ApiKey).body()["error"], "expired-credentials");
+        assert_eq!(AuthError::Unavailable.status(), Status::ServiceUnavailable);
+
+        let missing_scope = AuthError::MissingScope(AuthScheme::ApiKey, Scope::Write);
+        assert_eq!(missing_scope.status(), Status::Forbidden);
+        assert_eq!(missing_scope.body()["scope"], "WRITE");
+        assert_eq!(missing_scope.body()["message"], "The API key does not have the WRITE scope.");
+    }
+
+    #[test]
+    fn challenges()
+    {
+        assert_eq!(
+            AuthError::Missing.challenge().unwrap(),
+            "ApiKey realm=\"calendar-server\", Bearer realm=\"calendar-server\", Basic realm=\"calendar-server\""
+        );
+        assert_eq!(
+            AuthError::Expired(AuthScheme::Bearer).challenge().unwrap(),
+            "Bearer realm=\"calendar-server\", error=\"invalid_token\", error_description=\"The bearer token has expired.\""
+        );
+        assert!(AuthError::MissingScope(AuthScheme::ApiKey, Scope::Super).challenge().unwrap().ends_with("scope=\"SUPER\""));
+        assert!(AuthError::Unavailable.challenge().is_none());
+        assert!(AuthError::RateLimited.challenge().is_none());
+    }
+}
@@ -1,53 +1,189 @@
 use rocket::request::{FromRequest, Outcome};
 use rocket::Request;
-use rocket::http::Status;
 use crate::connection_pool::PgsqlConn;
 use crate::database_helpers::{get_cell_from_row};
-use rocket::outcome::IntoOutcome;
+use crate::authentication::scopes::{Scopes, RequiredScope};
+use crate::authentication::key_hashing;
+use crate::authentication::auth_error::{self, AuthError, AuthScheme};
+use crate::rate_limit::{self, RateLimit};
+use postgres::Row;
 use uuid::Uuid;
-use std::str::FromStr;
+use std::marker::PhantomData;
 
-pub struct InvalidScopeError;
+/// Request guard for routes that need an API key with the scope `S`.
+/// Fails with an `AuthError`: 401 if there's no valid API key, 403 if
+/// the key doesn't have the scope and 503 if the key can't be checked.
+pub struct ApiKey<S: RequiredScope>
+{
+    /// The key's id, not the key itself.
+    id: Uuid,
+
+    /// The tenant the key belongs to. The key can only access
+    /// calendars (and their events) of this tenant.
+    tenant_id: Uuid,
+
+    scopes: Scopes,
 
-pub struct ApiKey
+    /// The calendars the key is restricted to, None
+    /// if it can access all of its tenant's calendars.
+    calendar_ids: Option<Vec<Uuid>>,
+
+    /// The key's own rate limit, None if it uses the default one.
+    rate_limit: Option<RateLimit>,
+
+    _scope: PhantomData<S>,
+}
+
+impl<S: RequiredScope> ApiKey<S>
 {
-    key: Uuid,
+    pub fn get_id(&self) -> Uuid { self.id }
+
+    pub fn get_tenant_id(&self) -> Uuid { self.tenant_id }
+
+    pub fn get_scopes(&self) -> Scopes { self.scopes }
+
+    pub fn get_calendar_ids(&self) -> Option<&Vec<Uuid>> { self.calendar_ids.as_ref() }
+
+    pub fn get_rate_limit(&self) -> Option<RateLimit> { self.rate_limit }
 
-    // TODO: use a bitmask here to reduce allocations (?)
-    scopes: Vec<String>,
+    /// Checks the key's calendar restriction. This does NOT check whether the
+    /// calendar belongs to the key's tenant, see `Principal::calendar_role`.
+    pub fn allows_calendar(&self, calendar_id: &Uuid) -> bool
+    {
+        self.calendar_ids.as_ref().map_or(true, |ids| ids.contains(calendar_id))
+    }
+
+    /// Builds the key from its row, failing if it was revoked or has expired.
+    fn from_row(row: &Row) -> Result<Self, AuthError>
+    {
+        let revoked: bool = get_cell_from_row(row, "revoked").map_err(|_| AuthError::Unavailable)?;
+        let expired: bool = get_cell_from_row(row, "expired").map_err(|_| AuthError::Unavailable)?;
+
+        if revoked
+        {
+            return Err(AuthError::Unknown(AuthScheme::ApiKey));
+        }
+
+        if expired
+        {
+            return Err(AuthError::Expired(AuthScheme::ApiKey));
+        }
+
+        let scope_names: Vec<String> = get_cell_from_row(row, "scopes").map_err(|_| AuthError::Unavailable)?;
+
+        Ok(
+            ApiKey {
+                id: get_cell_from_row(row, "id").map_err(|_| AuthError::Unavailable)?,
+                tenant_id: get_cell_from_row(row, "tenant_id").map_err(|_| AuthError::Unavailable)?,
+                scopes: Scopes::parse(&scope_names).map_err(|_| AuthError::Unavailable)?,
+                calendar_ids: get_cell_from_row(row, "calendar_ids").map_err(|_| AuthError::Unavailable)?,
+                rate_limit: RateLimit::from_columns(
+                    get_cell_from_row(row, "rate_limit_per_minute").map_err(|_| AuthError::Unavailable)?,
+                    get_cell_from_row(row, "rate_limit_burst").map_err(|_| AuthError::Unavailable)?,
+                ),
+                _scope: PhantomData,
+            }
+        )
+    }
 }
 
-impl<'a, 'r> FromRequest<'a, 'r> for ApiKey
+/// The API key in an `Authorization` header: the header itself or, for clients
+/// that can only do Basic authentication (e.g. CalDAV apps), the password of
+/// `Basic` credentials. The user name is ignored.
+fn api_key_from_header(header: &str) -> Option<String>
 {
-    type Error = ();
+    match header.strip_prefix("Basic ")
+    {
+        Some(credentials) =>
+        {
+            let credentials = String::from_utf8(base64::decode(credentials.trim()).ok()?).ok()?;
+            credentials.split_once(':').map(|(_, password)| password.to_owned())
+        },
+        None => Some(header.to_owned()),
+    }
+}
 
-    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+impl<'a, 'r, S: RequiredScope> FromRequest<'a, 'r> for ApiKey<S>
+{
+    type Error = AuthError;
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error>
+    {
+        let header = match request.headers().get_one("Authorization")
+        {
+            Some(header) => header,
+            None => return auth_error::fail(request, AuthError::Missing),
+        };
 
-        // TODO: improve error handling here, this is horrendous.
+        let (prefix, api_key) = match api_key_from_header(header).and_then(|key| key_hashing::parse(&key))
+        {
+            Some(parsed) => parsed,
+            None => return auth_error::fail(request, AuthError::Malformed(AuthScheme::ApiKey)),
+        };
+
+        let mut db = match request.guard::<PgsqlConn>()
+        {
+            Outcome::Success(db) => db,
+            _ => return auth_error::fail(request, AuthError::Unavailable),
+        };
+
+        // Revoked and expired keys are selected too, so that
+        // expired keys can be told apart from unknown ones.
+        let query = "
+            SELECT
+                id, scopes, tenant_id, calendar_ids, key_salt, key_hash,
+                rate_limit_per_minute, rate_limit_burst,
+                revoked_at IS NOT NULL AS revoked,
+                (expires_at IS NOT NULL AND expires_at <= NOW()) AS expired
+            FROM api_keys
+            WHERE key_prefix = $1;
+        ";
+        let rows = match db.query(query, &[&prefix])
+        {
+            Ok(rows) => rows,
+            Err(_) => return auth_error::fail(request, AuthError::Unavailable),
+        };
 
-        // get Authorization header
-        let api_key = request.headers()
-            .get_one("Authorization")
-            .and_then(|x| Uuid::from_str(x).ok())
-            .into_outcome((Status::Unauthorized, ()))?;
+        // find the key that matches
+        let row = rows
+            .iter()
+            .find(|row| {
+                let salt: Vec<u8> = row.try_get("key_salt").unwrap_or_default();
+                let hash: Vec<u8> = row.try_get("key_hash").unwrap_or_default();
 
-        // get api key scopes from db
-        let mut db = request.guard::<PgsqlConn>().unwrap();
-        let query = "SELECT scopes FROM api_keys WHERE api_key = $1;";
-        let rows = db.query(query, &[&api_key]).unwrap();
+                key_hashing::verify(&api_key, &salt, &hash)
+            });
 
-        if let Some(row) = rows.get(0)
+        let row = match row
         {
-            Outcome::Success(
-                ApiKey {
-                    key: api_key,
-                    scopes: get_cell_from_row( & row, "scopes").ok().into_outcome((Status::InternalServerError, ()))?
-                }
-            )
+            Some(row) => row,
+            None => return auth_error::fail(request, AuthError::Unknown(AuthScheme::ApiKey)),
+        };
+
+        let key = match Self::from_row(row)
+        {
+            Ok(key) => key,
+            Err(error) => return auth_error::fail(request, error),
+        };
+
+        if !key.scopes.contains(S::SCOPE)
+        {
+            return auth_error::fail(request, AuthError::MissingScope(AuthScheme::ApiKey, S::SCOPE));
+        }
+
+        if let Err(error) = rate_limit::limit_request(request, &mut **db, &format!("api_key:{}", key.id), key.rate_limit)
+        {
+            return auth_error::fail(request, error);
         }
-        else
+
+        // Only update last_used_at once a minute, so that we don't
+        // write to the database on every request.
+        let query = "UPDATE api_keys SET last_used_at = NOW() WHERE id = $1 AND (last_used_at IS NULL OR last_used_at < NOW() - INTERVAL '1 minute');";
+        if db.execute(query, &[&key.id]).is_err()
         {
-            Outcome::Failure((Status::Unauthorized, ()))
+            return auth_error::fail(request, AuthError::Unavailable);
         }
+
+        Outcome::Success(key)
     }
-}
\ No newline at end of file
+}
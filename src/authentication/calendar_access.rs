@@ -0,0 +1,146 @@
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use rocket::http::Status;
+use rocket::outcome::IntoOutcome;
+use crate::authentication::principal::Principal;
+use crate::authentication::scopes::{self, RequiredScope};
+use crate::authentication::auth_error::{self, AuthError};
+use crate::acl::CalendarRole;
+use crate::connection_pool::PgsqlConn;
+use crate::database_helpers::{UuidParam, CalendarFileParam};
+use std::marker::PhantomData;
+use uuid::Uuid;
+
+/// Implemented by the marker types used as `CalendarAccess`'s type
+/// parameter, e.g. `CalendarAccess<Editor>`. Each role also requires
+/// a scope from API keys, `READ` for readers and `WRITE` for the rest.
+pub trait RequiredRole
+{
+    const ROLE: CalendarRole;
+    type Scope: RequiredScope;
+}
+
+pub struct Reader;
+impl RequiredRole for Reader { const ROLE: CalendarRole = CalendarRole::Reader; type Scope = scopes::Read; }
+
+/// On routes with an event id, self-writers can only access events they created.
+pub struct SelfWriter;
+impl RequiredRole for SelfWriter { const ROLE: CalendarRole = CalendarRole::SelfWriter; type Scope = scopes::Write; }
+
+pub struct Writer;
+impl RequiredRole for Writer { const ROLE: CalendarRole = CalendarRole::Writer; type Scope = scopes::Write; }
+
+pub struct Editor;
+impl RequiredRole for Editor { const ROLE: CalendarRole = CalendarRole::Editor; type Scope = scopes::Write; }
+
+pub struct Owner;
+impl RequiredRole for Owner { const ROLE: CalendarRole = CalendarRole::Owner; type Scope = scopes::Write; }
+
+/// Request guard for routes that work on a calendar, the calendar's id must be
+/// the route's first parameter. Gets the request's `Principal` and checks that
+/// it has at least the role `R` on the calendar.
+///
+/// Responds with 404 if the principal can't access the calendar at all (or it
+/// doesn't exist) and with 403 if it doesn't have the role (`AuthError::MissingRole`).
+/// Authentication errors are kept in the request's local cache, see `auth_error`.
+pub struct CalendarAccess<R: RequiredRole>
+{
+    principal: Principal<R::Scope>,
+    calendar_id: Uuid,
+    role: CalendarRole,
+    _role: PhantomData<R>,
+}
+
+impl<R: RequiredRole> CalendarAccess<R>
+{
+    pub fn get_principal(&self) -> &Principal<R::Scope> { &self.principal }
+
+    pub fn get_calendar_id(&self) -> Uuid { self.calendar_id }
+
+    pub fn get_tenant_id(&self) -> Uuid { self.principal.get_tenant_id() }
+
+    /// The principal's actual role, which might be higher than `R`.
+    pub fn get_role(&self) -> CalendarRole { self.role }
+
+    /// Checks if the principal can change an event created by `created_by`
+    /// (an event's `created_by` column). Self-writers can only change their
+    /// own events.
+    pub fn can_change_event(&self, created_by: Option<&str>) -> bool
+    {
+        self.role >= CalendarRole::Writer
+            || (self.role == CalendarRole::SelfWriter && created_by == Some(self.principal.as_principal_ref().to_string().as_str()))
+    }
+}
+
+impl<'a, 'r, R: RequiredRole> FromRequest<'a, 'r> for CalendarAccess<R>
+{
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error>
+    {
+        let principal = request.guard::<Principal<R::Scope>>().map_failure(|(status, _)| (status, ()))?;
+
+        // Routes that serve a calendar's file have an `<id>.ics` or `<id>.xml` instead.
+        let calendar_id = match (request.get_param::<UuidParam>(0), request.get_param::<CalendarFileParam>(0))
+        {
+            (Some(Ok(calendar_id)), _) => calendar_id.into_inner(),
+            (_, Some(Ok(calendar_file))) => calendar_file.into_inner(),
+            _ => return Outcome::Forward(()),
+        };
+
+        let mut db = match request.guard::<PgsqlConn>()
+        {
+            Outcome::Success(db) => db,
+            _ => return unavailable(request),
+        };
+
+        let role = match principal.calendar_role(&mut **db, &calendar_id)
+        {
+            Ok(role) => role.into_outcome((Status::NotFound, ()))?,
+            Err(_) => return unavailable(request),
+        };
+
+        if role < R::ROLE
+        {
+            return auth_error::fail(request, AuthError::MissingRole(R::ROLE)).map_failure(|(status, _)| (status, ()));
+        }
+
+        let access = CalendarAccess {
+            principal,
+            calendar_id,
+            role,
+            _role: PhantomData,
+        };
+
+        // Self-writers can only access their own events. Events that
+        // don't exist are left for the route to respond with 404.
+        if let Some(Ok(event_id)) = request.get_param::<UuidParam>(1)
+        {
+            if R::ROLE == CalendarRole::SelfWriter && role == CalendarRole::SelfWriter
+            {
+                let rows = match db.query("SELECT created_by FROM events WHERE id = $1 AND calendar_id = $2;", &[&event_id, &calendar_id])
+                {
+                    Ok(rows) => rows,
+                    Err(_) => return unavailable(request),
+                };
+
+                if let Some(row) = rows.get(0)
+                {
+                    let created_by: Option<String> = row.get("created_by");
+
+                    if !access.can_change_event(created_by.as_deref())
+                    {
+                        return auth_error::fail(request, AuthError::MissingRole(CalendarRole::Writer)).map_failure(|(status, _)| (status, ()));
+                    }
+                }
+            }
+        }
+
+        Outcome::Success(access)
+    }
+}
+
+fn unavailable<S>(request: &Request) -> Outcome<S, ()>
+{
+    auth_error::fail(request, AuthError::Unavailable).map_failure(|(status, _)| (status, ()))
+}
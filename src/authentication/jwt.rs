@@ -0,0 +1,651 @@
+//! Validation of JWTs issued by an OpenID Connect provider (FusionAuth in
+//! the docker-compose setup, but any provider that publishes a JWKS works).
+//!
+//! Tokens have to be signed with RS256 or ES256 by one of the keys in the
+//! provider's JWKS, which is loaded from a URL or a local file (see the JWT
+//! configs). Besides the signature we check `exp`, `nbf`, `iss` and `aud`.
+
+use ring::signature::{RsaPublicKeyComponents, UnparsedPublicKey, RSA_PKCS1_2048_8192_SHA256, ECDSA_P256_SHA256_FIXED};
+use serde_json::{Map, Value};
+use chrono::Utc;
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use log::warn;
+
+/// How many seconds of clock skew between us and the provider are tolerated
+/// when checking `exp` and `nbf`.
+const LEEWAY_SECONDS: i64 = 60;
+
+/// Minimum time between two JWKS reloads. Tokens signed with an unknown key
+/// trigger a reload (the provider may have rotated its keys), this stops
+/// clients from making us fetch the JWKS on every request.
+const JWKS_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug, PartialEq)]
+pub enum JwtError
+{
+    #[error("Malformed token.")]
+    Malformed,
+
+    #[error("Unsupported signing algorithm.")]
+    UnsupportedAlgorithm,
+
+    #[error("The token was not signed by any known key.")]
+    UnknownKey,
+
+    #[error("Invalid signature.")]
+    InvalidSignature,
+
+    #[error("The token has expired.")]
+    Expired,
+
+    #[error("The token is not valid yet.")]
+    NotYetValid,
+
+    #[error("Invalid issuer.")]
+    InvalidIssuer,
+
+    #[error("Invalid audience.")]
+    InvalidAudience,
+
+    #[error("Missing or invalid claim {0}.")]
+    InvalidClaim(String),
+}
+
+#[derive(Error, Debug)]
+pub enum JwksError
+{
+    #[error("Failed to fetch the JWKS: {0}")]
+    Fetch(String),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// Where the JWKS is loaded from.
+#[derive(Debug, Clone)]
+pub enum JwksSource
+{
+    /// E.g. FusionAuth's `http://fusionauth:9011/.well-known/jwks.json`.
+    Url(String),
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Algorithm
+{
+    RS256,
+    ES256,
+}
+
+impl Algorithm
+{
+    fn from_str(alg: &str) -> Option<Algorithm>
+    {
+        match alg
+        {
+            "RS256" => Some(Algorithm::RS256),
+            "ES256" => Some(Algorithm::ES256),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum VerificationKey
+{
+    /// Big-endian modulus and exponent.
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+
+    /// Uncompressed P-256 point (0x04 || x || y).
+    P256 { point: Vec<u8> },
+}
+
+impl VerificationKey
+{
+    fn algorithm(&self) -> Algorithm
+    {
+        match self
+        {
+            VerificationKey::Rsa { .. } => Algorithm::RS256,
+            VerificationKey::P256 { .. } => Algorithm::ES256,
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool
+    {
+        match self
+        {
+            VerificationKey::Rsa { n, e } =>
+                RsaPublicKeyComponents { n, e }.verify(&RSA_PKCS1_2048_8192_SHA256, message, signature).is_ok(),
+
+            VerificationKey::P256 { point } =>
+                UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point).verify(message, signature).is_ok(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Jwk
+{
+    kid: Option<String>,
+    key: VerificationKey,
+}
+
+#[derive(Deserialize)]
+struct RawJwks
+{
+    keys: Vec<RawJwk>,
+}
+
+#[derive(Deserialize)]
+struct RawJwk
+{
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    key_use: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+impl RawJwk
+{
+    /// Returns None for keys we can't use to verify tokens (encryption
+    /// keys, unsupported key types or curves, invalid keys).
+    fn into_jwk(self) -> Option<Jwk>
+    {
+        if self.key_use.as_deref().map_or(false, |key_use| key_use != "sig")
+        {
+            return None;
+        }
+
+        let key = match (self.kty.as_str(), self.crv.as_deref())
+        {
+            ("RSA", _) => VerificationKey::Rsa {
+                n: decode_base64url(self.n.as_ref()?)?,
+                e: decode_base64url(self.e.as_ref()?)?,
+            },
+            ("EC", Some("P-256")) =>
+            {
+                let x = decode_base64url(self.x.as_ref()?)?;
+                let y = decode_base64url(self.y.as_ref()?)?;
+
+                if x.len() != 32 || y.len() != 32
+                {
+                    return None;
+                }
+
+                let mut point = vec![0x04];
+                point.extend(x);
+                point.extend(y);
+
+                VerificationKey::P256 { point }
+            },
+            _ => return None,
+        };
+
+        // A key that states its algorithm can only be used with that algorithm.
+        if let Some(alg) = &self.alg
+        {
+            if Algorithm::from_str(alg) != Some(key.algorithm())
+            {
+                return None;
+            }
+        }
+
+        Some(Jwk { kid: self.kid, key })
+    }
+}
+
+/// The provider's public keys.
+#[derive(Debug, Clone)]
+pub struct Jwks
+{
+    keys: Vec<Jwk>,
+}
+
+impl Jwks
+{
+    /// Parses a JWKS document. Keys that can't be used to verify
+    /// RS256 or ES256 signatures are ignored.
+    pub fn parse(json: &str) -> Result<Jwks, JwksError>
+    {
+        let raw: RawJwks = serde_json::from_str(json)?;
+
+        Ok(
+            Jwks {
+                keys: raw.keys.into_iter().filter_map(|k| k.into_jwk()).collect(),
+            }
+        )
+    }
+
+    pub fn load(source: &JwksSource) -> Result<Jwks, JwksError>
+    {
+        let json = match source
+        {
+            JwksSource::Url(url) => ureq::get(url)
+                .call()
+                .map_err(|e| JwksError::Fetch(e.to_string()))?
+                .into_string()?,
+            JwksSource::File(path) => std::fs::read_to_string(path)?,
+        };
+
+        Jwks::parse(&json)
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.keys.is_empty()
+    }
+
+    /// Finds the key a token was signed with. If the token has a `kid`
+    /// only the key with that id is considered.
+    fn find(&self, kid: Option<&str>, algorithm: Algorithm) -> Option<&VerificationKey>
+    {
+        self.keys
+            .iter()
+            .filter(|jwk| jwk.key.algorithm() == algorithm)
+            .find(|jwk| kid.map_or(true, |kid| jwk.kid.as_deref() == Some(kid)))
+            .map(|jwk| &jwk.key)
+    }
+}
+
+/// What a token must contain to be accepted and where
+/// the user's id and roles are.
+#[derive(Debug, Clone)]
+pub struct JwtSettings
+{
+    /// Expected `iss` claim.
+    pub issuer: String,
+
+    /// Expected `aud` claim. With FusionAuth this is the application's id.
+    pub audience: String,
+
+    /// Claim with the user's id.
+    pub user_id_claim: String,
+
+    /// Claim with the user's roles, an array of strings. Missing means no roles.
+    pub roles_claim: String,
+
+    /// Name of the tenant users belong to.
+    pub tenant: String,
+}
+
+/// The user a valid token was issued to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenUser
+{
+    pub user_id: String,
+    pub roles: Vec<String>,
+}
+
+/// Validates `token` and returns the user it was issued to. `now` is
+/// a unix timestamp.
+pub fn validate(token: &str, jwks: &Jwks, settings: &JwtSettings, now: i64) -> Result<TokenUser, JwtError>
+{
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3
+    {
+        return Err(JwtError::Malformed);
+    }
+
+    let header = decode_json_object(parts[0])?;
+    let algorithm = header.get("alg")
+        .and_then(|alg| alg.as_str())
+        .and_then(Algorithm::from_str)
+        .ok_or(JwtError::UnsupportedAlgorithm)?;
+    let kid = header.get("kid").and_then(|kid| kid.as_str());
+
+    let key = jwks.find(kid, algorithm).ok_or(JwtError::UnknownKey)?;
+
+    let signing_input = &token[..parts[0].len() + 1 + parts[1].len()];
+    let signature = decode_base64url(parts[2]).ok_or(JwtError::Malformed)?;
+
+    if !key.verify(signing_input.as_bytes(), &signature)
+    {
+        return Err(JwtError::InvalidSignature);
+    }
+
+    let claims = decode_json_object(parts[1])?;
+
+    let exp = claims.get("exp").and_then(|exp| exp.as_i64()).ok_or(JwtError::InvalidClaim("exp".to_owned()))?;
+    if now > exp + LEEWAY_SECONDS
+    {
+        return Err(JwtError::Expired);
+    }
+
+    if let Some(nbf) = claims.get("nbf")
+    {
+        let nbf = nbf.as_i64().ok_or(JwtError::InvalidClaim("nbf".to_owned()))?;
+        if now + LEEWAY_SECONDS < nbf
+        {
+            return Err(JwtError::NotYetValid);
+        }
+    }
+
+    if claims.get("iss").and_then(|iss| iss.as_str()) != Some(settings.issuer.as_str())
+    {
+        return Err(JwtError::InvalidIssuer);
+    }
+
+    // aud can be a string or an array of strings
+    let audience_matches = match claims.get("aud")
+    {
+        Some(Value::String(aud)) => aud == &settings.audience,
+        Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(settings.audience.as_str())),
+        _ => false,
+    };
+    if !audience_matches
+    {
+        return Err(JwtError::InvalidAudience);
+    }
+
+    let user_id = claims.get(&settings.user_id_claim)
+        .and_then(|id| id.as_str())
+        .ok_or_else(|| JwtError::InvalidClaim(settings.user_id_claim.clone()))?
+        .to_owned();
+
+    let roles = match claims.get(&settings.roles_claim)
+    {
+        None | Some(Value::Null) => vec![],
+        Some(Value::Array(roles)) => roles
+            .iter()
+            .map(|role| role.as_str().map(|role| role.to_owned()))
+            .collect::<Option<Vec<String>>>()
+            .ok_or_else(|| JwtError::InvalidClaim(settings.roles_claim.clone()))?,
+        Some(_) => return Err(JwtError::InvalidClaim(settings.roles_claim.clone())),
+    };
+
+    Ok(TokenUser { user_id, roles })
+}
+
+/// Validates tokens against the provider's JWKS, reloading it when a token
+/// is signed with an unknown key. Managed by Rocket (as an `Option`, None if
+/// JWT authentication is not configured) and used by the `User` guard.
+pub struct JwtAuthenticator
+{
+    source: JwksSource,
+    settings: JwtSettings,
+    jwks: RwLock<Jwks>,
+    last_load: Mutex<Instant>,
+}
+
+impl JwtAuthenticator
+{
+    /// Loads the JWKS right away, so that a misconfigured
+    /// source is noticed when the server starts.
+    pub fn new(source: JwksSource, settings: JwtSettings) -> Result<JwtAuthenticator, JwksError>
+    {
+        let jwks = Jwks::load(&source)?;
+
+        Ok(
+            JwtAuthenticator {
+                source,
+                settings,
+                jwks: RwLock::new(jwks),
+                last_load: Mutex::new(Instant::now()),
+            }
+        )
+    }
+
+    pub fn get_settings(&self) -> &JwtSettings
+    {
+        &self.settings
+    }
+
+    pub fn authenticate(&self, token: &str) -> Result<TokenUser, JwtError>
+    {
+        let now = Utc::now().timestamp();
+
+        let result = validate(token, &self.jwks.read().unwrap(), &self.settings, now);
+
+        if result == Err(JwtError::UnknownKey) && self.reload()
+        {
+            validate(token, &self.jwks.read().unwrap(), &self.settings, now)
+        }
+        else
+        {
+            result
+        }
+    }
+
+    /// Reloads the JWKS unless it was loaded less than `JWKS_RELOAD_INTERVAL`
+    /// ago. Returns whether the keys were reloaded.
+    fn reload(&self) -> bool
+    {
+        let mut last_load = self.last_load.lock().unwrap();
+        if last_load.elapsed() < JWKS_RELOAD_INTERVAL
+        {
+            return false;
+        }
+        *last_load = Instant::now();
+
+        match Jwks::load(&self.source)
+        {
+            Ok(jwks) =>
+            {
+                *self.jwks.write().unwrap() = jwks;
+                true
+            },
+            Err(e) =>
+            {
+                warn!("Failed to reload the JWKS: {}", e);
+                false
+            },
+        }
+    }
+}
+
+fn decode_base64url(data: &str) -> Option<Vec<u8>>
+{
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD).ok()
+}
+
+fn decode_json_object(data: &str) -> Result<Map<String, Value>, JwtError>
+{
+    let bytes = decode_base64url(data).ok_or(JwtError::Malformed)?;
+
+    match serde_json::from_slice(&bytes)
+    {
+        Ok(Value::Object(object)) => Ok(object),
+        _ => Err(JwtError::Malformed),
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{validate, Jwks, JwksSource, JwtSettings, JwtError, TokenUser};
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::{json, Value};
+
+    const NOW: i64 = 1_600_000_000;
+
+    fn encode(data: &[u8]) -> String
+    {
+        base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn generate_key() -> EcdsaKeyPair
+    {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap()
+    }
+
+    fn jwks_json(key: &EcdsaKeyPair, kid: &str) -> String
+    {
+        let point = key.public_key().as_ref();
+
+        json!({
+            "keys": [{
+                "kty": "EC",
+                "crv": "P-256",
+                "use": "sig",
+                "kid": kid,
+                "x": encode(&point[1..33]),
+                "y": encode(&point[33..65]),
+            }]
+        }).to_string()
+    }
+
+    fn sign(key: &EcdsaKeyPair, header: Value, claims: Value) -> String
+    {
+        let signing_input = format!("{}.{}", encode(header.to_string().as_bytes()), encode(claims.to_string().as_bytes()));
+        let signature = key.sign(&SystemRandom::new(), signing_input.as_bytes()).unwrap();
+
+        format!("{}.{}", signing_input, encode(signature.as_ref()))
+    }
+
+    fn settings() -> JwtSettings
+    {
+        JwtSettings {
+            issuer: "https://auth.example.com".to_owned(),
+            audience: "calendar-app".to_owned(),
+            user_id_claim: "sub".to_owned(),
+            roles_claim: "roles".to_owned(),
+            tenant: "default".to_owned(),
+        }
+    }
+
+    fn claims() -> Value
+    {
+        json!({
+            "iss": "https://auth.example.com",
+            "aud": "calendar-app",
+            "sub": "8a3d1f4e-2b6c-4f1a-9e7d-5c2b1a0f3e4d",
+            "exp": NOW + 3600,
+            "roles": ["admin", "user"],
+        })
+    }
+
+    fn header() -> Value
+    {
+        json!({ "alg": "ES256", "typ": "JWT", "kid": "key-1" })
+    }
+
+    #[test]
+    fn valid_token()
+    {
+        let key = generate_key();
+        let jwks = Jwks::parse(&jwks_json(&key, "key-1")).unwrap();
+
+        let token = sign(&key, header(), claims());
+
+        assert_eq!(
+            validate(&token, &jwks, &settings(), NOW),
+            Ok(TokenUser {
+                user_id: "8a3d1f4e-2b6c-4f1a-9e7d-5c2b1a0f3e4d".to_owned(),
+                roles: vec!["admin".to_owned(), "user".to_owned()],
+            })
+        );
+
+        let mut claims = claims();
+        claims["aud"] = json!(["other-app", "calendar-app"]);
+        claims.as_object_mut().unwrap().remove("roles");
+        let token = sign(&key, header(), claims);
+
+        assert_eq!(validate(&token, &jwks, &settings(), NOW).unwrap().roles, Vec::<String>::new());
+    }
+
+    #[test]
+    fn invalid_signatures()
+    {
+        let key = generate_key();
+        let other_key = generate_key();
+        let jwks = Jwks::parse(&jwks_json(&key, "key-1")).unwrap();
+
+        let token = sign(&other_key, header(), claims());
+        assert_eq!(validate(&token, &jwks, &settings(), NOW), Err(JwtError::InvalidSignature));
+
+        // Swap the payload of a valid token.
+        let token = sign(&key, header(), claims());
+        let mut claims = claims();
+        claims["roles"] = json!(["super-admin"]);
+        let parts: Vec<&str> = token.split('.').collect();
+        let tampered = format!("{}.{}.{}", parts[0], encode(claims.to_string().as_bytes()), parts[2]);
+        assert_eq!(validate(&tampered, &jwks, &settings(), NOW), Err(JwtError::InvalidSignature));
+
+        let token = sign(&key, json!({ "alg": "ES256", "kid": "key-2" }), claims);
+        assert_eq!(validate(&token, &jwks, &settings(), NOW), Err(JwtError::UnknownKey));
+    }
+
+    #[test]
+    fn unsupported_algorithms()
+    {
+        let key = generate_key();
+        let jwks = Jwks::parse(&jwks_json(&key, "key-1")).unwrap();
+
+        let unsigned = format!("{}.{}.", encode(json!({ "alg": "none" }).to_string().as_bytes()), encode(claims().to_string().as_bytes()));
+        assert_eq!(validate(&unsigned, &jwks, &settings(), NOW), Err(JwtError::UnsupportedAlgorithm));
+
+        let token = sign(&key, json!({ "alg": "HS256", "kid": "key-1" }), claims());
+        assert_eq!(validate(&token, &jwks, &settings(), NOW), Err(JwtError::UnsupportedAlgorithm));
+
+        // An ES256 key can't verify RS256 tokens.
+        let token = sign(&key, json!({ "alg": "RS256", "kid": "key-1" }), claims());
+        assert_eq!(validate(&token, &jwks, &settings(), NOW), Err(JwtError::UnknownKey));
+
+        assert_eq!(validate("not a token", &jwks, &settings(), NOW), Err(JwtError::Malformed));
+    }
+
+    #[test]
+    fn invalid_claims()
+    {
+        let key = generate_key();
+        let jwks = Jwks::parse(&jwks_json(&key, "key-1")).unwrap();
+
+        let with = |name: &str, value: Value| {
+            let mut claims = claims();
+            claims[name] = value;
+            sign(&key, header(), claims)
+        };
+
+        assert_eq!(validate(&with("exp", json!(NOW - 120)), &jwks, &settings(), NOW), Err(JwtError::Expired));
+        assert!(validate(&with("exp", json!(NOW - 30)), &jwks, &settings(), NOW).is_ok());
+        assert_eq!(validate(&with("nbf", json!(NOW + 120)), &jwks, &settings(), NOW), Err(JwtError::NotYetValid));
+        assert_eq!(validate(&with("iss", json!("https://evil.example.com")), &jwks, &settings(), NOW), Err(JwtError::InvalidIssuer));
+        assert_eq!(validate(&with("aud", json!("other-app")), &jwks, &settings(), NOW), Err(JwtError::InvalidAudience));
+        assert_eq!(validate(&with("sub", json!(42)), &jwks, &settings(), NOW), Err(JwtError::InvalidClaim("sub".to_owned())));
+        assert_eq!(validate(&with("roles", json!("admin")), &jwks, &settings(), NOW), Err(JwtError::InvalidClaim("roles".to_owned())));
+    }
+
+    #[test]
+    fn jwks_from_file()
+    {
+        let key = generate_key();
+        let path = std::env::temp_dir().join(format!("jwks-test-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, jwks_json(&key, "key-1")).unwrap();
+
+        let jwks = Jwks::load(&JwksSource::File(path.clone())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let token = sign(&key, header(), claims());
+        assert!(validate(&token, &jwks, &settings(), NOW).is_ok());
+    }
+
+    #[test]
+    fn unusable_keys_are_ignored()
+    {
+        let jwks = Jwks::parse(r#"{
+            "keys": [
+                { "kty": "oct", "k": "c2VjcmV0" },
+                { "kty": "EC", "crv": "P-384", "x": "AA", "y": "AA" },
+                { "kty": "RSA", "use": "enc", "n": "AQAB", "e": "AQAB" }
+            ]
+        }"#).unwrap();
+
+        assert!(jwks.is_empty());
+    }
+}
@@ -0,0 +1,144 @@
+//! API keys are not stored, only a prefix to look them up and a salted
+//! SHA-256 hash of the whole key (see db_schema/11.sql).
+//!
+//! Keys look like `<prefix>.<secret>`, where the prefix is 8 and the secret
+//! 64 hexadecimal characters. Keys created before hashing was introduced are
+//! UUIDs, their prefix is the UUID's first 8 characters.
+
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::constant_time::verify_slices_are_equal;
+use crate::encoding_helpers::to_hex;
+use uuid::Uuid;
+use std::str::FromStr;
+
+const PREFIX_BYTES: usize = 4;
+const SECRET_BYTES: usize = 32;
+const SALT_BYTES: usize = 16;
+
+/// A newly generated key. `key` is what clients send, the
+/// other fields are what gets stored.
+pub struct GeneratedKey
+{
+    pub key: String,
+    pub prefix: String,
+    pub salt: Vec<u8>,
+    pub hash: Vec<u8>,
+}
+
+#[derive(Error, Debug)]
+#[error("Failed to generate random bytes.")]
+pub struct RandomError;
+
+pub fn generate() -> Result<GeneratedKey, RandomError>
+{
+    let rng = SystemRandom::new();
+
+    let prefix = to_hex(&random_bytes(&rng, PREFIX_BYTES)?);
+    let secret = to_hex(&random_bytes(&rng, SECRET_BYTES)?);
+    let salt = random_bytes(&rng, SALT_BYTES)?;
+
+    let key = format!("{}.{}", prefix, secret);
+    let hash = hash(&salt, &key);
+
+    Ok(GeneratedKey { key, prefix, salt, hash })
+}
+
+/// Normalizes a key sent by a client and splits its prefix off. Returns
+/// `(prefix, normalized key)`, or None if `key` is not a key at all.
+pub fn parse(key: &str) -> Option<(String, String)>
+{
+    let key = key.trim();
+
+    if let Some(dot) = key.find('.')
+    {
+        let (prefix, secret) = (&key[..dot], &key[dot + 1..]);
+
+        if prefix.len() != PREFIX_BYTES * 2 || secret.is_empty()
+        {
+            return None;
+        }
+
+        Some((prefix.to_owned(), key.to_owned()))
+    }
+    else
+    {
+        // Legacy key. UUIDs were compared as UUIDs, not strings, so
+        // any format Uuid accepts has to keep working.
+        let key = Uuid::from_str(key).ok()?.to_string();
+        Some((key[..PREFIX_BYTES * 2].to_owned(), key))
+    }
+}
+
+/// sha256(salt || key), same as pgcrypto's `digest(salt || key, 'sha256')`.
+pub fn hash(salt: &[u8], key: &str) -> Vec<u8>
+{
+    let mut data = salt.to_vec();
+    data.extend_from_slice(key.as_bytes());
+
+    digest(&SHA256, &data).as_ref().to_vec()
+}
+
+/// Checks `key` against a stored salt and hash in constant time.
+pub fn verify(key: &str, salt: &[u8], stored_hash: &[u8]) -> bool
+{
+    verify_slices_are_equal(&hash(salt, key), stored_hash).is_ok()
+}
+
+fn random_bytes(rng: &SystemRandom, len: usize) -> Result<Vec<u8>, RandomError>
+{
+    let mut bytes = vec![0; len];
+    rng.fill(&mut bytes).map_err(|_| RandomError)?;
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{generate, parse, hash, verify};
+
+    #[test]
+    fn generated_keys_verify()
+    {
+        let generated = generate().unwrap();
+        let (prefix, key) = parse(&generated.key).unwrap();
+
+        assert_eq!(prefix, generated.prefix);
+        assert!(verify(&key, &generated.salt, &generated.hash));
+
+        let mut wrong_key = key.clone();
+        wrong_key.pop();
+        wrong_key.push('x');
+        assert!(!verify(&wrong_key, &generated.salt, &generated.hash));
+        assert!(!verify(&key, b"other salt", &generated.hash));
+    }
+
+    #[test]
+    fn legacy_keys()
+    {
+        let (prefix, key) = parse("7D104549-8953-459B-A69B-8EF268A47170").unwrap();
+
+        assert_eq!(prefix, "7d104549");
+        assert_eq!(key, "7d104549-8953-459b-a69b-8ef268a47170");
+    }
+
+    #[test]
+    fn invalid_keys()
+    {
+        assert!(parse("").is_none());
+        assert!(parse("not a key").is_none());
+        assert!(parse("abc.def").is_none());
+        assert!(parse("7d104549.").is_none());
+    }
+
+    #[test]
+    fn known_hash()
+    {
+        // echo -n "saltkey" | sha256sum
+        assert_eq!(
+            crate::encoding_helpers::to_hex(&hash(b"salt", "key")),
+            "4a466ea0657e479545b1d6c2d994824f80d8eecd7030f3092ff42a9bcad751d8"
+        );
+    }
+}
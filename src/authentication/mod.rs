@@ -1 +1,9 @@
-pub mod auth_guard;
\ No newline at end of file
+pub mod auth_guard;
+pub mod auth_error;
+pub mod scopes;
+pub mod key_hashing;
+pub mod jwt;
+pub mod user_guard;
+pub mod principal;
+pub mod calendar_access;
+pub mod openapi_security;
\ No newline at end of file
@@ -0,0 +1,172 @@
+//! rocket_okapi doesn't know about our `ApiKey` guard, so the OpenAPI spec it
+//! generates has no security information. `OpenApiSecurity` is a fairing that
+//! adds it to the spec's response: an `ApiKey` security scheme and, for every
+//! route with a required scope, a security requirement plus an `x-required-scope`
+//! extension with the scope's name.
+//!
+//! Scopes are taken from a table of route names (see `routes::ROUTE_SCOPES`), since
+//! there's no way to get a route's guards at runtime.
+
+use rocket::{Request, Response, Route};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Method, Status};
+use serde_json::{json, Value};
+use std::io::Cursor;
+use crate::authentication::scopes::Scope;
+
+pub const SECURITY_SCHEME_NAME: &str = "ApiKey";
+
+pub struct OpenApiSecurity
+{
+    /// Path the spec is served at, e.g. `/api/openapi.json`.
+    spec_path: String,
+
+    /// (path as in the spec, lowercase method, required scope)
+    operations: Vec<(String, String, Scope)>,
+}
+
+impl OpenApiSecurity
+{
+    /// `routes` must not be mounted yet, so that their paths are the same
+    /// as the ones in the spec. Routes that are not in `route_scopes` are left
+    /// without a security requirement.
+    pub fn new(spec_path: &str, routes: &[Route], route_scopes: &[(&str, Scope)]) -> OpenApiSecurity
+    {
+        let operations = routes
+            .iter()
+            .filter_map(|route| {
+                let name = route.name?;
+                let (_, scope) = route_scopes.iter().find(|(n, _)| *n == name)?;
+
+                Some((to_openapi_path(route.uri.path()), route.method.as_str().to_lowercase(), *scope))
+            })
+            .collect();
+
+        OpenApiSecurity {
+            spec_path: spec_path.to_owned(),
+            operations,
+        }
+    }
+
+    fn add_security(&self, spec: &mut Value)
+    {
+        spec["components"]["securitySchemes"][SECURITY_SCHEME_NAME] = json!({
+            "type": "apiKey",
+            "in": "header",
+            "name": "Authorization",
+            "description": "An API key. See docs/scopes.md for the scopes each key can have.",
+        });
+
+        for (path, method, scope) in self.operations.iter()
+        {
+            let operation = spec
+                .get_mut("paths")
+                .and_then(|paths| paths.get_mut(path))
+                .and_then(|path| path.get_mut(method));
+
+            if let Some(operation) = operation
+            {
+                operation["security"] = json!([{ SECURITY_SCHEME_NAME: [] }]);
+                operation["x-required-scope"] = json!(scope.as_str());
+            }
+        }
+    }
+}
+
+impl Fairing for OpenApiSecurity
+{
+    fn info(&self) -> Info
+    {
+        Info {
+            name: "OpenAPI security",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response)
+    {
+        if request.method() != Method::Get
+            || request.uri().path() != self.spec_path
+            || response.status() != Status::Ok
+        {
+            return;
+        }
+
+        if let Some(body) = response.body_string()
+        {
+            match serde_json::from_str::<Value>(&body)
+            {
+                Ok(mut spec) =>
+                {
+                    self.add_security(&mut spec);
+                    response.set_sized_body(Cursor::new(spec.to_string()));
+                },
+
+                // Put the original body back, body_string() took it.
+                Err(_) => response.set_sized_body(Cursor::new(body)),
+            }
+        }
+    }
+}
+
+/// Turns a Rocket path like `/calendars/<calendar_id>` into
+/// an OpenAPI path like `/calendars/{calendar_id}`.
+fn to_openapi_path(path: &str) -> String
+{
+    path
+        .split('/')
+        .map(|segment| {
+            if segment.starts_with('<') && segment.ends_with('>')
+            {
+                format!("{{{}}}", segment[1..segment.len() - 1].trim_end_matches(".."))
+            }
+            else
+            {
+                segment.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{to_openapi_path, OpenApiSecurity};
+    use crate::authentication::scopes::Scope;
+    use serde_json::json;
+
+    #[test]
+    fn openapi_paths()
+    {
+        assert_eq!(to_openapi_path("/calendars"), "/calendars");
+        assert_eq!(to_openapi_path("/calendars/<calendar_id>/events/<event_id>"), "/calendars/{calendar_id}/events/{event_id}");
+        assert_eq!(to_openapi_path("/files/<path..>"), "/files/{path}");
+    }
+
+    #[test]
+    fn adds_security()
+    {
+        let security = OpenApiSecurity {
+            spec_path: "/api/openapi.json".to_owned(),
+            operations: vec![("/calendars/{calendar_id}".to_owned(), "delete".to_owned(), Scope::Write)],
+        };
+
+        let mut spec = json!({
+            "paths": {
+                "/calendars/{calendar_id}": {
+                    "get": {},
+                    "delete": {},
+                }
+            }
+        });
+
+        security.add_security(&mut spec);
+
+        let path = &spec["paths"]["/calendars/{calendar_id}"];
+        assert_eq!(path["delete"]["x-required-scope"], "WRITE");
+        assert_eq!(path["delete"]["security"], json!([{ "ApiKey": [] }]));
+        assert!(path["get"].get("security").is_none());
+        assert_eq!(spec["components"]["securitySchemes"]["ApiKey"]["type"], "apiKey");
+    }
+}
@@ -0,0 +1,169 @@
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use crate::authentication::auth_guard::ApiKey;
+use crate::authentication::auth_error::{self, AuthError, AuthScheme};
+use crate::authentication::user_guard::User;
+use crate::authentication::scopes::{Scope, Scopes, RequiredScope};
+use crate::acl::{self, CalendarRole, PrincipalRef, PrincipalType};
+use crate::database_error::DatabaseError;
+use crate::tenants;
+use postgres::GenericClient;
+use uuid::Uuid;
+
+/// Request guard for routes that can be used with either an API key or
+/// a user's JWT (`Authorization: Bearer <JWT>`). API keys need the scope
+/// `S`. Users can use any `READ` or `WRITE` route, what they can do on each
+/// calendar depends on their grants, see `acl`.
+pub enum Principal<S: RequiredScope>
+{
+    ApiKey(ApiKey<S>),
+    User(User),
+}
+
+impl<S: RequiredScope> Principal<S>
+{
+    pub fn get_tenant_id(&self) -> Uuid
+    {
+        match self
+        {
+            Principal::ApiKey(key) => key.get_tenant_id(),
+            Principal::User(user) => user.get_tenant_id(),
+        }
+    }
+
+    /// How the principal is referred to in grants and in `events.created_by`.
+    pub fn as_principal_ref(&self) -> PrincipalRef
+    {
+        match self
+        {
+            Principal::ApiKey(key) => PrincipalRef::new(PrincipalType::ApiKey, key.get_id().to_string()),
+            Principal::User(user) => PrincipalRef::new(PrincipalType::User, user.get_id()),
+        }
+    }
+
+    /// The principal plus, for users, the groups they're in (their roles).
+    /// Grants to any of these apply to the principal.
+    pub fn acl_principals(&self) -> Vec<PrincipalRef>
+    {
+        let mut principals = vec![self.as_principal_ref()];
+
+        if let Principal::User(user) = self
+        {
+            principals.extend(
+                user.get_roles()
+                    .iter()
+                    .map(|role| PrincipalRef::new(PrincipalType::Group, role.as_str()))
+            );
+        }
+
+        principals
+    }
+
+    /// The principal's role on the calendar, None if it can't access the
+    /// calendar at all (or the calendar doesn't exist).
+    ///
+    /// API keys that can access the calendar without a grant (because they're
+    /// not restricted to some calendars, or the calendar is one of them) get
+    /// the role of their scopes, see `role_of_scopes`.
+    pub fn calendar_role(&self, db: &mut impl GenericClient, calendar_id: &Uuid) -> Result<Option<CalendarRole>, DatabaseError>
+    {
+        if !tenants::owns_calendar(db, &self.get_tenant_id(), calendar_id)?
+        {
+            return Ok(None);
+        }
+
+        if let Principal::ApiKey(key) = self
+        {
+            if key.allows_calendar(calendar_id)
+            {
+                return Ok(Some(role_of_scopes(key.get_scopes())));
+            }
+        }
+
+        acl::granted_role(db, calendar_id, &self.acl_principals())
+    }
+
+    /// The calendars the principal can see, None if it can
+    /// see all of its tenant's calendars.
+    pub fn visible_calendar_ids(&self, db: &mut impl GenericClient) -> Result<Option<Vec<Uuid>>, DatabaseError>
+    {
+        let mut calendar_ids = match self
+        {
+            Principal::ApiKey(key) => match key.get_calendar_ids()
+            {
+                None => return Ok(None),
+                Some(calendar_ids) => calendar_ids.clone(),
+            },
+            Principal::User(_) => vec![],
+        };
+
+        calendar_ids.extend(acl::granted_calendar_ids(db, &self.get_tenant_id(), &self.acl_principals())?);
+        calendar_ids.sort();
+        calendar_ids.dedup();
+
+        Ok(Some(calendar_ids))
+    }
+}
+
+/// The role API keys with the scopes have on the calendars they can access
+/// without a grant. Only `SUPER` keys are owners, so that managing grants and
+/// feeds and deleting calendars needs a `SUPER` key. `WEBHOOKS` keys are
+/// readers, subscriptions send them the calendar's events.
+fn role_of_scopes(scopes: Scopes) -> CalendarRole
+{
+    if scopes.contains(Scope::Super)
+    {
+        CalendarRole::Owner
+    }
+    else if scopes.contains(Scope::Write)
+    {
+        CalendarRole::Editor
+    }
+    else
+    {
+        CalendarRole::Reader
+    }
+}
+
+impl<'a, 'r, S: RequiredScope> FromRequest<'a, 'r> for Principal<S>
+{
+    type Error = AuthError;
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error>
+    {
+        let is_bearer = request.headers()
+            .get_one("Authorization")
+            .map_or(false, |x| x.starts_with("Bearer "));
+
+        if !is_bearer
+        {
+            return request.guard::<ApiKey<S>>().map(Principal::ApiKey);
+        }
+
+        let user = request.guard::<User>()?;
+
+        // SUPER and WEBHOOKS are for API keys only.
+        match S::SCOPE
+        {
+            Scope::Read | Scope::Write => Outcome::Success(Principal::User(user)),
+            scope => auth_error::fail(request, AuthError::MissingScope(AuthScheme::Bearer, scope)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::role_of_scopes;
+    use crate::authentication::scopes::Scopes;
+    use crate::acl::CalendarRole;
+
+    #[test]
+    fn only_super_keys_are_owners()
+    {
+        assert_eq!(role_of_scopes(Scopes::parse(&["SUPER"]).unwrap()), CalendarRole::Owner);
+        assert_eq!(role_of_scopes(Scopes::parse(&["WRITE", "WEBHOOKS"]).unwrap()), CalendarRole::Editor);
+        assert_eq!(role_of_scopes(Scopes::parse(&["READ"]).unwrap()), CalendarRole::Reader);
+        assert_eq!(role_of_scopes(Scopes::parse(&["WEBHOOKS"]).unwrap()), CalendarRole::Reader);
+    }
+}
@@ -0,0 +1,139 @@
+//! API key scopes, see docs/scopes.md.
+//!
+//! Routes declare the scope they require with the type parameter of
+//! their `ApiKey` guard, e.g. `ApiKey<Write>`. Some scopes imply others,
+//! `SUPER` implies every scope and `WRITE` implies `READ`.
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Scope
+{
+    Read,
+    Write,
+    Super,
+    Webhooks,
+}
+
+impl Scope
+{
+    pub const ALL: [Scope; 4] = [Scope::Read, Scope::Write, Scope::Super, Scope::Webhooks];
+
+    /// The scope's name, as stored in the `scopes` column of `api_keys`.
+    pub fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            Scope::Read => "READ",
+            Scope::Write => "WRITE",
+            Scope::Super => "SUPER",
+            Scope::Webhooks => "WEBHOOKS",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<Scope>
+    {
+        Scope::ALL.iter().copied().find(|scope| scope.as_str() == s)
+    }
+
+    fn bit(&self) -> u32
+    {
+        match self
+        {
+            Scope::Read => 1 << 0,
+            Scope::Write => 1 << 1,
+            Scope::Super => 1 << 2,
+            Scope::Webhooks => 1 << 3,
+        }
+    }
+
+    /// The scope's bit plus the bits of all scopes it implies.
+    fn implied_bits(&self) -> u32
+    {
+        match self
+        {
+            Scope::Super => Scope::ALL.iter().fold(0, |bits, scope| bits | scope.bit()),
+            Scope::Write => Scope::Write.bit() | Scope::Read.bit(),
+            scope => scope.bit(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("Invalid scope: {0}.")]
+pub struct InvalidScopeError(pub String);
+
+/// A set of scopes, stored as a bitmask.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Default)]
+pub struct Scopes(u32);
+
+impl Scopes
+{
+    /// Parses scope names. Scopes implied by the ones in `names`
+    /// are included as well.
+    pub fn parse<S: AsRef<str>>(names: &[S]) -> Result<Scopes, InvalidScopeError>
+    {
+        let mut scopes = Scopes::default();
+
+        for name in names
+        {
+            let scope = Scope::from_str(name.as_ref())
+                .ok_or_else(|| InvalidScopeError(name.as_ref().to_owned()))?;
+
+            scopes.0 |= scope.implied_bits();
+        }
+
+        Ok(scopes)
+    }
+
+    pub fn contains(&self, scope: Scope) -> bool
+    {
+        self.0 & scope.bit() != 0
+    }
+}
+
+
+
+/// A scope a route requires, used as the type parameter of `ApiKey`.
+pub trait RequiredScope
+{
+    const SCOPE: Scope;
+}
+
+pub struct Read;
+pub struct Write;
+pub struct Super;
+pub struct Webhooks;
+
+impl RequiredScope for Read { const SCOPE: Scope = Scope::Read; }
+impl RequiredScope for Write { const SCOPE: Scope = Scope::Write; }
+impl RequiredScope for Super { const SCOPE: Scope = Scope::Super; }
+impl RequiredScope for Webhooks { const SCOPE: Scope = Scope::Webhooks; }
+
+#[cfg(test)]
+mod test
+{
+    use super::{Scope, Scopes};
+
+    #[test]
+    fn implied_scopes()
+    {
+        let write = Scopes::parse(&["WRITE"]).unwrap();
+        assert!(write.contains(Scope::Read));
+        assert!(write.contains(Scope::Write));
+        assert!(!write.contains(Scope::Super));
+        assert!(!write.contains(Scope::Webhooks));
+
+        let read = Scopes::parse(&["READ"]).unwrap();
+        assert!(!read.contains(Scope::Write));
+
+        let super_ = Scopes::parse(&["SUPER"]).unwrap();
+        assert!(Scope::ALL.iter().all(|scope| super_.contains(*scope)));
+    }
+
+    #[test]
+    fn invalid_scopes()
+    {
+        assert!(Scopes::parse(&["READ", "write"]).is_err());
+        assert!(Scopes::parse(&["ADMIN"]).is_err());
+        assert_eq!(Scopes::parse::<&str>(&[]).unwrap(), Scopes::default());
+    }
+}
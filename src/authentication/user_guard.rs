@@ -0,0 +1,112 @@
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Request, State};
+use crate::authentication::jwt::{JwtAuthenticator, JwtError};
+use crate::authentication::auth_error::{self, AuthError, AuthScheme};
+use crate::connection_pool::PgsqlConn;
+use crate::rate_limit;
+use crate::tenants;
+use uuid::Uuid;
+use log::error;
+
+/// Request guard for routes used on behalf of a user, authenticated with
+/// an `Authorization: Bearer <JWT>` header issued by the OpenID Connect
+/// provider (see `jwt`). Fails with an `AuthError`, 401 if the token is
+/// missing or invalid, or if JWT authentication is not configured.
+pub struct User
+{
+    /// The user's id in the provider, taken from the
+    /// configured user id claim (`sub` by default).
+    id: String,
+
+    /// The tenant the user belongs to, all users belong
+    /// to the tenant set in the `JWT_TENANT` config.
+    tenant_id: Uuid,
+
+    roles: Vec<String>,
+}
+
+impl User
+{
+    pub fn get_id(&self) -> &str { &self.id }
+
+    pub fn get_tenant_id(&self) -> Uuid { self.tenant_id }
+
+    pub fn get_roles(&self) -> &Vec<String> { &self.roles }
+
+    pub fn has_role(&self, role: &str) -> bool
+    {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for User
+{
+    type Error = AuthError;
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error>
+    {
+        let token = match request.headers().get_one("Authorization")
+        {
+            Some(header) => match header.strip_prefix("Bearer ")
+            {
+                Some(token) => token.trim(),
+                None => return auth_error::fail(request, AuthError::Malformed(AuthScheme::Bearer)),
+            },
+            None => return auth_error::fail(request, AuthError::Missing),
+        };
+
+        let authenticator = match request.guard::<State<Option<JwtAuthenticator>>>()
+        {
+            Outcome::Success(authenticator) => authenticator.inner().as_ref(),
+            _ => None,
+        };
+
+        // Bearer tokens are unknown credentials if JWT authentication is not configured.
+        let authenticator = match authenticator
+        {
+            Some(authenticator) => authenticator,
+            None => return auth_error::fail(request, AuthError::Unknown(AuthScheme::Bearer)),
+        };
+
+        let user = match authenticator.authenticate(token)
+        {
+            Ok(user) => user,
+            Err(JwtError::Malformed) => return auth_error::fail(request, AuthError::Malformed(AuthScheme::Bearer)),
+            Err(JwtError::Expired) => return auth_error::fail(request, AuthError::Expired(AuthScheme::Bearer)),
+            Err(_) => return auth_error::fail(request, AuthError::Unknown(AuthScheme::Bearer)),
+        };
+
+        let mut db = match request.guard::<PgsqlConn>()
+        {
+            Outcome::Success(db) => db,
+            _ => return auth_error::fail(request, AuthError::Unavailable),
+        };
+
+        // If the configured tenant doesn't exist no user can be authenticated. It's
+        // checked when the server starts, but it could've been deleted since.
+        let tenant_id = match tenants::get_id_by_name(&mut **db, &authenticator.get_settings().tenant)
+        {
+            Ok(Some(tenant_id)) => tenant_id,
+            Ok(None) =>
+            {
+                error!("The tenant of JWT users ({}) doesn't exist.", authenticator.get_settings().tenant);
+                return auth_error::fail(request, AuthError::Unavailable);
+            },
+            Err(_) => return auth_error::fail(request, AuthError::Unavailable),
+        };
+
+        // Users always get the default rate limit.
+        if let Err(error) = rate_limit::limit_request(request, &mut **db, &format!("user:{}", user.user_id), None)
+        {
+            return auth_error::fail(request, error);
+        }
+
+        Outcome::Success(
+            User {
+                id: user.user_id,
+                tenant_id,
+                roles: user.roles,
+            }
+        )
+    }
+}
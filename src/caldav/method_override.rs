@@ -0,0 +1,44 @@
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use rocket::http::Method;
+use std::marker::PhantomData;
+
+/// The header `proxy` sends the method of requests Rocket can't route in.
+pub const HEADER: &str = "X-HTTP-Method-Override";
+
+/// Implemented by the marker types used as `MethodOverride`'s type parameter.
+pub trait DavMethod
+{
+    const NAME: &'static str;
+}
+
+pub struct Propfind;
+impl DavMethod for Propfind { const NAME: &'static str = "PROPFIND"; }
+
+pub struct Report;
+impl DavMethod for Report { const NAME: &'static str = "REPORT"; }
+
+/// Request guard for `POST` routes that handle the WebDAV method `M` instead,
+/// see `proxy`. Forwards unless the request is a `POST` with `M` in the
+/// `X-HTTP-Method-Override` header, so the same path can have a route for
+/// each method.
+pub struct MethodOverride<M: DavMethod>(PhantomData<M>);
+
+impl<'a, 'r, M: DavMethod> FromRequest<'a, 'r> for MethodOverride<M>
+{
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error>
+    {
+        let method = request.headers().get_one(HEADER);
+
+        if request.method() == Method::Post && method.map_or(false, |method| method.eq_ignore_ascii_case(M::NAME))
+        {
+            Outcome::Success(MethodOverride(PhantomData))
+        }
+        else
+        {
+            Outcome::Forward(())
+        }
+    }
+}
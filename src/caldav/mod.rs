@@ -0,0 +1,53 @@
+//! A CalDAV server (RFC 4791), so that calendar apps (Apple Calendar,
+//! Thunderbird, DAVx⁵...) can sync with the server's calendars directly.
+//! See docs/caldav.md.
+//!
+//! Resources, under `/dav`:
+//!
+//! - `/principal/`: the principal of the request's API key or user.
+//! - `/calendars/`: the calendar home, with the calendars the principal can see.
+//! - `/calendars/<calendar-id>/`: a calendar collection.
+//! - `/calendars/<calendar-id>/<name>`: an event series as a calendar object
+//!   resource, see `objects`.
+//!
+//! Rocket can't route WebDAV's methods, so CalDAV clients connect to `proxy`,
+//! which turns them into `POST` requests that the routes recognize.
+
+pub mod proxy;
+mod method_override;
+mod xml;
+mod objects;
+mod responders;
+mod routes;
+
+use rocket::Route;
+
+pub use routes::MOUNT_POINT;
+
+/// The routes to mount at `MOUNT_POINT`.
+pub fn get_routes() -> Vec<Route>
+{
+    routes![
+        routes::propfind_root,
+        routes::propfind_principal,
+        routes::propfind_home,
+        routes::propfind_calendar,
+        routes::propfind_object,
+        routes::report_calendar,
+        routes::unsupported_method,
+        routes::get_object,
+        routes::put_object,
+        routes::delete_object,
+        routes::options_root,
+        routes::options,
+    ]
+}
+
+/// The routes to mount at `/`, where clients look for the server.
+pub fn get_well_known_routes() -> Vec<Route>
+{
+    routes![
+        routes::well_known,
+        routes::well_known_propfind,
+    ]
+}
@@ -0,0 +1,397 @@
+//! Calendar object resources (RFC 4791 section 4.1), the files CalDAV clients
+//! read and write in a calendar collection.
+//!
+//! Each event series, an event plus the children that override its instances,
+//! is one object, since iCalendar keeps the overrides in the same VCALENDAR as
+//! the event they override (with the same UID). Objects are named after the
+//! name they were created with through CalDAV (`events.dav_name`, see
+//! db_schema/19.sql) or `<event-id>.ics`.
+//!
+//! An object's ETag is the highest change sequence number (see `sync`) of the
+//! rows of its series, deleted children included, so it changes with every
+//! change to the event or its overrides.
+
+use super::xml::TimeRange;
+use crate::calendar::Calendar;
+use crate::database_error::{DatabaseError, DatabaseErrorKind};
+use crate::database_helpers::{FromRow, get_cell_from_row};
+use crate::event::{Event, RangeMode, ToPlain};
+use crate::ical::{export, Component};
+use crate::ical::import::{self, ObjectError};
+use crate::scheduling;
+use crate::sync::{self, SyncOutcome, SyncToken};
+use crate::webhooks::{self, Change, WebhookEventType};
+use chrono::NaiveDateTime;
+use postgres::GenericClient;
+use std::collections::HashMap;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Prefix of the sync tokens clients get, RFC 6578 requires them to be URIs.
+const SYNC_TOKEN_PREFIX: &str = "urn:calendar-server:sync:";
+
+pub struct CalendarObject
+{
+    series_id: Uuid,
+    name: String,
+
+    /// Without quotes.
+    etag: String,
+
+    last_modified: NaiveDateTime,
+    created_by: Option<String>,
+
+    /// The UID the event was imported with, if any.
+    uid: Option<String>,
+
+    /// The event first, then its children.
+    events: Vec<Event>,
+}
+
+impl CalendarObject
+{
+    pub fn get_series_id(&self) -> Uuid { self.series_id }
+
+    pub fn get_name(&self) -> &str { &self.name }
+
+    pub fn get_etag(&self) -> &str { &self.etag }
+
+    pub fn get_last_modified(&self) -> NaiveDateTime { self.last_modified }
+
+    pub fn get_created_by(&self) -> Option<&str> { self.created_by.as_deref() }
+
+    /// The object's VCALENDAR.
+    pub fn to_component(&self) -> Component
+    {
+        let uids: HashMap<Uuid, String> = self.uid.iter().map(|uid| (self.series_id, uid.clone())).collect();
+
+        export::events_component(&self.events.clone().into_plain(), &[], &uids)
+    }
+
+    /// Whether the object matches a calendar-query's filter: its component (only
+    /// VEVENTs exist) and a time range that one of the event's instances or
+    /// overrides must overlap.
+    pub fn matches(&self, component: Option<&str>, time_range: Option<TimeRange>) -> bool
+    {
+        if component.map_or(false, |component| component != "VEVENT")
+        {
+            return false;
+        }
+
+        let time_range = match time_range
+        {
+            Some(time_range) => time_range,
+            None => return true,
+        };
+
+        self.events.iter().any(|event| match event
+        {
+            Event::Recurring(event) => event.has_instance_in_range(RangeMode::Overlaps, time_range.start, time_range.end),
+            Event::Single(event) => RangeMode::Overlaps.matches(event.get_span(), time_range.start, time_range.end),
+        })
+    }
+}
+
+/// The name of objects that weren't created through CalDAV.
+fn default_name(series_id: &Uuid) -> String
+{
+    format!("{}.ics", series_id)
+}
+
+/// Gets the calendar's objects, ordered by name. Only gets those with the given
+/// names or series ids if `names` or `series_ids` are Some.
+pub fn get_objects(db: &mut impl GenericClient, calendar_id: &Uuid, names: Option<&[String]>, series_ids: Option<&[Uuid]>) -> Result<Vec<CalendarObject>, DatabaseError>
+{
+    let query = "
+        SELECT
+            id, ical_uid, created_by,
+            COALESCE(dav_name, id::TEXT || '.ics') AS name,
+            (SELECT MAX(change_seq) FROM events series WHERE series.id = events.id OR series.parent_event_id = events.id) AS etag,
+            (SELECT MAX(last_modified) FROM events series WHERE series.id = events.id OR series.parent_event_id = events.id) AS series_last_modified
+        FROM events
+        WHERE
+            calendar_id = $1
+            AND parent_event_id IS NULL
+            AND deleted_at IS NULL
+            AND ($2::TEXT[] IS NULL OR COALESCE(dav_name, id::TEXT || '.ics') = ANY($2))
+            AND ($3::UUID[] IS NULL OR id = ANY($3))
+        ORDER BY name;
+    ";
+
+    let mut objects = vec![];
+    let mut by_id = HashMap::new();
+
+    for row in db.query(query, &[calendar_id, &names, &series_ids])?.iter()
+    {
+        let series_id: Uuid = get_cell_from_row(row, "id")?;
+        by_id.insert(series_id, objects.len());
+
+        objects.push(
+            CalendarObject {
+                series_id,
+                name: get_cell_from_row(row, "name")?,
+                etag: get_cell_from_row::<i64>(row, "etag")?.to_string(),
+                last_modified: get_cell_from_row(row, "series_last_modified")?,
+                created_by: get_cell_from_row(row, "created_by")?,
+                uid: get_cell_from_row(row, "ical_uid")?,
+                events: vec![],
+            }
+        );
+    }
+
+    let ids: Vec<Uuid> = objects.iter().map(|object| object.series_id).collect();
+
+    let query = "
+        SELECT * FROM events
+        WHERE COALESCE(parent_event_id, id) = ANY($1) AND deleted_at IS NULL
+        ORDER BY parent_event_id IS NOT NULL, start_date, id;
+    ";
+
+    for row in db.query(query, &[&ids])?.iter()
+    {
+        let parent_id: Option<Uuid> = get_cell_from_row(row, "parent_event_id")?;
+        let series_id = match parent_id
+        {
+            Some(parent_id) => parent_id,
+            None => get_cell_from_row(row, "id")?,
+        };
+
+        if let Some(i) = by_id.get(&series_id)
+        {
+            objects[*i].events.push(Event::from_row(row)?);
+        }
+    }
+
+    Ok(objects)
+}
+
+/// Gets the object and locks its event until the end of the transaction, so
+/// that it can't change between checking its ETag and changing it.
+pub fn find_for_update(db: &mut impl GenericClient, calendar_id: &Uuid, name: &str) -> Result<Option<CalendarObject>, DatabaseError>
+{
+    let query = "
+        SELECT id FROM events
+        WHERE
+            calendar_id = $1
+            AND parent_event_id IS NULL
+            AND deleted_at IS NULL
+            AND COALESCE(dav_name, id::TEXT || '.ics') = $2
+        FOR UPDATE;
+    ";
+
+    let series_id: Uuid = match db.query(query, &[calendar_id, &name])?.get(0)
+    {
+        Some(row) => get_cell_from_row(row, "id")?,
+        None => return Ok(None),
+    };
+
+    Ok(get_objects(db, calendar_id, None, Some(&[series_id]))?.pop())
+}
+
+/// Stores the VCALENDAR as the object named `name`, replacing `existing` (the
+/// object with that name, if there is one). Returns the stored object.
+pub fn put(
+    db: &mut impl GenericClient,
+    calendar: &Calendar,
+    created_by: &str,
+    name: &str,
+    vcalendar: &Component,
+    existing: Option<&CalendarObject>
+) -> Result<CalendarObject, ObjectError>
+{
+    let mut transaction = db.transaction()?;
+
+    let series_id = import::import_object(&mut transaction, calendar, created_by, vcalendar, existing.map(|object| object.series_id))?;
+
+    // Clients expect to find the object under the name they created it with.
+    if existing.is_none() && name != default_name(&series_id)
+    {
+        transaction.execute("UPDATE events SET dav_name = $2 WHERE id = $1;", &[&series_id, &name])?;
+    }
+
+    scheduling::notify(&mut transaction, &series_id)?;
+
+    let object = get_objects(&mut transaction, &calendar.get_id(), None, Some(&[series_id]))?
+        .pop()
+        .ok_or_else(|| DatabaseError::from(DatabaseErrorKind::ReturningIsEmpty))?;
+
+    transaction.commit()?;
+
+    Ok(object)
+}
+
+/// Deletes the object's event and its children, they're kept as tombstones
+/// like those deleted through the API.
+pub fn delete(db: &mut impl GenericClient, calendar_id: &Uuid, object: &CalendarObject) -> Result<(), DatabaseError>
+{
+    let mut transaction = db.transaction()?;
+
+    let query = "
+        UPDATE events SET deleted_at = NOW()
+        WHERE (id = $1 OR parent_event_id = $1) AND deleted_at IS NULL
+        RETURNING *;
+    ";
+
+    let mut changes = vec![];
+
+    for row in transaction.query(query, &[&object.series_id])?.iter()
+    {
+        changes.push(Change::event(WebhookEventType::EventDeleted, *calendar_id, &Event::from_row(row)?.into_plain()));
+    }
+
+    webhooks::enqueue(&mut transaction, &changes)?;
+    scheduling::notify(&mut transaction, &object.series_id)?;
+    transaction.commit()?;
+
+    Ok(())
+}
+
+/// The calendar's current sync token, as clients get it (see `ObjectChanges`).
+/// None if the calendar doesn't exist.
+pub fn current_sync_token(db: &mut impl GenericClient, calendar_id: &Uuid) -> Result<Option<String>, DatabaseError>
+{
+    Ok(SyncToken::current(db, calendar_id)?.map(|token| format!("{}{}", SYNC_TOKEN_PREFIX, token)))
+}
+
+/// The objects that changed since a sync token, see `get_changes`.
+pub struct ObjectChanges
+{
+    pub changed: Vec<CalendarObject>,
+
+    /// The names of deleted objects.
+    pub deleted: Vec<String>,
+
+    pub sync_token: String,
+
+    /// If true, there are more changes, which clients get by syncing
+    /// again with `sync_token`.
+    pub has_more: bool,
+}
+
+pub enum ObjectSyncOutcome
+{
+    Changes(ObjectChanges),
+
+    /// The token isn't one of the calendar's or has expired, see `SyncOutcome`.
+    InvalidToken,
+
+    CalendarNotFound,
+}
+
+/// Gets the objects that changed since `token` (a sync-collection report's
+/// token), or all of them if it's None. The changes of at most `max_results`
+/// events are looked at, an object is reported once even if several of its
+/// events changed.
+///
+/// Uses `sync::get_changes`, so it can't be called inside of a transaction.
+pub fn get_changes(db: &mut impl GenericClient, calendar_id: &Uuid, token: Option<&str>, max_results: usize) -> Result<ObjectSyncOutcome, DatabaseError>
+{
+    let token = match token
+    {
+        Some(token) => match token.strip_prefix(SYNC_TOKEN_PREFIX).and_then(|token| SyncToken::from_str(token).ok())
+        {
+            Some(token) => Some(token),
+            None => return Ok(ObjectSyncOutcome::InvalidToken),
+        },
+        None => None,
+    };
+
+    let response = match sync::get_changes(db, *calendar_id, token, max_results)?
+    {
+        SyncOutcome::Changes(response) => response,
+        SyncOutcome::InvalidToken => return Ok(ObjectSyncOutcome::InvalidToken),
+        SyncOutcome::CalendarNotFound => return Ok(ObjectSyncOutcome::CalendarNotFound),
+    };
+
+    let mut series_ids: Vec<Uuid> = response.events
+        .iter()
+        .filter_map(|event| event.parent_id.or(event.id))
+        .collect();
+
+    series_ids.sort();
+    series_ids.dedup();
+
+    let changed = get_objects(db, calendar_id, None, Some(&series_ids))?;
+
+    // The series that don't have an object anymore were deleted.
+    let deleted_ids: Vec<Uuid> = series_ids
+        .into_iter()
+        .filter(|id| !changed.iter().any(|object| object.series_id == *id))
+        .collect();
+
+    let mut deleted = vec![];
+
+    for row in db.query("SELECT COALESCE(dav_name, id::TEXT || '.ics') AS name FROM events WHERE id = ANY($1);", &[&deleted_ids])?.iter()
+    {
+        deleted.push(get_cell_from_row(row, "name")?);
+    }
+
+    Ok(
+        ObjectSyncOutcome::Changes(
+            ObjectChanges {
+                changed,
+                deleted,
+                sync_token: format!("{}{}", SYNC_TOKEN_PREFIX, response.sync_token),
+                has_more: response.has_more,
+            }
+        )
+    )
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{find_for_update, get_objects, put, delete};
+    use crate::calendar;
+    use crate::ical::parser;
+    use crate::ical::import::ObjectError;
+    use crate::test_helpers::{connect, insert_tenant, insert_calendar};
+
+    #[test]
+    #[ignore]
+    fn puts_and_deletes_objects()
+    {
+        let mut client = connect();
+        let mut db = client.transaction().unwrap();
+
+        let tenant_id = insert_tenant(&mut db);
+        let calendar_id = insert_calendar(&mut db, &tenant_id);
+
+        let calendar = calendar::get_calendar(&mut db, &tenant_id, &calendar_id).unwrap().unwrap();
+
+        let ics = "BEGIN:VCALENDAR\r\n\
+            VERSION:2.0\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:standup\r\n\
+            DTSTART:20200901T100000Z\r\n\
+            DTEND:20200901T101500Z\r\n\
+            RRULE:FREQ=DAILY;COUNT=5\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:standup\r\n\
+            RECURRENCE-ID:20200902T100000Z\r\n\
+            DTSTART:20200902T110000Z\r\n\
+            DTEND:20200902T111500Z\r\n\
+            END:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+
+        let vcalendar = parser::parse(ics).unwrap().remove(0);
+
+        let object = put(&mut db, &calendar, "user:test", "standup.ics", &vcalendar, None).unwrap();
+        assert_eq!(object.get_name(), "standup.ics");
+        assert_eq!(object.events.len(), 2);
+
+        // Another object can't have the same UID.
+        assert!(matches!(put(&mut db, &calendar, "user:test", "other.ics", &vcalendar, None), Err(ObjectError::UidConflict)));
+
+        let existing = find_for_update(&mut db, &calendar_id, "standup.ics").unwrap().unwrap();
+        let updated = put(&mut db, &calendar, "user:test", "standup.ics", &vcalendar, Some(&existing)).unwrap();
+
+        assert_eq!(updated.get_series_id(), object.get_series_id());
+        assert_ne!(updated.get_etag(), object.get_etag());
+        assert_eq!(get_objects(&mut db, &calendar_id, None, None).unwrap().len(), 1);
+
+        delete(&mut db, &calendar_id, &updated).unwrap();
+        assert!(find_for_update(&mut db, &calendar_id, "standup.ics").unwrap().is_none());
+    }
+}
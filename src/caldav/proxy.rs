@@ -0,0 +1,330 @@
+//! The listener CalDAV clients connect to, in front of Rocket.
+//!
+//! Rocket's HTTP server only understands the methods of RFC 7231 and answers
+//! any other method, like WebDAV's PROPFIND and REPORT, with 400 before routing
+//! the request. So requests to this listener are forwarded to Rocket with those
+//! methods turned into `POST` plus an `X-HTTP-Method-Override` header, which
+//! the CalDAV routes check for (see `method_override`). Requests with methods
+//! Rocket understands are forwarded as they are.
+//!
+//! Each connection is forwarded to its own connection to Rocket and serves a
+//! single request, both connections are closed after the response. There's a
+//! limit on how many connections are forwarded at once, and connections that
+//! stall are closed, so that clients can't pile up threads.
+
+use super::method_override;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use log::{info, error};
+
+/// The methods Rocket can route, which are forwarded without an override.
+const ROCKET_METHODS: [&str; 9] = ["GET", "PUT", "POST", "DELETE", "OPTIONS", "HEAD", "TRACE", "CONNECT", "PATCH"];
+
+/// Headers clients can't set, the proxy sets them itself.
+const DROPPED_HEADERS: [&str; 4] = ["connection", "keep-alive", "x-real-ip", method_override::HEADER];
+
+/// Maximum size of a request's or response's head.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// How many connections are forwarded at once, more get a 503.
+const MAX_CONNECTIONS: usize = 256;
+
+/// How long reading from or writing to either connection can take before
+/// both are closed.
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Spawns a thread that accepts connections on `port` and forwards them to
+/// Rocket, which listens on `rocket_port` on the same host.
+pub fn spawn(port: u16, rocket_port: u16)
+{
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).expect("Failed to listen on CALDAV_PORT.");
+
+    info!("Serving CalDAV on port {}", port);
+
+    let open_connections = Arc::new(AtomicUsize::new(0));
+
+    thread::spawn(move || {
+        for client in listener.incoming()
+        {
+            match client
+            {
+                Ok(client) =>
+                {
+                    let connection = match ConnectionSlot::take(&open_connections)
+                    {
+                        Some(connection) => connection,
+                        None =>
+                        {
+                            reject(client);
+                            continue;
+                        },
+                    };
+
+                    thread::spawn(move || {
+                        if let Err(e) = forward(client, rocket_port)
+                        {
+                            error!("Failed to forward a CalDAV request: {}", e);
+                        }
+
+                        drop(connection);
+                    });
+                },
+                Err(e) => error!("Failed to accept a CalDAV connection: {}", e),
+            }
+        }
+    });
+}
+
+/// One of the `MAX_CONNECTIONS` connections that can be forwarded at once,
+/// it's free again once this is dropped.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot
+{
+    fn take(open_connections: &Arc<AtomicUsize>) -> Option<ConnectionSlot>
+    {
+        if open_connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS
+        {
+            open_connections.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+
+        Some(ConnectionSlot(open_connections.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot
+{
+    fn drop(&mut self)
+    {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Answers a connection over `MAX_CONNECTIONS` with a 503 without reading
+/// its request, on the accepting thread, so it can't block.
+fn reject(mut client: TcpStream)
+{
+    let _ = client.set_write_timeout(Some(Duration::from_secs(1)));
+    let _ = client.write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    let _ = client.shutdown(Shutdown::Both);
+}
+
+fn forward(mut client: TcpStream, rocket_port: u16) -> io::Result<()>
+{
+    client.set_read_timeout(Some(IO_TIMEOUT))?;
+    client.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let client_ip = client.peer_addr()?.ip();
+
+    let (head, body_start) = match read_head(&mut client, vec![])?
+    {
+        Some(head) => head,
+        None => return Ok(()),
+    };
+
+    let head = match rewrite_request_head(&head, client_ip)
+    {
+        Some(head) => head,
+        None => return client.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+    };
+
+    let mut rocket = TcpStream::connect((Ipv4Addr::LOCALHOST, rocket_port))?;
+    rocket.set_read_timeout(Some(IO_TIMEOUT))?;
+    rocket.set_write_timeout(Some(IO_TIMEOUT))?;
+    rocket.write_all(head.as_bytes())?;
+    rocket.write_all(&body_start)?;
+
+    // The rest of the body. The thread stops when the client's connection
+    // is closed, below.
+    let mut client_reader = client.try_clone()?;
+    let mut rocket_writer = rocket.try_clone()?;
+
+    thread::spawn(move || {
+        let _ = io::copy(&mut client_reader, &mut rocket_writer);
+        let _ = rocket_writer.shutdown(Shutdown::Write);
+    });
+
+    // Interim responses (100 Continue) are passed on as they are, the
+    // bytes read after them are the start of the next response.
+    let mut read = vec![];
+
+    loop
+    {
+        let (head, body_start) = match read_head(&mut rocket, read)?
+        {
+            Some(head) => head,
+            None => break,
+        };
+
+        if head.starts_with("HTTP/1.1 1")
+        {
+            client.write_all(head.as_bytes())?;
+            read = body_start;
+            continue;
+        }
+
+        client.write_all(rewrite_response_head(&head).as_bytes())?;
+        client.write_all(&body_start)?;
+        io::copy(&mut rocket, &mut client)?;
+        break;
+    }
+
+    client.shutdown(Shutdown::Both)
+}
+
+/// Reads a message's head, up to and including the empty line, starting with
+/// the bytes in `buffer` that were already read. Returns the head and the
+/// bytes that were read after it, None if the connection was closed before
+/// anything was sent.
+fn read_head(stream: &mut TcpStream, mut buffer: Vec<u8>) -> io::Result<Option<(String, Vec<u8>)>>
+{
+    let mut chunk = [0; 4096];
+
+    loop
+    {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n")
+        {
+            let body_start = buffer.split_off(end + 4);
+            let head = String::from_utf8(buffer).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "The message's head isn't valid UTF-8."))?;
+
+            return Ok(Some((head, body_start)));
+        }
+
+        if buffer.len() > MAX_HEAD_SIZE
+        {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "The message's head is too large."));
+        }
+
+        let read = stream.read(&mut chunk)?;
+
+        if read == 0
+        {
+            if buffer.is_empty()
+            {
+                return Ok(None);
+            }
+
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The connection was closed in the middle of a message's head."));
+        }
+
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+}
+
+/// The head of a request as it's forwarded to Rocket, see the module's docs.
+/// Returns None if the request line is invalid.
+fn rewrite_request_head(head: &str, client_ip: IpAddr) -> Option<String>
+{
+    let mut lines = head.split("\r\n");
+    let request_line: Vec<&str> = lines.next()?.split(' ').collect();
+
+    let (method, target, version) = match request_line.as_slice()
+    {
+        [method, target, version] if !method.is_empty() && method.chars().all(|c| c.is_ascii_uppercase()) => (*method, *target, *version),
+        _ => return None,
+    };
+
+    let mut rewritten = if ROCKET_METHODS.contains(&method)
+    {
+        format!("{} {} {}\r\n", method, target, version)
+    }
+    else
+    {
+        format!("POST {} {}\r\n{}: {}\r\n", target, version, method_override::HEADER, method)
+    };
+
+    push_headers(&mut rewritten, lines);
+    rewritten.push_str(&format!("X-Real-IP: {}\r\nConnection: close\r\n\r\n", client_ip));
+
+    Some(rewritten)
+}
+
+/// The head of Rocket's response, with `Connection: close` since the
+/// connection is closed after it.
+fn rewrite_response_head(head: &str) -> String
+{
+    let mut lines = head.split("\r\n");
+    let mut rewritten = format!("{}\r\n", lines.next().unwrap_or_default());
+
+    push_headers(&mut rewritten, lines);
+    rewritten.push_str("Connection: close\r\n\r\n");
+
+    rewritten
+}
+
+/// Appends the header lines, except for those in `DROPPED_HEADERS`.
+fn push_headers<'a>(head: &mut String, lines: impl Iterator<Item = &'a str>)
+{
+    for line in lines.filter(|line| !line.is_empty())
+    {
+        let name = line.split(':').next().unwrap_or_default().trim();
+
+        if DROPPED_HEADERS.iter().all(|dropped| !dropped.eq_ignore_ascii_case(name))
+        {
+            head.push_str(line);
+            head.push_str("\r\n");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{rewrite_request_head, rewrite_response_head, ConnectionSlot, MAX_CONNECTIONS};
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+
+    const CLIENT_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2));
+
+    #[test]
+    fn overrides_dav_methods()
+    {
+        let head = "PROPFIND /dav/calendars/ HTTP/1.1\r\nHost: localhost\r\nDepth: 1\r\nConnection: keep-alive\r\n\r\n";
+
+        assert_eq!(
+            rewrite_request_head(head, CLIENT_IP).unwrap(),
+            "POST /dav/calendars/ HTTP/1.1\r\nX-HTTP-Method-Override: PROPFIND\r\nHost: localhost\r\nDepth: 1\r\nX-Real-IP: 192.168.1.2\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn forwards_other_methods_as_they_are()
+    {
+        let head = "PUT /dav/calendars/1/a.ics HTTP/1.1\r\nx-http-method-override: DELETE\r\nX-Real-IP: 10.0.0.1\r\nContent-Length: 0\r\n\r\n";
+
+        assert_eq!(
+            rewrite_request_head(head, CLIENT_IP).unwrap(),
+            "PUT /dav/calendars/1/a.ics HTTP/1.1\r\nContent-Length: 0\r\nX-Real-IP: 192.168.1.2\r\nConnection: close\r\n\r\n"
+        );
+
+        assert!(rewrite_request_head("GET /\r\n\r\n", CLIENT_IP).is_none());
+        assert!(rewrite_request_head("get / HTTP/1.1\r\n\r\n", CLIENT_IP).is_none());
+    }
+
+    #[test]
+    fn closes_responses()
+    {
+        assert_eq!(
+            rewrite_response_head("HTTP/1.1 207 Multi-Status\r\nConnection: keep-alive\r\nContent-Length: 10\r\n\r\n"),
+            "HTTP/1.1 207 Multi-Status\r\nContent-Length: 10\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn connections_are_limited()
+    {
+        let open_connections = Arc::new(AtomicUsize::new(0));
+
+        let mut slots: Vec<ConnectionSlot> = (0..MAX_CONNECTIONS).map(|_| ConnectionSlot::take(&open_connections).unwrap()).collect();
+        assert!(ConnectionSlot::take(&open_connections).is_none());
+
+        slots.pop();
+        assert!(ConnectionSlot::take(&open_connections).is_some());
+    }
+}
@@ -0,0 +1,117 @@
+use super::xml::{self, Multistatus, Name};
+use crate::database_error::DatabaseError;
+use crate::routes::calendar_format::CalendarFileFormat;
+use rocket::{Request, Response};
+use rocket::response::{self, Responder};
+use rocket::http::{Status, ContentType, Header};
+use std::io::Cursor;
+use log::error;
+
+/// The response of a CalDAV route.
+pub enum DavResponse
+{
+    /// 207 with a multistatus body.
+    Multistatus(Multistatus),
+
+    /// A calendar object, as `text/calendar`, and its ETag (with quotes).
+    Object(String, String),
+
+    /// A calendar object was stored by a PUT, 201 if it was created and 204
+    /// if it was replaced. Has the object's new ETag (with quotes).
+    Stored
+    {
+        created: bool,
+        etag: String,
+    },
+
+    /// A response without a body.
+    Status(Status),
+
+    /// The response to OPTIONS requests, which tells clients what the
+    /// server supports.
+    Options,
+
+    /// A failed precondition or postcondition (RFC 4918 section 16), e.g.
+    /// 403 with `CALDAV:supported-filter`.
+    Error(Status, Name),
+}
+
+impl<'r> Responder<'r> for DavResponse
+{
+    fn respond_to(self, _request: &Request) -> response::Result<'r>
+    {
+        match self
+        {
+            DavResponse::Multistatus(multistatus) =>
+            {
+                Response::build()
+                    .status(Status::MultiStatus)
+                    .header(ContentType::XML)
+                    .sized_body(Cursor::new(multistatus.to_xml()))
+                    .ok()
+            },
+            DavResponse::Object(body, etag) =>
+            {
+                Response::build()
+                    .header(CalendarFileFormat::ICalendar.content_type())
+                    .header(Header::new("ETag", etag))
+                    .sized_body(Cursor::new(body))
+                    .ok()
+            },
+            DavResponse::Stored { created, etag } =>
+            {
+                Response::build()
+                    .status(if created { Status::Created } else { Status::NoContent })
+                    .header(Header::new("ETag", etag))
+                    .ok()
+            },
+            DavResponse::Status(status) => Response::build().status(status).ok(),
+            DavResponse::Options =>
+            {
+                Response::build()
+                    .raw_header("DAV", "1, 3, calendar-access")
+                    .raw_header("Allow", "OPTIONS, GET, PUT, DELETE, PROPFIND, REPORT")
+                    .ok()
+            },
+            DavResponse::Error(status, condition) =>
+            {
+                Response::build()
+                    .status(status)
+                    .header(ContentType::XML)
+                    .sized_body(Cursor::new(xml::error_body(&condition)))
+                    .ok()
+            },
+        }
+    }
+}
+
+/// A database error, responds with 500. Lets CalDAV routes use `?` on database
+/// results, they return `Result<DavResponse, DavError>`.
+#[derive(Debug)]
+pub struct DavError(DatabaseError);
+
+impl From<DatabaseError> for DavError
+{
+    fn from(e: DatabaseError) -> Self
+    {
+        DavError(e)
+    }
+}
+
+impl From<postgres::Error> for DavError
+{
+    fn from(e: postgres::Error) -> Self
+    {
+        DavError(e.into())
+    }
+}
+
+impl<'r> Responder<'r> for DavError
+{
+    fn respond_to(self, _request: &Request) -> response::Result<'r>
+    {
+        error!("CalDAV request failed: {}", self.0);
+
+        Err(Status::InternalServerError)
+    }
+}
@@ -0,0 +1,660 @@
+//! The CalDAV routes, mounted at `/dav` (see `MOUNT_POINT`), except for
+//! `well_known`, which is mounted at `/`.
+//!
+//! PROPFIND and REPORT routes are `POST` routes with a `MethodOverride` guard,
+//! see `proxy`. They're told apart from each other by the guard, which forwards
+//! requests for other methods, so routes on the same path have different ranks.
+
+use super::method_override::{MethodOverride, Propfind, Report};
+use super::objects::{self, CalendarObject, ObjectSyncOutcome};
+use super::responders::{DavResponse, DavError};
+use super::xml::{self, Multistatus, Name, PropRequest, PropValue, Response, XmlError, CALDAV, CALENDARSERVER, APPLE_ICAL, DAV};
+use crate::acl::CalendarRole;
+use crate::authentication::calendar_access::{CalendarAccess, Reader, SelfWriter};
+use crate::authentication::principal::Principal;
+use crate::authentication::scopes::Read;
+use crate::calendar::{self, Calendar};
+use crate::configs::Configs;
+use crate::connection_pool::PgsqlConn;
+use crate::database_helpers::UuidParam;
+use crate::ical::import::ObjectError;
+use crate::ical::parser;
+use crate::routes::calendar_format::CalendarFileFormat;
+use crate::routes::conditional_request::HTTP_DATE_FORMAT;
+use rocket::{Request, Data, Outcome, State};
+use rocket::data::{self, FromDataSimple};
+use rocket::request::{self, FromRequest};
+use rocket::response::Redirect;
+use rocket::http::Status;
+use rocket::http::uri::Uri;
+use std::io::{self, Read as IoRead};
+use std::path::PathBuf;
+use postgres::GenericClient;
+use uuid::Uuid;
+
+pub const MOUNT_POINT: &str = "/dav";
+
+/// Default maximum size of request bodies, can be changed with the `ics`
+/// limit, like the size of imported files.
+const DEFAULT_BODY_LIMIT: u64 = 10 * 1024 * 1024;
+
+fn principal_href() -> String
+{
+    format!("{}/principal/", MOUNT_POINT)
+}
+
+fn home_href() -> String
+{
+    format!("{}/calendars/", MOUNT_POINT)
+}
+
+fn calendar_href(calendar_id: &Uuid) -> String
+{
+    format!("{}/calendars/{}/", MOUNT_POINT, calendar_id)
+}
+
+fn object_href(calendar_id: &Uuid, name: &str) -> String
+{
+    format!("{}{}", calendar_href(calendar_id), Uri::percent_encode(name))
+}
+
+/// The name of the object with the href, None if it's not in the calendar.
+/// Hrefs can be absolute URLs, the name is what follows the calendar's path.
+fn object_name(calendar_id: &Uuid, href: &str) -> Option<String>
+{
+    let prefix = calendar_href(calendar_id);
+    let name = &href[href.find(&prefix)? + prefix.len()..];
+
+    if name.is_empty() || name.contains('/')
+    {
+        return None;
+    }
+
+    Some(Uri::percent_decode_lossy(name.as_bytes()).into_owned())
+}
+
+/// The `Depth` header of PROPFIND requests. Only 0 and 1 are supported,
+/// `infinity` (the default) is treated as 1.
+pub struct Depth(u8);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Depth
+{
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error>
+    {
+        match request.headers().get_one("Depth").map(|depth| depth.trim())
+        {
+            Some("0") => Outcome::Success(Depth(0)),
+            _ => Outcome::Success(Depth(1)),
+        }
+    }
+}
+
+/// The `If-Match` and `If-None-Match` headers of PUT and DELETE requests,
+/// which clients use to avoid overwriting changes they haven't seen.
+pub struct Preconditions
+{
+    if_match: Option<String>,
+    if_none_match: Option<String>,
+}
+
+impl Preconditions
+{
+    /// Whether the request can change the object with the ETag (with quotes),
+    /// None if the object doesn't exist.
+    fn allow(&self, etag: Option<&str>) -> bool
+    {
+        let matches = |header: &str| header.split(',').map(|tag| tag.trim()).any(|tag| tag == "*" && etag.is_some() || Some(tag) == etag);
+
+        self.if_match.as_deref().map_or(true, matches) && !self.if_none_match.as_deref().map_or(false, matches)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Preconditions
+{
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error>
+    {
+        Outcome::Success(
+            Preconditions {
+                if_match: request.headers().get_one("If-Match").map(|value| value.to_owned()),
+                if_none_match: request.headers().get_one("If-None-Match").map(|value| value.to_owned()),
+            }
+        )
+    }
+}
+
+/// The body of a CalDAV request, an XML document or an iCalendar object.
+pub struct DavBody(String);
+
+impl FromDataSimple for DavBody
+{
+    type Error = io::Error;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error>
+    {
+        let limit = request.limits().get("ics").unwrap_or(DEFAULT_BODY_LIMIT);
+
+        let mut body = String::new();
+
+        // Read one byte more than the limit to know if the body is over it.
+        if let Err(e) = data.open().take(limit + 1).read_to_string(&mut body)
+        {
+            return Outcome::Failure((Status::BadRequest, e));
+        }
+
+        if body.len() as u64 > limit
+        {
+            return Outcome::Failure((Status::PayloadTooLarge, io::Error::new(io::ErrorKind::Other, "The body is too large.")));
+        }
+
+        Outcome::Success(DavBody(body))
+    }
+}
+
+fn quoted(etag: &str) -> String
+{
+    format!("\"{}\"", etag)
+}
+
+/// The properties every resource has.
+fn common_props(resource_types: &str) -> Vec<(Name, PropValue)>
+{
+    vec![
+        (Name::new(DAV, "resourcetype"), PropValue::Xml(resource_types.to_owned())),
+        (Name::new(DAV, "current-user-principal"), PropValue::Href(principal_href())),
+    ]
+}
+
+fn principal_response(principal: &Principal<Read>, request: &PropRequest) -> Response
+{
+    let mut props = common_props("<d:collection/><d:principal/>");
+    props.push((Name::new(DAV, "principal-URL"), PropValue::Href(principal_href())));
+    props.push((Name::new(CALDAV, "calendar-home-set"), PropValue::Href(home_href())));
+    props.push((Name::new(DAV, "displayname"), PropValue::Text(principal.as_principal_ref().to_string())));
+
+    Response::props(principal_href(), props, request)
+}
+
+fn home_response(request: &PropRequest) -> Response
+{
+    let mut props = common_props("<d:collection/>");
+    props.push((Name::new(DAV, "displayname"), PropValue::Text("Calendars".to_owned())));
+
+    Response::props(home_href(), props, request)
+}
+
+/// The privileges of the role, RFC 3744 section 5.4.
+fn privileges(role: CalendarRole) -> String
+{
+    let mut privileges = vec!["read"];
+
+    if role >= CalendarRole::SelfWriter
+    {
+        privileges.extend(&["write-content", "bind"]);
+    }
+
+    if role >= CalendarRole::Writer
+    {
+        privileges.extend(&["write", "unbind"]);
+    }
+
+    if role >= CalendarRole::Editor
+    {
+        privileges.push("write-properties");
+    }
+
+    privileges
+        .iter()
+        .map(|privilege| format!("<d:privilege><d:{}/></d:privilege>", privilege))
+        .collect()
+}
+
+fn calendar_response(calendar: &Calendar, role: CalendarRole, sync_token: Option<String>, request: &PropRequest) -> Response
+{
+    let reports: String = ["c:calendar-query", "c:calendar-multiget", "d:sync-collection"]
+        .iter()
+        .map(|report| format!("<d:supported-report><d:report><{}/></d:report></d:supported-report>", report))
+        .collect();
+
+    let mut props = common_props("<d:collection/><c:calendar/>");
+    props.push((Name::new(DAV, "displayname"), PropValue::Text(calendar.get_name().to_owned())));
+    props.push((Name::new(CALDAV, "supported-calendar-component-set"), PropValue::Xml("<c:comp name=\"VEVENT\"/>".to_owned())));
+    props.push((Name::new(DAV, "supported-report-set"), PropValue::Xml(reports)));
+    props.push((Name::new(DAV, "current-user-privilege-set"), PropValue::Xml(privileges(role))));
+
+    if let Some(description) = calendar.get_description()
+    {
+        props.push((Name::new(CALDAV, "calendar-description"), PropValue::Text(description.clone())));
+    }
+
+    if let Some(color) = calendar.get_color()
+    {
+        props.push((Name::new(APPLE_ICAL, "calendar-color"), PropValue::Text(color.clone())));
+    }
+
+    // The sync token changes with every change to the calendar's
+    // events, so it's also the CTag clients poll for changes.
+    if let Some(sync_token) = sync_token
+    {
+        props.push((Name::new(CALENDARSERVER, "getctag"), PropValue::Text(sync_token.clone())));
+        props.push((Name::new(DAV, "sync-token"), PropValue::Text(sync_token)));
+    }
+
+    Response::props(calendar_href(&calendar.get_id()), props, request)
+}
+
+/// The object's response. It only has the object's data if it's asked
+/// for by name, as RFC 4791 section 9.6 says.
+fn object_response(calendar_id: &Uuid, object: &CalendarObject, request: &PropRequest) -> Response
+{
+    let mut props = vec![
+        (Name::new(DAV, "resourcetype"), PropValue::Empty),
+        (Name::new(DAV, "getetag"), PropValue::Text(quoted(object.get_etag()))),
+        (Name::new(DAV, "getcontenttype"), PropValue::Text(CalendarFileFormat::ICalendar.content_type().to_string())),
+        (Name::new(DAV, "getlastmodified"), PropValue::Text(object.get_last_modified().format(HTTP_DATE_FORMAT).to_string())),
+    ];
+
+    if request.names(CALDAV, "calendar-data")
+    {
+        props.push((Name::new(CALDAV, "calendar-data"), PropValue::Text(object.to_component().to_ics())));
+    }
+
+    Response::props(object_href(calendar_id, object.get_name()), props, request)
+}
+
+/// The calendar's response, with the principal's role on it.
+fn get_calendar_response(db: &mut impl GenericClient, principal: &Principal<Read>, calendar: &Calendar, request: &PropRequest) -> Result<Response, DavError>
+{
+    let role = principal.calendar_role(db, &calendar.get_id())?.unwrap_or(CalendarRole::Reader);
+    let sync_token = objects::current_sync_token(db, &calendar.get_id())?;
+
+    Ok(calendar_response(calendar, role, sync_token, request))
+}
+
+/// The response to request bodies that can't be parsed or ask for
+/// something that isn't supported.
+fn xml_error(e: XmlError) -> DavResponse
+{
+    match e
+    {
+        XmlError::UnsupportedReport(_) => DavResponse::Error(Status::Forbidden, Name::new(DAV, "supported-report")),
+        XmlError::UnsupportedFilter(_) => DavResponse::Error(Status::Forbidden, Name::new(CALDAV, "supported-filter")),
+        XmlError::Xml(_) | XmlError::Invalid(_) => DavResponse::Status(Status::BadRequest),
+    }
+}
+
+/// The root, which only points to the principal.
+#[post("/", data = "<body>")]
+pub fn propfind_root(_method: MethodOverride<Propfind>, principal: Principal<Read>, depth: Depth, body: DavBody) -> Result<DavResponse, DavError>
+{
+    let request = match xml::parse_propfind(&body.0)
+    {
+        Ok(request) => request,
+        Err(e) => return Ok(xml_error(e)),
+    };
+
+    let mut responses = vec![Response::props(format!("{}/", MOUNT_POINT), common_props("<d:collection/>"), &request)];
+
+    if depth.0 > 0
+    {
+        responses.push(principal_response(&principal, &request));
+        responses.push(home_response(&request));
+    }
+
+    Ok(DavResponse::Multistatus(Multistatus { responses, sync_token: None }))
+}
+
+/// The principal of the request's API key or user, whose calendar
+/// home has the calendars it can see.
+#[post("/principal", data = "<body>")]
+pub fn propfind_principal(_method: MethodOverride<Propfind>, principal: Principal<Read>, body: DavBody) -> Result<DavResponse, DavError>
+{
+    match xml::parse_propfind(&body.0)
+    {
+        Ok(request) => Ok(DavResponse::Multistatus(Multistatus { responses: vec![principal_response(&principal, &request)], sync_token: None })),
+        Err(e) => Ok(xml_error(e)),
+    }
+}
+
+/// The calendar home, with the calendars the principal can see.
+#[post("/calendars", data = "<body>")]
+pub fn propfind_home(
+    _method: MethodOverride<Propfind>,
+    mut db: PgsqlConn,
+    principal: Principal<Read>,
+    configs: State<Configs>,
+    depth: Depth,
+    body: DavBody
+) -> Result<DavResponse, DavError>
+{
+    let request = match xml::parse_propfind(&body.0)
+    {
+        Ok(request) => request,
+        Err(e) => return Ok(xml_error(e)),
+    };
+
+    let mut responses = vec![home_response(&request)];
+
+    if depth.0 > 0
+    {
+        let calendar_ids = principal.visible_calendar_ids(&mut **db)?;
+
+        for calendar in calendar::list_calendars(&mut **db, &principal.get_tenant_id(), calendar_ids.as_ref(), 0, configs.get_page_size() as i64)?.iter()
+        {
+            responses.push(get_calendar_response(&mut **db, &principal, calendar, &request)?);
+        }
+    }
+
+    Ok(DavResponse::Multistatus(Multistatus { responses, sync_token: None }))
+}
+
+/// A calendar collection and, with `Depth: 1`, its objects.
+#[post("/calendars/<calendar_id>", data = "<body>", rank = 1)]
+pub fn propfind_calendar(
+    _method: MethodOverride<Propfind>,
+    mut db: PgsqlConn,
+    access: CalendarAccess<Reader>,
+    calendar_id: UuidParam,
+    depth: Depth,
+    body: DavBody
+) -> Result<DavResponse, DavError>
+{
+    let request = match xml::parse_propfind(&body.0)
+    {
+        Ok(request) => request,
+        Err(e) => return Ok(xml_error(e)),
+    };
+
+    let calendar_id = calendar_id.into_inner();
+
+    let calendar = match calendar::get_calendar(&mut **db, &access.get_tenant_id(), &calendar_id)?
+    {
+        Some(calendar) => calendar,
+        None => return Ok(DavResponse::Status(Status::NotFound)),
+    };
+
+    let sync_token = objects::current_sync_token(&mut **db, &calendar_id)?;
+    let mut responses = vec![calendar_response(&calendar, access.get_role(), sync_token, &request)];
+
+    if depth.0 > 0
+    {
+        for object in objects::get_objects(&mut **db, &calendar_id, None, None)?.iter()
+        {
+            responses.push(object_response(&calendar_id, object, &request));
+        }
+    }
+
+    Ok(DavResponse::Multistatus(Multistatus { responses, sync_token: None }))
+}
+
+#[post("/calendars/<calendar_id>/<object>", data = "<body>", rank = 1)]
+pub fn propfind_object(
+    _method: MethodOverride<Propfind>,
+    mut db: PgsqlConn,
+    _access: CalendarAccess<Reader>,
+    calendar_id: UuidParam,
+    object: String,
+    body: DavBody
+) -> Result<DavResponse, DavError>
+{
+    let request = match xml::parse_propfind(&body.0)
+    {
+        Ok(request) => request,
+        Err(e) => return Ok(xml_error(e)),
+    };
+
+    let calendar_id = calendar_id.into_inner();
+
+    match objects::get_objects(&mut **db, &calendar_id, Some(&[object]), None)?.pop()
+    {
+        Some(object) => Ok(DavResponse::Multistatus(Multistatus { responses: vec![object_response(&calendar_id, &object, &request)], sync_token: None })),
+        None => Ok(DavResponse::Status(Status::NotFound)),
+    }
+}
+
+/// The calendar-query, calendar-multiget and sync-collection reports.
+#[post("/calendars/<calendar_id>", data = "<body>", rank = 2)]
+pub fn report_calendar(
+    _method: MethodOverride<Report>,
+    mut db: PgsqlConn,
+    _access: CalendarAccess<Reader>,
+    calendar_id: UuidParam,
+    configs: State<Configs>,
+    body: DavBody
+) -> Result<DavResponse, DavError>
+{
+    let calendar_id = calendar_id.into_inner();
+
+    let report = match xml::parse_report(&body.0)
+    {
+        Ok(report) => report,
+        Err(e) => return Ok(xml_error(e)),
+    };
+
+    let multistatus = match report
+    {
+        xml::Report::CalendarQuery { props, component, time_range } =>
+        {
+            let responses = objects::get_objects(&mut **db, &calendar_id, None, None)?
+                .iter()
+                .filter(|object| object.matches(component.as_deref(), time_range))
+                .map(|object| object_response(&calendar_id, object, &props))
+                .collect();
+
+            Multistatus { responses, sync_token: None }
+        },
+
+        xml::Report::CalendarMultiget { props, hrefs } =>
+        {
+            let names: Vec<Option<String>> = hrefs.iter().map(|href| object_name(&calendar_id, href)).collect();
+            let found = objects::get_objects(&mut **db, &calendar_id, Some(&names.iter().flatten().cloned().collect::<Vec<String>>()), None)?;
+
+            let responses = hrefs
+                .iter()
+                .zip(names.iter())
+                .map(|(href, name)| match name.as_ref().and_then(|name| found.iter().find(|object| object.get_name() == name))
+                {
+                    Some(object) => object_response(&calendar_id, object, &props),
+                    None => Response::Status { href: href.clone(), status: Status::NotFound },
+                })
+                .collect();
+
+            Multistatus { responses, sync_token: None }
+        },
+
+        xml::Report::SyncCollection { props, sync_token, limit } =>
+        {
+            // The page size is also the most a client can ask for.
+            let page_size = configs.get_page_size() as usize;
+            let max_results = limit.map_or(page_size, |limit| limit.min(page_size));
+
+            let changes = match objects::get_changes(&mut **db, &calendar_id, sync_token.as_deref(), max_results)?
+            {
+                ObjectSyncOutcome::Changes(changes) => changes,
+                ObjectSyncOutcome::InvalidToken => return Ok(DavResponse::Error(Status::Forbidden, Name::new(DAV, "valid-sync-token"))),
+                ObjectSyncOutcome::CalendarNotFound => return Ok(DavResponse::Status(Status::NotFound)),
+            };
+
+            let mut responses: Vec<Response> = changes.changed
+                .iter()
+                .map(|object| object_response(&calendar_id, object, &props))
+                .collect();
+
+            responses.extend(
+                changes.deleted
+                    .iter()
+                    .map(|name| Response::Status { href: object_href(&calendar_id, name), status: Status::NotFound })
+            );
+
+            // RFC 6578 section 3.6, the client has to sync
+            // again with the new token to get the rest.
+            if changes.has_more
+            {
+                responses.push(Response::Status { href: calendar_href(&calendar_id), status: Status::InsufficientStorage });
+            }
+
+            Multistatus { responses, sync_token: Some(changes.sync_token) }
+        },
+    };
+
+    Ok(DavResponse::Multistatus(multistatus))
+}
+
+/// Answers WebDAV methods that aren't supported, e.g. PROPPATCH and MKCALENDAR.
+#[post("/<_path..>", rank = 10)]
+pub fn unsupported_method(_path: PathBuf) -> Status
+{
+    Status::MethodNotAllowed
+}
+
+#[get("/calendars/<calendar_id>/<object>")]
+pub fn get_object(mut db: PgsqlConn, _access: CalendarAccess<Reader>, calendar_id: UuidParam, object: String) -> Result<DavResponse, DavError>
+{
+    match objects::get_objects(&mut **db, &calendar_id.into_inner(), Some(&[object]), None)?.pop()
+    {
+        Some(object) => Ok(DavResponse::Object(object.to_component().to_ics(), quoted(object.get_etag()))),
+        None => Ok(DavResponse::Status(Status::NotFound)),
+    }
+}
+
+/// Creates or replaces an object. Self-writers can only replace the
+/// objects of events they created.
+#[put("/calendars/<calendar_id>/<object>", data = "<body>")]
+pub fn put_object(
+    mut db: PgsqlConn,
+    access: CalendarAccess<SelfWriter>,
+    calendar_id: UuidParam,
+    object: String,
+    preconditions: Preconditions,
+    body: DavBody
+) -> Result<DavResponse, DavError>
+{
+    let vcalendar = match parser::parse(&body.0).map(|mut components| (components.len(), components.pop()))
+    {
+        Ok((1, Some(vcalendar))) => vcalendar,
+        _ => return Ok(DavResponse::Error(Status::Forbidden, Name::new(CALDAV, "valid-calendar-data"))),
+    };
+
+    let calendar = match calendar::get_calendar(&mut **db, &access.get_tenant_id(), &calendar_id.into_inner())?
+    {
+        Some(calendar) => calendar,
+        None => return Ok(DavResponse::Status(Status::NotFound)),
+    };
+
+    let mut transaction = db.transaction()?;
+    let existing = objects::find_for_update(&mut transaction, &calendar.get_id(), &object)?;
+
+    if !preconditions.allow(existing.as_ref().map(|existing| quoted(existing.get_etag())).as_deref())
+    {
+        return Ok(DavResponse::Status(Status::PreconditionFailed));
+    }
+
+    if let Some(existing) = &existing
+    {
+        if !access.can_change_event(existing.get_created_by())
+        {
+            return Ok(DavResponse::Status(Status::Forbidden));
+        }
+    }
+
+    let created_by = access.get_principal().as_principal_ref().to_string();
+
+    let stored = match objects::put(&mut transaction, &calendar, &created_by, &object, &vcalendar, existing.as_ref())
+    {
+        Ok(stored) => stored,
+        Err(ObjectError::Invalid(_)) => return Ok(DavResponse::Error(Status::Forbidden, Name::new(CALDAV, "valid-calendar-object-resource"))),
+        Err(ObjectError::UidConflict) => return Ok(DavResponse::Error(Status::Forbidden, Name::new(CALDAV, "no-uid-conflict"))),
+        Err(ObjectError::Database(e)) => return Err(e.into()),
+    };
+
+    transaction.commit()?;
+
+    Ok(DavResponse::Stored { created: existing.is_none(), etag: quoted(stored.get_etag()) })
+}
+
+/// Deletes an object, see `objects::delete`. Self-writers can only
+/// delete the objects of events they created.
+#[delete("/calendars/<calendar_id>/<object>")]
+pub fn delete_object(mut db: PgsqlConn, access: CalendarAccess<SelfWriter>, calendar_id: UuidParam, object: String, preconditions: Preconditions) -> Result<DavResponse, DavError>
+{
+    let calendar_id = calendar_id.into_inner();
+
+    let mut transaction = db.transaction()?;
+
+    let existing = match objects::find_for_update(&mut transaction, &calendar_id, &object)?
+    {
+        Some(existing) => existing,
+        None => return Ok(DavResponse::Status(Status::NotFound)),
+    };
+
+    if !preconditions.allow(Some(&quoted(existing.get_etag())))
+    {
+        return Ok(DavResponse::Status(Status::PreconditionFailed));
+    }
+
+    if !access.can_change_event(existing.get_created_by())
+    {
+        return Ok(DavResponse::Status(Status::Forbidden));
+    }
+
+    objects::delete(&mut transaction, &calendar_id, &existing)?;
+    transaction.commit()?;
+
+    Ok(DavResponse::Status(Status::NoContent))
+}
+
+#[options("/")]
+pub fn options_root() -> DavResponse
+{
+    DavResponse::Options
+}
+
+#[options("/<_path..>")]
+pub fn options(_path: PathBuf) -> DavResponse
+{
+    DavResponse::Options
+}
+
+/// Clients look for the CalDAV server at `/.well-known/caldav`
+/// (RFC 6764), mounted at `/`.
+#[get("/.well-known/caldav")]
+pub fn well_known() -> Redirect
+{
+    Redirect::moved(format!("{}/", MOUNT_POINT))
+}
+
+#[post("/.well-known/caldav")]
+pub fn well_known_propfind(_method: MethodOverride<Propfind>) -> Redirect
+{
+    Redirect::moved(format!("{}/", MOUNT_POINT))
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::Preconditions;
+
+    fn preconditions(if_match: Option<&str>, if_none_match: Option<&str>) -> Preconditions
+    {
+        Preconditions {
+            if_match: if_match.map(|value| value.to_owned()),
+            if_none_match: if_none_match.map(|value| value.to_owned()),
+        }
+    }
+
+    #[test]
+    fn checks_preconditions()
+    {
+        assert!(preconditions(None, None).allow(None));
+        assert!(preconditions(Some("\"1\", \"2\""), None).allow(Some("\"2\"")));
+        assert!(!preconditions(Some("\"1\""), None).allow(Some("\"2\"")));
+        assert!(!preconditions(Some("*"), None).allow(None));
+
+        // Creating an object that must not exist yet.
+        assert!(preconditions(None, Some("*")).allow(None));
+        assert!(!preconditions(None, Some("*")).allow(Some("\"1\"")));
+    }
+}
@@ -0,0 +1,751 @@
+//! The XML bodies of WebDAV (RFC 4918) and CalDAV (RFC 4791) requests and
+//! responses.
+//!
+//! Request bodies are parsed into a tree of `Element`s with their namespaces
+//! resolved, which `parse_propfind` and `parse_report` read the request from.
+//! Responses are multistatus documents (`Multistatus`), written with fixed
+//! prefixes for the namespaces in `PREFIXES`.
+
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use quick_xml::escape::escape;
+use chrono::NaiveDateTime;
+use rocket::http::Status;
+use std::fmt::Write;
+
+pub const DAV: &str = "DAV:";
+pub const CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+
+/// CalendarServer's and Apple's extensions (`getctag`, `calendar-color`),
+/// which most clients use.
+pub const CALENDARSERVER: &str = "http://calendarserver.org/ns/";
+pub const APPLE_ICAL: &str = "http://apple.com/ns/ical/";
+
+/// The prefixes of the namespaces in responses. Other namespaces are
+/// declared on the elements that use them.
+const PREFIXES: [(&str, &str); 4] = [
+    ("d", DAV),
+    ("c", CALDAV),
+    ("cs", CALENDARSERVER),
+    ("ic", APPLE_ICAL),
+];
+
+#[derive(Error, Debug)]
+pub enum XmlError
+{
+    #[error(transparent)]
+    Xml(#[from] quick_xml::Error),
+
+    #[error("{0}")]
+    Invalid(&'static str),
+
+    #[error("Unsupported report {0}.")]
+    UnsupportedReport(String),
+
+    #[error("Unsupported filter {0}.")]
+    UnsupportedFilter(String),
+}
+
+/// An element's or property's name, e.g. `DAV:` `getetag`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Name
+{
+    pub namespace: String,
+    pub local: String,
+}
+
+impl Name
+{
+    pub fn new(namespace: &str, local: &str) -> Name
+    {
+        Name {
+            namespace: namespace.to_owned(),
+            local: local.to_owned(),
+        }
+    }
+
+    pub fn is(&self, namespace: &str, local: &str) -> bool
+    {
+        self.namespace == namespace && self.local == local
+    }
+
+    fn prefix(&self) -> Option<&'static str>
+    {
+        PREFIXES
+            .iter()
+            .find(|(_, namespace)| *namespace == self.namespace)
+            .map(|(prefix, _)| *prefix)
+    }
+
+    fn write_start(&self, xml: &mut String)
+    {
+        let _ = match self.prefix()
+        {
+            Some(prefix) => write!(xml, "<{}:{}", prefix, self.local),
+            None => write!(xml, "<{} xmlns=\"{}\"", self.local, escape_str(&self.namespace)),
+        };
+    }
+
+    fn write_end(&self, xml: &mut String)
+    {
+        let _ = match self.prefix()
+        {
+            Some(prefix) => write!(xml, "</{}:{}>", prefix, self.local),
+            None => write!(xml, "</{}>", self.local),
+        };
+    }
+
+    /// Writes the element with the content, which must already be XML.
+    fn write_element(&self, xml: &mut String, content: &str)
+    {
+        self.write_start(xml);
+
+        if content.is_empty()
+        {
+            xml.push_str("/>");
+        }
+        else
+        {
+            xml.push('>');
+            xml.push_str(content);
+            self.write_end(xml);
+        }
+    }
+}
+
+/// An XML element, with its text if it has no children.
+#[derive(Debug)]
+pub struct Element
+{
+    pub name: Name,
+    attributes: Vec<(String, String)>,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element
+{
+    pub fn child(&self, namespace: &str, local: &str) -> Option<&Element>
+    {
+        self.children.iter().find(|child| child.name.is(namespace, local))
+    }
+
+    /// The value of an attribute without a namespace.
+    pub fn attribute(&self, name: &str) -> Option<&str>
+    {
+        self.attributes.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+/// Parses the document into a tree of elements, returns its root.
+pub fn parse(xml: &str) -> Result<Element, XmlError>
+{
+    let mut reader = Reader::from_str(xml);
+    let mut buf = vec![];
+    let mut namespace_buf = vec![];
+
+    // The elements that were started but not ended yet.
+    let mut stack: Vec<Element> = vec![];
+    let mut root = None;
+
+    loop
+    {
+        let (namespace, event) = reader.read_namespaced_event(&mut buf, &mut namespace_buf)?;
+        let namespace = namespace.map(|namespace| String::from_utf8_lossy(namespace).into_owned()).unwrap_or_default();
+
+        let element = match event
+        {
+            Event::Start(start) =>
+            {
+                stack.push(new_element(&reader, namespace, &start)?);
+                None
+            },
+
+            Event::Empty(empty) => Some(new_element(&reader, namespace, &empty)?),
+            Event::End(_) => stack.pop(),
+
+            Event::Text(text) =>
+            {
+                if let Some(element) = stack.last_mut()
+                {
+                    element.text.push_str(&text.unescape_and_decode(&reader)?);
+                }
+
+                None
+            },
+
+            Event::CData(text) =>
+            {
+                if let Some(element) = stack.last_mut()
+                {
+                    element.text.push_str(&String::from_utf8_lossy(text.escaped()));
+                }
+
+                None
+            },
+
+            Event::Eof => break,
+            _ => None,
+        };
+
+        // An element ended, add it to its parent.
+        if let Some(element) = element
+        {
+            match stack.last_mut()
+            {
+                Some(parent) => parent.children.push(element),
+                None => root = Some(element),
+            }
+        }
+
+        buf.clear();
+    }
+
+    root.ok_or(XmlError::Invalid("The body is not an XML document."))
+}
+
+fn new_element(reader: &Reader<&[u8]>, namespace: String, start: &quick_xml::events::BytesStart) -> Result<Element, XmlError>
+{
+    let mut attributes = vec![];
+
+    for attribute in start.attributes()
+    {
+        let attribute = attribute?;
+
+        // Namespace declarations were already resolved.
+        if attribute.key.starts_with(b"xmlns")
+        {
+            continue;
+        }
+
+        attributes.push((
+            String::from_utf8_lossy(attribute.key).into_owned(),
+            attribute.unescape_and_decode_value(reader)?,
+        ));
+    }
+
+    Ok(
+        Element {
+            name: Name {
+                namespace,
+                local: String::from_utf8_lossy(start.local_name()).into_owned(),
+            },
+            attributes,
+            children: vec![],
+            text: String::new(),
+        }
+    )
+}
+
+/// The properties a PROPFIND or a REPORT asks for.
+#[derive(Debug, Eq, PartialEq)]
+pub enum PropRequest
+{
+    /// All properties, except for expensive ones like `calendar-data`.
+    AllProp,
+
+    /// The names of all properties, without their values.
+    PropName,
+
+    Prop(Vec<Name>),
+}
+
+impl PropRequest
+{
+    /// The request in a `propfind` element or a report's element.
+    fn from_element(element: &Element) -> PropRequest
+    {
+        if let Some(prop) = element.child(DAV, "prop")
+        {
+            PropRequest::Prop(prop.children.iter().map(|child| child.name.clone()).collect())
+        }
+        else if element.child(DAV, "propname").is_some()
+        {
+            PropRequest::PropName
+        }
+        else
+        {
+            PropRequest::AllProp
+        }
+    }
+
+    /// Whether the property was asked for by name.
+    pub fn names(&self, namespace: &str, local: &str) -> bool
+    {
+        match self
+        {
+            PropRequest::Prop(names) => names.iter().any(|name| name.is(namespace, local)),
+            _ => false,
+        }
+    }
+}
+
+/// A window of time, in UTC. Either bound can be open.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TimeRange
+{
+    pub start: Option<NaiveDateTime>,
+    pub end: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Report
+{
+    /// The objects that match the filter (RFC 4791 section 7.8). Only
+    /// filters on the component and a time range are supported: None
+    /// matches every object, `component` is e.g. `VEVENT`.
+    CalendarQuery
+    {
+        props: PropRequest,
+        component: Option<String>,
+        time_range: Option<TimeRange>,
+    },
+
+    /// The objects with the hrefs (RFC 4791 section 7.9).
+    CalendarMultiget
+    {
+        props: PropRequest,
+        hrefs: Vec<String>,
+    },
+
+    /// The objects that changed since the sync token (RFC 6578).
+    SyncCollection
+    {
+        props: PropRequest,
+        sync_token: Option<String>,
+        limit: Option<usize>,
+    },
+}
+
+/// Parses a PROPFIND's body. An empty body asks for all properties.
+pub fn parse_propfind(body: &str) -> Result<PropRequest, XmlError>
+{
+    if body.trim().is_empty()
+    {
+        return Ok(PropRequest::AllProp);
+    }
+
+    let root = parse(body)?;
+
+    if !root.name.is(DAV, "propfind")
+    {
+        return Err(XmlError::Invalid("The body must be a propfind element."));
+    }
+
+    Ok(PropRequest::from_element(&root))
+}
+
+pub fn parse_report(body: &str) -> Result<Report, XmlError>
+{
+    let root = parse(body)?;
+    let props = PropRequest::from_element(&root);
+
+    if root.name.is(CALDAV, "calendar-query")
+    {
+        let filter = root.child(CALDAV, "filter").ok_or(XmlError::Invalid("calendar-query must have a filter."))?;
+        let (component, time_range) = parse_filter(filter)?;
+
+        Ok(Report::CalendarQuery { props, component, time_range })
+    }
+    else if root.name.is(CALDAV, "calendar-multiget")
+    {
+        let hrefs = root.children
+            .iter()
+            .filter(|child| child.name.is(DAV, "href"))
+            .map(|href| href.text.trim().to_owned())
+            .collect();
+
+        Ok(Report::CalendarMultiget { props, hrefs })
+    }
+    else if root.name.is(DAV, "sync-collection")
+    {
+        let sync_token = root.child(DAV, "sync-token")
+            .map(|token| token.text.trim().to_owned())
+            .filter(|token| !token.is_empty());
+
+        let limit = match root.child(DAV, "limit").and_then(|limit| limit.child(DAV, "nresults"))
+        {
+            Some(nresults) => Some(nresults.text.trim().parse().map_err(|_| XmlError::Invalid("nresults must be a positive integer."))?),
+            None => None,
+        };
+
+        Ok(Report::SyncCollection { props, sync_token, limit })
+    }
+    else
+    {
+        Err(XmlError::UnsupportedReport(root.name.local))
+    }
+}
+
+/// Reads a calendar-query's filter: the component that's filtered for (inside
+/// the VCALENDAR's comp-filter) and its time range.
+fn parse_filter(filter: &Element) -> Result<(Option<String>, Option<TimeRange>), XmlError>
+{
+    let vcalendar = match filter.children.as_slice()
+    {
+        [vcalendar] if vcalendar.name.is(CALDAV, "comp-filter") && vcalendar.attribute("name") == Some("VCALENDAR") => vcalendar,
+        _ => return Err(XmlError::Invalid("The filter must have a comp-filter for VCALENDAR.")),
+    };
+
+    let component = match vcalendar.children.as_slice()
+    {
+        [] => return Ok((None, None)),
+        [component] if component.name.is(CALDAV, "comp-filter") => component,
+        [other, ..] => return Err(XmlError::UnsupportedFilter(other.name.local.clone())),
+    };
+
+    let name = component.attribute("name").ok_or(XmlError::Invalid("comp-filter must have a name."))?;
+    let mut time_range = None;
+
+    for child in component.children.iter()
+    {
+        if !child.name.is(CALDAV, "time-range")
+        {
+            return Err(XmlError::UnsupportedFilter(child.name.local.clone()));
+        }
+
+        time_range = Some(TimeRange {
+            start: child.attribute("start").map(parse_utc_date_time).transpose()?,
+            end: child.attribute("end").map(parse_utc_date_time).transpose()?,
+        });
+    }
+
+    Ok((Some(name.to_ascii_uppercase()), time_range))
+}
+
+/// Parses a UTC date-time like `20200901T100000Z`.
+fn parse_utc_date_time(value: &str) -> Result<NaiveDateTime, XmlError>
+{
+    value
+        .strip_suffix('Z')
+        .and_then(|value| NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok())
+        .ok_or(XmlError::Invalid("Time ranges must be UTC date-times."))
+}
+
+/// A property's value in a response.
+#[derive(Debug)]
+pub enum PropValue
+{
+    Empty,
+    Text(String),
+    Href(String),
+
+    /// Elements, which must use the prefixes in `PREFIXES`.
+    Xml(String),
+}
+
+#[derive(Debug)]
+pub enum Response
+{
+    Props
+    {
+        href: String,
+        found: Vec<(Name, PropValue)>,
+        not_found: Vec<Name>,
+    },
+
+    /// A resource without properties, e.g. one that was deleted (404).
+    Status
+    {
+        href: String,
+        status: Status,
+    },
+}
+
+impl Response
+{
+    /// The response for a resource with the properties `props`, with those
+    /// the request asks for.
+    pub fn props(href: String, props: Vec<(Name, PropValue)>, request: &PropRequest) -> Response
+    {
+        let (found, not_found) = match request
+        {
+            PropRequest::AllProp => (props, vec![]),
+            PropRequest::PropName => (props.into_iter().map(|(name, _)| (name, PropValue::Empty)).collect(), vec![]),
+            PropRequest::Prop(names) =>
+            {
+                let mut props = props;
+                let mut found = vec![];
+                let mut not_found = vec![];
+
+                for name in names.iter()
+                {
+                    match props.iter().position(|(prop, _)| prop == name)
+                    {
+                        Some(i) => found.push(props.remove(i)),
+                        None => not_found.push(name.clone()),
+                    }
+                }
+
+                (found, not_found)
+            },
+        };
+
+        Response::Props { href, found, not_found }
+    }
+
+    fn write(&self, xml: &mut String)
+    {
+        xml.push_str("<d:response>");
+
+        match self
+        {
+            Response::Props { href, found, not_found } =>
+            {
+                write_href(xml, href);
+
+                if !found.is_empty()
+                {
+                    xml.push_str("<d:propstat><d:prop>");
+
+                    for (name, value) in found.iter()
+                    {
+                        let content = match value
+                        {
+                            PropValue::Empty => String::new(),
+                            PropValue::Text(text) => escape_str(text),
+                            PropValue::Href(href) =>
+                            {
+                                let mut content = String::new();
+                                write_href(&mut content, href);
+                                content
+                            },
+                            PropValue::Xml(content) => content.clone(),
+                        };
+
+                        name.write_element(xml, &content);
+                    }
+
+                    xml.push_str("</d:prop>");
+                    write_status(xml, Status::Ok);
+                    xml.push_str("</d:propstat>");
+                }
+
+                if !not_found.is_empty()
+                {
+                    xml.push_str("<d:propstat><d:prop>");
+
+                    for name in not_found.iter()
+                    {
+                        name.write_element(xml, "");
+                    }
+
+                    xml.push_str("</d:prop>");
+                    write_status(xml, Status::NotFound);
+                    xml.push_str("</d:propstat>");
+                }
+            },
+
+            Response::Status { href, status } =>
+            {
+                write_href(xml, href);
+                write_status(xml, *status);
+            },
+        }
+
+        xml.push_str("</d:response>");
+    }
+}
+
+/// A 207 response's body (RFC 4918 section 13).
+#[derive(Debug, Default)]
+pub struct Multistatus
+{
+    pub responses: Vec<Response>,
+
+    /// The sync token of sync-collection reports.
+    pub sync_token: Option<String>,
+}
+
+impl Multistatus
+{
+    pub fn to_xml(&self) -> String
+    {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<d:multistatus");
+        write_namespaces(&mut xml);
+        xml.push('>');
+
+        for response in self.responses.iter()
+        {
+            response.write(&mut xml);
+        }
+
+        if let Some(sync_token) = &self.sync_token
+        {
+            let _ = write!(xml, "<d:sync-token>{}</d:sync-token>", escape_str(sync_token));
+        }
+
+        xml.push_str("</d:multistatus>\n");
+
+        xml
+    }
+}
+
+/// The body of an error response with a precondition or postcondition
+/// (RFC 4918 section 16), e.g. `supported-filter`.
+pub fn error_body(condition: &Name) -> String
+{
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<d:error");
+    write_namespaces(&mut xml);
+    xml.push('>');
+    condition.write_element(&mut xml, "");
+    xml.push_str("</d:error>\n");
+
+    xml
+}
+
+fn write_namespaces(xml: &mut String)
+{
+    for (prefix, namespace) in PREFIXES.iter()
+    {
+        let _ = write!(xml, " xmlns:{}=\"{}\"", prefix, namespace);
+    }
+}
+
+pub fn write_href(xml: &mut String, href: &str)
+{
+    let _ = write!(xml, "<d:href>{}</d:href>", escape_str(href));
+}
+
+fn write_status(xml: &mut String, status: Status)
+{
+    let _ = write!(xml, "<d:status>HTTP/1.1 {} {}</d:status>", status.code, status.reason);
+}
+
+pub fn escape_str(text: &str) -> String
+{
+    String::from_utf8_lossy(&escape(text.as_bytes())).into_owned()
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{parse_propfind, parse_report, Multistatus, Name, PropRequest, PropValue, Report, Response, TimeRange, XmlError, CALDAV, DAV};
+    use chrono::NaiveDate;
+    use rocket::http::Status;
+
+    #[test]
+    fn parses_propfinds()
+    {
+        let body = r#"<?xml version="1.0" encoding="utf-8" ?>
+            <D:propfind xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+              <D:prop>
+                <D:resourcetype/>
+                <C:calendar-home-set/>
+                <x:color xmlns:x="urn:example"/>
+              </D:prop>
+            </D:propfind>"#;
+
+        assert_eq!(
+            parse_propfind(body).unwrap(),
+            PropRequest::Prop(vec![
+                Name::new(DAV, "resourcetype"),
+                Name::new(CALDAV, "calendar-home-set"),
+                Name::new("urn:example", "color"),
+            ])
+        );
+
+        assert_eq!(parse_propfind("").unwrap(), PropRequest::AllProp);
+        assert_eq!(parse_propfind("<propfind xmlns=\"DAV:\"><propname/></propfind>").unwrap(), PropRequest::PropName);
+        assert!(parse_propfind("<propfind><allprop/></propfind>").is_err());
+    }
+
+    #[test]
+    fn parses_calendar_queries()
+    {
+        // RFC 4791 section 7.8.1, without the calendar-data's expand.
+        let body = r#"<?xml version="1.0" encoding="utf-8" ?>
+            <C:calendar-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+              <D:prop>
+                <D:getetag/>
+                <C:calendar-data/>
+              </D:prop>
+              <C:filter>
+                <C:comp-filter name="VCALENDAR">
+                  <C:comp-filter name="VEVENT">
+                    <C:time-range start="20060104T000000Z" end="20060105T000000Z"/>
+                  </C:comp-filter>
+                </C:comp-filter>
+              </C:filter>
+            </C:calendar-query>"#;
+
+        match parse_report(body).unwrap()
+        {
+            Report::CalendarQuery { props, component, time_range } =>
+            {
+                assert!(props.names(CALDAV, "calendar-data"));
+                assert_eq!(component.as_deref(), Some("VEVENT"));
+                assert_eq!(time_range, Some(TimeRange {
+                    start: Some(NaiveDate::from_ymd(2006, 1, 4).and_hms(0, 0, 0)),
+                    end: Some(NaiveDate::from_ymd(2006, 1, 5).and_hms(0, 0, 0)),
+                }));
+            },
+            report => panic!("Unexpected report {:?}", report),
+        }
+
+        let prop_filter = r#"<C:calendar-query xmlns:C="urn:ietf:params:xml:ns:caldav"><C:filter><C:comp-filter name="VCALENDAR">
+            <C:comp-filter name="VEVENT"><C:prop-filter name="UID"/></C:comp-filter>
+            </C:comp-filter></C:filter></C:calendar-query>"#;
+
+        assert!(matches!(parse_report(prop_filter), Err(XmlError::UnsupportedFilter(_))));
+        assert!(matches!(parse_report("<D:expand-property xmlns:D=\"DAV:\"/>"), Err(XmlError::UnsupportedReport(_))));
+    }
+
+    #[test]
+    fn parses_multigets_and_syncs()
+    {
+        let multiget = r#"<C:calendar-multiget xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+            <D:prop><D:getetag/></D:prop>
+            <D:href>/dav/calendars/1/a.ics</D:href>
+            <D:href>/dav/calendars/1/b%20c.ics</D:href>
+            </C:calendar-multiget>"#;
+
+        assert_eq!(
+            parse_report(multiget).unwrap(),
+            Report::CalendarMultiget {
+                props: PropRequest::Prop(vec![Name::new(DAV, "getetag")]),
+                hrefs: vec!["/dav/calendars/1/a.ics".to_owned(), "/dav/calendars/1/b%20c.ics".to_owned()],
+            }
+        );
+
+        let sync = r#"<sync-collection xmlns="DAV:"><sync-token/><sync-level>1</sync-level><prop><getetag/></prop></sync-collection>"#;
+
+        assert_eq!(
+            parse_report(sync).unwrap(),
+            Report::SyncCollection {
+                props: PropRequest::Prop(vec![Name::new(DAV, "getetag")]),
+                sync_token: None,
+                limit: None,
+            }
+        );
+    }
+
+    #[test]
+    fn writes_multistatus()
+    {
+        let request = PropRequest::Prop(vec![Name::new(DAV, "displayname"), Name::new("urn:example", "color")]);
+
+        let multistatus = Multistatus {
+            responses: vec![
+                Response::props(
+                    "/dav/calendars/1/".to_owned(),
+                    vec![
+                        (Name::new(DAV, "displayname"), PropValue::Text("Work & co".to_owned())),
+                        (Name::new(DAV, "getetag"), PropValue::Text("\"1\"".to_owned())),
+                    ],
+                    &request
+                ),
+                Response::Status { href: "/dav/calendars/1/a.ics".to_owned(), status: Status::NotFound },
+            ],
+            sync_token: Some("urn:token".to_owned()),
+        };
+
+        let xml = multistatus.to_xml();
+
+        assert!(xml.contains("<d:prop><d:displayname>Work &amp; co</d:displayname></d:prop><d:status>HTTP/1.1 200 OK</d:status>"));
+        assert!(xml.contains("<d:prop><color xmlns=\"urn:example\"/></d:prop><d:status>HTTP/1.1 404 Not Found</d:status>"));
+        assert!(xml.contains("<d:response><d:href>/dav/calendars/1/a.ics</d:href><d:status>HTTP/1.1 404 Not Found</d:status></d:response>"));
+        assert!(xml.ends_with("<d:sync-token>urn:token</d:sync-token></d:multistatus>\n"));
+        assert!(!xml.contains("getetag"));
+    }
+}
@@ -1,9 +1,15 @@
 use crate::database_helpers::{FromRow, get_cell_from_row};
-use postgres::Row;
+use postgres::{Row, GenericClient};
 use crate::database_error::DatabaseError;
 use uuid::Uuid;
+use chrono::NaiveDateTime;
+use chrono_tz::Tz;
+use std::str::FromStr;
+use serde::Deserialize;
 
-pub const CALENDAR_FIELDS: &str = "id, tenant_id";
+pub const CALENDAR_FIELDS: &str = "id, name, description, timezone, color, owner_id, last_modified, deleted_at";
+
+pub const MAX_NAME_LENGTH: usize = 255;
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct Calendar
@@ -14,19 +20,71 @@ pub struct Calendar
     /// for create requests.
     #[serde(default = "Uuid::nil")]
     id: Uuid,
+
+    name: String,
+
+    #[serde(default)]
+    description: Option<String>,
+
+    /// IANA time zone name (e.g. `America/Sao_Paulo`) used
+    /// for the calendar's events by default.
+    #[serde(default = "default_timezone")]
+    timezone: String,
+
+    /// Hex color, like `#1a2b3c`.
+    #[serde(default)]
+    color: Option<String>,
+
+    /// Id of the calendar's owner in the client's system.
+    #[serde(default)]
+    owner_id: Option<Uuid>,
+
+    /// Ignored in requests.
+    #[serde(default, with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    last_modified: Option<NaiveDateTime>,
+
+    /// True if this is the tombstone of a deleted calendar.
+    /// Tombstones are only returned when checking for changes.
+    #[serde(default)]
+    deleted: bool,
 }
 
+fn default_timezone() -> String { "UTC".to_owned() }
+
 
 impl Calendar
 {
-    pub fn new(id: Uuid) -> Calendar
+    pub fn get_id(&self) -> Uuid { self.id }
+
+    pub fn get_name(&self) -> &str { &self.name }
+
+    pub fn get_description(&self) -> Option<&String> { self.description.as_ref() }
+
+    pub fn get_timezone(&self) -> &str { &self.timezone }
+
+    pub fn get_color(&self) -> Option<&String> { self.color.as_ref() }
+
+    pub fn get_owner_id(&self) -> Option<Uuid> { self.owner_id }
+
+    pub fn get_last_modified(&self) -> Option<NaiveDateTime> { self.last_modified }
+
+    /// Validate the calendar's data for an insert or
+    /// a PUT request.
+    ///
+    /// List of validation checks:
+    ///
+    /// - Checks if `name` is not empty and not longer than `MAX_NAME_LENGTH`.
+    /// - Checks if `timezone` is a known IANA time zone.
+    /// - Checks if `color` is a hex color, if it's set.
+    ///
+    /// Returns `true` if the calendar is valid, `false` it it's not.
+    pub fn validate(&self) -> bool
     {
-        Calendar {
-            id
-        }
+        is_valid_name(&self.name)
+            && is_valid_timezone(&self.timezone)
+            && self.color.as_ref().map_or(true, |c| is_valid_color(c))
     }
-
-    pub fn get_id(&self) -> Uuid { self.id }
 }
 
 impl FromRow for Calendar
@@ -37,8 +95,144 @@ impl FromRow for Calendar
     {
         Ok (
             Calendar {
-                id: get_cell_from_row(row, "id")?
+                id: get_cell_from_row(row, "id")?,
+                name: get_cell_from_row(row, "name")?,
+                description: get_cell_from_row(row, "description")?,
+                timezone: get_cell_from_row(row, "timezone")?,
+                color: get_cell_from_row(row, "color")?,
+                owner_id: get_cell_from_row(row, "owner_id")?,
+                last_modified: Some(get_cell_from_row(row, "last_modified")?),
+                deleted: get_cell_from_row::<Option<NaiveDateTime>>(row, "deleted_at")?.is_some(),
             }
         )
     }
-}
\ No newline at end of file
+}
+
+/// Gets one of the tenant's calendars, unless it's deleted.
+pub fn get_calendar(db: &mut impl GenericClient, tenant_id: &Uuid, calendar_id: &Uuid) -> Result<Option<Calendar>, DatabaseError>
+{
+    let query = format!("SELECT {} FROM calendars WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NULL;", CALENDAR_FIELDS);
+
+    let rows = db.query(query.as_str(), &[calendar_id, tenant_id])?;
+
+    rows.get(0)
+        .map(|row| Calendar::from_row(row))
+        .transpose()
+}
+
+/// Lists the tenant's calendars, except for deleted ones. If `calendar_ids`
+/// is set, only calendars in it are listed.
+pub fn list_calendars(db: &mut impl GenericClient, tenant_id: &Uuid, calendar_ids: Option<&Vec<Uuid>>, offset: i64, limit: i64) -> Result<Vec<Calendar>, DatabaseError>
+{
+    let query = format!("
+        SELECT {} FROM calendars
+        WHERE
+            tenant_id = $1
+            AND deleted_at IS NULL
+            AND ($2::UUID[] IS NULL OR id = ANY($2))
+        ORDER BY id
+        OFFSET $3
+        LIMIT $4;
+    ", CALENDAR_FIELDS);
+
+    let rows = db.query(query.as_str(), &[tenant_id, &calendar_ids, &offset, &limit])?;
+
+    rows.iter()
+        .map(|row| Calendar::from_row(row))
+        .collect()
+}
+
+/// Request body of PATCH requests. Fields that are not set are
+/// left unchanged, fields that can be null are cleared if they're
+/// set to null.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct CalendarPatch
+{
+    #[serde(default)]
+    pub name: Option<String>,
+
+    #[serde(default, deserialize_with = "double_option")]
+    pub description: Option<Option<String>>,
+
+    #[serde(default)]
+    pub timezone: Option<String>,
+
+    #[serde(default, deserialize_with = "double_option")]
+    pub color: Option<Option<String>>,
+
+    #[serde(default, deserialize_with = "double_option")]
+    pub owner_id: Option<Option<Uuid>>,
+}
+
+impl CalendarPatch
+{
+    /// Runs the same checks as `Calendar::validate` on the
+    /// fields that are set.
+    pub fn validate(&self) -> bool
+    {
+        self.name.as_ref().map_or(true, |n| is_valid_name(n))
+            && self.timezone.as_ref().map_or(true, |tz| is_valid_timezone(tz))
+            && self.color.as_ref().map_or(true, |c| c.as_ref().map_or(true, |c| is_valid_color(c)))
+    }
+}
+
+/// Deserializes a field that is present (even if it's null) into Some, so
+/// that together with `#[serde(default)]` a missing field is None and a null
+/// field is Some(None).
+fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: serde::Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+fn is_valid_name(name: &str) -> bool
+{
+    !name.trim().is_empty() && name.chars().count() <= MAX_NAME_LENGTH
+}
+
+fn is_valid_timezone(timezone: &str) -> bool
+{
+    Tz::from_str(timezone).is_ok()
+}
+
+/// Checks if `color` is formatted like `#1a2b3c`.
+fn is_valid_color(color: &str) -> bool
+{
+    color.len() == 7
+        && color.starts_with('#')
+        && color[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{is_valid_color, is_valid_timezone, CalendarPatch};
+
+    #[test]
+    fn colors()
+    {
+        assert!(is_valid_color("#1a2B3c"));
+        assert!(!is_valid_color("1a2b3c"));
+        assert!(!is_valid_color("#1a2b3"));
+        assert!(!is_valid_color("#1a2b3g"));
+    }
+
+    #[test]
+    fn timezones()
+    {
+        assert!(is_valid_timezone("UTC"));
+        assert!(is_valid_timezone("America/Sao_Paulo"));
+        assert!(!is_valid_timezone("America/Nowhere"));
+    }
+
+    #[test]
+    fn patch_null_vs_missing()
+    {
+        let patch: CalendarPatch = serde_json::from_str(r#"{ "description": null }"#).unwrap();
+
+        assert_eq!(patch.description, Some(None));
+        assert_eq!(patch.color, None);
+    }
+}
@@ -0,0 +1,109 @@
+//! The change feed records every change to calendars and events, along with
+//! the change sequence number (see `sync`) it got. Changes to events are recorded
+//! by database triggers, changes to calendars have to be recorded manually
+//! with `record_calendar_change`.
+//!
+//! Entries should always be recorded in the same transaction as the
+//! change they describe.
+
+use postgres::{Row, GenericClient};
+use crate::database_error::{DatabaseError, DatabaseErrorKind};
+use crate::database_helpers::{FromRow, get_cell_from_row};
+use uuid::Uuid;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeType
+{
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl ChangeType
+{
+    /// The value stored in the `change_type` column.
+    pub fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            ChangeType::Created => "created",
+            ChangeType::Updated => "updated",
+            ChangeType::Deleted => "deleted",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<ChangeType>
+    {
+        match s
+        {
+            "created" => Some(ChangeType::Created),
+            "updated" => Some(ChangeType::Updated),
+            "deleted" => Some(ChangeType::Deleted),
+            _ => None,
+        }
+    }
+}
+
+/// An entry of the change feed, as sent to change streams (see `change_stream`).
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct FeedEntry
+{
+    pub calendar_id: Uuid,
+
+    /// The changed event, None if the calendar itself changed.
+    pub event_id: Option<Uuid>,
+
+    pub change_type: ChangeType,
+
+    /// The change's sequence number, see `sync`.
+    pub seq: i64,
+}
+
+impl FromRow for FeedEntry
+{
+    type SelfType = FeedEntry;
+
+    fn from_row(row: &Row) -> Result<Self::SelfType, DatabaseError>
+    {
+        let change_type: String = get_cell_from_row(row, "change_type")?;
+
+        Ok(
+            FeedEntry {
+                calendar_id: get_cell_from_row(row, "calendar_id")?,
+                event_id: get_cell_from_row(row, "event_id")?,
+                change_type: ChangeType::from_str(&change_type)
+                    .ok_or_else(|| DatabaseErrorKind::FailedConstraint("change_type".to_owned()))?,
+                seq: get_cell_from_row(row, "seq")?,
+            }
+        )
+    }
+}
+
+/// Gets at most `limit` of the calendar's entries with a sequence
+/// number greater than `seq`, in order.
+pub fn entries_since(db: &mut impl GenericClient, calendar_id: &Uuid, seq: i64, limit: i64) -> Result<Vec<FeedEntry>, DatabaseError>
+{
+    let query = "
+        SELECT calendar_id, event_id, change_type, seq FROM change_feed
+        WHERE calendar_id = $1 AND seq > $2
+        ORDER BY seq
+        LIMIT $3;
+    ";
+
+    db.query(query, &[calendar_id, &seq, &limit])?
+        .iter()
+        .map(|row| FeedEntry::from_row(row))
+        .collect()
+}
+
+/// Records a change to the calendar itself and gives it the
+/// calendar's next change sequence number.
+pub fn record_calendar_change(db: &mut impl GenericClient, calendar_id: &Uuid, change_type: ChangeType) -> Result<(), DatabaseError>
+{
+    let query = "INSERT INTO change_feed (calendar_id, change_type, seq) VALUES ($1, $2, next_change_seq($1));";
+
+    db.execute(query, &[calendar_id, &change_type.as_str()])?;
+
+    Ok(())
+}
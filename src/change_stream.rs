@@ -0,0 +1,533 @@
+//! Pushes changes to calendars and their events to clients as they happen,
+//! as Server-Sent Events, instead of them polling for changes.
+//!
+//! Every entry of the change feed is `NOTIFY`ed to the `calendar_changes` channel
+//! (see db_schema/16.sql). A listener thread gets them and hands them to the
+//! `ChangeBroadcaster`, which passes them on to the streams of their calendar.
+//! Each event of a stream has the change's sequence number as its id, so clients
+//! that reconnect with a `Last-Event-ID` get what they missed from the change feed.
+//!
+//! Every stream keeps one of Rocket's workers busy, so there's a limit on how many
+//! can be open at once. Streams are also closed after a while, so that clients
+//! that lost access to the calendar since they connected don't keep getting its
+//! changes: reconnecting checks their access again.
+
+use crate::change_feed::{self, ChangeType, FeedEntry};
+use crate::connection_pool::PgsqlPool;
+use crate::database_error::DatabaseError;
+use crate::database_helpers::get_cell_from_row;
+use postgres::GenericClient;
+use postgres::fallible_iterator::FallibleIterator;
+use rocket::Request;
+use rocket::request::{FromRequest, Outcome};
+use uuid::Uuid;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{warn, error};
+
+const CHANNEL: &str = "calendar_changes";
+
+/// How many changes a stream can fall behind before it's closed. The client
+/// then reconnects and gets what it missed from the change feed.
+const STREAM_BUFFER: usize = 256;
+
+/// How often streams send a comment when there are no changes, so that
+/// proxies keep them open and closed connections are noticed.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often the listener checks its connection when there are no changes.
+const LISTENER_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+type Streams = HashMap<Uuid, Vec<(u64, SyncSender<FeedEntry>)>>;
+
+/// Hands the changes the listener gets to the streams of their calendar.
+#[derive(Clone)]
+pub struct ChangeBroadcaster
+{
+    streams: Arc<Mutex<Streams>>,
+    next_id: Arc<AtomicU64>,
+
+    /// How many subscriptions there are, including the ones of
+    /// streams that were dropped from `streams` but are still open.
+    open_streams: Arc<AtomicUsize>,
+
+    max_streams: usize,
+}
+
+impl ChangeBroadcaster
+{
+    pub fn new(max_streams: usize) -> ChangeBroadcaster
+    {
+        ChangeBroadcaster {
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(0)),
+            open_streams: Arc::new(AtomicUsize::new(0)),
+            max_streams,
+        }
+    }
+
+    /// Starts getting the calendar's changes, until the subscription is dropped.
+    /// Returns None if there are `max_streams` subscriptions already.
+    fn subscribe(&self, calendar_id: Uuid) -> Option<Subscription>
+    {
+        if self.open_streams.fetch_add(1, Ordering::SeqCst) >= self.max_streams
+        {
+            self.open_streams.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+
+        let (sender, receiver) = mpsc::sync_channel(STREAM_BUFFER);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        self.lock().entry(calendar_id).or_default().push((id, sender));
+
+        Some(
+            Subscription {
+                broadcaster: self.clone(),
+                calendar_id,
+                id,
+                receiver,
+            }
+        )
+    }
+
+    /// Sends the entry to the streams of its calendar. Streams that fell too
+    /// far behind are dropped, they're closed once they send what they have.
+    fn publish(&self, entry: &FeedEntry)
+    {
+        let mut streams = self.lock();
+
+        if let Some(senders) = streams.get_mut(&entry.calendar_id)
+        {
+            senders.retain(|(_, sender)| sender.try_send(entry.clone()).is_ok());
+
+            if senders.is_empty()
+            {
+                streams.remove(&entry.calendar_id);
+            }
+        }
+    }
+
+    /// Drops every stream, see `spawn_listener`.
+    fn close_all(&self)
+    {
+        self.lock().clear();
+    }
+
+    fn unsubscribe(&self, calendar_id: &Uuid, id: u64)
+    {
+        let mut streams = self.lock();
+
+        if let Some(senders) = streams.get_mut(calendar_id)
+        {
+            senders.retain(|(sender_id, _)| *sender_id != id);
+
+            if senders.is_empty()
+            {
+                streams.remove(calendar_id);
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<Streams>
+    {
+        // The map is never left half changed, so it's fine to keep using it
+        // if a thread panicked while holding the lock.
+        self.streams.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+struct Subscription
+{
+    broadcaster: ChangeBroadcaster,
+    calendar_id: Uuid,
+    id: u64,
+    receiver: Receiver<FeedEntry>,
+}
+
+impl Drop for Subscription
+{
+    fn drop(&mut self)
+    {
+        self.broadcaster.unsubscribe(&self.calendar_id, self.id);
+        self.broadcaster.open_streams.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Spawns a thread that `LISTEN`s for changes on a connection of its own
+/// and hands them to `broadcaster`. Reconnects if the connection is lost.
+pub fn spawn_listener(pool: PgsqlPool, broadcaster: ChangeBroadcaster)
+{
+    thread::spawn(move || {
+        loop
+        {
+            if let Err(e) = listen(&pool, &broadcaster)
+            {
+                error!("Stopped listening for changes: {}", e);
+            }
+
+            // Changes made while we're not listening would never reach the
+            // streams, so they're closed and their clients resume from the
+            // change feed once we're back.
+            broadcaster.close_all();
+
+            thread::sleep(RECONNECT_DELAY);
+        }
+    });
+}
+
+fn listen(pool: &PgsqlPool, broadcaster: &ChangeBroadcaster) -> Result<(), postgres::Error>
+{
+    let mut client = pool.connect()?;
+    client.batch_execute(&format!("LISTEN {};", CHANNEL))?;
+
+    // Streams opened before we were listening may have missed changes.
+    broadcaster.close_all();
+
+    loop
+    {
+        match client.notifications().timeout_iter(LISTENER_CHECK_INTERVAL).next()?
+        {
+            Some(notification) => match serde_json::from_str::<FeedEntry>(notification.payload())
+            {
+                Ok(entry) => broadcaster.publish(&entry),
+                Err(e) => warn!("Invalid change notification: {}", e),
+            },
+
+            // The iterator doesn't tell a quiet channel from a lost connection.
+            None => client.batch_execute("SELECT 1;")?,
+        }
+    }
+}
+
+/// The `Last-Event-ID` header EventSource clients send when reconnecting.
+pub struct LastEventId(Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for LastEventId
+{
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error>
+    {
+        Outcome::Success(LastEventId(request.headers().get_one("Last-Event-ID").map(|id| id.to_owned())))
+    }
+}
+
+/// A stream of a calendar's changes as Server-Sent Events, see
+/// docs/resources.md. Reading blocks until there's something to send.
+pub struct ChangeStream
+{
+    subscription: Subscription,
+
+    /// Sequence number of the last change sent. Changes
+    /// up to it that come from the broadcaster are skipped.
+    last_seq: i64,
+
+    buffer: Vec<u8>,
+    position: usize,
+
+    /// Whether what was read since the last flush should be flushed.
+    flush: bool,
+
+    closed: bool,
+
+    /// When the stream is closed, even if there are more changes to come.
+    closes_at: Instant,
+}
+
+impl ChangeStream
+{
+    fn new(subscription: Subscription, last_seq: i64, max_duration: Duration) -> ChangeStream
+    {
+        ChangeStream {
+            subscription,
+            last_seq,
+            // Tells clients to wait as long as the listener
+            // takes to reconnect before reconnecting.
+            buffer: format!("retry: {}\n\n", RECONNECT_DELAY.as_millis()).into_bytes(),
+            position: 0,
+            flush: false,
+            closed: false,
+            closes_at: Instant::now() + max_duration,
+        }
+    }
+
+    fn push(&mut self, entry: &FeedEntry)
+    {
+        if entry.seq <= self.last_seq
+        {
+            return;
+        }
+
+        self.last_seq = entry.seq;
+
+        let data = serde_json::to_string(entry).unwrap_or_default();
+        self.buffer.extend(format!("id: {}\nevent: change\ndata: {}\n\n", entry.seq, data).into_bytes());
+
+        // There won't be any more changes.
+        if entry.event_id.is_none() && entry.change_type == ChangeType::Deleted
+        {
+            self.closed = true;
+        }
+    }
+
+    /// Tells the client its copy of the calendar is out of date and it has to
+    /// sync it again. The stream goes on from `seq`, the current state.
+    fn reset(&mut self, seq: i64)
+    {
+        self.last_seq = seq;
+        self.buffer.extend(format!("id: {}\nevent: reset\ndata: {{}}\n\n", seq).into_bytes());
+    }
+
+    /// Waits for changes, or writes a keep-alive comment if there are none.
+    /// Closes the stream once it's open for its maximum duration.
+    fn wait(&mut self)
+    {
+        let now = Instant::now();
+
+        if now >= self.closes_at
+        {
+            self.closed = true;
+            return;
+        }
+
+        match self.subscription.receiver.recv_timeout(KEEP_ALIVE_INTERVAL.min(self.closes_at - now))
+        {
+            Ok(entry) =>
+            {
+                self.push(&entry);
+
+                while let Ok(entry) = self.subscription.receiver.try_recv()
+                {
+                    self.push(&entry);
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => self.buffer.extend(b": keep-alive\n\n"),
+            Err(RecvTimeoutError::Disconnected) => self.closed = true,
+        }
+    }
+}
+
+impl Read for ChangeStream
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        loop
+        {
+            if self.position < self.buffer.len()
+            {
+                let n = buf.len().min(self.buffer.len() - self.position);
+                buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+
+                self.position += n;
+                self.flush = true;
+
+                return Ok(n);
+            }
+
+            self.buffer.clear();
+            self.position = 0;
+
+            // Rocket flushes what it got so far on WouldBlock (with the `sse` feature),
+            // so that events are sent right away instead of when a chunk is full.
+            if self.flush
+            {
+                self.flush = false;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+
+            if self.closed
+            {
+                return Ok(0);
+            }
+
+            self.wait();
+        }
+    }
+}
+
+/// Why a stream couldn't be opened.
+#[derive(Error, Debug)]
+pub enum OpenStreamError
+{
+    #[error("The calendar doesn't exist.")]
+    UnknownCalendar,
+
+    #[error("There are too many open streams.")]
+    TooManyStreams,
+
+    #[error(transparent)]
+    Database(#[from] DatabaseError),
+}
+
+/// Opens a stream of the calendar's changes, which is closed after `max_duration`.
+/// If the client sent a `Last-Event-ID` the stream starts with the changes after
+/// it, if there are no more than `max_backlog` of them, otherwise with a reset.
+pub fn open(
+    db: &mut impl GenericClient,
+    broadcaster: &ChangeBroadcaster,
+    calendar_id: Uuid,
+    last_event_id: &LastEventId,
+    max_backlog: i64,
+    max_duration: Duration,
+) -> Result<ChangeStream, OpenStreamError>
+{
+    // Subscribe before reading anything so that changes committed in the meantime
+    // are not lost. They might be read twice, but the stream skips what it sent.
+    let subscription = broadcaster.subscribe(calendar_id).ok_or(OpenStreamError::TooManyStreams)?;
+
+    let rows = db.query("SELECT change_seq, purged_seq FROM calendars WHERE id = $1 AND deleted_at IS NULL;", &[&calendar_id])
+        .map_err(DatabaseError::from)?;
+
+    let (current_seq, purged_seq): (i64, i64) = match rows.get(0)
+    {
+        Some(row) => (get_cell_from_row(row, "change_seq")?, get_cell_from_row(row, "purged_seq")?),
+        None => return Err(OpenStreamError::UnknownCalendar),
+    };
+
+    let mut stream = ChangeStream::new(subscription, current_seq, max_duration);
+
+    let last_seq = match &last_event_id.0
+    {
+        Some(id) => id.parse::<i64>().ok(),
+        None => return Ok(stream),
+    };
+
+    match last_seq
+    {
+        Some(last_seq) if last_seq >= purged_seq && last_seq <= current_seq =>
+        {
+            let entries = change_feed::entries_since(db, &calendar_id, last_seq, max_backlog + 1)?;
+
+            if entries.len() as i64 > max_backlog
+            {
+                stream.reset(current_seq);
+            }
+            else
+            {
+                stream.last_seq = last_seq;
+
+                for entry in entries.iter()
+                {
+                    stream.push(entry);
+                }
+            }
+        },
+        _ => stream.reset(current_seq),
+    }
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{ChangeBroadcaster, ChangeStream};
+    use crate::change_feed::{ChangeType, FeedEntry};
+    use uuid::Uuid;
+    use std::io::{self, Read};
+    use std::time::Duration;
+
+    const MAX_DURATION: Duration = Duration::from_secs(60);
+
+    /// Reads everything up to the next flush.
+    fn read_events(stream: &mut ChangeStream) -> Option<String>
+    {
+        let mut text = vec![];
+        let mut buf = [0; 16];
+
+        loop
+        {
+            match stream.read(&mut buf)
+            {
+                Ok(0) => return if text.is_empty() { None } else { Some(String::from_utf8(text).unwrap()) },
+                Ok(n) => text.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Some(String::from_utf8(text).unwrap()),
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn streams_changes_of_their_calendar()
+    {
+        let broadcaster = ChangeBroadcaster::new(10);
+        let calendar_id = Uuid::new_v4();
+        let event_id = Uuid::new_v4();
+
+        let mut stream = ChangeStream::new(broadcaster.subscribe(calendar_id).unwrap(), 3, MAX_DURATION);
+        assert_eq!(read_events(&mut stream).unwrap(), "retry: 5000\n\n");
+
+        let entry = |calendar_id, event_id, change_type, seq| FeedEntry { calendar_id, event_id, change_type, seq };
+
+        // Already sent, another calendar's and a new one.
+        broadcaster.publish(&entry(calendar_id, Some(event_id), ChangeType::Updated, 3));
+        broadcaster.publish(&entry(Uuid::new_v4(), Some(event_id), ChangeType::Updated, 4));
+        broadcaster.publish(&entry(calendar_id, Some(event_id), ChangeType::Created, 4));
+
+        assert_eq!(
+            read_events(&mut stream).unwrap(),
+            format!(
+                "id: 4\nevent: change\ndata: {{\"calendar_id\":\"{}\",\"event_id\":\"{}\",\"change_type\":\"created\",\"seq\":4}}\n\n",
+                calendar_id,
+                event_id
+            )
+        );
+
+        // The stream ends once the calendar is deleted.
+        broadcaster.publish(&entry(calendar_id, None, ChangeType::Deleted, 5));
+        assert!(read_events(&mut stream).unwrap().starts_with("id: 5\nevent: change\n"));
+        assert_eq!(read_events(&mut stream), None);
+    }
+
+    #[test]
+    fn dropped_streams_unsubscribe()
+    {
+        let broadcaster = ChangeBroadcaster::new(10);
+        let calendar_id = Uuid::new_v4();
+
+        let first = broadcaster.subscribe(calendar_id).unwrap();
+        let second = broadcaster.subscribe(calendar_id).unwrap();
+        assert_eq!(broadcaster.lock()[&calendar_id].len(), 2);
+
+        drop(first);
+        assert_eq!(broadcaster.lock()[&calendar_id].len(), 1);
+
+        drop(second);
+        assert!(broadcaster.lock().is_empty());
+    }
+
+    #[test]
+    fn streams_are_limited()
+    {
+        let broadcaster = ChangeBroadcaster::new(2);
+
+        let first = broadcaster.subscribe(Uuid::new_v4()).unwrap();
+        let _second = broadcaster.subscribe(Uuid::new_v4()).unwrap();
+        assert!(broadcaster.subscribe(Uuid::new_v4()).is_none());
+
+        // Streams dropped by the broadcaster are still open until they end.
+        broadcaster.close_all();
+        assert!(broadcaster.subscribe(Uuid::new_v4()).is_none());
+
+        drop(first);
+        assert!(broadcaster.subscribe(Uuid::new_v4()).is_some());
+    }
+
+    #[test]
+    fn streams_close_after_their_max_duration()
+    {
+        let broadcaster = ChangeBroadcaster::new(10);
+        let mut stream = ChangeStream::new(broadcaster.subscribe(Uuid::new_v4()).unwrap(), 0, Duration::from_millis(10));
+
+        assert_eq!(read_events(&mut stream).unwrap(), "retry: 5000\n\n");
+        assert_eq!(read_events(&mut stream), None);
+    }
+}
@@ -1,5 +1,8 @@
-
-use crate::env_helpers::get_env_default;
+use crate::env_helpers::{get_env_default, get_env_optional};
+use crate::authentication::jwt::{JwksSource, JwtSettings};
+use crate::rate_limit::RateLimit;
+use crate::scheduling::imip::SmtpSettings;
+use std::path::PathBuf;
 
 /// Stores the server's configuration variables.
 ///
@@ -14,6 +17,68 @@ pub struct Configs
     ///
     /// Generally used as a LIMIT clause in SQL queries.
     page_size: u32,
+
+    /// For how many days the tombstones of deleted calendars and
+    /// events are kept before being purged.
+    tombstone_retention_days: u32,
+
+    /// How often (in seconds) expired tombstones are purged.
+    tombstone_purge_interval: u64,
+
+    /// Where to load the JWKS used to validate JWTs from. None if
+    /// JWT authentication is disabled.
+    jwks_source: Option<JwksSource>,
+
+    jwt_settings: Option<JwtSettings>,
+
+    /// Rate limit of users and of API keys without their own
+    /// limit. None if only those keys are limited.
+    rate_limit: Option<RateLimit>,
+
+    rate_limit_store: RateLimitStoreKind,
+
+    /// How many times a webhook delivery is attempted
+    /// before it's marked as dead.
+    webhook_max_attempts: u32,
+
+    /// How often (in seconds) the delivery worker looks
+    /// for webhook deliveries that are due.
+    webhook_poll_interval: u64,
+
+    /// Whether webhook targets can be private addresses (e.g. localhost),
+    /// see `network_helpers`. Only meant for development.
+    webhook_allow_private_targets: bool,
+
+    /// How many change streams can be open at once, see `change_stream`.
+    /// None to use half of Rocket's workers.
+    max_streams: Option<usize>,
+
+    /// For how long (in seconds) a change stream is open before it's closed.
+    stream_max_duration: u64,
+
+    /// The port CalDAV clients connect to, see `caldav::proxy`.
+    /// None if CalDAV is disabled.
+    caldav_port: Option<u16>,
+
+    /// The SMTP server scheduling messages are sent through, see
+    /// `scheduling::imip`. None if they aren't sent.
+    smtp_settings: Option<SmtpSettings>,
+
+    /// How many times a scheduling message is sent
+    /// before it's marked as dead.
+    scheduling_max_attempts: u32,
+
+    /// How often (in seconds) the scheduling worker looks
+    /// for messages that are due.
+    scheduling_poll_interval: u64,
+}
+
+/// Where rate limit buckets are kept, see `rate_limit`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RateLimitStoreKind
+{
+    Memory,
+    Postgres,
 }
 
 impl Configs
@@ -23,10 +88,142 @@ impl Configs
         self.page_size
     }
 
+    pub fn get_tombstone_retention_days(&self) -> u32
+    {
+        self.tombstone_retention_days
+    }
+
+    pub fn get_tombstone_purge_interval(&self) -> u64
+    {
+        self.tombstone_purge_interval
+    }
+
+    pub fn get_jwks_source(&self) -> Option<&JwksSource>
+    {
+        self.jwks_source.as_ref()
+    }
+
+    pub fn get_jwt_settings(&self) -> Option<&JwtSettings>
+    {
+        self.jwt_settings.as_ref()
+    }
+
+    pub fn get_rate_limit(&self) -> Option<RateLimit>
+    {
+        self.rate_limit
+    }
+
+    pub fn get_rate_limit_store(&self) -> RateLimitStoreKind
+    {
+        self.rate_limit_store
+    }
+
+    pub fn get_webhook_max_attempts(&self) -> u32
+    {
+        self.webhook_max_attempts
+    }
+
+    pub fn get_webhook_poll_interval(&self) -> u64
+    {
+        self.webhook_poll_interval
+    }
+
+    pub fn get_webhook_allow_private_targets(&self) -> bool
+    {
+        self.webhook_allow_private_targets
+    }
+
+    pub fn get_max_streams(&self) -> Option<usize>
+    {
+        self.max_streams
+    }
+
+    pub fn get_stream_max_duration(&self) -> u64
+    {
+        self.stream_max_duration
+    }
+
+    pub fn get_caldav_port(&self) -> Option<u16>
+    {
+        self.caldav_port
+    }
+
+    pub fn get_smtp_settings(&self) -> Option<&SmtpSettings>
+    {
+        self.smtp_settings.as_ref()
+    }
+
+    pub fn get_scheduling_max_attempts(&self) -> u32
+    {
+        self.scheduling_max_attempts
+    }
+
+    pub fn get_scheduling_poll_interval(&self) -> u64
+    {
+        self.scheduling_poll_interval
+    }
+
     pub fn get_configs() -> Configs
     {
+        let jwks_source = match (get_env_optional("JWT_JWKS_URL"), get_env_optional("JWT_JWKS_FILE"))
+        {
+            (Some(_), Some(_)) => panic!("JWT_JWKS_URL and JWT_JWKS_FILE can't be both set."),
+            (Some(url), None) => Some(JwksSource::Url(url)),
+            (None, Some(path)) => Some(JwksSource::File(PathBuf::from(path))),
+            (None, None) => None,
+        };
+
+        let jwt_settings = jwks_source.as_ref().map(|_| JwtSettings {
+            issuer: get_env_optional("JWT_ISSUER").expect("JWT_ISSUER must be set when JWT authentication is enabled."),
+            audience: get_env_optional("JWT_AUDIENCE").expect("JWT_AUDIENCE must be set when JWT authentication is enabled."),
+            user_id_claim: get_env_default("JWT_USER_ID_CLAIM", "sub"),
+            roles_claim: get_env_default("JWT_ROLES_CLAIM", "roles"),
+            tenant: get_env_default("JWT_TENANT", "default"),
+        });
+
+        let rate_limit = get_env_optional("RATE_LIMIT_PER_MINUTE").map(|per_minute| {
+            let limit = RateLimit {
+                per_minute: per_minute.parse().expect("RATE_LIMIT_PER_MINUTE is not a positive integer."),
+                burst: get_env_optional("RATE_LIMIT_BURST")
+                    .unwrap_or(per_minute)
+                    .parse()
+                    .expect("RATE_LIMIT_BURST is not a positive integer."),
+            };
+
+            assert!(limit.is_valid(), "RATE_LIMIT_PER_MINUTE and RATE_LIMIT_BURST must be > 0.");
+            limit
+        });
+
+        let rate_limit_store = match get_env_default("RATE_LIMIT_STORE", "memory").as_str()
+        {
+            "memory" => RateLimitStoreKind::Memory,
+            "postgres" => RateLimitStoreKind::Postgres,
+            _ => panic!("RATE_LIMIT_STORE must be either memory or postgres."),
+        };
+
+        let smtp_settings = get_env_optional("SMTP_SERVER").map(|server| SmtpSettings {
+            server,
+            from: get_env_optional("SMTP_FROM").expect("SMTP_FROM must be set when SMTP_SERVER is set."),
+            hello_name: get_env_default("SMTP_HELO_NAME", "localhost"),
+        });
+
         Configs {
             page_size: get_env_default("PAGE_SIZE", "1000").parse().expect("PAGE_SIZE is not a positive integer."),
+            tombstone_retention_days: get_env_default("TOMBSTONE_RETENTION_DAYS", "30").parse().expect("TOMBSTONE_RETENTION_DAYS is not a positive integer."),
+            tombstone_purge_interval: get_env_default("TOMBSTONE_PURGE_INTERVAL", "3600").parse().expect("TOMBSTONE_PURGE_INTERVAL is not a positive integer."),
+            jwks_source,
+            jwt_settings,
+            rate_limit,
+            rate_limit_store,
+            webhook_max_attempts: get_env_default("WEBHOOK_MAX_ATTEMPTS", "10").parse().expect("WEBHOOK_MAX_ATTEMPTS is not a positive integer."),
+            webhook_poll_interval: get_env_default("WEBHOOK_POLL_INTERVAL", "5").parse().expect("WEBHOOK_POLL_INTERVAL is not a positive integer."),
+            webhook_allow_private_targets: get_env_default("WEBHOOK_ALLOW_PRIVATE_TARGETS", "false").parse().expect("WEBHOOK_ALLOW_PRIVATE_TARGETS is not true or false."),
+            max_streams: get_env_optional("STREAM_MAX_CONNECTIONS").map(|max| max.parse().expect("STREAM_MAX_CONNECTIONS is not a positive integer.")),
+            stream_max_duration: get_env_default("STREAM_MAX_DURATION", "900").parse().expect("STREAM_MAX_DURATION is not a positive integer."),
+            caldav_port: get_env_optional("CALDAV_PORT").map(|port| port.parse().expect("CALDAV_PORT is not a valid port.")),
+            smtp_settings,
+            scheduling_max_attempts: get_env_default("SCHEDULING_MAX_ATTEMPTS", "10").parse().expect("SCHEDULING_MAX_ATTEMPTS is not a positive integer."),
+            scheduling_poll_interval: get_env_default("SCHEDULING_POLL_INTERVAL", "5").parse().expect("SCHEDULING_POLL_INTERVAL is not a positive integer."),
         }
     }
 }
\ No newline at end of file
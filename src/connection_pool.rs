@@ -1,15 +1,17 @@
 use r2d2_postgres::r2d2::{Pool, PooledConnection};
 use r2d2_postgres::PostgresConnectionManager;
-use postgres::NoTls;
+use postgres::{Client, NoTls};
 use std::ops::{Deref, DerefMut};
 use rocket::request::{FromRequest, Outcome};
 use crate::database_error::{DatabaseError, DatabaseErrorKind};
 use rocket::http::Status;
 use rocket::{Request, State};
 
+#[derive(Clone)]
 pub struct PgsqlPool
 {
     pool: Pool<PostgresConnectionManager<NoTls>>,
+    settings: String,
 }
 
 impl PgsqlPool
@@ -23,13 +25,20 @@ impl PgsqlPool
 
         let pool = Pool::new(manager).unwrap();
 
-        PgsqlPool { pool }
+        PgsqlPool { pool, settings: settings.to_owned() }
     }
 
     pub fn get_conn(&self) -> Result<PgsqlConn, r2d2_postgres::r2d2::Error>
     {
         Ok( PgsqlConn { conn: self.pool.get()? } )
     }
+
+    /// Opens a connection that's not part of the pool, for
+    /// long-lived connections like the one `LISTEN`ing for changes.
+    pub fn connect(&self) -> Result<Client, postgres::Error>
+    {
+        Client::connect(&self.settings, NoTls)
+    }
 }
 
 pub struct PgsqlConn
@@ -74,7 +83,7 @@ impl<'a, 'r> FromRequest<'a, 'r> for PgsqlConn
         let conn = match pool.pool.get()
         {
             Ok(c) => c,
-            Err(_) => return Outcome::Failure((Status::InternalServerError, DatabaseErrorKind::Other(Box::new(PoolGetFail {})).into())),
+            Err(_) => return Outcome::Failure((Status::ServiceUnavailable, DatabaseErrorKind::Other(Box::new(PoolGetFail {})).into())),
         };
 
         Outcome::Success(PgsqlConn { conn })
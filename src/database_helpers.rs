@@ -12,6 +12,7 @@ use serde::export::Formatter;
 use rocket_okapi::request::OpenApiFromParam;
 use rocket_okapi::gen::OpenApiGenerator;
 use okapi::openapi3::{Parameter, ParameterValue};
+use crate::routes::calendar_format::CalendarFileFormat;
 
 pub fn get_cell_from_row<'a, T: FromSql<'a>>(row: &'a Row, col: &str) -> Result<T, DatabaseError>
 {
@@ -68,6 +69,8 @@ pub struct UuidParam(Uuid);
 impl UuidParam
 {
     pub fn into_inner(self) -> Uuid { self.0 }
+
+    pub fn get_inner(&self) -> Uuid { self.0 }
 }
 
 impl Display for UuidParam
@@ -128,4 +131,52 @@ impl ToSql for UuidParam
     fn to_sql_checked(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
         self.0.to_sql_checked(ty, out)
     }
-}
\ No newline at end of file
+}
+/// A path parameter like `<uuid>.ics` or `<uuid>.xml`, for routes that serve
+/// an iCalendar or xCal file of a calendar or event.
+#[derive(Debug)]
+pub struct CalendarFileParam(Uuid, CalendarFileFormat);
+impl CalendarFileParam
+{
+    pub fn format(&self) -> CalendarFileFormat { self.1 }
+    pub fn into_inner(self) -> Uuid { self.0 }
+}
+
+impl FromParam<'_> for CalendarFileParam
+{
+    type Error = ();
+
+    fn from_param(param: &RawStr) -> Result<Self, Self::Error>
+    {
+        let (format, id) = CalendarFileFormat::from_file_name(param.as_str()).ok_or(())?;
+
+        Uuid::from_str(id)
+            .map(|uuid| CalendarFileParam(uuid, format))
+            .map_err(|_| ())
+    }
+}
+
+impl OpenApiFromParam<'_> for CalendarFileParam
+{
+    fn path_parameter(gen: &mut OpenApiGenerator, name: String) -> rocket_okapi::Result<Parameter>
+    {
+        let schema = gen.json_schema::<String>();
+        Ok(Parameter {
+            name,
+            location: "path".to_owned(),
+            description: Some("An id followed by `.ics` (iCalendar) or `.xml` (xCal).".to_owned()),
+            required: true,
+            deprecated: false,
+            allow_empty_value: false,
+            value: ParameterValue::Schema {
+                style: None,
+                explode: None,
+                allow_reserved: false,
+                schema,
+                example: None,
+                examples: None,
+            },
+            extensions: Default::default(),
+        })
+    }
+}
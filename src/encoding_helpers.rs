@@ -0,0 +1,47 @@
+/// Encodes `bytes` as a lowercase hexadecimal string.
+pub fn to_hex(bytes: &[u8]) -> String
+{
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Decodes a hexadecimal string (either case). Returns None if
+/// `hex` has an odd length or any non-hexadecimal character.
+pub fn from_hex(hex: &str) -> Option<Vec<u8>>
+{
+    if hex.len() % 2 != 0 || !hex.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{to_hex, from_hex};
+
+    #[test]
+    fn hex_round_trip()
+    {
+        let bytes = vec![0x00, 0x0f, 0xa0, 0xff, 0x42];
+
+        assert_eq!(to_hex(&bytes), "000fa0ff42");
+        assert_eq!(from_hex("000fa0ff42"), Some(bytes.clone()));
+        assert_eq!(from_hex("000FA0FF42"), Some(bytes));
+    }
+
+    #[test]
+    fn hex_invalid()
+    {
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(from_hex("+f"), None);
+    }
+}
@@ -8,6 +8,14 @@ pub fn get_env(name: &str) -> String
         .1
 }
 
+pub fn get_env_optional(name: &str) -> Option<String>
+{
+    env::vars()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value)
+        .filter(|value| !value.is_empty())
+}
+
 pub fn get_env_default(name: &str, default: &str) -> String
 {
     env::vars()
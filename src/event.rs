@@ -8,6 +8,7 @@ use crate::database_error::{DatabaseError, DatabaseErrorKind};
 use postgres::Row;
 use crate::database_helpers::{get_cell_from_row, get_cell_from_row_with_default, FromRow};
 use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Duration};
+use std::collections::HashSet;
 use crate::recurrence::RecurrenceRule;
 
 
@@ -18,7 +19,7 @@ use itertools::Itertools;
 use crate::iter_helpers::MergeOrderedTrait;
 
 
-pub const EVENT_FIELDS: &str = "id, parent_event_id, start_date, start_time, end_date, end_time, rrule, exdates, rdates";
+pub const EVENT_FIELDS: &str = "id, parent_event_id, original_start_date, start_date, start_time, end_date, end_time, rrule, exdates, rdates, last_modified";
 
 #[derive(Copy, Clone, Debug)]
 pub struct EventDateSpan
@@ -101,6 +102,28 @@ impl EventSpan
         self.get_date_time_span().map(|dt| dt.end.time())
     }
 
+    /// Start of the span as a date-time. Date spans (all-day events)
+    /// start at midnight.
+    pub fn get_start_naive_date_time(&self) -> NaiveDateTime
+    {
+        match self
+        {
+            EventSpan::Date(date_span) => date_span.start.and_hms(0, 0, 0),
+            EventSpan::DateTime(datetime_span) => datetime_span.start,
+        }
+    }
+
+    /// End of the span as a date-time. The end date of date spans (all-day events)
+    /// is inclusive, so they end at the midnight after their end date.
+    pub fn get_end_naive_date_time(&self) -> NaiveDateTime
+    {
+        match self
+        {
+            EventSpan::Date(date_span) => (date_span.end + Duration::days(1)).and_hms(0, 0, 0),
+            EventSpan::DateTime(datetime_span) => datetime_span.end,
+        }
+    }
+
     pub fn get_duration(&self) -> Duration
     {
         match self
@@ -170,6 +193,41 @@ impl EventSpan
 
 
 
+/// How a time window is matched against an event's span when
+/// filtering events by date.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RangeMode
+{
+    /// The event starts and ends inside the window.
+    Contained,
+
+    /// Any part of the event is inside the window.
+    Overlaps,
+}
+
+impl RangeMode
+{
+    /// Checks if `span` matches this mode for the window between `window_start` (inclusive)
+    /// and `window_end` (exclusive). A None bound means the window is open on that side.
+    pub fn matches(&self, span: EventSpan, window_start: Option<NaiveDateTime>, window_end: Option<NaiveDateTime>) -> bool
+    {
+        let start = span.get_start_naive_date_time();
+        let end = span.get_end_naive_date_time();
+
+        match self
+        {
+            RangeMode::Contained => window_start.map_or(true, |ws| start >= ws) && window_end.map_or(true, |we| end <= we),
+            RangeMode::Overlaps => window_start.map_or(true, |ws| end > ws) && window_end.map_or(true, |we| start < we),
+        }
+    }
+}
+
+
+
+
+
+
 #[derive(Clone, Debug)]
 pub struct EventRecurrence
 {
@@ -185,7 +243,7 @@ pub struct EventRecurrence
 
 
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Event
 {
     Recurring(EventRecurring),
@@ -265,7 +323,7 @@ impl EventRecurring
     /// instead!
     pub fn get_children(&self, db: &mut PgsqlConn) -> Result<Vec<EventSingle>, DatabaseError>
     {
-        let query = format!("SELECT {} FROM events WHERE parent_id = $1 ORDER BY start_date;", EVENT_FIELDS);
+        let query = format!("SELECT {} FROM events WHERE parent_event_id = $1 AND deleted_at IS NULL ORDER BY start_date;", EVENT_FIELDS);
 
         let rows = db.query(query.as_str(), &[&self.id])?;
 
@@ -279,18 +337,28 @@ impl EventRecurring
     ///
     /// Does **NOT** get child events! Use `get_children` for that!
     pub fn generate_instances(&self, from_date: Option<NaiveDate>, to_date: Option<NaiveDate>, skip: usize, max_results: usize) -> Result<Vec<EventInstance>, DatabaseError>
+    {
+        let instances = self.iter_instances(from_date, to_date)
+            .skip(skip)
+            .take(max_results)
+            .collect_vec();
+
+        Ok(instances)
+    }
+
+    /// Lazy version of `generate_instances`. Generates instances as they're needed,
+    /// so be careful when `to_date` is None: the iterator might never end.
+    pub fn iter_instances<'a>(&'a self, from_date: Option<NaiveDate>, to_date: Option<NaiveDate>) -> impl Iterator<Item = EventInstance> + 'a
     {
         let duration = self.span.get_duration();
 
-        let instances = self.recurrence.rule
+        self.recurrence.rule
             .calculate_instances(self.span.get_date_span().start)
-            .filter(|x| from_date.is_none() || *x >= from_date.unwrap())
-            .filter(|x| !self.recurrence.exdates.contains(x))
-            .take_while(|x| to_date.is_none() || *x <= to_date.unwrap())
+            .filter(move |x| from_date.is_none() || *x >= from_date.unwrap())
+            .filter(move |x| !self.recurrence.exdates.contains(x))
+            .take_while(move |x| to_date.is_none() || *x <= to_date.unwrap())
             .merge_ordered(self.recurrence.rdates.clone().into_iter().sorted())
-            .skip(skip)
-            .take(max_results)
-            .map(|date| {
+            .map(move |date| {
                 EventInstance {
                     parent_id: self.id,
                     span: match self.span
@@ -300,11 +368,88 @@ impl EventRecurring
                     },
                 }
             })
+    }
+
+    /// Checks if any of this event's instances matches `range_mode` for the
+    /// window between `window_start` (inclusive) and `window_end` (exclusive).
+    /// A None bound means the window is open on that side.
+    pub fn has_instance_in_range(&self, range_mode: RangeMode, window_start: Option<NaiveDateTime>, window_end: Option<NaiveDateTime>) -> bool
+    {
+        // Instances that start up to this many days before the
+        // window might still overlap with it.
+        let overlap_days = Duration::days((self.span.get_end_date() - self.span.get_start_date()).num_days() + 1);
+
+        // If window_end is None the iterator might be infinite, but then the
+        // first instance that ends after window_start matches both modes, so
+        // `any` always returns.
+        self.iter_instances(
+            window_start.map(|ws| ws.date() - overlap_days),
+            window_end.map(|we| we.date())
+        )
+            .any(|instance| range_mode.matches(instance.get_span(), window_start, window_end))
+    }
+
+    /// Generates event instances between dates `from_date` and `to_date` (both inclusive),
+    /// just like `generate_instances`, but also merges in the child events that override
+    /// some of this event's instances (see `EventSingle::parent_id`).
+    ///
+    /// The result is ordered by the date each entry actually happens at, so an overridden
+    /// instance that was moved shows up at its new date. Each entry still knows the
+    /// date it originally had in the recurrence (`ExpandedInstance::get_original_start_date`),
+    /// akin to RFC 5545's RECURRENCE-ID, so clients can correlate them.
+    ///
+    /// `skip` and `max_results` are applied to the merged result.
+    pub fn expand_instances(
+        &self,
+        db: &mut PgsqlConn,
+        from_date: Option<NaiveDate>,
+        to_date: Option<NaiveDate>,
+        skip: usize,
+        max_results: usize
+    ) -> Result<Vec<ExpandedInstance>, DatabaseError>
+    {
+        let children = self.get_children(db)?;
+
+        let instances = self.merge_overrides(children, from_date, to_date)
+            .skip(skip)
+            .take(max_results)
             .collect_vec();
 
         Ok(instances)
     }
 
+    /// Lazily merges `children` (this event's overrides) with the instances
+    /// generated between `from_date` and `to_date`, see `expand_instances`.
+    ///
+    /// A generated instance is left out if one of the children overrides it (its
+    /// `original_start_date` is the instance's date), like RFC 5545 does with
+    /// RECURRENCE-IDs, even if the date wasn't added to the exdates. Children whose
+    /// instance isn't generated anymore (e.g. the rrule changed) are still listed.
+    fn merge_overrides<'a>(
+        &'a self,
+        children: Vec<EventSingle>,
+        from_date: Option<NaiveDate>,
+        to_date: Option<NaiveDate>
+    ) -> impl Iterator<Item = ExpandedInstance> + 'a
+    {
+        let overridden: HashSet<NaiveDate> = children.iter()
+            .filter_map(|child| child.original_start_date)
+            .collect();
+
+        let generated = self.iter_instances(from_date, to_date)
+            .filter(move |instance| !overridden.contains(&instance.get_original_start_date()))
+            .map(ExpandedInstance::Generated);
+
+        let overrides = children
+            .into_iter()
+            .filter(move |child| from_date.is_none() || child.span.get_start_date() >= from_date.unwrap())
+            .filter(move |child| to_date.is_none() || child.span.get_start_date() <= to_date.unwrap())
+            .sorted_by_key(|child| child.span.get_start_naive_date_time())
+            .map(ExpandedInstance::Overridden);
+
+        generated.merge_by(overrides, |a, b| a.get_span().get_start_naive_date_time() <= b.get_span().get_start_naive_date_time())
+    }
+
     fn from_row(row: &Row) -> Result<Self, DatabaseError>
     {
         let span = EventSpan::from_row(row)?;
@@ -343,6 +488,7 @@ impl ToPlain<EventPlain> for EventRecurring
         EventPlain {
             id: Some(self.id),
             parent_id: None,
+            original_start_date: None,
 
             start_date: Some(self.span.get_start_date()),
             end_date: Some(self.span.get_end_date()),
@@ -358,6 +504,10 @@ impl ToPlain<EventPlain> for EventRecurring
             ),
 
             last_modified: Some(self.last_modified),
+
+            instance_origin: None,
+
+            deleted: false,
         }
     }
 }
@@ -394,7 +544,13 @@ pub struct EventSingle
     /// 1. The date 2020-09-08 was added to the recurrent event's EXDATES property.
     /// 2. A (non-recurring) event was created at 2020-09-09, with the ID `cde`.
     /// 3. The parent_id of the `cde` event was set to `abc`.
+    /// 4. The original_start_date of the `cde` event was set to 2020-09-08.
     parent_id: Option<Uuid>,
+
+    /// The start date of the instance this event took the place of, akin to RFC 5545's
+    /// RECURRENCE-ID. Always Some if `parent_id` is Some and None otherwise.
+    original_start_date: Option<NaiveDate>,
+
     span: EventSpan,
 
     last_modified: NaiveDateTime,
@@ -408,12 +564,15 @@ impl EventSingle
 
     pub fn get_parent_id(&self) -> Option<Uuid> { self.parent_id }
 
+    pub fn get_original_start_date(&self) -> Option<NaiveDate> { self.original_start_date }
+
     fn from_row(row: &Row) -> Result<Self, DatabaseError>
     {
         Ok(
             EventSingle {
                 id: get_cell_from_row(row, "id")?,
                 parent_id: get_cell_from_row(row, "parent_event_id")?,
+                original_start_date: get_cell_from_row(row, "original_start_date")?,
                 span: EventSpan::from_row(row)?,
                 last_modified: get_cell_from_row(row, "last_modified")?,
             }
@@ -428,6 +587,7 @@ impl ToPlain<EventPlain> for EventSingle
         EventPlain {
             id: Some(self.id),
             parent_id: self.parent_id,
+            original_start_date: self.original_start_date,
 
             start_date: Some(self.span.get_start_date()),
             end_date: Some(self.span.get_end_date()),
@@ -437,6 +597,10 @@ impl ToPlain<EventPlain> for EventSingle
             recurrence: None,
 
             last_modified: Some(self.last_modified),
+
+            instance_origin: None,
+
+            deleted: false,
         }
     }
 }
@@ -460,6 +624,10 @@ impl EventInstance
 
     pub fn get_parent_id(&self) -> Uuid { self.parent_id }
 
+    /// Generated instances always happen at the date the
+    /// recurrence says they happen.
+    pub fn get_original_start_date(&self) -> NaiveDate { self.span.get_start_date() }
+
     fn from_row(row: &Row) -> Result<Self, DatabaseError>
     {
         Ok(
@@ -478,6 +646,7 @@ impl ToPlain<EventPlain> for EventInstance
         EventPlain {
             id: None,
             parent_id: Some(self.parent_id),
+            original_start_date: Some(self.get_original_start_date()),
 
             start_date: Some(self.span.get_start_date()),
             end_date: Some(self.span.get_end_date()),
@@ -487,11 +656,90 @@ impl ToPlain<EventPlain> for EventInstance
             recurrence: None,
 
             last_modified: None,
+
+            instance_origin: None,
+
+            deleted: false,
         }
     }
 }
 
 
+
+
+
+/// An entry of `EventRecurring::expand_instances`, either an instance
+/// generated from the recurrence or a child event that overrides one.
+#[derive(Clone, Debug)]
+pub enum ExpandedInstance
+{
+    Generated(EventInstance),
+    Overridden(EventSingle),
+}
+
+impl ExpandedInstance
+{
+    pub fn get_span(&self) -> EventSpan
+    {
+        match self
+        {
+            ExpandedInstance::Generated(e) => e.get_span(),
+            ExpandedInstance::Overridden(e) => e.get_span(),
+        }
+    }
+
+    pub fn get_original_start_date(&self) -> NaiveDate
+    {
+        match self
+        {
+            ExpandedInstance::Generated(e) => e.get_original_start_date(),
+            // Children always have an original_start_date (there's a DB constraint for that),
+            // but fall back to the actual start date just in case.
+            ExpandedInstance::Overridden(e) => e.get_original_start_date().unwrap_or(e.get_span().get_start_date()),
+        }
+    }
+
+    pub fn get_origin(&self) -> InstanceOrigin
+    {
+        match self
+        {
+            ExpandedInstance::Generated(_) => InstanceOrigin::Generated,
+            ExpandedInstance::Overridden(_) => InstanceOrigin::Overridden,
+        }
+    }
+}
+
+impl ToPlain<EventPlain> for ExpandedInstance
+{
+    fn into_plain(self) -> EventPlain
+    {
+        let origin = self.get_origin();
+        let original_start_date = self.get_original_start_date();
+
+        let mut plain = match self
+        {
+            ExpandedInstance::Generated(e) => e.into_plain(),
+            ExpandedInstance::Overridden(e) => e.into_plain(),
+        };
+
+        plain.original_start_date = Some(original_start_date);
+        plain.instance_origin = Some(origin);
+
+        plain
+    }
+}
+
+/// Tells whether an instance was generated from its parent's
+/// recurrence or is a child event that overrides a generated one.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceOrigin
+{
+    Generated,
+    Overridden,
+}
+
+
 /// This is a serializable representation of an event
 /// (single, recurrent or instance), it has two purposes:
 ///
@@ -506,7 +754,7 @@ impl ToPlain<EventPlain> for EventInstance
 /// - Recurring events have an rrule value.
 /// - Instance events don't have an id and have a parent id.
 /// - Edited instance events (instance events with overridden
-/// dates) have an id and a parent id.
+/// dates) have an id, a parent id and an original start date.
 ///
 /// If you want to create an EventPlain, call `to_plain`
 /// on an `EventSingle`, `EventInstance` or `EventRecurring`.
@@ -527,6 +775,12 @@ pub struct EventPlain
     pub id: Option<Uuid>,
     pub parent_id: Option<Uuid>,
 
+    /// The date of the instance this event originated from or took the place of
+    /// (akin to RFC 5545's RECURRENCE-ID). Only set on instances and their overrides.
+    #[serde(default, with = "event_plain_serde::date_option")]
+    #[schemars(with = "Option<NaiveDate>")]
+    pub original_start_date: Option<NaiveDate>,
+
     #[serde(default, with = "event_plain_serde::date_option")]
     #[schemars(with = "Option<NaiveDate>")]
     pub start_date: Option<NaiveDate>,
@@ -548,6 +802,15 @@ pub struct EventPlain
     #[serde(default, with = "event_plain_serde::date_time_option")]
     #[schemars(with = "Option<NaiveDateTime>")]
     pub last_modified: Option<NaiveDateTime>,
+
+    /// Only set when listing instances together with their overrides.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance_origin: Option<InstanceOrigin>,
+
+    /// True if this is the tombstone of a deleted event. Tombstones
+    /// are only returned when checking for changes.
+    #[serde(default)]
+    pub deleted: bool,
 }
 
 
@@ -579,6 +842,9 @@ impl EventPlain
     /// - Checks if `start_date` and `end_date` are both set.
     /// - Checks if `end_time` is set if `start_time` is also set
     /// and vice-versa.
+    /// - Checks if `parent_id` is set if `original_start_date` is also set.
+    /// `parent_id` may be set without `original_start_date`, which clients
+    /// written before it existed don't send, see `with_default_original_start_date`.
     /// - Checks if `rrule`, `exdates` and `rdates` are all set
     /// if `recurrence` is set.
     ///
@@ -595,6 +861,11 @@ impl EventPlain
             return false;
         }
 
+        if self.original_start_date.is_some() && self.parent_id.is_none()
+        {
+            return false;
+        }
+
         if let Some(recurrence) = &self.recurrence
         {
             if recurrence.rrule.is_none()
@@ -607,6 +878,20 @@ impl EventPlain
 
         true
     }
+
+    /// Sets `original_start_date` to `start_date` if this event overrides an
+    /// instance (`parent_id` is set) but doesn't say which one, i.e. assumes the
+    /// instance wasn't moved to another date. That's what clients that predate
+    /// `original_start_date` do, and what the migration assumed for existing children.
+    pub fn with_default_original_start_date(mut self) -> Self
+    {
+        if self.parent_id.is_some() && self.original_start_date.is_none()
+        {
+            self.original_start_date = self.start_date;
+        }
+
+        self
+    }
 }
 
 pub trait ToPlain<T: Serialize + Deserialize<'static>>
@@ -620,7 +905,7 @@ pub trait ToPlain<T: Serialize + Deserialize<'static>>
 ///
 /// Dates are formatted like `YYYY-MM-DD`.
 /// Times are formatted like `HH:MM:SS`.
-mod event_plain_serde
+pub(crate) mod event_plain_serde
 {
     const DATE_FORMAT: &'static str = "%Y-%m-%d";
     const TIME_FORMAT: &'static str = "%H:%M";
@@ -774,4 +1059,159 @@ mod event_plain_serde
                 .transpose()
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{EventRecurring, EventRecurrence, EventSingle, EventSpan, ExpandedInstance, InstanceOrigin, RangeMode};
+    use crate::recurrence::RecurrenceRule;
+    use chrono::{NaiveDate, NaiveDateTime, Duration};
+    use uuid::Uuid;
+
+    /// An hour long event at 10:00 every Tuesday of September 2020.
+    fn weekly(exdates: Vec<NaiveDate>) -> EventRecurring
+    {
+        EventRecurring {
+            id: Uuid::new_v4(),
+            span: EventSpan::from_date_time_and_duration(NaiveDate::from_ymd(2020, 9, 1).and_hms(10, 0, 0), Duration::hours(1)),
+            recurrence: EventRecurrence {
+                rule: RecurrenceRule::new("FREQ=WEEKLY;COUNT=5").unwrap(),
+                exdates,
+                rdates: vec![],
+            },
+            last_modified: NaiveDate::from_ymd(2020, 8, 1).and_hms(0, 0, 0),
+        }
+    }
+
+    fn child(parent: &EventRecurring, original_start_date: NaiveDate, start: NaiveDateTime) -> EventSingle
+    {
+        EventSingle {
+            id: Uuid::new_v4(),
+            parent_id: Some(parent.id),
+            original_start_date: Some(original_start_date),
+            span: EventSpan::from_date_time_and_duration(start, Duration::hours(1)),
+            last_modified: NaiveDate::from_ymd(2020, 8, 1).and_hms(0, 0, 0),
+        }
+    }
+
+    /// The start, origin and original start date of each instance.
+    fn summary(instances: impl Iterator<Item = ExpandedInstance>) -> Vec<(NaiveDateTime, InstanceOrigin, NaiveDate)>
+    {
+        instances
+            .map(|i| (i.get_span().get_start_naive_date_time(), i.get_origin(), i.get_original_start_date()))
+            .collect()
+    }
+
+    fn at(day: u32, hour: u32) -> NaiveDateTime
+    {
+        NaiveDate::from_ymd(2020, 9, day).and_hms(hour, 0, 0)
+    }
+
+    #[test]
+    fn overrides_are_merged_in_date_order()
+    {
+        // The instance of the 8th was moved to the 16th, after the one of the 15th.
+        let event = weekly(vec![NaiveDate::from_ymd(2020, 9, 8)]);
+        let children = vec![child(&event, NaiveDate::from_ymd(2020, 9, 8), at(16, 10))];
+
+        assert_eq!(
+            summary(event.merge_overrides(children, None, None)),
+            vec![
+                (at(1, 10), InstanceOrigin::Generated, NaiveDate::from_ymd(2020, 9, 1)),
+                (at(15, 10), InstanceOrigin::Generated, NaiveDate::from_ymd(2020, 9, 15)),
+                (at(16, 10), InstanceOrigin::Overridden, NaiveDate::from_ymd(2020, 9, 8)),
+                (at(22, 10), InstanceOrigin::Generated, NaiveDate::from_ymd(2020, 9, 22)),
+                (at(29, 10), InstanceOrigin::Generated, NaiveDate::from_ymd(2020, 9, 29)),
+            ]
+        );
+    }
+
+    #[test]
+    fn overrides_replace_their_instance()
+    {
+        // Only the time of the 15th changed and the date wasn't added to the exdates,
+        // the override still takes the place of the generated instance.
+        let event = weekly(vec![]);
+        let children = vec![child(&event, NaiveDate::from_ymd(2020, 9, 15), at(15, 14))];
+
+        assert_eq!(
+            summary(event.merge_overrides(children, Some(NaiveDate::from_ymd(2020, 9, 8)), Some(NaiveDate::from_ymd(2020, 9, 22)))),
+            vec![
+                (at(8, 10), InstanceOrigin::Generated, NaiveDate::from_ymd(2020, 9, 8)),
+                (at(15, 14), InstanceOrigin::Overridden, NaiveDate::from_ymd(2020, 9, 15)),
+                (at(22, 10), InstanceOrigin::Generated, NaiveDate::from_ymd(2020, 9, 22)),
+            ]
+        );
+    }
+
+    #[test]
+    fn overrides_of_removed_instances_are_kept()
+    {
+        // The 22nd was excluded after its instance was overridden, and the
+        // override of the 10th doesn't match any instance.
+        let event = weekly(vec![NaiveDate::from_ymd(2020, 9, 22)]);
+        let children = vec![
+            child(&event, NaiveDate::from_ymd(2020, 9, 22), at(23, 10)),
+            child(&event, NaiveDate::from_ymd(2020, 9, 10), at(10, 10)),
+        ];
+
+        let instances = summary(event.merge_overrides(children, Some(NaiveDate::from_ymd(2020, 9, 9)), None));
+
+        assert_eq!(
+            instances,
+            vec![
+                (at(10, 10), InstanceOrigin::Overridden, NaiveDate::from_ymd(2020, 9, 10)),
+                (at(15, 10), InstanceOrigin::Generated, NaiveDate::from_ymd(2020, 9, 15)),
+                (at(23, 10), InstanceOrigin::Overridden, NaiveDate::from_ymd(2020, 9, 22)),
+                (at(29, 10), InstanceOrigin::Generated, NaiveDate::from_ymd(2020, 9, 29)),
+            ]
+        );
+    }
+
+    #[test]
+    fn range_modes_match_date_time_spans()
+    {
+        // 10:00 to 11:00 on the 1st.
+        let span = EventSpan::from_date_time_and_duration(at(1, 10), Duration::hours(1));
+
+        for mode in &[RangeMode::Contained, RangeMode::Overlaps]
+        {
+            assert!(mode.matches(span, None, None));
+            assert!(mode.matches(span, Some(at(1, 10)), Some(at(1, 11))));
+            assert!(!mode.matches(span, Some(at(1, 11)), None));
+            assert!(!mode.matches(span, None, Some(at(1, 10))));
+        }
+
+        assert!(!RangeMode::Contained.matches(span, Some(at(1, 10)), Some(at(1, 10) + Duration::minutes(30))));
+        assert!(RangeMode::Overlaps.matches(span, Some(at(1, 10)), Some(at(1, 10) + Duration::minutes(30))));
+        assert!(!RangeMode::Contained.matches(span, Some(at(1, 10) + Duration::minutes(30)), None));
+        assert!(RangeMode::Overlaps.matches(span, Some(at(1, 10) + Duration::minutes(30)), None));
+    }
+
+    #[test]
+    fn range_modes_match_all_day_spans()
+    {
+        // All day on the 1st and the 2nd, i.e. until the midnight of the 3rd.
+        let span = EventSpan::from_date_and_duration(NaiveDate::from_ymd(2020, 9, 1), Duration::days(1));
+
+        assert!(RangeMode::Contained.matches(span, Some(at(1, 0)), Some(at(3, 0))));
+        assert!(!RangeMode::Contained.matches(span, Some(at(1, 0)), Some(at(2, 23))));
+        assert!(RangeMode::Overlaps.matches(span, Some(at(2, 23)), None));
+        assert!(!RangeMode::Overlaps.matches(span, Some(at(3, 0)), None));
+    }
+
+    #[test]
+    fn recurring_events_match_by_their_instances()
+    {
+        let event = weekly(vec![NaiveDate::from_ymd(2020, 9, 8)]);
+
+        // Between two instances, or on an excluded one.
+        assert!(!event.has_instance_in_range(RangeMode::Overlaps, Some(at(2, 0)), Some(at(8, 0))));
+        assert!(!event.has_instance_in_range(RangeMode::Overlaps, Some(at(8, 0)), Some(at(9, 0))));
+
+        assert!(event.has_instance_in_range(RangeMode::Contained, Some(at(15, 0)), Some(at(16, 0))));
+        assert!(event.has_instance_in_range(RangeMode::Overlaps, Some(at(29, 10) + Duration::minutes(30)), None));
+        assert!(!event.has_instance_in_range(RangeMode::Overlaps, Some(at(29, 11)), None));
+    }
+}
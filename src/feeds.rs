@@ -0,0 +1,224 @@
+//! Calendar feeds, see docs/ical.md.
+//!
+//! A feed gives read-only access to a calendar as an iCalendar file, without
+//! authentication, to anyone with its URL. The URL has a secret token, so that
+//! it can be pasted into calendar apps (e.g. as a `webcal://` URL), which poll
+//! it for changes.
+//!
+//! Tokens are generated and stored like API keys (see `key_hashing`), so the
+//! token is only returned when a feed is created.
+
+use crate::database_error::DatabaseError;
+use crate::database_helpers::{FromRow, get_cell_from_row};
+use crate::authentication::key_hashing::{self, RandomError};
+use postgres::{Row, GenericClient};
+use chrono::NaiveDateTime;
+use uuid::Uuid;
+
+const FEED_FIELDS: &str = "id, calendar_id, label, created_at, last_used_at, revoked_at";
+
+/// A feed, without its token.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct Feed
+{
+    pub id: Uuid,
+    pub calendar_id: Uuid,
+    pub label: String,
+
+    #[serde(with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub created_at: Option<NaiveDateTime>,
+
+    #[serde(with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub last_used_at: Option<NaiveDateTime>,
+
+    #[serde(with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl FromRow for Feed
+{
+    type SelfType = Feed;
+
+    fn from_row(row: &Row) -> Result<Self::SelfType, DatabaseError>
+    {
+        Ok(
+            Feed {
+                id: get_cell_from_row(row, "id")?,
+                calendar_id: get_cell_from_row(row, "calendar_id")?,
+                label: get_cell_from_row(row, "label")?,
+                created_at: Some(get_cell_from_row(row, "created_at")?),
+                last_used_at: get_cell_from_row(row, "last_used_at")?,
+                revoked_at: get_cell_from_row(row, "revoked_at")?,
+            }
+        )
+    }
+}
+
+/// A feed together with its token. Only returned when the feed is created.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct FeedWithToken
+{
+    #[serde(flatten)]
+    pub info: Feed,
+
+    /// This is the only time it's shown, it can't be retrieved later.
+    pub token: String,
+
+    /// The feed's path, relative to the server's address.
+    pub path: String,
+}
+
+/// Request body for creating a feed.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct NewFeed
+{
+    #[serde(default)]
+    pub label: String,
+}
+
+#[derive(Error, Debug)]
+pub enum NewFeedError
+{
+    #[error(transparent)]
+    Database(#[from] DatabaseError),
+
+    #[error(transparent)]
+    Random(#[from] RandomError),
+}
+
+/// The path a feed is served at.
+pub fn feed_path(token: &str) -> String
+{
+    //TODO: prepend host to url.
+    format!("/api/feeds/{}.ics", token)
+}
+
+/// Creates a feed of the calendar.
+pub fn create(db: &mut impl GenericClient, calendar_id: &Uuid, new_feed: &NewFeed) -> Result<FeedWithToken, NewFeedError>
+{
+    let generated = key_hashing::generate()?;
+
+    let query = format!("
+        INSERT INTO calendar_feeds (calendar_id, label, token_prefix, token_salt, token_hash)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING {};
+    ", FEED_FIELDS);
+
+    let row = db.query_one(query.as_str(), &[calendar_id, &new_feed.label, &generated.prefix, &generated.salt, &generated.hash])
+        .map_err(DatabaseError::from)?;
+
+    Ok(
+        FeedWithToken {
+            info: Feed::from_row(&row)?,
+            path: feed_path(&generated.key),
+            token: generated.key,
+        }
+    )
+}
+
+/// Lists the calendar's feeds, including revoked ones.
+pub fn list(db: &mut impl GenericClient, calendar_id: &Uuid) -> Result<Vec<Feed>, DatabaseError>
+{
+    let query = format!("SELECT {} FROM calendar_feeds WHERE calendar_id = $1 ORDER BY created_at, id;", FEED_FIELDS);
+
+    db.query(query.as_str(), &[calendar_id])?
+        .iter()
+        .map(|row| Feed::from_row(row))
+        .collect()
+}
+
+/// Revokes one of the calendar's feeds. Returns false if the feed
+/// doesn't exist or was already revoked.
+pub fn revoke(db: &mut impl GenericClient, calendar_id: &Uuid, id: &Uuid) -> Result<bool, DatabaseError>
+{
+    let updated = db.execute(
+        "UPDATE calendar_feeds SET revoked_at = NOW() WHERE id = $1 AND calendar_id = $2 AND revoked_at IS NULL;",
+        &[id, calendar_id]
+    )?;
+
+    Ok(updated > 0)
+}
+
+/// Finds the calendar of the feed with the token. Returns the calendar's tenant
+/// and id, or None if there's no such feed, it was revoked or the calendar
+/// was deleted.
+pub fn find_calendar(db: &mut impl GenericClient, token: &str) -> Result<Option<(Uuid, Uuid)>, DatabaseError>
+{
+    let (prefix, token) = match key_hashing::parse(token)
+    {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+
+    let query = "
+        SELECT calendar_feeds.id, token_salt, token_hash, calendar_id, calendars.tenant_id
+        FROM calendar_feeds
+        INNER JOIN calendars ON calendars.id = calendar_feeds.calendar_id
+        WHERE
+            token_prefix = $1
+            AND revoked_at IS NULL
+            AND calendars.deleted_at IS NULL;
+    ";
+
+    let rows = db.query(query, &[&prefix])?;
+
+    let row = rows
+        .iter()
+        .find(|row|
+        {
+            let salt: Vec<u8> = row.try_get("token_salt").unwrap_or_default();
+            let hash: Vec<u8> = row.try_get("token_hash").unwrap_or_default();
+
+            key_hashing::verify(&token, &salt, &hash)
+        });
+
+    let row = match row
+    {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    let id: Uuid = get_cell_from_row(row, "id")?;
+
+    // Apps poll feeds often, only update last_used_at once a minute.
+    db.execute(
+        "UPDATE calendar_feeds SET last_used_at = NOW() WHERE id = $1 AND (last_used_at IS NULL OR last_used_at < NOW() - INTERVAL '1 minute');",
+        &[&id]
+    )?;
+
+    Ok(Some((get_cell_from_row(row, "tenant_id")?, get_cell_from_row(row, "calendar_id")?)))
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{create, revoke, find_calendar, NewFeed};
+    use crate::test_helpers::{connect, insert_tenant, insert_calendar};
+
+    #[test]
+    #[ignore]
+    fn tokens_find_their_calendar_until_revoked()
+    {
+        let mut client = connect();
+        let mut db = client.transaction().unwrap();
+
+        let tenant_id = insert_tenant(&mut db);
+        let calendar_id = insert_calendar(&mut db, &tenant_id);
+
+        let feed = create(&mut db, &calendar_id, &NewFeed { label: "phone".to_owned() }).unwrap();
+        assert_eq!(feed.path, format!("/api/feeds/{}.ics", feed.token));
+
+        assert_eq!(find_calendar(&mut db, &feed.token).unwrap(), Some((tenant_id, calendar_id)));
+
+        let mut wrong_token = feed.token.clone();
+        wrong_token.pop();
+        assert_eq!(find_calendar(&mut db, &wrong_token).unwrap(), None);
+
+        assert!(revoke(&mut db, &calendar_id, &feed.info.id).unwrap());
+        assert!(!revoke(&mut db, &calendar_id, &feed.info.id).unwrap());
+        assert_eq!(find_calendar(&mut db, &feed.token).unwrap(), None);
+    }
+}
@@ -0,0 +1,302 @@
+//! Exports calendars and events as iCalendar objects.
+//!
+//! Events are stored in UTC, so their dates and times are exported as UTC
+//! date-times (or dates, for all-day events). Children that override instances
+//! of a recurring event are exported as components with their parent's UID
+//! and a RECURRENCE-ID, as RFC 5545 expects.
+
+use super::{Component, Property, ValueType};
+use crate::calendar::Calendar;
+use crate::event::EventPlain;
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+pub const PRODUCT_ID: &str = "-//calendar-server//calendar-server//EN";
+
+/// The UID of an event, derived from its id so it's the same in every export.
+/// Imported events keep the UID they were imported with instead.
+pub fn event_uid(id: &Uuid) -> String
+{
+    format!("{}@calendar-server", id)
+}
+
+/// The id of the event with the UID, if it's one given by `event_uid`.
+pub fn event_id_from_uid(uid: &str) -> Option<Uuid>
+{
+    uid.strip_suffix("@calendar-server").and_then(|id| Uuid::parse_str(id).ok())
+}
+
+fn format_date(date: NaiveDate) -> String
+{
+    date.format("%Y%m%d").to_string()
+}
+
+fn format_date_time(date_time: NaiveDateTime) -> String
+{
+    date_time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// A date property of an event, as a date if the event is all-day (`time` is None)
+/// or as a UTC date-time otherwise.
+fn date_property(name: &str, dates: &[NaiveDate], time: Option<NaiveTime>) -> Property
+{
+    match time
+    {
+        Some(time) => Property::with_type(name, ValueType::DateTime, dates.iter().map(|d| format_date_time(d.and_time(time))).collect()),
+        None => Property::with_type(name, ValueType::Date, dates.iter().map(|d| format_date(*d)).collect()),
+    }
+}
+
+/// UNTIL has to have the same type as DTSTART, but rrules only have dates. The
+/// last instance can start on that date, so for timed events it becomes the
+/// last second of the day.
+fn rrule_value(rrule: &str, all_day: bool) -> String
+{
+    if all_day
+    {
+        return rrule.to_owned();
+    }
+
+    rrule
+        .split(';')
+        .map(|part| match part.strip_prefix("UNTIL=")
+        {
+            Some(until) if until.len() == 8 => format!("UNTIL={}T235959Z", until),
+            _ => part.to_owned(),
+        })
+        .collect::<Vec<String>>()
+        .join(";")
+}
+
+/// The VEVENT of an event. `parent` is the event's parent, if it
+/// overrides an instance of a recurring event. `uid` is the UID of the
+/// event's series, which is its parent's if it has one.
+pub fn event_component(event: &EventPlain, parent: Option<&EventPlain>, uid: &str) -> Option<Component>
+{
+    let start_date = event.start_date?;
+    let end_date = event.end_date?;
+
+    let mut component = Component::new("VEVENT");
+
+    component.push(Property::new("UID", uid));
+
+    // DTSTAMP is required. Events that were never stored (so they don't
+    // have a last modification) get the time of the export.
+    let dtstamp = event.last_modified.unwrap_or_else(|| Utc::now().naive_utc());
+    component.push(Property::new("DTSTAMP", format_date_time(dtstamp)));
+
+    if let Some(last_modified) = event.last_modified
+    {
+        component.push(Property::new("LAST-MODIFIED", format_date_time(last_modified)));
+    }
+
+    match (event.start_time, event.end_time)
+    {
+        (Some(start_time), Some(end_time)) =>
+        {
+            component.push(date_property("DTSTART", &[start_date], Some(start_time)));
+            component.push(date_property("DTEND", &[end_date], Some(end_time)));
+        },
+        _ =>
+        {
+            // End dates are inclusive here, DTEND is exclusive.
+            component.push(date_property("DTSTART", &[start_date], None));
+            component.push(date_property("DTEND", &[end_date + Duration::days(1)], None));
+        },
+    }
+
+    // The RECURRENCE-ID has the type and time the parent's instances have.
+    if let Some(original_start_date) = event.original_start_date
+    {
+        let time = match parent
+        {
+            Some(parent) => parent.start_time,
+            None => event.start_time,
+        };
+
+        component.push(date_property("RECURRENCE-ID", &[original_start_date], time));
+    }
+
+    if let Some(recurrence) = &event.recurrence
+    {
+        if let Some(rrule) = &recurrence.rrule
+        {
+            component.push(Property::new("RRULE", rrule_value(rrule, event.start_time.is_none())));
+        }
+
+        for (name, dates) in vec![("EXDATE", &recurrence.exdates), ("RDATE", &recurrence.rdates)]
+        {
+            if let Some(dates) = dates.as_ref().filter(|dates| !dates.is_empty())
+            {
+                component.push(date_property(name, dates, event.start_time));
+            }
+        }
+    }
+
+    Some(component)
+}
+
+/// A VCALENDAR with the properties every exported VCALENDAR has.
+pub fn vcalendar() -> Component
+{
+    let mut component = Component::new("VCALENDAR");
+
+    component.push(Property::new("VERSION", "2.0"));
+    component.push(Property::new("PRODID", PRODUCT_ID));
+    component.push(Property::new("CALSCALE", "GREGORIAN"));
+
+    component
+}
+
+/// The VEVENTs of the events. The parents of events that override instances
+/// are looked for in `events` and `parents`. `uids` are the UIDs events were
+/// imported with, by event id.
+fn event_components(events: &[EventPlain], parents: &[EventPlain], uids: &HashMap<Uuid, String>) -> Vec<Component>
+{
+    let by_id: HashMap<Uuid, &EventPlain> = events
+        .iter()
+        .chain(parents.iter())
+        .filter_map(|event| event.id.map(|id| (id, event)))
+        .collect();
+
+    events
+        .iter()
+        .filter_map(|event|
+        {
+            let series_id = event.parent_id.or(event.id)?;
+
+            let uid = uids
+                .get(&series_id)
+                .cloned()
+                .unwrap_or_else(|| event_uid(&series_id));
+
+            event_component(event, event.parent_id.and_then(|id| by_id.get(&id).copied()), &uid)
+        })
+        .collect()
+}
+
+/// The VCALENDAR of a calendar with the given events. `uids` are the
+/// UIDs events were imported with, by event id.
+pub fn calendar_component(calendar: &Calendar, events: &[EventPlain], uids: &HashMap<Uuid, String>) -> Component
+{
+    let mut component = vcalendar();
+
+    // Not standard, but understood by most clients.
+    component.push(Property::new("X-WR-CALNAME", calendar.get_name()));
+    component.push(Property::new("X-WR-TIMEZONE", calendar.get_timezone()));
+
+    if let Some(description) = calendar.get_description()
+    {
+        component.push(Property::new("X-WR-CALDESC", description.as_str()));
+    }
+
+    component.components = event_components(events, &[], uids);
+
+    component
+}
+
+/// A VCALENDAR with some of a calendar's events, without the calendar's
+/// properties. `parents` are the parents of the events that override
+/// instances, if they aren't in `events`.
+pub fn events_component(events: &[EventPlain], parents: &[EventPlain], uids: &HashMap<Uuid, String>) -> Component
+{
+    let mut component = vcalendar();
+    component.components = event_components(events, parents, uids);
+    component
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{event_component, rrule_value, event_uid, event_id_from_uid};
+    use crate::event::{EventPlain, RecurrencePlain};
+    use chrono::{NaiveDate, NaiveTime};
+    use uuid::Uuid;
+
+    fn event(id: Uuid) -> EventPlain
+    {
+        EventPlain {
+            id: Some(id),
+            parent_id: None,
+            original_start_date: None,
+            start_date: Some(NaiveDate::from_ymd(2020, 9, 1)),
+            start_time: Some(NaiveTime::from_hms(10, 0, 0)),
+            end_date: Some(NaiveDate::from_ymd(2020, 9, 1)),
+            end_time: Some(NaiveTime::from_hms(11, 30, 0)),
+            recurrence: None,
+            last_modified: Some(NaiveDate::from_ymd(2020, 8, 20).and_hms(12, 0, 0)),
+            instance_origin: None,
+            deleted: false,
+        }
+    }
+
+    #[test]
+    fn exports_recurring_events_and_overrides()
+    {
+        let id = Uuid::new_v4();
+
+        let mut parent = event(id);
+        parent.recurrence = Some(RecurrencePlain {
+            rrule: Some("FREQ=WEEKLY;BYDAY=TU;UNTIL=20201231".to_owned()),
+            exdates: Some(vec![NaiveDate::from_ymd(2020, 9, 8)]),
+            rdates: Some(vec![]),
+        });
+
+        let ics = event_component(&parent, None, &event_uid(&id)).unwrap().to_ics();
+
+        assert_eq!(
+            ics,
+            format!(
+                "BEGIN:VEVENT\r\n\
+                UID:{}\r\n\
+                DTSTAMP:20200820T120000Z\r\n\
+                LAST-MODIFIED:20200820T120000Z\r\n\
+                DTSTART:20200901T100000Z\r\n\
+                DTEND:20200901T113000Z\r\n\
+                RRULE:FREQ=WEEKLY;BYDAY=TU;UNTIL=20201231T235959Z\r\n\
+                EXDATE:20200908T100000Z\r\n\
+                END:VEVENT\r\n",
+                event_uid(&id)
+            )
+        );
+
+        let mut child = event(Uuid::new_v4());
+        child.parent_id = Some(id);
+        child.original_start_date = Some(NaiveDate::from_ymd(2020, 9, 8));
+        child.start_date = Some(NaiveDate::from_ymd(2020, 9, 9));
+        child.end_date = Some(NaiveDate::from_ymd(2020, 9, 9));
+
+        let component = event_component(&child, Some(&parent), &event_uid(&id)).unwrap();
+
+        assert_eq!(component.property("UID").unwrap().value(), event_uid(&id));
+        assert_eq!(component.property("RECURRENCE-ID").unwrap().value(), "20200908T100000Z");
+        assert_eq!(event_id_from_uid(&event_uid(&id)), Some(id));
+    }
+
+    #[test]
+    fn exports_all_day_events_with_exclusive_end()
+    {
+        let mut all_day = event(Uuid::new_v4());
+        all_day.start_time = None;
+        all_day.end_time = None;
+
+        let ics = event_component(&all_day, None, "all-day").unwrap().to_ics();
+
+        assert!(ics.contains("\r\nDTSTART;VALUE=DATE:20200901\r\n"));
+        assert!(ics.contains("\r\nDTEND;VALUE=DATE:20200902\r\n"));
+        assert_eq!(rrule_value("FREQ=DAILY;UNTIL=20201231", true), "FREQ=DAILY;UNTIL=20201231");
+    }
+
+    #[test]
+    fn always_exports_a_dtstamp()
+    {
+        let mut unsaved = event(Uuid::new_v4());
+        unsaved.last_modified = None;
+
+        let component = event_component(&unsaved, None, "unsaved").unwrap();
+
+        assert!(component.property("DTSTAMP").is_some());
+        assert!(component.property("LAST-MODIFIED").is_none());
+    }
+}
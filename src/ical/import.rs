@@ -0,0 +1,950 @@
+//! Imports iCalendar files into a calendar.
+//!
+//! Each VEVENT becomes an event. Recurring events keep their RRULE, EXDATEs and
+//! RDATEs. VEVENTs with a RECURRENCE-ID override an instance of the recurring
+//! event with the same UID, so they become its children and the instance's date
+//! is added to its exdates.
+//!
+//! Components are imported one at a time, each in its own savepoint, so one
+//! that can't be imported is reported and doesn't stop the others. CalDAV's
+//! calendar object resources are imported as a whole instead, see `import_object`.
+
+use super::{Component, ValueType};
+use super::parser::{self, ParseError};
+use super::timezone::{Timezones, UnknownTimezone};
+use super::export::event_id_from_uid;
+use crate::calendar::Calendar;
+use crate::database_error::{DatabaseError, DatabaseErrorKind};
+use crate::database_helpers::{FromRow, get_cell_from_row};
+use crate::event::{Event, ToPlain};
+use crate::recurrence::RecurrenceRule;
+use crate::webhooks::{self, Change, WebhookEventType};
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime};
+use chrono_tz::Tz;
+use postgres::GenericClient;
+use std::collections::HashSet;
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[derive(Serialize, Debug, Default, JsonSchema)]
+pub struct ImportReport
+{
+    pub imported: Vec<ImportedComponent>,
+
+    /// Components that aren't imported by design, e.g. VTODOs, cancelled
+    /// events or events that were already imported.
+    pub skipped: Vec<RejectedComponent>,
+
+    /// Components that couldn't be imported, e.g. because they're invalid.
+    pub failed: Vec<RejectedComponent>,
+}
+
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct ImportedComponent
+{
+    pub uid: Option<String>,
+
+    /// The RECURRENCE-ID of overrides, as it is in the file.
+    pub recurrence_id: Option<String>,
+
+    pub event_id: Uuid,
+}
+
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct RejectedComponent
+{
+    /// The component's name, e.g. `VEVENT`.
+    pub component: String,
+
+    pub uid: Option<String>,
+    pub recurrence_id: Option<String>,
+    pub reason: String,
+}
+
+#[derive(Error, Debug)]
+pub enum ImportError
+{
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+
+    #[error(transparent)]
+    Database(#[from] DatabaseError),
+}
+
+impl From<postgres::Error> for ImportError
+{
+    fn from(e: postgres::Error) -> Self
+    {
+        ImportError::Database(e.into())
+    }
+}
+
+/// Why a calendar object resource wasn't imported, see `import_object`.
+#[derive(Error, Debug)]
+pub enum ObjectError
+{
+    #[error("{0}")]
+    Invalid(String),
+
+    #[error("Another event has the same UID.")]
+    UidConflict,
+
+    #[error(transparent)]
+    Database(#[from] DatabaseError),
+}
+
+impl From<postgres::Error> for ObjectError
+{
+    fn from(e: postgres::Error) -> Self
+    {
+        ObjectError::Database(e.into())
+    }
+}
+
+/// Why a component wasn't imported.
+#[derive(Error, Debug)]
+enum ComponentError
+{
+    #[error("Only VEVENTs are imported.")]
+    NotAnEvent,
+
+    #[error("The event is cancelled.")]
+    Cancelled,
+
+    #[error("The event was already imported.")]
+    AlreadyImported,
+
+    #[error("Property {0} is required but missing.")]
+    MissingProperty(&'static str),
+
+    #[error("Property {0} has an invalid value.")]
+    InvalidValue(&'static str),
+
+    #[error("Invalid RRULE: {0}")]
+    InvalidRRule(String),
+
+    #[error("RDATEs are only supported in events with an RRULE.")]
+    RDatesWithoutRRule,
+
+    #[error("The event must end after it starts.")]
+    EndsBeforeStart,
+
+    #[error("There is no recurring event with the same UID.")]
+    ParentNotFound,
+
+    #[error(transparent)]
+    UnknownTimezone(#[from] UnknownTimezone),
+
+    #[error(transparent)]
+    Database(#[from] DatabaseError),
+}
+
+impl From<postgres::Error> for ComponentError
+{
+    fn from(e: postgres::Error) -> Self
+    {
+        ComponentError::Database(e.into())
+    }
+}
+
+impl From<ComponentError> for ObjectError
+{
+    fn from(e: ComponentError) -> Self
+    {
+        match e
+        {
+            ComponentError::Database(e) => ObjectError::Database(e),
+            e => ObjectError::Invalid(e.to_string()),
+        }
+    }
+}
+
+impl ComponentError
+{
+    /// Whether the component is skipped by design, instead of having failed.
+    fn is_skip(&self) -> bool
+    {
+        matches!(self, ComponentError::NotAnEvent | ComponentError::Cancelled | ComponentError::AlreadyImported)
+    }
+}
+
+/// What importing a component did.
+enum Outcome
+{
+    /// An event was created, `parent_id` is the parent of overrides.
+    Created { event_id: Uuid, parent_id: Option<Uuid> },
+
+    /// The component cancels an instance of the event, which was excluded.
+    Excluded { parent_id: Uuid },
+}
+
+/// A DATE or DATE-TIME value.
+#[derive(Copy, Clone, Debug)]
+enum DateValue
+{
+    Date(NaiveDate),
+
+    /// The date-time in UTC and the date it has in its own time zone.
+    DateTime(NaiveDateTime, NaiveDate),
+}
+
+impl DateValue
+{
+    fn utc_date(&self) -> NaiveDate
+    {
+        match self
+        {
+            DateValue::Date(date) => *date,
+            DateValue::DateTime(date_time, _) => date_time.date(),
+        }
+    }
+}
+
+/// The fields of an events row.
+struct EventFields
+{
+    start_date: NaiveDate,
+    start_time: Option<NaiveTime>,
+    end_date: NaiveDate,
+    end_time: Option<NaiveTime>,
+    rrule: Option<String>,
+    exdates: Option<Vec<NaiveDate>>,
+    rdates: Option<Vec<NaiveDate>>,
+}
+
+/// Imports the events of an iCalendar file into the calendar, see the module's
+/// docs. Fails only if the file can't be parsed or on database errors other
+/// than those caused by a component.
+pub fn import(db: &mut impl GenericClient, calendar: &Calendar, created_by: &str, ics: &str) -> Result<ImportReport, ImportError>
+{
+    let components = parser::parse(ics)?;
+
+    Ok(import_components(db, calendar, created_by, &components)?)
+}
+
+/// Imports the events of VCALENDARs that were already parsed, e.g. from jCal or
+/// xCal (see `jcal` and `xcal`). Fails only on database errors other than those
+/// caused by a component.
+pub fn import_components(db: &mut impl GenericClient, calendar: &Calendar, created_by: &str, components: &[Component]) -> Result<ImportReport, DatabaseError>
+{
+    let calendar_id = calendar.get_id();
+    let default_timezone = Tz::from_str(calendar.get_timezone()).unwrap_or(Tz::UTC);
+
+    let mut report = ImportReport::default();
+    let mut created = HashSet::new();
+    let mut parents = HashSet::new();
+
+    let mut transaction = db.transaction()?;
+
+    for vcalendar in components.iter()
+    {
+        if vcalendar.name != "VCALENDAR"
+        {
+            report.skipped.push(rejected(vcalendar, &ComponentError::NotAnEvent));
+            continue;
+        }
+
+        let timezones = Timezones::new(vcalendar, default_timezone);
+
+        // Overrides go after the other events, their parents might be in the same file.
+        let mut events: Vec<&Component> = vcalendar.components
+            .iter()
+            .filter(|component| component.name != "VTIMEZONE")
+            .collect();
+
+        events.sort_by_key(|component| component.property("RECURRENCE-ID").is_some());
+
+        for component in events
+        {
+            let mut savepoint = transaction.transaction()?;
+
+            match import_component(&mut savepoint, &calendar_id, created_by, component, &timezones)
+            {
+                Ok(Outcome::Created { event_id, parent_id }) =>
+                {
+                    savepoint.commit()?;
+
+                    created.insert(event_id);
+                    parents.extend(parent_id);
+
+                    report.imported.push(ImportedComponent {
+                        uid: component.property("UID").map(|uid| uid.value().to_owned()),
+                        recurrence_id: component.property("RECURRENCE-ID").map(|id| id.value().to_owned()),
+                        event_id,
+                    });
+                },
+
+                Ok(Outcome::Excluded { parent_id }) =>
+                {
+                    savepoint.commit()?;
+
+                    parents.insert(parent_id);
+                    report.skipped.push(rejected(component, &ComponentError::Cancelled));
+                },
+
+                Err(e) =>
+                {
+                    savepoint.rollback()?;
+
+                    if e.is_skip()
+                    {
+                        report.skipped.push(rejected(component, &e));
+                    }
+                    else
+                    {
+                        report.failed.push(rejected(component, &e));
+                    }
+                },
+            }
+        }
+    }
+
+    let ids: Vec<Uuid> = created.union(&parents).copied().collect();
+    let mut changes = vec![];
+
+    for row in transaction.query("SELECT * FROM events WHERE id = ANY($1);", &[&ids])?.iter()
+    {
+        let event = Event::from_row(row)?.into_plain();
+
+        let event_type = match event.id
+        {
+            Some(id) if created.contains(&id) => WebhookEventType::EventCreated,
+            _ => WebhookEventType::EventUpdated,
+        };
+
+        changes.push(Change::event(event_type, calendar_id, &event));
+    }
+
+    webhooks::enqueue(&mut transaction, &changes)?;
+    transaction.commit()?;
+
+    Ok(report)
+}
+
+/// Imports a calendar object resource (RFC 4791 section 4.1), as CalDAV clients
+/// send them: a VCALENDAR whose VEVENTs are an event and the overrides of its
+/// instances, all with the same UID. Unlike `import_components`, the object is
+/// imported as a whole or not at all. Returns the event's id.
+///
+/// If `series_id` is Some the object replaces that event's series: the event is
+/// updated in place and its overrides are replaced by the object's.
+pub fn import_object(db: &mut impl GenericClient, calendar: &Calendar, created_by: &str, vcalendar: &Component, series_id: Option<Uuid>) -> Result<Uuid, ObjectError>
+{
+    let calendar_id = calendar.get_id();
+    let timezones = Timezones::new(vcalendar, Tz::from_str(calendar.get_timezone()).unwrap_or(Tz::UTC));
+
+    let events: Vec<&Component> = vcalendar.components
+        .iter()
+        .filter(|component| component.name != "VTIMEZONE")
+        .collect();
+
+    if vcalendar.name != "VCALENDAR" || events.iter().any(|event| event.name != "VEVENT")
+    {
+        return Err(ObjectError::Invalid(ComponentError::NotAnEvent.to_string()));
+    }
+
+    let uid = match events.get(0).and_then(|event| event.property("UID"))
+    {
+        Some(uid) => uid.value(),
+        None => return Err(ComponentError::MissingProperty("UID").into()),
+    };
+
+    if events.iter().any(|event| event.property("UID").map(|uid| uid.value()) != Some(uid))
+    {
+        return Err(ObjectError::Invalid("All VEVENTs must have the same UID.".to_owned()));
+    }
+
+    let (masters, overrides): (Vec<&Component>, Vec<&Component>) = events
+        .into_iter()
+        .partition(|event| event.property("RECURRENCE-ID").is_none());
+
+    let master = match masters.as_slice()
+    {
+        [master] => *master,
+        _ => return Err(ObjectError::Invalid("There must be exactly one VEVENT without a RECURRENCE-ID.".to_owned())),
+    };
+
+    let mut transaction = db.transaction()?;
+
+    match find_series(&mut transaction, &calendar_id, uid)?
+    {
+        Some((id, _)) if Some(id) != series_id => return Err(ObjectError::UidConflict),
+        _ => {},
+    }
+
+    let fields = event_fields(master, &timezones, true)?;
+
+    let mut created = vec![];
+    let mut changes = vec![];
+
+    let event_id = match series_id
+    {
+        Some(series_id) =>
+        {
+            let query = "UPDATE events SET deleted_at = NOW() WHERE parent_event_id = $1 AND deleted_at IS NULL RETURNING *;";
+
+            for row in transaction.query(query, &[&series_id])?.iter()
+            {
+                changes.push(Change::event(WebhookEventType::EventDeleted, calendar_id, &Event::from_row(row)?.into_plain()));
+            }
+
+            update(&mut transaction, &series_id, uid, &fields)?;
+            series_id
+        },
+        None =>
+        {
+            let event_id = insert(&mut transaction, &calendar_id, created_by, Some(uid), None, &fields)?;
+            created.push(event_id);
+            event_id
+        },
+    };
+
+    for component in overrides
+    {
+        if let Outcome::Created { event_id, .. } = import_component(&mut transaction, &calendar_id, created_by, component, &timezones)?
+        {
+            created.push(event_id);
+        }
+    }
+
+    let ids: Vec<Uuid> = created.iter().copied().chain(std::iter::once(event_id)).collect();
+
+    for row in transaction.query("SELECT * FROM events WHERE id = ANY($1);", &[&ids])?.iter()
+    {
+        let event = Event::from_row(row)?.into_plain();
+
+        let event_type = match event.id
+        {
+            Some(id) if created.contains(&id) => WebhookEventType::EventCreated,
+            _ => WebhookEventType::EventUpdated,
+        };
+
+        changes.push(Change::event(event_type, calendar_id, &event));
+    }
+
+    webhooks::enqueue(&mut transaction, &changes)?;
+    transaction.commit()?;
+
+    Ok(event_id)
+}
+
+fn rejected(component: &Component, reason: &ComponentError) -> RejectedComponent
+{
+    RejectedComponent {
+        component: component.name.clone(),
+        uid: component.property("UID").map(|uid| uid.value().to_owned()),
+        recurrence_id: component.property("RECURRENCE-ID").map(|id| id.value().to_owned()),
+        reason: reason.to_string(),
+    }
+}
+
+fn import_component(db: &mut impl GenericClient, calendar_id: &Uuid, created_by: &str, component: &Component, timezones: &Timezones) -> Result<Outcome, ComponentError>
+{
+    if component.name != "VEVENT"
+    {
+        return Err(ComponentError::NotAnEvent);
+    }
+
+    let uid = component.property("UID").map(|uid| uid.value());
+    let cancelled = component.property("STATUS").map_or(false, |status| status.value().eq_ignore_ascii_case("CANCELLED"));
+
+    // The event with the same UID, that this component is or overrides an instance of.
+    let series = match uid
+    {
+        Some(uid) => find_series(db, calendar_id, uid)?,
+        None => None,
+    };
+
+    if component.property("RECURRENCE-ID").is_none()
+    {
+        if series.is_some()
+        {
+            return Err(ComponentError::AlreadyImported);
+        }
+
+        if cancelled
+        {
+            return Err(ComponentError::Cancelled);
+        }
+
+        let fields = event_fields(component, timezones, true)?;
+        let event_id = insert(db, calendar_id, created_by, uid, None, &fields)?;
+
+        return Ok(Outcome::Created { event_id, parent_id: None });
+    }
+
+    let parent_id = match series
+    {
+        Some((id, true)) => id,
+        _ => return Err(ComponentError::ParentNotFound),
+    };
+
+    let original_start_date = date_values(component, "RECURRENCE-ID", timezones)?
+        .get(0)
+        .map(|date| date.utc_date())
+        .ok_or(ComponentError::InvalidValue("RECURRENCE-ID"))?;
+
+    let rows = db.query(
+        "SELECT id FROM events WHERE parent_event_id = $1 AND original_start_date = $2 AND deleted_at IS NULL;",
+        &[&parent_id, &original_start_date]
+    )?;
+
+    if !rows.is_empty()
+    {
+        return Err(ComponentError::AlreadyImported);
+    }
+
+    db.execute(
+        "UPDATE events SET exdates = array_append(COALESCE(exdates, '{}'), $2) WHERE id = $1 AND NOT ($2 = ANY(COALESCE(exdates, '{}')));",
+        &[&parent_id, &original_start_date]
+    )?;
+
+    if cancelled
+    {
+        return Ok(Outcome::Excluded { parent_id });
+    }
+
+    let fields = event_fields(component, timezones, false)?;
+    let event_id = insert(db, calendar_id, created_by, uid, Some((parent_id, original_start_date)), &fields)?;
+
+    Ok(Outcome::Created { event_id, parent_id: Some(parent_id) })
+}
+
+/// Finds the event (not a child) with the UID, either imported with it or
+/// exported with it (see `export::event_uid`). Returns its id and whether
+/// it's recurring.
+fn find_series(db: &mut impl GenericClient, calendar_id: &Uuid, uid: &str) -> Result<Option<(Uuid, bool)>, ComponentError>
+{
+    let query = "
+        SELECT id, rrule IS NOT NULL AS recurring FROM events
+        WHERE
+            calendar_id = $1
+            AND parent_event_id IS NULL
+            AND deleted_at IS NULL
+            AND (ical_uid = $2 OR id = $3)
+        LIMIT 1;
+    ";
+
+    match db.query(query, &[calendar_id, &uid, &event_id_from_uid(uid)])?.get(0)
+    {
+        Some(row) => Ok(Some((get_cell_from_row(row, "id")?, get_cell_from_row(row, "recurring")?))),
+        None => Ok(None),
+    }
+}
+
+fn insert(
+    db: &mut impl GenericClient,
+    calendar_id: &Uuid,
+    created_by: &str,
+    uid: Option<&str>,
+    parent: Option<(Uuid, NaiveDate)>,
+    fields: &EventFields
+) -> Result<Uuid, ComponentError>
+{
+    let query = "INSERT INTO events
+    (
+        parent_event_id, original_start_date,
+        start_date, start_time, end_date, end_time, rrule, exdates,
+        rdates, calendar_id, created_by, ical_uid
+    )
+
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+    RETURNING id;";
+
+    let rows = db.query(query, &[
+        &parent.map(|(id, _)| id),
+        &parent.map(|(_, date)| date),
+        &fields.start_date,
+        &fields.start_time,
+        &fields.end_date,
+        &fields.end_time,
+        &fields.rrule,
+        &fields.exdates,
+        &fields.rdates,
+        calendar_id,
+        &created_by,
+        &uid,
+    ])?;
+
+    let row = rows
+        .get(0)
+        .ok_or_else(|| DatabaseError::from(DatabaseErrorKind::ReturningIsEmpty))?;
+
+    Ok(get_cell_from_row(row, "id")?)
+}
+
+/// Updates an event that isn't a child with the fields of a VEVENT.
+fn update(db: &mut impl GenericClient, event_id: &Uuid, uid: &str, fields: &EventFields) -> Result<(), ComponentError>
+{
+    let query = "UPDATE events SET
+        start_date = $2, start_time = $3, end_date = $4, end_time = $5,
+        rrule = $6, exdates = $7, rdates = $8, ical_uid = $9
+    WHERE id = $1;";
+
+    db.execute(query, &[
+        event_id,
+        &fields.start_date,
+        &fields.start_time,
+        &fields.end_date,
+        &fields.end_time,
+        &fields.rrule,
+        &fields.exdates,
+        &fields.rdates,
+        &uid,
+    ])?;
+
+    Ok(())
+}
+
+/// Maps a VEVENT to the fields of an event. Recurrence properties are
+/// ignored unless `recurring` is true.
+fn event_fields(event: &Component, timezones: &Timezones, recurring: bool) -> Result<EventFields, ComponentError>
+{
+    let start = date_values(event, "DTSTART", timezones)?
+        .get(0)
+        .copied()
+        .ok_or(ComponentError::MissingProperty("DTSTART"))?;
+
+    let end = date_values(event, "DTEND", timezones)?.get(0).copied();
+
+    let duration = match event.property("DURATION")
+    {
+        Some(duration) => Some(parse_duration(duration.value()).ok_or(ComponentError::InvalidValue("DURATION"))?),
+        None => None,
+    };
+
+    let mut fields = match start
+    {
+        DateValue::Date(start_date) =>
+        {
+            // DTEND is exclusive, the end dates of all-day events are inclusive.
+            let end_date = match (end, duration)
+            {
+                (Some(DateValue::Date(end)), _) => end - Duration::days(1),
+                (Some(_), _) => return Err(ComponentError::InvalidValue("DTEND")),
+                (None, Some(duration)) => start_date + Duration::days(duration.num_days() - 1),
+                (None, None) => start_date,
+            };
+
+            EventFields {
+                start_date,
+                start_time: None,
+                end_date: end_date.max(start_date),
+                end_time: None,
+                rrule: None,
+                exdates: None,
+                rdates: None,
+            }
+        },
+
+        DateValue::DateTime(start, _) =>
+        {
+            let end = match (end, duration)
+            {
+                (Some(DateValue::DateTime(end, _)), _) => end,
+                (Some(_), _) => return Err(ComponentError::InvalidValue("DTEND")),
+                (None, Some(duration)) => start + duration,
+                (None, None) => start,
+            };
+
+            if end <= start
+            {
+                return Err(ComponentError::EndsBeforeStart);
+            }
+
+            EventFields {
+                start_date: start.date(),
+                start_time: Some(start.time()),
+                end_date: end.date(),
+                end_time: Some(end.time()),
+                rrule: None,
+                exdates: None,
+                rdates: None,
+            }
+        },
+    };
+
+    if !recurring
+    {
+        return Ok(fields);
+    }
+
+    let rdates: Vec<NaiveDate> = date_values(event, "RDATE", timezones)?.iter().map(|date| date.utc_date()).collect();
+
+    if let Some(rrule) = event.property("RRULE")
+    {
+        // Instances are generated from the UTC start date, so weekdays move along
+        // with it when the start is on another day in UTC than in its time zone.
+        let days = match start
+        {
+            DateValue::DateTime(utc, local) => (utc.date() - local).num_days(),
+            DateValue::Date(_) => 0,
+        };
+
+        fields.rrule = Some(rrule_value(rrule.value(), days, timezones)?);
+        fields.exdates = Some(date_values(event, "EXDATE", timezones)?.iter().map(|date| date.utc_date()).collect());
+        fields.rdates = Some(rdates);
+    }
+    else if !rdates.is_empty()
+    {
+        return Err(ComponentError::RDatesWithoutRRule);
+    }
+
+    Ok(fields)
+}
+
+/// The values of all of the component's properties with the name, e.g. all
+/// of its EXDATEs. Date-times are converted to UTC and periods are taken as
+/// their start.
+fn date_values(component: &Component, name: &'static str, timezones: &Timezones) -> Result<Vec<DateValue>, ComponentError>
+{
+    let mut dates = vec![];
+
+    for property in component.properties.iter().filter(|property| property.name == name)
+    {
+        for value in property.values.iter()
+        {
+            let date = match property.value_type
+            {
+                ValueType::Date => DateValue::Date(
+                    NaiveDate::parse_from_str(value, "%Y%m%d").map_err(|_| ComponentError::InvalidValue(name))?
+                ),
+
+                ValueType::DateTime | ValueType::Period =>
+                {
+                    let value = value.split('/').next().unwrap_or_default();
+
+                    let local = NaiveDateTime::parse_from_str(value.trim_end_matches('Z'), "%Y%m%dT%H%M%S")
+                        .map_err(|_| ComponentError::InvalidValue(name))?;
+
+                    let utc = if value.ends_with('Z')
+                    {
+                        local
+                    }
+                    else
+                    {
+                        timezones.to_utc(local, property.get_param("TZID"))?
+                    };
+
+                    DateValue::DateTime(utc, local.date())
+                },
+
+                _ => return Err(ComponentError::InvalidValue(name)),
+            };
+
+            dates.push(date);
+        }
+    }
+
+    Ok(dates)
+}
+
+/// Converts an RRULE to the form events store: UNTIL as a UTC date and BYDAY
+/// weekdays moved `days` days. Fails if `RecurrenceRule` doesn't support the rule.
+fn rrule_value(rrule: &str, days: i64, timezones: &Timezones) -> Result<String, ComponentError>
+{
+    let mut parts = vec![];
+
+    for part in rrule.split(';').filter(|part| !part.is_empty())
+    {
+        let (name, value) = part.split_once('=').ok_or(ComponentError::InvalidValue("RRULE"))?;
+
+        let value = match name.to_ascii_uppercase().as_str()
+        {
+            "UNTIL" if value.len() > 8 =>
+            {
+                let local = NaiveDateTime::parse_from_str(value.trim_end_matches('Z'), "%Y%m%dT%H%M%S")
+                    .map_err(|_| ComponentError::InvalidValue("RRULE"))?;
+
+                // UNTIL is in UTC, unless the event's start is a floating time.
+                let utc = if value.ends_with('Z')
+                {
+                    local
+                }
+                else
+                {
+                    timezones.to_utc(local, None)?
+                };
+
+                utc.format("%Y%m%d").to_string()
+            },
+
+            "BYDAY" if days != 0 => value
+                .split(',')
+                .map(|day| shift_weekday(day, days).ok_or(ComponentError::InvalidValue("RRULE")))
+                .collect::<Result<Vec<String>, ComponentError>>()?
+                .join(","),
+
+            _ => value.to_owned(),
+        };
+
+        parts.push(format!("{}={}", name.to_ascii_uppercase(), value));
+    }
+
+    let rule = RecurrenceRule::new(&parts.join(";")).map_err(|e| ComponentError::InvalidRRule(e.to_string()))?;
+
+    Ok(rule.to_string())
+}
+
+/// Moves a BYDAY weekday (with its ordinal, if any) by the number of days.
+fn shift_weekday(day: &str, days: i64) -> Option<String>
+{
+    const WEEKDAYS: [&str; 7] = ["MO", "TU", "WE", "TH", "FR", "SA", "SU"];
+
+    let split = day.len().checked_sub(2)?;
+    let (ordinal, weekday) = (day.get(..split)?, day.get(split..)?);
+
+    let weekday = WEEKDAYS.iter().position(|w| *w == weekday)? as i64;
+
+    Some(format!("{}{}", ordinal, WEEKDAYS[(weekday + days).rem_euclid(7) as usize]))
+}
+
+/// Parses a DURATION value, e.g. `PT1H30M` or `-P1W`.
+fn parse_duration(value: &str) -> Option<Duration>
+{
+    let (sign, value) = match value.strip_prefix('-')
+    {
+        Some(value) => (-1, value),
+        None => (1, value.strip_prefix('+').unwrap_or(value)),
+    };
+
+    let mut duration = Duration::zero();
+    let mut number = String::new();
+
+    for c in value.strip_prefix('P')?.chars()
+    {
+        match c
+        {
+            '0'..='9' => number.push(c),
+            'T' if number.is_empty() => {},
+
+            'W' | 'D' | 'H' | 'M' | 'S' =>
+            {
+                let n: i64 = number.parse().ok()?;
+                number.clear();
+
+                duration = duration + match c
+                {
+                    'W' => Duration::weeks(n),
+                    'D' => Duration::days(n),
+                    'H' => Duration::hours(n),
+                    'M' => Duration::minutes(n),
+                    _ => Duration::seconds(n),
+                };
+            },
+
+            _ => return None,
+        }
+    }
+
+    if !number.is_empty()
+    {
+        return None;
+    }
+
+    Some(duration * sign)
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{import, parse_duration, rrule_value, shift_weekday};
+    use crate::calendar;
+    use crate::ical::Component;
+    use crate::ical::timezone::Timezones;
+    use chrono::Duration;
+    use chrono_tz::Tz;
+    use crate::test_helpers::{connect, insert_tenant, insert_calendar};
+
+    #[test]
+    fn parses_durations()
+    {
+        assert_eq!(parse_duration("PT1H30M"), Some(Duration::minutes(90)));
+        assert_eq!(parse_duration("P1W"), Some(Duration::weeks(1)));
+        assert_eq!(parse_duration("-P1DT2S"), Some(-Duration::seconds(86402)));
+        assert_eq!(parse_duration("PT1"), None);
+        assert_eq!(parse_duration("1H"), None);
+    }
+
+    #[test]
+    fn converts_rrules()
+    {
+        let timezones = Timezones::new(&Component::new("VCALENDAR"), Tz::UTC);
+
+        assert_eq!(
+            rrule_value("FREQ=WEEKLY;BYDAY=MO,SU;UNTIL=20201231T225959Z;", 1, &timezones).unwrap(),
+            rrule_value("FREQ=WEEKLY;BYDAY=TU,MO;UNTIL=20201231", 0, &timezones).unwrap()
+        );
+
+        assert_eq!(shift_weekday("-1SU", -1).unwrap(), "-1SA");
+        assert!(rrule_value("FREQ=WEEKLY;BYDAY", 0, &timezones).is_err());
+        assert!(rrule_value("FREQ=HOURLY", 0, &timezones).is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn imports_events_and_overrides()
+    {
+        let mut client = connect();
+        let mut db = client.transaction().unwrap();
+
+        let tenant_id = insert_tenant(&mut db);
+        let calendar_id = insert_calendar(&mut db, &tenant_id);
+
+        let calendar = calendar::get_calendar(&mut db, &tenant_id, &calendar_id).unwrap().unwrap();
+
+        let ics = "BEGIN:VCALENDAR\r\n\
+            VERSION:2.0\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:override\r\n\
+            RECURRENCE-ID;TZID=Europe/Paris:20200908T100000\r\n\
+            DTSTART;TZID=Europe/Paris:20200909T100000\r\n\
+            DTEND;TZID=Europe/Paris:20200909T110000\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:override\r\n\
+            DTSTART;TZID=Europe/Paris:20200901T100000\r\n\
+            DURATION:PT1H\r\n\
+            RRULE:FREQ=WEEKLY;BYDAY=TU\r\n\
+            EXDATE;TZID=Europe/Paris:20200915T100000\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:all-day\r\n\
+            DTSTART;VALUE=DATE:20200901\r\n\
+            DTEND;VALUE=DATE:20200903\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:orphan\r\n\
+            RECURRENCE-ID:20200901T100000Z\r\n\
+            DTSTART:20200901T100000Z\r\n\
+            DTEND:20200901T110000Z\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VTODO\r\n\
+            UID:todo\r\n\
+            END:VTODO\r\n\
+            END:VCALENDAR\r\n";
+
+        let report = import(&mut db, &calendar, "user:test", ics).unwrap();
+
+        assert_eq!(report.imported.len(), 3);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].uid.as_deref(), Some("orphan"));
+
+        let row = db.query_one(
+            "SELECT start_time::TEXT AS start_time, exdates::TEXT AS exdates FROM events WHERE ical_uid = 'override' AND parent_event_id IS NULL;",
+            &[]
+        ).unwrap();
+
+        assert_eq!(row.get::<_, String>("start_time"), "08:00:00");
+        assert_eq!(row.get::<_, String>("exdates"), "{2020-09-15,2020-09-08}");
+
+        let row = db.query_one("SELECT end_date::TEXT AS end_date FROM events WHERE ical_uid = 'all-day';", &[]).unwrap();
+        assert_eq!(row.get::<_, String>("end_date"), "2020-09-02");
+
+        // Importing the same file again imports nothing.
+        let report = import(&mut db, &calendar, "user:test", ics).unwrap();
+        assert!(report.imported.is_empty());
+        assert_eq!(report.skipped.len(), 4);
+    }
+}
@@ -0,0 +1,350 @@
+//! Converts components to and from jCal (RFC 7265), iCalendar's JSON form.
+//!
+//! A component is an array with its name, its properties and its components.
+//! A property is an array with its name, its parameters, its value type and its
+//! values. Names are lower case and values are in their JSON form: dates are
+//! `2020-09-01`, recurrence rules are objects and integers are numbers.
+
+use super::{Component, Property, ValueType};
+use serde_json::{json, Map, Value};
+
+/// Recurrence rule parts whose values are integers.
+const INTEGER_RULE_PARTS: [&str; 10] = [
+    "COUNT", "INTERVAL", "BYSECOND", "BYMINUTE", "BYHOUR",
+    "BYMONTHDAY", "BYYEARDAY", "BYWEEKNO", "BYMONTH", "BYSETPOS",
+];
+
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum JCalError
+{
+    #[error("Invalid component, components are arrays of a name, properties and components.")]
+    InvalidComponent,
+
+    #[error("Invalid property in component {0}.")]
+    InvalidProperty(String),
+
+    #[error("Property {0} has an invalid value.")]
+    InvalidValue(String),
+}
+
+/// The jCal form of the component.
+pub fn to_jcal(component: &Component) -> Value
+{
+    let properties: Vec<Value> = component.properties.iter().map(property_to_jcal).collect();
+    let components: Vec<Value> = component.components.iter().map(to_jcal).collect();
+
+    json!([component.name.to_ascii_lowercase(), properties, components])
+}
+
+fn property_to_jcal(property: &Property) -> Value
+{
+    let params: Map<String, Value> = property.params
+        .iter()
+        .map(|(name, value)| (name.to_ascii_lowercase(), Value::String(value.clone())))
+        .collect();
+
+    let mut jcal = vec![
+        Value::String(property.name.to_ascii_lowercase()),
+        Value::Object(params),
+        Value::String(property.value_type.as_str().to_ascii_lowercase()),
+    ];
+
+    jcal.extend(property.values.iter().map(|value| value_to_jcal(property.value_type, value)));
+
+    Value::Array(jcal)
+}
+
+fn value_to_jcal(value_type: ValueType, value: &str) -> Value
+{
+    match value_type
+    {
+        ValueType::Date | ValueType::DateTime => Value::String(date_to_jcal(value)),
+        ValueType::Period => Value::String(value.split('/').map(date_to_jcal).collect::<Vec<String>>().join("/")),
+        ValueType::Recur => recur_to_jcal(value),
+        ValueType::UtcOffset => Value::String(offset_to_jcal(value)),
+
+        ValueType::Integer => match value.parse::<i64>()
+        {
+            Ok(n) => Value::from(n),
+            Err(_) => Value::String(value.to_owned()),
+        },
+
+        _ => Value::String(value.to_owned()),
+    }
+}
+
+/// `20200901` to `2020-09-01` and `20200901T100000Z` to `2020-09-01T10:00:00Z`.
+/// Anything else (e.g. the durations of periods) is left as is.
+fn date_to_jcal(value: &str) -> String
+{
+    let (date, time) = match value.split_once('T')
+    {
+        Some((date, time)) => (date, Some(time)),
+        None => (value, None),
+    };
+
+    if date.len() != 8 || !date.chars().all(|c| c.is_ascii_digit())
+    {
+        return value.to_owned();
+    }
+
+    let mut jcal = format!("{}-{}-{}", &date[..4], &date[4..6], &date[6..]);
+
+    if let Some(time) = time
+    {
+        match (time.get(..2), time.get(2..4), time.get(4..))
+        {
+            (Some(hours), Some(minutes), Some(rest)) => jcal.push_str(&format!("T{}:{}:{}", hours, minutes, rest)),
+            _ => return value.to_owned(),
+        }
+    }
+
+    jcal
+}
+
+/// `-0500` to `-05:00`, seconds are kept if there are any.
+fn offset_to_jcal(value: &str) -> String
+{
+    match (value.get(..3), value.get(3..5), value.get(5..))
+    {
+        (Some(hours), Some(minutes), Some("")) => format!("{}:{}", hours, minutes),
+        (Some(hours), Some(minutes), Some(seconds)) => format!("{}:{}:{}", hours, minutes, seconds),
+        _ => value.to_owned(),
+    }
+}
+
+/// `FREQ=WEEKLY;BYDAY=MO,TU` to `{"freq": "WEEKLY", "byday": ["MO", "TU"]}`.
+fn recur_to_jcal(value: &str) -> Value
+{
+    let mut rule = Map::new();
+
+    for (name, values) in value.split(';').filter_map(|part| part.split_once('='))
+    {
+        let name = name.to_ascii_uppercase();
+
+        let values: Vec<Value> = values
+            .split(',')
+            .map(|value| match name.as_str()
+            {
+                "UNTIL" => Value::String(date_to_jcal(value)),
+                _ if INTEGER_RULE_PARTS.contains(&name.as_str()) => value.parse::<i64>().map_or_else(|_| Value::String(value.to_owned()), Value::from),
+                _ => Value::String(value.to_owned()),
+            })
+            .collect();
+
+        let value = match values.len()
+        {
+            1 => values.into_iter().next().unwrap_or(Value::Null),
+            _ => Value::Array(values),
+        };
+
+        rule.insert(name.to_ascii_lowercase(), value);
+    }
+
+    Value::Object(rule)
+}
+
+/// The component in the jCal value.
+pub fn from_jcal(jcal: &Value) -> Result<Component, JCalError>
+{
+    let (name, properties, components) = match jcal.as_array().map(|array| array.as_slice())
+    {
+        Some([Value::String(name), Value::Array(properties), Value::Array(components)]) => (name, properties, components),
+        _ => return Err(JCalError::InvalidComponent),
+    };
+
+    let mut component = Component::new(&name.to_ascii_uppercase());
+
+    for property in properties.iter()
+    {
+        let property = property_from_jcal(property).ok_or_else(|| JCalError::InvalidProperty(component.name.clone()))??;
+        component.push(property);
+    }
+
+    component.components = components
+        .iter()
+        .map(from_jcal)
+        .collect::<Result<Vec<Component>, JCalError>>()?;
+
+    Ok(component)
+}
+
+/// Returns None if the property isn't an array with a name, parameters and
+/// a type, and an error if its values are invalid.
+fn property_from_jcal(jcal: &Value) -> Option<Result<Property, JCalError>>
+{
+    let (name, params, value_type, values) = match jcal.as_array()?.as_slice()
+    {
+        [Value::String(name), Value::Object(params), Value::String(value_type), values @ ..] => (name.to_ascii_uppercase(), params, value_type, values),
+        _ => return None,
+    };
+
+    // Types this model doesn't know about (e.g. `unknown` or `boolean`) are treated as text.
+    let value_type = ValueType::from_str(value_type).unwrap_or(ValueType::Text);
+
+    let mut property = Property::with_type(&name, value_type, vec![]);
+
+    for (param_name, param_value) in params.iter()
+    {
+        // Parameters with several values are arrays.
+        let param_value = match param_value
+        {
+            Value::Array(values) => values.iter().map(scalar_from_jcal).collect::<Option<Vec<String>>>()?.join(","),
+            value => scalar_from_jcal(value)?,
+        };
+
+        property.params.push((param_name.to_ascii_uppercase(), param_value));
+    }
+
+    for value in values.iter()
+    {
+        match value_from_jcal(value_type, value)
+        {
+            Some(value) => property.values.push(value),
+            None => return Some(Err(JCalError::InvalidValue(name))),
+        }
+    }
+
+    Some(Ok(property))
+}
+
+fn value_from_jcal(value_type: ValueType, value: &Value) -> Option<String>
+{
+    match (value_type, value)
+    {
+        (ValueType::Date, Value::String(date)) | (ValueType::DateTime, Value::String(date)) => Some(date_from_jcal(date)),
+        (ValueType::Period, Value::String(period)) => Some(period.split('/').map(date_from_jcal).collect::<Vec<String>>().join("/")),
+        (ValueType::UtcOffset, Value::String(offset)) => Some(offset.replace(':', "")),
+        (ValueType::Recur, Value::Object(rule)) => recur_from_jcal(rule),
+
+        // Structured values (e.g. GEO's) are arrays of their parts.
+        (_, Value::Array(parts)) => Some(parts.iter().map(scalar_from_jcal).collect::<Option<Vec<String>>>()?.join(";")),
+
+        (_, value) => scalar_from_jcal(value),
+    }
+}
+
+/// The reverse of `date_to_jcal`. Durations start with `P` (or a sign) and are left as is.
+fn date_from_jcal(value: &str) -> String
+{
+    if value.starts_with(|c| c == 'P' || c == '+' || c == '-')
+    {
+        value.to_owned()
+    }
+    else
+    {
+        value.chars().filter(|c| *c != '-' && *c != ':').collect()
+    }
+}
+
+/// The reverse of `recur_to_jcal`. FREQ goes first, as RFC 5545 recommends.
+fn recur_from_jcal(rule: &Map<String, Value>) -> Option<String>
+{
+    let mut parts = vec![];
+
+    for (name, value) in rule.iter()
+    {
+        let name = name.to_ascii_uppercase();
+
+        let values = match value
+        {
+            Value::Array(values) => values.iter().map(scalar_from_jcal).collect::<Option<Vec<String>>>()?,
+            value => vec![scalar_from_jcal(value)?],
+        };
+
+        let values: Vec<String> = match name.as_str()
+        {
+            "UNTIL" => values.iter().map(|value| date_from_jcal(value)).collect(),
+            _ => values,
+        };
+
+        parts.push((name, values.join(",")));
+    }
+
+    parts.sort_by_key(|(name, _)| name != "FREQ");
+
+    Some(
+        parts
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<String>>()
+            .join(";")
+    )
+}
+
+fn scalar_from_jcal(value: &Value) -> Option<String>
+{
+    match value
+    {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(if *b { "TRUE" } else { "FALSE" }.to_owned()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{to_jcal, from_jcal, JCalError};
+    use crate::ical::parser;
+    use serde_json::json;
+
+    /// RFC 7265's example (appendix B.1), with an RRULE and a TZID.
+    #[test]
+    fn converts_to_and_from_jcal()
+    {
+        let ics = "BEGIN:VCALENDAR\r\n\
+            CALSCALE:GREGORIAN\r\n\
+            PRODID:-//Example Inc.//Example Calendar//EN\r\n\
+            VERSION:2.0\r\n\
+            BEGIN:VEVENT\r\n\
+            DTSTAMP:20080205T191224Z\r\n\
+            DTSTART;VALUE=DATE:20081006\r\n\
+            SUMMARY:Planning meeting\r\n\
+            UID:4088E990AD89CB3DBB484909\r\n\
+            RRULE:FREQ=WEEKLY;BYDAY=MO,TU;INTERVAL=2;UNTIL=20081231\r\n\
+            EXDATE;TZID=Europe/Paris:20081013T100000,20081020T100000\r\n\
+            END:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+
+        let jcal = json!(
+            ["vcalendar",
+                [
+                    ["calscale", {}, "text", "GREGORIAN"],
+                    ["prodid", {}, "text", "-//Example Inc.//Example Calendar//EN"],
+                    ["version", {}, "text", "2.0"]
+                ],
+                [
+                    ["vevent",
+                        [
+                            ["dtstamp", {}, "date-time", "2008-02-05T19:12:24Z"],
+                            ["dtstart", {}, "date", "2008-10-06"],
+                            ["summary", {}, "text", "Planning meeting"],
+                            ["uid", {}, "text", "4088E990AD89CB3DBB484909"],
+                            ["rrule", {}, "recur", { "freq": "WEEKLY", "byday": ["MO", "TU"], "interval": 2, "until": "2008-12-31" }],
+                            ["exdate", { "tzid": "Europe/Paris" }, "date-time", "2008-10-13T10:00:00", "2008-10-20T10:00:00"]
+                        ],
+                        []
+                    ]
+                ]
+            ]
+        );
+
+        let components = parser::parse(ics).unwrap();
+
+        assert_eq!(to_jcal(&components[0]), jcal);
+        assert_eq!(from_jcal(&jcal).unwrap(), components[0]);
+    }
+
+    #[test]
+    fn rejects_invalid_jcal()
+    {
+        assert_eq!(from_jcal(&json!({ "vcalendar": [] })), Err(JCalError::InvalidComponent));
+        assert_eq!(from_jcal(&json!(["vcalendar", [["version", "2.0"]], []])), Err(JCalError::InvalidProperty("VCALENDAR".to_owned())));
+        assert_eq!(from_jcal(&json!(["vevent", [["dtstart", {}, "date", { "year": 2020 }]], []])), Err(JCalError::InvalidValue("DTSTART".to_owned())));
+
+        let unknown = from_jcal(&json!(["vevent", [["x-flag", {}, "boolean", true]], []])).unwrap();
+        assert_eq!(unknown.properties[0].values, vec!["TRUE".to_owned()]);
+    }
+}
@@ -0,0 +1,355 @@
+//! A minimal model of iCalendar (RFC 5545) objects, used to export calendars
+//! and events (see `export`) and to import them (see `parser` and `import`).
+//! `jcal` and `xcal` convert them to and from their JSON and XML forms.
+//!
+//! Objects are trees of `Component`s (VCALENDAR, VEVENT...) with `Property`s.
+//! Property values are kept unescaped and in their iCalendar form (e.g. dates
+//! as `20200901`), `to_ics` escapes and folds them.
+
+pub mod export;
+pub mod parser;
+pub mod timezone;
+pub mod import;
+pub mod jcal;
+pub mod xcal;
+
+use std::fmt::Write;
+
+/// Maximum length of a line, in octets, not counting the line break.
+const MAX_LINE_LENGTH: usize = 75;
+
+/// The value types this model knows about, see RFC 5545 section 3.3.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ValueType
+{
+    Text,
+    Date,
+    DateTime,
+    Recur,
+    Integer,
+    Uri,
+    CalAddress,
+    Duration,
+    Period,
+    UtcOffset,
+}
+
+impl ValueType
+{
+    /// The type's name, as used in the VALUE parameter.
+    pub fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            ValueType::Text => "TEXT",
+            ValueType::Date => "DATE",
+            ValueType::DateTime => "DATE-TIME",
+            ValueType::Recur => "RECUR",
+            ValueType::Integer => "INTEGER",
+            ValueType::Uri => "URI",
+            ValueType::CalAddress => "CAL-ADDRESS",
+            ValueType::Duration => "DURATION",
+            ValueType::Period => "PERIOD",
+            ValueType::UtcOffset => "UTC-OFFSET",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<ValueType>
+    {
+        match s.to_ascii_uppercase().as_str()
+        {
+            "TEXT" => Some(ValueType::Text),
+            "DATE" => Some(ValueType::Date),
+            "DATE-TIME" => Some(ValueType::DateTime),
+            "RECUR" => Some(ValueType::Recur),
+            "INTEGER" => Some(ValueType::Integer),
+            "URI" => Some(ValueType::Uri),
+            "CAL-ADDRESS" => Some(ValueType::CalAddress),
+            "DURATION" => Some(ValueType::Duration),
+            "PERIOD" => Some(ValueType::Period),
+            "UTC-OFFSET" => Some(ValueType::UtcOffset),
+            _ => None,
+        }
+    }
+
+    /// The type a property's value has when there's no VALUE parameter.
+    pub fn default_for(property_name: &str) -> ValueType
+    {
+        match property_name
+        {
+            "DTSTART" | "DTEND" | "DTSTAMP" | "LAST-MODIFIED" | "CREATED" | "RECURRENCE-ID" | "EXDATE" | "RDATE" => ValueType::DateTime,
+            "RRULE" => ValueType::Recur,
+            "DURATION" => ValueType::Duration,
+            "SEQUENCE" => ValueType::Integer,
+            "URL" => ValueType::Uri,
+            "ORGANIZER" | "ATTENDEE" => ValueType::CalAddress,
+            "TZOFFSETFROM" | "TZOFFSETTO" => ValueType::UtcOffset,
+            _ => ValueType::Text,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Property
+{
+    /// Upper case name, e.g. `DTSTART`.
+    pub name: String,
+
+    /// Parameters other than VALUE, which is given by `value_type`.
+    pub params: Vec<(String, String)>,
+
+    pub value_type: ValueType,
+
+    /// Usually one, properties like EXDATE can have several.
+    pub values: Vec<String>,
+}
+
+impl Property
+{
+    /// A property with a value of the property's default type.
+    pub fn new(name: &str, value: impl Into<String>) -> Property
+    {
+        Property::with_type(name, ValueType::default_for(name), vec![value.into()])
+    }
+
+    pub fn with_type(name: &str, value_type: ValueType, values: Vec<String>) -> Property
+    {
+        Property {
+            name: name.to_owned(),
+            params: vec![],
+            value_type,
+            values,
+        }
+    }
+
+    pub fn param(mut self, name: &str, value: impl Into<String>) -> Property
+    {
+        self.params.push((name.to_owned(), value.into()));
+        self
+    }
+
+    pub fn get_param(&self, name: &str) -> Option<&str>
+    {
+        self.params.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// The first value.
+    pub fn value(&self) -> &str
+    {
+        self.values.get(0).map_or("", |v| v.as_str())
+    }
+
+    /// The property as a content line, not folded.
+    fn to_line(&self) -> String
+    {
+        let mut line = self.name.clone();
+
+        if self.value_type != ValueType::default_for(&self.name)
+        {
+            line.push_str(";VALUE=");
+            line.push_str(self.value_type.as_str());
+        }
+
+        for (name, value) in self.params.iter()
+        {
+            let _ = write!(line, ";{}={}", name, quote_param(value));
+        }
+
+        line.push(':');
+
+        let values: Vec<String> = self.values
+            .iter()
+            .map(|value| match self.value_type
+            {
+                ValueType::Text => escape_text(value),
+                _ => value.clone(),
+            })
+            .collect();
+
+        line.push_str(&values.join(","));
+
+        line
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Component
+{
+    /// Upper case name, e.g. `VEVENT`.
+    pub name: String,
+    pub properties: Vec<Property>,
+    pub components: Vec<Component>,
+}
+
+impl Component
+{
+    pub fn new(name: &str) -> Component
+    {
+        Component {
+            name: name.to_owned(),
+            properties: vec![],
+            components: vec![],
+        }
+    }
+
+    pub fn push(&mut self, property: Property)
+    {
+        self.properties.push(property);
+    }
+
+    /// The first property with the name.
+    pub fn property(&self, name: &str) -> Option<&Property>
+    {
+        self.properties.iter().find(|p| p.name == name)
+    }
+
+    /// Serializes the component as an iCalendar stream, with
+    /// CRLF line breaks and long lines folded.
+    pub fn to_ics(&self) -> String
+    {
+        let mut ics = String::new();
+        self.write_ics(&mut ics);
+        ics
+    }
+
+    fn write_ics(&self, ics: &mut String)
+    {
+        write_folded(ics, &format!("BEGIN:{}", self.name));
+
+        for property in self.properties.iter()
+        {
+            write_folded(ics, &property.to_line());
+        }
+
+        for component in self.components.iter()
+        {
+            component.write_ics(ics);
+        }
+
+        write_folded(ics, &format!("END:{}", self.name));
+    }
+}
+
+/// Escapes a TEXT value (RFC 5545 section 3.3.11).
+pub fn escape_text(text: &str) -> String
+{
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars()
+    {
+        match c
+        {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {},
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Unescapes a TEXT value, the reverse of `escape_text`.
+pub fn unescape_text(text: &str) -> String
+{
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next()
+    {
+        match c
+        {
+            '\\' => match chars.next()
+            {
+                Some('n') | Some('N') => unescaped.push('\n'),
+                Some(c) => unescaped.push(c),
+                None => unescaped.push('\\'),
+            },
+            c => unescaped.push(c),
+        }
+    }
+
+    unescaped
+}
+
+/// Quotes parameter values that have characters that aren't allowed unquoted.
+/// Double quotes aren't allowed at all, so they're dropped.
+fn quote_param(value: &str) -> String
+{
+    let value: String = value.chars().filter(|c| *c != '"').collect();
+
+    if value.contains(|c| c == ':' || c == ';' || c == ',')
+    {
+        format!("\"{}\"", value)
+    }
+    else
+    {
+        value
+    }
+}
+
+/// Appends the line, folded into lines of at most `MAX_LINE_LENGTH` octets
+/// (RFC 5545 section 3.1). Continuation lines start with a space. Lines are
+/// only broken between characters, never in the middle of a UTF-8 sequence.
+fn write_folded(ics: &mut String, line: &str)
+{
+    let mut length = 0;
+
+    for c in line.chars()
+    {
+        if length + c.len_utf8() > MAX_LINE_LENGTH
+        {
+            ics.push_str("\r\n ");
+            length = 1;
+        }
+
+        ics.push(c);
+        length += c.len_utf8();
+    }
+
+    ics.push_str("\r\n");
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{Component, Property, ValueType, escape_text, unescape_text};
+
+    #[test]
+    fn escapes_text()
+    {
+        assert_eq!(escape_text("a, b; c\\d\r\ne"), "a\\, b\\; c\\\\d\\ne");
+        assert_eq!(unescape_text("a\\, b\\; c\\\\d\\ne\\N"), "a, b; c\\d\ne\n");
+    }
+
+    #[test]
+    fn folds_long_lines()
+    {
+        let mut event = Component::new("VEVENT");
+        event.push(Property::new("DESCRIPTION", "é".repeat(50)));
+
+        let ics = event.to_ics();
+        let lines: Vec<&str> = ics.split("\r\n").collect();
+
+        assert!(lines.iter().all(|line| line.len() <= 75));
+        assert_eq!(lines[1].len(), 12 + 62);
+        assert!(lines[2].starts_with(' '));
+        assert_eq!(lines[1].to_owned() + &lines[2][1..], format!("DESCRIPTION:{}", "é".repeat(50)));
+        assert_eq!(lines[3], "END:VEVENT");
+    }
+
+    #[test]
+    fn adds_value_and_params()
+    {
+        let mut event = Component::new("VEVENT");
+        event.push(Property::with_type("EXDATE", ValueType::Date, vec!["20200908".to_owned(), "20200915".to_owned()]));
+        event.push(Property::new("X-NOTE", "a").param("X-PARAM", "b:c"));
+
+        assert_eq!(
+            event.to_ics(),
+            "BEGIN:VEVENT\r\nEXDATE;VALUE=DATE:20200908,20200915\r\nX-NOTE;X-PARAM=\"b:c\":a\r\nEND:VEVENT\r\n"
+        );
+    }
+}
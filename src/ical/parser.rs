@@ -0,0 +1,217 @@
+//! Parses iCalendar streams (RFC 5545) into `Component`s.
+//!
+//! The parser is lenient about things clients commonly get wrong: lines can
+//! end with LF instead of CRLF and names are case insensitive. It doesn't know
+//! which properties and parameters each component can have, that's up to
+//! whoever uses the components.
+
+use super::{Component, Property, ValueType, unescape_text};
+
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum ParseError
+{
+    #[error("Line {0} is not a valid content line.")]
+    InvalidLine(usize),
+
+    #[error("Line {0} ends a component that wasn't begun.")]
+    UnexpectedEnd(usize),
+
+    #[error("Component {0} is never ended.")]
+    MissingEnd(String),
+
+    #[error("There are no components.")]
+    Empty,
+}
+
+/// Parses an iCalendar stream into its top level components, usually
+/// one VCALENDAR.
+pub fn parse(ics: &str) -> Result<Vec<Component>, ParseError>
+{
+    let mut components = vec![];
+
+    // The components that were begun but not ended yet.
+    let mut stack: Vec<Component> = vec![];
+
+    for (number, line) in unfold(ics)
+    {
+        let property = parse_line(&line).ok_or(ParseError::InvalidLine(number))?;
+
+        match property.name.as_str()
+        {
+            "BEGIN" => stack.push(Component::new(&property.value().to_ascii_uppercase())),
+
+            "END" =>
+            {
+                let component = match stack.pop()
+                {
+                    Some(component) if component.name.eq_ignore_ascii_case(property.value()) => component,
+                    _ => return Err(ParseError::UnexpectedEnd(number)),
+                };
+
+                match stack.last_mut()
+                {
+                    Some(parent) => parent.components.push(component),
+                    None => components.push(component),
+                }
+            },
+
+            _ => match stack.last_mut()
+            {
+                Some(component) => component.push(property),
+                None => return Err(ParseError::InvalidLine(number)),
+            },
+        }
+    }
+
+    if let Some(component) = stack.pop()
+    {
+        return Err(ParseError::MissingEnd(component.name));
+    }
+
+    if components.is_empty()
+    {
+        return Err(ParseError::Empty);
+    }
+
+    Ok(components)
+}
+
+/// Joins folded lines (RFC 5545 section 3.1). Returns the non-empty lines
+/// with the number of the line they start at.
+fn unfold(ics: &str) -> Vec<(usize, String)>
+{
+    let mut lines: Vec<(usize, String)> = vec![];
+
+    for (i, line) in ics.split('\n').enumerate()
+    {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+
+        match lines.last_mut()
+        {
+            Some((_, previous)) if line.starts_with(' ') || line.starts_with('\t') => previous.push_str(&line[1..]),
+            _ if line.is_empty() => {},
+            _ => lines.push((i + 1, line.to_owned())),
+        }
+    }
+
+    lines
+}
+
+/// Parses a content line, `NAME;PARAM=VALUE:VALUE`. The property's type is
+/// given by the VALUE parameter, types this model doesn't know about are
+/// treated as text and keep the parameter.
+fn parse_line(line: &str) -> Option<Property>
+{
+    // The value starts after the first colon that isn't in a quoted parameter value.
+    let colon = unquoted_indices(line, ':').next()?;
+    let (head, value) = (&line[..colon], &line[colon + 1..]);
+
+    let mut separators = unquoted_indices(head, ';').collect::<Vec<usize>>();
+    separators.push(head.len());
+
+    let name = head[..separators[0]].to_ascii_uppercase();
+
+    if name.is_empty()
+    {
+        return None;
+    }
+
+    let mut params = vec![];
+    let mut value_type = ValueType::default_for(&name);
+
+    for bounds in separators.windows(2)
+    {
+        let (param_name, param_value) = head[bounds[0] + 1..bounds[1]].split_once('=')?;
+        let param_name = param_name.to_ascii_uppercase();
+        let param_value: String = param_value.chars().filter(|c| *c != '"').collect();
+
+        match ValueType::from_str(&param_value)
+        {
+            Some(param_type) if param_name == "VALUE" => value_type = param_type,
+            _ => params.push((param_name, param_value)),
+        }
+    }
+
+    let values = match value_type
+    {
+        ValueType::Text => vec![unescape_text(value)],
+        ValueType::Recur => vec![value.to_owned()],
+        _ => value.split(',').map(|v| v.to_owned()).collect(),
+    };
+
+    Some(Property {
+        name,
+        params,
+        value_type,
+        values,
+    })
+}
+
+/// The indices of `delimiter` in the string, ignoring those between double quotes.
+fn unquoted_indices(s: &str, delimiter: char) -> impl Iterator<Item = usize> + '_
+{
+    let mut quoted = false;
+
+    s.char_indices().filter_map(move |(i, c)|
+    {
+        if c == '"'
+        {
+            quoted = !quoted;
+        }
+
+        if !quoted && c == delimiter { Some(i) } else { None }
+    })
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{parse, ParseError};
+    use crate::ical::ValueType;
+
+    #[test]
+    fn parses_components()
+    {
+        let ics = "BEGIN:VCALENDAR\r\n\
+            VERSION:2.0\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:1\r\n\
+            DESCRIPTION;ALTREP=\"cid:a;b\":Line\\, one\\n\r\n \
+            and two\r\n\
+            dtstart;tzid=Europe/Paris:20200901T100000\r\n\
+            EXDATE;VALUE=DATE:20200908,20200915\n\
+            RDATE;VALUE=PERIOD:20200902T100000Z/PT1H\r\n\
+            END:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+
+        let components = parse(ics).unwrap();
+        assert_eq!(components.len(), 1);
+
+        let event = &components[0].components[0];
+        assert_eq!(event.name, "VEVENT");
+
+        let description = event.property("DESCRIPTION").unwrap();
+        assert_eq!(description.value(), "Line, one\nand two");
+        assert_eq!(description.get_param("ALTREP"), Some("cid:a;b"));
+
+        let start = event.property("DTSTART").unwrap();
+        assert_eq!(start.value_type, ValueType::DateTime);
+        assert_eq!(start.get_param("TZID"), Some("Europe/Paris"));
+        assert_eq!(start.value(), "20200901T100000");
+
+        let exdate = event.property("EXDATE").unwrap();
+        assert_eq!(exdate.value_type, ValueType::Date);
+        assert_eq!(exdate.values, vec!["20200908", "20200915"]);
+
+        assert_eq!(event.property("RDATE").unwrap().value_type, ValueType::Period);
+    }
+
+    #[test]
+    fn rejects_malformed_streams()
+    {
+        assert_eq!(parse("BEGIN:VCALENDAR\r\nEND:VEVENT\r\n"), Err(ParseError::UnexpectedEnd(2)));
+        assert_eq!(parse("BEGIN:VCALENDAR\r\nVERSION\r\n"), Err(ParseError::InvalidLine(2)));
+        assert_eq!(parse("BEGIN:VCALENDAR\r\n"), Err(ParseError::MissingEnd("VCALENDAR".to_owned())));
+        assert_eq!(parse("\r\n"), Err(ParseError::Empty));
+    }
+}
@@ -0,0 +1,389 @@
+//! Converts the local date-times of iCalendar files to UTC.
+//!
+//! TZIDs that are IANA names (e.g. `Europe/Paris`, which is what most clients
+//! use) are resolved with the IANA database. Other TZIDs (e.g. Outlook's
+//! `W. Europe Standard Time`) are resolved with the VTIMEZONEs in the file.
+//! Date-times without a TZID or `Z` (floating times) are in the calendar's
+//! time zone.
+
+use super::Component;
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Weekday};
+use chrono_tz::Tz;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// A STANDARD or DAYLIGHT sub-component of a VTIMEZONE: the offset from UTC
+/// becomes `offset_to` at `start` and at each of the rule's and `rdates`' onsets.
+struct Observance
+{
+    /// Onsets are in local time, in the offset that was in effect before them.
+    start: NaiveDateTime,
+    offset_from: Duration,
+    offset_to: Duration,
+    rule: Option<YearlyRule>,
+    rdates: Vec<NaiveDateTime>,
+}
+
+/// The kind of RRULE VTIMEZONEs use: once a year, on a day of a month (e.g. the
+/// last sunday of March, `FREQ=YEARLY;BYMONTH=3;BYDAY=-1SU`).
+struct YearlyRule
+{
+    month: u32,
+
+    /// The ordinal (0 if there's none) and the day of the week.
+    weekday: Option<(i32, Weekday)>,
+    month_days: Vec<u32>,
+
+    /// In UTC.
+    until: Option<NaiveDateTime>,
+    count: Option<i32>,
+}
+
+pub struct Timezones
+{
+    /// The observances of each VTIMEZONE in the file, by TZID.
+    definitions: HashMap<String, Vec<Observance>>,
+
+    /// The calendar's time zone, used for floating times.
+    default: Tz,
+}
+
+#[derive(Error, Debug)]
+#[error("Unknown time zone {0}.")]
+pub struct UnknownTimezone(pub String);
+
+impl Timezones
+{
+    /// The time zones defined in the VCALENDAR.
+    pub fn new(calendar: &Component, default: Tz) -> Timezones
+    {
+        let definitions = calendar.components
+            .iter()
+            .filter(|component| component.name == "VTIMEZONE")
+            .filter_map(|timezone|
+            {
+                let observances = timezone.components
+                    .iter()
+                    .filter(|component| component.name == "STANDARD" || component.name == "DAYLIGHT")
+                    .filter_map(Observance::from_component)
+                    .collect::<Vec<Observance>>();
+
+                match timezone.property("TZID")
+                {
+                    Some(tzid) if !observances.is_empty() => Some((tzid.value().to_owned(), observances)),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        Timezones {
+            definitions,
+            default,
+        }
+    }
+
+    /// Converts a local date-time to UTC. `tzid` is the TZID parameter of the
+    /// property the date-time is from, None for floating times.
+    pub fn to_utc(&self, local: NaiveDateTime, tzid: Option<&str>) -> Result<NaiveDateTime, UnknownTimezone>
+    {
+        let tzid = match tzid
+        {
+            Some(tzid) => tzid,
+            None => return Ok(iana_to_utc(self.default, local)),
+        };
+
+        if let Ok(tz) = Tz::from_str(tzid)
+        {
+            return Ok(iana_to_utc(tz, local));
+        }
+
+        let observances = self.definitions
+            .get(tzid)
+            .ok_or_else(|| UnknownTimezone(tzid.to_owned()))?;
+
+        // The observance with the latest onset before the local time is the one
+        // in effect. If there's none, it's before any of them and the offset is
+        // the one in effect before the earliest.
+        let offset = observances
+            .iter()
+            .filter_map(|observance| observance.latest_onset(local).map(|onset| (onset, observance.offset_to)))
+            .max_by_key(|(onset, _)| *onset)
+            .map(|(_, offset)| offset)
+            .or_else(|| observances.iter().min_by_key(|observance| observance.start).map(|observance| observance.offset_from))
+            .unwrap_or_else(Duration::zero);
+
+        Ok(local - offset)
+    }
+}
+
+/// Converts a local time in an IANA time zone to UTC. Times that are skipped
+/// when clocks go forward are taken as if the clocks hadn't gone forward yet.
+fn iana_to_utc(tz: Tz, local: NaiveDateTime) -> NaiveDateTime
+{
+    tz.from_local_datetime(&local)
+        .earliest()
+        .or_else(|| tz.from_local_datetime(&(local + Duration::hours(1))).earliest().map(|dt| dt - Duration::hours(1)))
+        .map(|dt| dt.naive_utc())
+        .unwrap_or(local)
+}
+
+impl Observance
+{
+    fn from_component(component: &Component) -> Option<Observance>
+    {
+        let start = parse_date_time(component.property("DTSTART")?.value())?;
+
+        Some(Observance {
+            start,
+            offset_from: parse_offset(component.property("TZOFFSETFROM")?.value())?,
+            offset_to: parse_offset(component.property("TZOFFSETTO")?.value())?,
+            rule: match component.property("RRULE")
+            {
+                Some(rrule) => Some(YearlyRule::parse(rrule.value(), start)?),
+                None => None,
+            },
+            rdates: component.properties
+                .iter()
+                .filter(|property| property.name == "RDATE")
+                .flat_map(|property| property.values.iter())
+                .filter_map(|value| parse_date_time(value))
+                .collect(),
+        })
+    }
+
+    /// The observance's latest onset that isn't after the local time.
+    fn latest_onset(&self, local: NaiveDateTime) -> Option<NaiveDateTime>
+    {
+        if self.start > local
+        {
+            return None;
+        }
+
+        let rule_onset = self.rule.as_ref().and_then(|rule|
+        {
+            vec![local.year(), local.year() - 1]
+                .into_iter()
+                .filter_map(|year| rule.onset(year, self.start))
+                .find(|onset| *onset >= self.start && *onset <= local && rule.until.map_or(true, |until| *onset - self.offset_from <= until))
+        });
+
+        self.rdates
+            .iter()
+            .copied()
+            .filter(|rdate| *rdate <= local)
+            .chain(rule_onset)
+            .chain(Some(self.start))
+            .max()
+    }
+}
+
+impl YearlyRule
+{
+    fn parse(rrule: &str, start: NaiveDateTime) -> Option<YearlyRule>
+    {
+        let parts: HashMap<&str, &str> = rrule
+            .split(';')
+            .filter_map(|part| part.split_once('='))
+            .collect();
+
+        if parts.get("FREQ") != Some(&"YEARLY") || parts.get("INTERVAL").map_or(false, |interval| *interval != "1")
+        {
+            return None;
+        }
+
+        let weekday = match parts.get("BYDAY")
+        {
+            Some(day) => Some(parse_weekday(day)?),
+            None => None,
+        };
+
+        let month_days = match parts.get("BYMONTHDAY")
+        {
+            Some(days) => days.split(',').map(|day| day.parse().ok()).collect::<Option<Vec<u32>>>()?,
+            None if weekday.is_none() => vec![start.day()],
+            None => vec![],
+        };
+
+        Some(YearlyRule {
+            month: match parts.get("BYMONTH")
+            {
+                Some(month) => month.parse().ok()?,
+                None => start.month(),
+            },
+            weekday,
+            month_days,
+            until: match parts.get("UNTIL")
+            {
+                Some(until) => Some(parse_date_time(until)?),
+                None => None,
+            },
+            count: match parts.get("COUNT")
+            {
+                Some(count) => Some(count.parse().ok()?),
+                None => None,
+            },
+        })
+    }
+
+    /// The onset in the year, at the time the observance starts at.
+    fn onset(&self, year: i32, start: NaiveDateTime) -> Option<NaiveDateTime>
+    {
+        if self.count.map_or(false, |count| year - start.year() >= count)
+        {
+            return None;
+        }
+
+        let date = match self.weekday
+        {
+            Some((0, weekday)) => self.month_days
+                .iter()
+                .filter_map(|day| NaiveDate::from_ymd_opt(year, self.month, *day))
+                .find(|date| date.weekday() == weekday),
+
+            Some((n, weekday)) => nth_weekday(year, self.month, weekday, n),
+
+            None => NaiveDate::from_ymd_opt(year, self.month, *self.month_days.get(0)?),
+        };
+
+        date.map(|date| date.and_time(start.time()))
+    }
+}
+
+/// The nth (or the -nth from the end) weekday of the month.
+fn nth_weekday(year: i32, month: u32, weekday: Weekday, n: i32) -> Option<NaiveDate>
+{
+    let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+
+    if n > 0
+    {
+        let days = (7 + weekday.num_days_from_monday() as i64 - first.weekday().num_days_from_monday() as i64) % 7;
+        let date = first + Duration::days(days + 7 * (n as i64 - 1));
+
+        Some(date).filter(|date| date.month() == month)
+    }
+    else
+    {
+        let last = NaiveDate::from_ymd_opt(year + month as i32 / 12, month % 12 + 1, 1)? - Duration::days(1);
+        let days = (7 + last.weekday().num_days_from_monday() as i64 - weekday.num_days_from_monday() as i64) % 7;
+        let date = last - Duration::days(days + 7 * (-n as i64 - 1));
+
+        Some(date).filter(|date| date.month() == month)
+    }
+}
+
+/// Parses a BYDAY value like `-1SU`, `2MO` or `SU`.
+fn parse_weekday(value: &str) -> Option<(i32, Weekday)>
+{
+    let split = value.len().checked_sub(2)?;
+
+    let weekday = match value.get(split..)?
+    {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    };
+
+    let n = match &value[..split]
+    {
+        "" => 0,
+        n => n.parse().ok()?,
+    };
+
+    Some((n, weekday))
+}
+
+/// Parses a DATE-TIME value, ignoring whether it's in UTC.
+fn parse_date_time(value: &str) -> Option<NaiveDateTime>
+{
+    NaiveDateTime::parse_from_str(value.trim_end_matches('Z'), "%Y%m%dT%H%M%S").ok()
+}
+
+/// Parses a UTC-OFFSET value, e.g. `-0500` or `+013045`.
+fn parse_offset(value: &str) -> Option<Duration>
+{
+    let sign = match value.get(..1)?
+    {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+
+    let digits = value.get(1..)?;
+
+    if !(digits.len() == 4 || digits.len() == 6) || !digits.chars().all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let hours: i64 = digits[0..2].parse().ok()?;
+    let minutes: i64 = digits[2..4].parse().ok()?;
+    let seconds: i64 = digits.get(4..6).map_or(Some(0), |s| s.parse().ok())?;
+
+    Some(Duration::seconds(sign * (hours * 3600 + minutes * 60 + seconds)))
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::Timezones;
+    use crate::ical::parser;
+    use chrono::NaiveDate;
+    use chrono_tz::Tz;
+
+    #[test]
+    fn converts_with_vtimezones()
+    {
+        let calendar = parser::parse(
+            "BEGIN:VCALENDAR\r\n\
+            BEGIN:VTIMEZONE\r\n\
+            TZID:W. Europe Standard Time\r\n\
+            BEGIN:STANDARD\r\n\
+            DTSTART:16011028T030000\r\n\
+            RRULE:FREQ=YEARLY;BYDAY=-1SU;BYMONTH=10\r\n\
+            TZOFFSETFROM:+0200\r\n\
+            TZOFFSETTO:+0100\r\n\
+            END:STANDARD\r\n\
+            BEGIN:DAYLIGHT\r\n\
+            DTSTART:16010325T020000\r\n\
+            RRULE:FREQ=YEARLY;BYDAY=-1SU;BYMONTH=3\r\n\
+            TZOFFSETFROM:+0100\r\n\
+            TZOFFSETTO:+0200\r\n\
+            END:DAYLIGHT\r\n\
+            END:VTIMEZONE\r\n\
+            END:VCALENDAR\r\n"
+        ).unwrap().remove(0);
+
+        let timezones = Timezones::new(&calendar, Tz::UTC);
+        let tzid = Some("W. Europe Standard Time");
+
+        // Summer time started on 2020-03-29 and ended on 2020-10-25.
+        let cases = vec![
+            (NaiveDate::from_ymd(2020, 3, 28).and_hms(10, 0, 0), NaiveDate::from_ymd(2020, 3, 28).and_hms(9, 0, 0)),
+            (NaiveDate::from_ymd(2020, 3, 29).and_hms(10, 0, 0), NaiveDate::from_ymd(2020, 3, 29).and_hms(8, 0, 0)),
+            (NaiveDate::from_ymd(2020, 10, 25).and_hms(10, 0, 0), NaiveDate::from_ymd(2020, 10, 25).and_hms(9, 0, 0)),
+            (NaiveDate::from_ymd(2021, 1, 1).and_hms(0, 30, 0), NaiveDate::from_ymd(2020, 12, 31).and_hms(23, 30, 0)),
+        ];
+
+        for (local, utc) in cases
+        {
+            assert_eq!(timezones.to_utc(local, tzid).unwrap(), utc);
+        }
+
+        assert!(timezones.to_utc(NaiveDate::from_ymd(2020, 1, 1).and_hms(0, 0, 0), Some("Nowhere")).is_err());
+    }
+
+    #[test]
+    fn converts_with_iana_names_and_the_default()
+    {
+        let timezones = Timezones::new(&crate::ical::Component::new("VCALENDAR"), Tz::America__Sao_Paulo);
+        let local = NaiveDate::from_ymd(2020, 9, 1).and_hms(10, 0, 0);
+
+        assert_eq!(timezones.to_utc(local, Some("Europe/Paris")).unwrap(), NaiveDate::from_ymd(2020, 9, 1).and_hms(8, 0, 0));
+        assert_eq!(timezones.to_utc(local, None).unwrap(), NaiveDate::from_ymd(2020, 9, 1).and_hms(13, 0, 0));
+    }
+}
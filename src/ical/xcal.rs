@@ -0,0 +1,494 @@
+//! Converts components to and from xCal (RFC 6321), iCalendar's XML form.
+//!
+//! xCal has the same structure as jCal, so components are converted through
+//! their jCal form (see `jcal`): a component is an element with `properties`
+//! and `components` elements, a property is an element with a `parameters`
+//! element and an element for each value, named after the value's type.
+
+use super::Component;
+use super::jcal::{self, JCalError};
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use quick_xml::escape::escape;
+use serde_json::{json, Map, Value};
+use std::fmt::Write;
+
+pub const NAMESPACE: &str = "urn:ietf:params:xml:ns:icalendar-2.0";
+
+/// The order recurrence rule parts must be in, see RFC 6321's schema.
+const RULE_PARTS: [&str; 14] = [
+    "freq", "until", "count", "interval", "bysecond", "byminute", "byhour",
+    "byday", "bymonthday", "byyearday", "byweekno", "bymonth", "bysetpos", "wkst",
+];
+
+#[derive(Error, Debug)]
+pub enum XCalError
+{
+    #[error(transparent)]
+    Xml(#[from] quick_xml::Error),
+
+    #[error("The document's root must be an icalendar element.")]
+    InvalidRoot,
+
+    #[error("Invalid property {0}.")]
+    InvalidProperty(String),
+
+    #[error(transparent)]
+    JCal(#[from] JCalError),
+}
+
+/// An XML element, with its text if it has no children.
+struct Element
+{
+    /// Local name (without the namespace prefix), lower case.
+    name: String,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element
+{
+    fn new(name: &[u8]) -> Element
+    {
+        Element {
+            name: String::from_utf8_lossy(name).to_ascii_lowercase(),
+            children: vec![],
+            text: String::new(),
+        }
+    }
+
+    fn child(&self, name: &str) -> Option<&Element>
+    {
+        self.children.iter().find(|child| child.name == name)
+    }
+}
+
+/// An xCal document (an `icalendar` element) with the components.
+pub fn to_xcal(components: &[Component]) -> String
+{
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+
+    let _ = write!(xml, "<icalendar xmlns=\"{}\">", NAMESPACE);
+
+    for component in components.iter()
+    {
+        write_component(&mut xml, &jcal::to_jcal(component));
+    }
+
+    xml.push_str("</icalendar>\n");
+
+    xml
+}
+
+fn write_text(xml: &mut String, name: &str, text: &str)
+{
+    let _ = write!(xml, "<{}>{}</{}>", name, String::from_utf8_lossy(&escape(text.as_bytes())), name);
+}
+
+/// Writes a component in its jCal form.
+fn write_component(xml: &mut String, jcal: &Value)
+{
+    let (name, properties, components) = match jcal.as_array().map(|array| array.as_slice())
+    {
+        Some([Value::String(name), Value::Array(properties), Value::Array(components)]) => (name, properties, components),
+        _ => return,
+    };
+
+    let _ = write!(xml, "<{}><properties>", name);
+
+    for property in properties.iter()
+    {
+        write_property(xml, property);
+    }
+
+    xml.push_str("</properties>");
+
+    if !components.is_empty()
+    {
+        xml.push_str("<components>");
+
+        for component in components.iter()
+        {
+            write_component(xml, component);
+        }
+
+        xml.push_str("</components>");
+    }
+
+    let _ = write!(xml, "</{}>", name);
+}
+
+/// Writes a property in its jCal form.
+fn write_property(xml: &mut String, jcal: &Value)
+{
+    let (name, params, value_type, values) = match jcal.as_array().map(|array| array.as_slice())
+    {
+        Some([Value::String(name), Value::Object(params), Value::String(value_type), values @ ..]) => (name, params, value_type, values),
+        _ => return,
+    };
+
+    let _ = write!(xml, "<{}>", name);
+
+    if !params.is_empty()
+    {
+        xml.push_str("<parameters>");
+
+        for (param_name, param_value) in params.iter()
+        {
+            // Parameters have the type of their values too.
+            let param_type = match param_name.as_str()
+            {
+                "altrep" | "dir" => "uri",
+                "delegated-from" | "delegated-to" | "member" | "sent-by" => "cal-address",
+                _ => "text",
+            };
+
+            let _ = write!(xml, "<{}>", param_name);
+            write_text(xml, param_type, &scalar_text(param_value));
+            let _ = write!(xml, "</{}>", param_name);
+        }
+
+        xml.push_str("</parameters>");
+    }
+
+    for value in values.iter()
+    {
+        match (value_type.as_str(), value)
+        {
+            ("recur", Value::Object(rule)) => write_recur(xml, rule),
+
+            ("period", Value::String(period)) =>
+            {
+                let (start, end) = period.split_once('/').unwrap_or((period, ""));
+
+                xml.push_str("<period>");
+                write_text(xml, "start", start);
+
+                if end.starts_with(|c| c == 'P' || c == '+' || c == '-')
+                {
+                    write_text(xml, "duration", end);
+                }
+                else
+                {
+                    write_text(xml, "end", end);
+                }
+
+                xml.push_str("</period>");
+            },
+
+            (value_type, value) => write_text(xml, value_type, &scalar_text(value)),
+        }
+    }
+
+    let _ = write!(xml, "</{}>", name);
+}
+
+fn write_recur(xml: &mut String, rule: &Map<String, Value>)
+{
+    let mut parts: Vec<(&String, &Value)> = rule.iter().collect();
+    parts.sort_by_key(|(name, _)| RULE_PARTS.iter().position(|part| *part == name.as_str()).unwrap_or(RULE_PARTS.len()));
+
+    xml.push_str("<recur>");
+
+    for (name, value) in parts
+    {
+        match value
+        {
+            // Parts with several values are repeated.
+            Value::Array(values) => values.iter().for_each(|value| write_text(xml, name, &scalar_text(value))),
+            value => write_text(xml, name, &scalar_text(value)),
+        }
+    }
+
+    xml.push_str("</recur>");
+}
+
+fn scalar_text(value: &Value) -> String
+{
+    match value
+    {
+        Value::String(s) => s.clone(),
+        Value::Array(values) => values.iter().map(scalar_text).collect::<Vec<String>>().join(","),
+        value => value.to_string(),
+    }
+}
+
+/// The components of an xCal document.
+pub fn from_xcal(xml: &str) -> Result<Vec<Component>, XCalError>
+{
+    let root = parse_tree(xml)?;
+
+    if root.name != "icalendar"
+    {
+        return Err(XCalError::InvalidRoot);
+    }
+
+    root.children
+        .iter()
+        .map(|component| Ok(jcal::from_jcal(&component_to_jcal(component)?)?))
+        .collect()
+}
+
+/// Parses the document into a tree of elements, returns its root.
+fn parse_tree(xml: &str) -> Result<Element, XCalError>
+{
+    let mut reader = Reader::from_str(xml);
+    let mut buf = vec![];
+
+    // The elements that were started but not ended yet.
+    let mut stack: Vec<Element> = vec![];
+    let mut root = None;
+
+    loop
+    {
+        let element = match reader.read_event(&mut buf)?
+        {
+            Event::Start(start) =>
+            {
+                stack.push(Element::new(start.local_name()));
+                None
+            },
+
+            Event::Empty(empty) => Some(Element::new(empty.local_name())),
+            Event::End(_) => stack.pop(),
+
+            Event::Text(text) =>
+            {
+                if let Some(element) = stack.last_mut()
+                {
+                    element.text.push_str(&text.unescape_and_decode(&reader)?);
+                }
+
+                None
+            },
+
+            Event::CData(text) =>
+            {
+                if let Some(element) = stack.last_mut()
+                {
+                    element.text.push_str(&String::from_utf8_lossy(text.escaped()));
+                }
+
+                None
+            },
+
+            Event::Eof => break,
+            _ => None,
+        };
+
+        // An element ended, add it to its parent.
+        if let Some(element) = element
+        {
+            match stack.last_mut()
+            {
+                Some(parent) => parent.children.push(element),
+                None => root = Some(element),
+            }
+        }
+
+        buf.clear();
+    }
+
+    root.ok_or(XCalError::InvalidRoot)
+}
+
+/// The jCal form of a component element.
+fn component_to_jcal(element: &Element) -> Result<Value, XCalError>
+{
+    let properties = match element.child("properties")
+    {
+        Some(properties) => properties.children.iter().map(property_to_jcal).collect::<Result<Vec<Value>, XCalError>>()?,
+        None => vec![],
+    };
+
+    let components = match element.child("components")
+    {
+        Some(components) => components.children.iter().map(component_to_jcal).collect::<Result<Vec<Value>, XCalError>>()?,
+        None => vec![],
+    };
+
+    Ok(json!([element.name, properties, components]))
+}
+
+/// The jCal form of a property element.
+fn property_to_jcal(element: &Element) -> Result<Value, XCalError>
+{
+    let mut params = Map::new();
+
+    if let Some(parameters) = element.child("parameters")
+    {
+        for param in parameters.children.iter()
+        {
+            let values: Vec<Value> = param.children.iter().map(|value| Value::String(value.text.clone())).collect();
+
+            let value = match values.len()
+            {
+                1 => values.into_iter().next().unwrap_or(Value::Null),
+                _ => Value::Array(values),
+            };
+
+            params.insert(param.name.clone(), value);
+        }
+    }
+
+    let values: Vec<&Element> = element.children.iter().filter(|child| child.name != "parameters").collect();
+
+    let value_type = match values.first()
+    {
+        Some(value) => value.name.clone(),
+        None => return Err(XCalError::InvalidProperty(element.name.clone())),
+    };
+
+    let mut jcal = vec![Value::String(element.name.clone()), Value::Object(params), Value::String(value_type)];
+
+    for value in values
+    {
+        jcal.push(value_to_jcal(value));
+    }
+
+    Ok(Value::Array(jcal))
+}
+
+fn value_to_jcal(element: &Element) -> Value
+{
+    match element.name.as_str()
+    {
+        "recur" =>
+        {
+            let mut rule = Map::new();
+
+            for part in element.children.iter()
+            {
+                let value = Value::String(part.text.clone());
+
+                // Repeated parts have several values.
+                match rule.get_mut(&part.name)
+                {
+                    Some(Value::Array(values)) => values.push(value),
+                    Some(previous) => *previous = Value::Array(vec![previous.clone(), value]),
+                    None => { rule.insert(part.name.clone(), value); },
+                }
+            }
+
+            Value::Object(rule)
+        },
+
+        "period" =>
+        {
+            let start = element.child("start").map_or("", |start| start.text.as_str());
+            let end = element.child("end").or_else(|| element.child("duration")).map_or("", |end| end.text.as_str());
+
+            Value::String(format!("{}/{}", start, end))
+        },
+
+        // Structured values (e.g. REQUEST-STATUS) are elements with an element for each part.
+        _ if !element.children.is_empty() => Value::Array(element.children.iter().map(|part| Value::String(part.text.clone())).collect()),
+
+        _ => Value::String(element.text.clone()),
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{to_xcal, from_xcal, XCalError};
+    use crate::ical::parser;
+
+    /// RFC 6321's example (appendix B.2).
+    const ICS: &str = "BEGIN:VCALENDAR\r\n\
+        VERSION:2.0\r\n\
+        PRODID:-//Example Inc.//Example Client//EN\r\n\
+        BEGIN:VTIMEZONE\r\n\
+        LAST-MODIFIED:20040110T032845Z\r\n\
+        TZID:US/Eastern\r\n\
+        BEGIN:DAYLIGHT\r\n\
+        DTSTART:20000404T020000\r\n\
+        RRULE:FREQ=YEARLY;BYDAY=1SU;BYMONTH=4\r\n\
+        TZNAME:EDT\r\n\
+        TZOFFSETFROM:-0500\r\n\
+        TZOFFSETTO:-0400\r\n\
+        END:DAYLIGHT\r\n\
+        BEGIN:STANDARD\r\n\
+        DTSTART:20001026T020000\r\n\
+        RRULE:FREQ=YEARLY;BYDAY=-1SU;BYMONTH=10\r\n\
+        TZNAME:EST\r\n\
+        TZOFFSETFROM:-0400\r\n\
+        TZOFFSETTO:-0500\r\n\
+        END:STANDARD\r\n\
+        END:VTIMEZONE\r\n\
+        BEGIN:VEVENT\r\n\
+        DTSTAMP:20060206T001121Z\r\n\
+        DTSTART;TZID=US/Eastern:20060102T120000\r\n\
+        DURATION:PT1H\r\n\
+        RRULE:FREQ=DAILY;COUNT=5\r\n\
+        RDATE;TZID=US/Eastern;VALUE=PERIOD:20060102T150000/PT2H\r\n\
+        SUMMARY:Event #2\r\n\
+        DESCRIPTION:We are having a meeting all this week at 12 pm for one hour\\, \r\n \
+         with an additional meeting on the first day 2 hours long.\\nPlease bring \r\n \
+         your own lunch for the 12 pm meetings.\r\n\
+        UID:00959BC664CA650E933C892C@example.com\r\n\
+        END:VEVENT\r\n\
+        BEGIN:VEVENT\r\n\
+        DTSTAMP:20060206T001121Z\r\n\
+        DTSTART;TZID=US/Eastern:20060104T140000\r\n\
+        DURATION:PT1H\r\n\
+        RECURRENCE-ID;TZID=US/Eastern:20060104T120000\r\n\
+        SUMMARY:Event #2 bis\r\n\
+        UID:00959BC664CA650E933C892C@example.com\r\n\
+        END:VEVENT\r\n\
+        END:VCALENDAR\r\n";
+
+    /// The last event of RFC 6321's example, in xCal.
+    const XCAL_EVENT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+        <icalendar xmlns="urn:ietf:params:xml:ns:icalendar-2.0">
+          <vcalendar>
+            <properties>
+              <prodid><text>-//Example Inc.//Example Client//EN</text></prodid>
+              <version><text>2.0</text></version>
+            </properties>
+            <components>
+              <vevent>
+                <properties>
+                  <dtstamp><date-time>2006-02-06T00:11:21Z</date-time></dtstamp>
+                  <dtstart>
+                    <parameters><tzid><text>US/Eastern</text></tzid></parameters>
+                    <date-time>2006-01-04T14:00:00</date-time>
+                  </dtstart>
+                  <duration><duration>PT1H</duration></duration>
+                  <recurrence-id>
+                    <parameters><tzid><text>US/Eastern</text></tzid></parameters>
+                    <date-time>2006-01-04T12:00:00</date-time>
+                  </recurrence-id>
+                  <summary><text>Event #2 bis</text></summary>
+                  <uid><text>00959BC664CA650E933C892C@example.com</text></uid>
+                </properties>
+              </vevent>
+            </components>
+          </vcalendar>
+        </icalendar>"#;
+
+    #[test]
+    fn round_trips_rfc_example()
+    {
+        let components = parser::parse(ICS).unwrap();
+        let xcal = to_xcal(&components);
+
+        assert!(xcal.contains("<rrule><recur><freq>YEARLY</freq><byday>-1SU</byday><bymonth>10</bymonth></recur></rrule>"));
+        assert!(xcal.contains("<rdate><parameters><tzid><text>US/Eastern</text></tzid></parameters><period><start>2006-01-02T15:00:00</start><duration>PT2H</duration></period></rdate>"));
+        assert!(xcal.contains("<tzoffsetfrom><utc-offset>-05:00</utc-offset></tzoffsetfrom>"));
+
+        assert_eq!(from_xcal(&xcal).unwrap(), components);
+    }
+
+    #[test]
+    fn parses_rfc_example()
+    {
+        let mut vcalendar = parser::parse(ICS).unwrap().remove(0);
+        vcalendar.properties.reverse();
+        vcalendar.components.drain(..2);
+
+        assert_eq!(from_xcal(XCAL_EVENT).unwrap(), vec![vcalendar]);
+        assert!(matches!(from_xcal("<vcalendar></vcalendar>"), Err(XCalError::InvalidRoot)));
+        assert!(matches!(from_xcal("<icalendar><vcalendar></icalendar>"), Err(XCalError::Xml(_))));
+    }
+}
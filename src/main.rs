@@ -9,11 +9,31 @@ mod database_helpers;
 mod routes;
 mod calendar;
 mod event;
+mod agenda;
+mod change_feed;
+mod change_stream;
+mod sync;
+mod tombstones;
+mod tenants;
+mod acl;
+mod rate_limit;
+mod webhooks;
+mod webhook_delivery;
+mod outbox_worker;
+mod api_keys;
 mod recurrence;
+mod ical;
+mod feeds;
 mod configs;
 mod env_helpers;
 mod iter_helpers;
+mod encoding_helpers;
+mod network_helpers;
 mod authentication;
+mod caldav;
+mod scheduling;
+#[cfg(test)]
+mod test_helpers;
 
 extern crate dotenv;
 #[macro_use] extern crate thiserror;
@@ -22,19 +42,78 @@ extern crate dotenv;
 #[macro_use] extern crate rocket_okapi;
 
 use crate::connection_pool::PgsqlPool;
-use rocket::Request;
+use rocket::{Request, Rocket};
+use rocket::http::Status;
+use log::info;
 use env_helpers::{get_env, get_env_default};
-use crate::configs::Configs;
+use crate::configs::{Configs, RateLimitStoreKind};
 use rocket_okapi::swagger_ui::{make_swagger_ui, SwaggerUIConfig};
+use rocket_contrib::json::Json;
+use crate::authentication::auth_error::{self, AuthError};
+use crate::authentication::openapi_security::OpenApiSecurity;
+use crate::authentication::jwt::JwtAuthenticator;
+use crate::rate_limit::{RateLimiter, RateLimitHeaders, MemoryStore, PgsqlStore};
+use crate::change_stream::ChangeBroadcaster;
+use crate::scheduling::transport::Transport;
+use crate::scheduling::imip::SmtpTransport;
 
 fn main()
 {
     dotenv::dotenv().ok();
 
-    rocket::ignite()
-        .manage(get_pgsql_pool())
-        .manage(Configs::get_configs())
-        .mount("/api", routes::get_routes())
+    // Igniting sets up the logger, so it comes before anything that logs.
+    let rocket = rocket::ignite();
+
+    let pool = get_pgsql_pool();
+    let configs = Configs::get_configs();
+
+    tombstones::spawn_purge_job(pool.clone(), configs.get_tombstone_retention_days(), configs.get_tombstone_purge_interval());
+    webhook_delivery::spawn_delivery_worker(pool.clone(), configs.get_webhook_poll_interval(), configs.get_webhook_max_attempts(), configs.get_webhook_allow_private_targets());
+    spawn_scheduling_worker(&configs, &pool);
+
+    let change_broadcaster = get_change_broadcaster(&configs, &rocket);
+    change_stream::spawn_listener(pool.clone(), change_broadcaster.clone());
+
+    let jwt_authenticator = get_jwt_authenticator(&configs, &pool);
+    let rate_limiter = get_rate_limiter(&configs, &pool);
+
+    let caldav_port = configs.get_caldav_port();
+
+    let rocket = build_rocket(rocket, pool, configs, jwt_authenticator, rate_limiter, change_broadcaster);
+
+    if let Some(port) = caldav_port
+    {
+        caldav::proxy::spawn(port, rocket.config().port);
+    }
+
+    rocket.launch();
+}
+
+/// Mounts the routes and catchers and manages the state they need. The workers
+/// and the CalDAV proxy are started by `main`, so that tests can build the
+/// server without them.
+fn build_rocket(
+    rocket: Rocket,
+    pool: PgsqlPool,
+    configs: Configs,
+    jwt_authenticator: Option<JwtAuthenticator>,
+    rate_limiter: RateLimiter,
+    change_broadcaster: ChangeBroadcaster,
+) -> Rocket
+{
+    let routes = routes::get_routes();
+
+    rocket
+        .manage(pool)
+        .manage(jwt_authenticator)
+        .manage(rate_limiter)
+        .manage(change_broadcaster)
+        .manage(configs)
+        .attach(RateLimitHeaders)
+        .attach(OpenApiSecurity::new("/api/openapi.json", &routes, routes::ROUTE_SCOPES))
+        .mount("/api", routes)
+        .mount(caldav::MOUNT_POINT, caldav::get_routes())
+        .mount("/", caldav::get_well_known_routes())
         .mount(
             "/swagger-ui/",
             make_swagger_ui(&SwaggerUIConfig {
@@ -42,8 +121,7 @@ fn main()
                 ..Default::default()
             }),
         )
-        .register(catchers![not_found])
-        .launch();
+        .register(catchers![not_found, unauthorized, forbidden, too_many_requests, service_unavailable])
 }
 
 fn get_pgsql_pool() -> PgsqlPool
@@ -59,12 +137,105 @@ fn get_pgsql_pool() -> PgsqlPool
     let pg_password = get_env("POSTGRES_PASSWORD");
     let pg_user = get_env("POSTGRES_USER");
 
-    println!("Connecting to DB at {}:{}", pg_host, pg_port);
+    info!("Connecting to DB at {}:{}", pg_host, pg_port);
 
     PgsqlPool::new(&format!("host={} port={} dbname={} user={} password={}", pg_host, pg_port, pg_user, pg_user, pg_password))
 }
 
+/// Returns None if JWT authentication is not configured. Panics if the
+/// tenant users belong to (`JWT_TENANT`) doesn't exist.
+fn get_jwt_authenticator(configs: &Configs, pool: &PgsqlPool) -> Option<JwtAuthenticator>
+{
+    let source = configs.get_jwks_source()?;
+    let settings = configs.get_jwt_settings()?;
+
+    let mut db = pool.get_conn().expect("Failed to connect to the database.");
+
+    if tenants::get_id_by_name(&mut **db, &settings.tenant).expect("Failed to get the tenant of JWT users.").is_none()
+    {
+        panic!("The tenant of JWT users ({}) doesn't exist.", settings.tenant);
+    }
+
+    info!("Loading JWKS from {:?}", source);
+
+    let authenticator = JwtAuthenticator::new(source.clone(), settings.clone()).expect("Failed to load the JWKS.");
+    Some(authenticator)
+}
+
+/// Sends scheduling messages through the configured transport. Without one
+/// they're queued but not sent, so they can be sent once one is configured.
+fn spawn_scheduling_worker(configs: &Configs, pool: &PgsqlPool)
+{
+    let transport: Box<dyn Transport> = match configs.get_smtp_settings()
+    {
+        Some(settings) => Box::new(SmtpTransport::new(settings.clone())),
+        None => return,
+    };
+
+    scheduling::outbox::spawn_worker(pool.clone(), transport, configs.get_scheduling_poll_interval(), configs.get_scheduling_max_attempts());
+}
+
+/// The broadcaster of the change streams, limited to `STREAM_MAX_CONNECTIONS`
+/// streams or, by default, half of Rocket's workers, so that streams can't keep
+/// every worker busy.
+fn get_change_broadcaster(configs: &Configs, rocket: &Rocket) -> ChangeBroadcaster
+{
+    let max_streams = configs.get_max_streams().unwrap_or(rocket.config().workers as usize / 2);
+    ChangeBroadcaster::new(max_streams)
+}
+
+fn get_rate_limiter(configs: &Configs, pool: &PgsqlPool) -> RateLimiter
+{
+    match configs.get_rate_limit_store()
+    {
+        RateLimitStoreKind::Memory => RateLimiter::new(Box::new(MemoryStore::new()), configs.get_rate_limit()),
+        RateLimitStoreKind::Postgres =>
+        {
+            rate_limit::spawn_prune_job(pool.clone());
+            RateLimiter::new(Box::new(PgsqlStore), configs.get_rate_limit())
+        },
+    }
+}
+
 
 
 #[catch(404)]
-fn not_found(_req: &Request) -> () {}
\ No newline at end of file
+fn not_found(_req: &Request) -> () {}
+
+#[catch(401)]
+fn unauthorized(req: &Request) -> Result<AuthError, Json<serde_json::Value>>
+{
+    auth_failure_or(req, Status::Unauthorized, "unauthorized")
+}
+
+#[catch(403)]
+fn forbidden(req: &Request) -> Result<AuthError, Json<serde_json::Value>>
+{
+    auth_failure_or(req, Status::Forbidden, "forbidden")
+}
+
+#[catch(429)]
+fn too_many_requests(_req: &Request) -> AuthError
+{
+    AuthError::RateLimited
+}
+
+#[catch(503)]
+fn service_unavailable(req: &Request) -> Result<AuthError, Json<serde_json::Value>>
+{
+    auth_failure_or(req, Status::ServiceUnavailable, "unavailable")
+}
+
+/// The auth error the request failed with if it failed authentication with
+/// the status, otherwise a generic body with the given error code, so that
+/// errors from elsewhere aren't reported as auth errors.
+fn auth_failure_or(req: &Request, status: Status, error: &str) -> Result<AuthError, Json<serde_json::Value>>
+{
+    match auth_error::get_failure(req)
+    {
+        Some(failure) if failure.status() == status => Ok(failure),
+        _ => Err(Json(serde_json::json!({
+            "error": error,
+        }))),
+    }
+}
@@ -0,0 +1,172 @@
+//! Checks for requests the server makes to URLs its clients give it (webhook
+//! targets), so that they can't be used to reach the server's own network or
+//! the cloud provider's metadata service.
+//!
+//! Hosts are checked when they're resolved: `resolve_public` is used as the
+//! resolver of the HTTP agent, so a host can't resolve to a public address
+//! when it's checked and to a private one when it's connected to.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+use url::Url;
+
+/// Whether the address is reachable on the internet, i.e. it's not loopback,
+/// private, link-local (which has 169.254.169.254, the metadata service),
+/// unspecified, multicast, or reserved for documentation or other uses.
+pub fn is_public_ip(ip: &IpAddr) -> bool
+{
+    match ip
+    {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => is_public_ipv6(ip),
+    }
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool
+{
+    let [a, b, c, _] = ip.octets();
+
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // "This network", 0.0.0.0/8.
+        || a == 0
+        // Shared address space (carrier-grade NAT), 100.64.0.0/10.
+        || (a == 100 && (b & 0xc0) == 64)
+        // IETF protocol assignments, 192.0.0.0/24.
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking, 198.18.0.0/15.
+        || (a == 198 && (b & 0xfe) == 18)
+        // Reserved, 240.0.0.0/4.
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: &Ipv6Addr) -> bool
+{
+    if let Some(ipv4) = ipv4_in_ipv6(ip)
+    {
+        return is_public_ipv4(&ipv4);
+    }
+
+    let first = ip.segments()[0];
+
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local, fc00::/7.
+        || (first & 0xfe00) == 0xfc00
+        // Link-local, fe80::/10.
+        || (first & 0xffc0) == 0xfe80
+        // Documentation, 2001:db8::/32.
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+/// The IPv4 address in an IPv4-mapped (::ffff:0:0/96) or
+/// IPv4-compatible (::/96) IPv6 address.
+fn ipv4_in_ipv6(ip: &Ipv6Addr) -> Option<Ipv4Addr>
+{
+    match ip.segments()
+    {
+        [0, 0, 0, 0, 0, 0xffff, _, _] | [0, 0, 0, 0, 0, 0, _, _] if !ip.is_loopback() && !ip.is_unspecified() =>
+        {
+            let [.., a, b, c, d] = ip.octets();
+            Some(Ipv4Addr::new(a, b, c, d))
+        },
+        _ => None,
+    }
+}
+
+/// Resolves `<host>:<port>`, failing if any of its addresses isn't public.
+/// Has the signature of `ureq::Resolver`.
+pub fn resolve_public(netloc: &str) -> io::Result<Vec<SocketAddr>>
+{
+    let addrs: Vec<SocketAddr> = netloc.to_socket_addrs()?.collect();
+
+    if addrs.iter().any(|addr| !is_public_ip(&addr.ip()))
+    {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("{} resolves to an address that's not public.", netloc)));
+    }
+
+    Ok(addrs)
+}
+
+/// Whether the URL is an http or https URL whose host resolves to public addresses.
+pub fn is_public_url(url: &str) -> bool
+{
+    let url = match Url::parse(url)
+    {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+        _ => return false,
+    };
+
+    match (url.host_str(), url.port_or_known_default())
+    {
+        (Some(host), Some(port)) => resolve_public(&format!("{}:{}", host, port)).is_ok(),
+        _ => false,
+    }
+}
+
+/// An agent for requests to URLs given by clients. It doesn't follow redirects,
+/// which could point anywhere, and unless `allow_private` is set it only
+/// connects to public addresses.
+pub fn outbound_agent(timeout: Duration, allow_private: bool) -> ureq::Agent
+{
+    let builder = ureq::AgentBuilder::new()
+        .timeout(timeout)
+        .redirects(0);
+
+    if allow_private
+    {
+        builder.build()
+    }
+    else
+    {
+        builder.resolver(resolve_public).build()
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{is_public_ip, is_public_url, resolve_public};
+    use std::net::IpAddr;
+
+    #[test]
+    fn private_addresses_are_not_public()
+    {
+        for ip in &[
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0", "100.64.0.1",
+            "255.255.255.255", "224.0.0.1", "::1", "::", "fd00::1", "fe80::1", "::ffff:127.0.0.1", "::ffff:169.254.169.254",
+        ]
+        {
+            assert!(!is_public_ip(&ip.parse::<IpAddr>().unwrap()), "{}", ip);
+        }
+
+        for ip in &["93.184.216.34", "8.8.8.8", "2606:2800:220:1:248:1893:25c8:1946", "::ffff:8.8.8.8"]
+        {
+            assert!(is_public_ip(&ip.parse::<IpAddr>().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn urls_must_be_public_http_urls()
+    {
+        assert!(is_public_url("https://93.184.216.34/hook"));
+        assert!(is_public_url("http://[2606:2800:220:1:248:1893:25c8:1946]:8080/hook"));
+
+        assert!(!is_public_url("http://localhost/hook"));
+        assert!(!is_public_url("http://127.0.0.1:8000/hook"));
+        assert!(!is_public_url("http://169.254.169.254/latest/meta-data/"));
+        assert!(!is_public_url("http://[::1]/hook"));
+        assert!(!is_public_url("ftp://93.184.216.34/hook"));
+        assert!(!is_public_url("not a url"));
+
+        assert!(resolve_public("127.0.0.1:80").is_err());
+        assert!(resolve_public("93.184.216.34:80").is_ok());
+    }
+}
@@ -0,0 +1,228 @@
+//! The worker that sends the messages of an outbox: a table of messages queued
+//! in the same transaction as the change they're about, see docs/webhooks.md.
+//! Webhook deliveries (`webhook_delivery`) and scheduling messages
+//! (`scheduling::outbox`) are both sent by it.
+//!
+//! The worker claims the messages that are due, sends them and records the
+//! outcome. Failed messages are retried with exponential backoff until they
+//! run out of attempts, then they're dead.
+
+use crate::connection_pool::PgsqlPool;
+use crate::database_error::DatabaseError;
+use crate::database_helpers::get_cell_from_row;
+use postgres::GenericClient;
+use uuid::Uuid;
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+use log::{warn, error};
+
+/// How many messages the worker claims at a time.
+const BATCH_SIZE: i64 = 10;
+
+/// For how long claimed messages are left alone by other workers. If the worker
+/// dies while sending them they're sent again once this expires. Must be longer
+/// than it takes to send a whole batch.
+const CLAIM_LEASE: Duration = Duration::from_secs(5 * 60);
+
+/// Delay before the first retry, it doubles on each attempt.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(6 * 60 * 60);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus
+{
+    /// Waiting to be sent, at `next_attempt_at`.
+    Pending,
+
+    /// Sent successfully.
+    Delivered,
+
+    /// Ran out of attempts, won't be sent again unless redelivered.
+    Dead,
+}
+
+impl DeliveryStatus
+{
+    pub const ALL: [DeliveryStatus; 3] = [DeliveryStatus::Pending, DeliveryStatus::Delivered, DeliveryStatus::Dead];
+
+    /// The status' name, as stored in the `status` column of the outboxes.
+    pub fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Dead => "dead",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<DeliveryStatus>
+    {
+        DeliveryStatus::ALL.iter().copied().find(|status| status.as_str() == s)
+    }
+}
+
+/// An outbox whose messages are sent by the worker.
+pub trait Outbox: Send + 'static
+{
+    /// A claimed message, with what's needed to send it.
+    type Message;
+
+    /// The outbox's table. It must have the `id`, `status`, `attempts`,
+    /// `next_attempt_at`, `last_attempt_at` and `last_error` columns.
+    const TABLE: &'static str;
+
+    /// What the messages are, for logs.
+    const NAME: &'static str;
+
+    /// Loads the messages with the given ids.
+    fn load(&self, db: &mut impl GenericClient, ids: &[Uuid]) -> Result<Vec<Self::Message>, DatabaseError>;
+
+    fn id(message: &Self::Message) -> Uuid;
+
+    /// Where the message is sent, for logs.
+    fn recipient(message: &Self::Message) -> &str;
+
+    /// Sends the message. Errors are a description of what went wrong.
+    fn send(&self, message: &Self::Message) -> Result<(), String>;
+}
+
+/// How long to wait before retrying a message that failed `attempts` times.
+pub fn backoff(attempts: u32) -> Duration
+{
+    // Past 2^20 the delay is way over the maximum anyway.
+    let factor = 1 << attempts.saturating_sub(1).min(20);
+
+    (FIRST_RETRY_DELAY * factor).min(MAX_RETRY_DELAY)
+}
+
+/// Claims up to `limit` pending messages that are due, returning their ids and
+/// how many times they were sent so far. Claimed messages are rescheduled
+/// `CLAIM_LEASE` later, so that other workers skip them while they're being sent.
+fn claim_due<O: Outbox>(db: &mut impl GenericClient, limit: i64) -> Result<HashMap<Uuid, i32>, DatabaseError>
+{
+    let query = format!("
+        UPDATE {table}
+        SET next_attempt_at = NOW() + make_interval(secs => $2)
+        WHERE id IN (
+            SELECT id FROM {table}
+            WHERE status = 'pending' AND next_attempt_at <= NOW()
+            ORDER BY next_attempt_at
+            LIMIT $1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, attempts;
+    ", table = O::TABLE);
+
+    db.query(query.as_str(), &[&limit, &CLAIM_LEASE.as_secs_f64()])?
+        .iter()
+        .map(|row| Ok((get_cell_from_row(row, "id")?, get_cell_from_row(row, "attempts")?)))
+        .collect()
+}
+
+/// Records the outcome of an attempt. Failed messages are rescheduled
+/// with `backoff`, or marked as dead if that was their last attempt.
+fn record_attempt<O: Outbox>(db: &mut impl GenericClient, id: &Uuid, attempts: i32, result: &Result<(), String>, max_attempts: u32) -> Result<(), DatabaseError>
+{
+    let attempts = attempts as u32 + 1;
+
+    let (status, retry_in) = match result
+    {
+        Ok(()) => (DeliveryStatus::Delivered, Duration::from_secs(0)),
+        Err(_) if attempts >= max_attempts => (DeliveryStatus::Dead, Duration::from_secs(0)),
+        Err(_) => (DeliveryStatus::Pending, backoff(attempts)),
+    };
+
+    let query = format!("
+        UPDATE {}
+        SET status = $2, attempts = $3, last_attempt_at = NOW(), last_error = $4, next_attempt_at = NOW() + make_interval(secs => $5)
+        WHERE id = $1;
+    ", O::TABLE);
+
+    db.execute(query.as_str(), &[id, &status.as_str(), &(attempts as i32), &result.as_ref().err(), &retry_in.as_secs_f64()])?;
+
+    Ok(())
+}
+
+/// Sends a batch of the outbox's due messages. Returns how many were claimed,
+/// if it's a whole batch there may be more due.
+pub fn process_due<O: Outbox>(db: &mut impl GenericClient, outbox: &O, max_attempts: u32) -> Result<usize, DatabaseError>
+{
+    let claimed = claim_due::<O>(db, BATCH_SIZE)?;
+    let ids: Vec<Uuid> = claimed.keys().copied().collect();
+
+    for message in outbox.load(db, &ids)?.iter()
+    {
+        let id = O::id(message);
+        let result = outbox.send(message);
+
+        if let Err(e) = &result
+        {
+            warn!("Failed to send {} {} to {}: {}", O::NAME, id, O::recipient(message), e);
+        }
+
+        record_attempt::<O>(db, &id, claimed[&id], &result, max_attempts)?;
+    }
+
+    Ok(claimed.len())
+}
+
+/// Spawns a thread that sends the outbox's due messages every `poll_interval` seconds.
+pub fn spawn_worker<O: Outbox>(pool: PgsqlPool, outbox: O, poll_interval: u64, max_attempts: u32)
+{
+    thread::spawn(move || {
+        loop
+        {
+            match pool.get_conn()
+            {
+                Ok(mut conn) => loop
+                {
+                    match process_due(&mut **conn, &outbox, max_attempts)
+                    {
+                        Ok(claimed) if claimed as i64 == BATCH_SIZE => continue,
+                        Ok(_) => break,
+                        Err(e) =>
+                        {
+                            error!("Failed to send {}: {}", O::NAME, e);
+                            break;
+                        },
+                    }
+                },
+                Err(e) => error!("Failed to send {}: {}", O::NAME, e),
+            }
+
+            thread::sleep(Duration::from_secs(poll_interval));
+        }
+    });
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{backoff, DeliveryStatus};
+    use std::time::Duration;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum()
+    {
+        assert_eq!(backoff(1), Duration::from_secs(30));
+        assert_eq!(backoff(2), Duration::from_secs(60));
+        assert_eq!(backoff(5), Duration::from_secs(480));
+        assert_eq!(backoff(12), Duration::from_secs(6 * 60 * 60));
+        assert_eq!(backoff(u32::MAX), Duration::from_secs(6 * 60 * 60));
+    }
+
+    #[test]
+    fn statuses_round_trip()
+    {
+        for status in DeliveryStatus::ALL.iter()
+        {
+            assert_eq!(DeliveryStatus::from_str(status.as_str()), Some(*status));
+        }
+
+        assert_eq!(DeliveryStatus::from_str("sent"), None);
+    }
+}
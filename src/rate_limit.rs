@@ -0,0 +1,445 @@
+//! Token bucket rate limiting of API keys and users.
+//!
+//! Every API key (and every user) has a bucket that holds up to `burst`
+//! requests and refills at `per_minute` requests per minute. Each request
+//! takes one; requests that find the bucket empty get a 429. Keys can have
+//! their own limit, everything else uses the configured default, and if
+//! there's no default only keys with their own limit are limited.
+//!
+//! Buckets are taken from by the `ApiKey` and `User` guards (see
+//! `limit_request`), and `RateLimitHeaders` adds the `RateLimit-*` and
+//! `Retry-After` headers to the response.
+//!
+//! Buckets are kept in memory by default. Deployments with several
+//! instances can keep them in Postgres instead so that they're shared.
+
+use crate::connection_pool::PgsqlPool;
+use crate::database_error::DatabaseError;
+use crate::authentication::auth_error::AuthError;
+use rocket::{Request, Response, State};
+use rocket::request::Outcome;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Status};
+use postgres::{Client, GenericClient};
+use chrono::{NaiveDateTime, Utc, Duration};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use log::{info, error};
+
+/// The memory store forgets full buckets once it has this many.
+const MAX_MEMORY_BUCKETS: usize = 10_000;
+
+/// How often full buckets are deleted from the `rate_limit_buckets` table, in seconds.
+const TABLE_PRUNE_INTERVAL: u64 = 600;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RateLimit
+{
+    /// How many requests per minute the bucket refills.
+    pub per_minute: u32,
+
+    /// How many requests the bucket holds, i.e. how many
+    /// requests can be made at once after a pause.
+    pub burst: u32,
+}
+
+impl RateLimit
+{
+    /// Both values must be > 0 and fit in the database's INTEGER columns.
+    pub fn is_valid(&self) -> bool
+    {
+        self.per_minute > 0 && self.burst > 0
+            && self.per_minute <= i32::MAX as u32 && self.burst <= i32::MAX as u32
+    }
+
+    /// From the `rate_limit_per_minute` and `rate_limit_burst` columns of `api_keys`.
+    pub fn from_columns(per_minute: Option<i32>, burst: Option<i32>) -> Option<RateLimit>
+    {
+        Some(
+            RateLimit {
+                per_minute: per_minute? as u32,
+                burst: burst? as u32,
+            }
+        )
+    }
+
+    fn tokens_per_second(&self) -> f64
+    {
+        self.per_minute as f64 / 60.0
+    }
+}
+
+/// The state of a bucket after taking from it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RateLimitStatus
+{
+    /// The bucket's size.
+    pub limit: u32,
+
+    /// Requests left in the bucket.
+    pub remaining: u32,
+
+    /// Seconds until the bucket is full again.
+    pub reset: u64,
+
+    /// Seconds until the next request can be made. None if
+    /// the request was allowed.
+    pub retry_after: Option<u64>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Bucket
+{
+    tokens: f64,
+    updated_at: NaiveDateTime,
+}
+
+impl Bucket
+{
+    pub fn full(limit: &RateLimit, now: NaiveDateTime) -> Bucket
+    {
+        Bucket {
+            tokens: limit.burst as f64,
+            updated_at: now,
+        }
+    }
+
+    /// Refills the bucket for the time since it was last updated.
+    fn refill(&mut self, limit: &RateLimit, now: NaiveDateTime)
+    {
+        // Clocks of different instances might not agree, time never goes back for a bucket.
+        let elapsed = (now - self.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+
+        self.tokens = (self.tokens + elapsed * limit.tokens_per_second()).min(limit.burst as f64);
+        self.updated_at = self.updated_at.max(now);
+    }
+
+    /// Refills the bucket, then takes a request from it.
+    pub fn take(&mut self, limit: &RateLimit, now: NaiveDateTime) -> RateLimitStatus
+    {
+        self.refill(limit, now);
+
+        let rate = limit.tokens_per_second();
+
+        let retry_after = if self.tokens >= 1.0
+        {
+            self.tokens -= 1.0;
+            None
+        }
+        else
+        {
+            Some(((1.0 - self.tokens) / rate).ceil() as u64)
+        };
+
+        RateLimitStatus {
+            limit: limit.burst,
+            remaining: self.tokens.floor() as u32,
+            reset: ((limit.burst as f64 - self.tokens) / rate).ceil() as u64,
+            retry_after,
+        }
+    }
+
+    /// Checks if the bucket would be full at `now`.
+    fn is_full(&self, limit: &RateLimit, now: NaiveDateTime) -> bool
+    {
+        let mut bucket = *self;
+        bucket.refill(limit, now);
+        bucket.tokens >= limit.burst as f64
+    }
+}
+
+/// Where buckets are kept. `bucket_key` is `api_key:<id>` or `user:<id>`.
+/// `db` is the request's connection, so that checking the limit doesn't
+/// take another one from the pool.
+pub trait RateLimitStore: Send + Sync
+{
+    fn take(&self, db: &mut Client, bucket_key: &str, limit: &RateLimit, now: NaiveDateTime) -> Result<RateLimitStatus, DatabaseError>;
+}
+
+/// Keeps buckets in memory, each instance of the server has its own.
+pub struct MemoryStore
+{
+    buckets: Mutex<HashMap<String, (Bucket, RateLimit)>>,
+}
+
+impl MemoryStore
+{
+    pub fn new() -> MemoryStore
+    {
+        MemoryStore {
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn take_from_memory(&self, bucket_key: &str, limit: &RateLimit, now: NaiveDateTime) -> RateLimitStatus
+    {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        // Full buckets are the same as no bucket at all.
+        if buckets.len() >= MAX_MEMORY_BUCKETS
+        {
+            buckets.retain(|_, (bucket, limit)| !bucket.is_full(limit, now));
+        }
+
+        let (bucket, bucket_limit) = buckets
+            .entry(bucket_key.to_owned())
+            .or_insert_with(|| (Bucket::full(limit, now), *limit));
+
+        // The key's limit might have changed.
+        *bucket_limit = *limit;
+
+        bucket.take(limit, now)
+    }
+}
+
+impl RateLimitStore for MemoryStore
+{
+    fn take(&self, _db: &mut Client, bucket_key: &str, limit: &RateLimit, now: NaiveDateTime) -> Result<RateLimitStatus, DatabaseError>
+    {
+        Ok(self.take_from_memory(bucket_key, limit, now))
+    }
+}
+
+/// Keeps buckets in the `rate_limit_buckets` table, shared by all instances
+/// of the server. Full buckets are deleted by `spawn_prune_job`.
+pub struct PgsqlStore;
+
+impl RateLimitStore for PgsqlStore
+{
+    fn take(&self, db: &mut Client, bucket_key: &str, limit: &RateLimit, now: NaiveDateTime) -> Result<RateLimitStatus, DatabaseError>
+    {
+        take_from_table(db, bucket_key, limit, now)
+    }
+}
+
+/// Takes from a bucket in the `rate_limit_buckets` table. The bucket's row
+/// is locked until the transaction ends so that concurrent requests don't
+/// take the same request.
+pub fn take_from_table(db: &mut impl GenericClient, bucket_key: &str, limit: &RateLimit, now: NaiveDateTime) -> Result<RateLimitStatus, DatabaseError>
+{
+    let mut transaction = db.transaction()?;
+
+    transaction.execute(
+        "INSERT INTO rate_limit_buckets (bucket_key, tokens, updated_at, full_at) VALUES ($1, $2, $3, $3) ON CONFLICT DO NOTHING;",
+        &[&bucket_key, &(limit.burst as f64), &now]
+    )?;
+
+    let row = transaction.query_one("SELECT tokens, updated_at FROM rate_limit_buckets WHERE bucket_key = $1 FOR UPDATE;", &[&bucket_key])?;
+
+    let mut bucket = Bucket {
+        tokens: row.try_get("tokens")?,
+        updated_at: row.try_get("updated_at")?,
+    };
+
+    let status = bucket.take(limit, now);
+    let full_at = bucket.updated_at + Duration::seconds(status.reset as i64);
+
+    transaction.execute(
+        "UPDATE rate_limit_buckets SET tokens = $2, updated_at = $3, full_at = $4 WHERE bucket_key = $1;",
+        &[&bucket_key, &bucket.tokens, &bucket.updated_at, &full_at]
+    )?;
+
+    transaction.commit()?;
+
+    Ok(status)
+}
+
+/// Deletes the buckets that are full at `now`, they're the same as no bucket
+/// at all. Returns how many were deleted.
+pub fn prune_table(db: &mut impl GenericClient, now: NaiveDateTime) -> Result<u64, DatabaseError>
+{
+    Ok(db.execute("DELETE FROM rate_limit_buckets WHERE full_at <= $1;", &[&now])?)
+}
+
+/// Spawns a thread that deletes full buckets from the `rate_limit_buckets`
+/// table every `TABLE_PRUNE_INTERVAL` seconds. Only needed with `PgsqlStore`.
+pub fn spawn_prune_job(pool: PgsqlPool)
+{
+    thread::spawn(move || {
+        loop
+        {
+            match pool.get_conn()
+            {
+                Ok(mut conn) => match prune_table(&mut **conn, Utc::now().naive_utc())
+                {
+                    Ok(pruned) => if pruned > 0 { info!("Pruned {} full rate limit buckets.", pruned) },
+                    Err(e) => error!("Failed to prune rate limit buckets: {}", e),
+                },
+                Err(e) => error!("Failed to prune rate limit buckets: {}", e),
+            }
+
+            thread::sleep(std::time::Duration::from_secs(TABLE_PRUNE_INTERVAL));
+        }
+    });
+}
+
+/// Managed state, the configured store plus the default limit.
+pub struct RateLimiter
+{
+    store: Box<dyn RateLimitStore>,
+
+    /// Limit of users and of API keys without their own limit.
+    default_limit: Option<RateLimit>,
+}
+
+impl RateLimiter
+{
+    pub fn new(store: Box<dyn RateLimitStore>, default_limit: Option<RateLimit>) -> RateLimiter
+    {
+        RateLimiter { store, default_limit }
+    }
+
+    pub fn get_default_limit(&self) -> Option<RateLimit> { self.default_limit }
+
+    /// Takes a request from the bucket. Returns None if there's no limit, or if
+    /// the store failed, in which case the request is let through.
+    pub fn take(&self, db: &mut Client, bucket_key: &str, limit: Option<RateLimit>) -> Option<RateLimitStatus>
+    {
+        let limit = limit.or(self.default_limit)?;
+
+        match self.store.take(db, bucket_key, &limit, Utc::now().naive_utc())
+        {
+            Ok(status) => Some(status),
+            Err(e) =>
+            {
+                error!("Failed to check the rate limit of {}, letting the request through: {}", bucket_key, e);
+                None
+            },
+        }
+    }
+}
+
+/// The status of the request's bucket, kept in the request's local cache.
+struct RequestRateLimit(Option<RateLimitStatus>);
+
+/// Takes a request from the bucket the first time it's called for a request,
+/// later calls (e.g. from another guard) use the same result. Fails with
+/// `AuthError::RateLimited` if the bucket is empty. `db` is the connection
+/// of the guard calling this.
+pub fn limit_request(request: &Request, db: &mut Client, bucket_key: &str, limit: Option<RateLimit>) -> Result<(), AuthError>
+{
+    let status = request.local_cache(|| {
+        match request.guard::<State<RateLimiter>>()
+        {
+            Outcome::Success(limiter) => RequestRateLimit(limiter.take(db, bucket_key, limit)),
+            _ => RequestRateLimit(None),
+        }
+    });
+
+    match status.0
+    {
+        Some(RateLimitStatus { retry_after: Some(_), .. }) => Err(AuthError::RateLimited),
+        _ => Ok(()),
+    }
+}
+
+/// Fairing that adds the `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`
+/// headers to responses to rate limited requests, plus `Retry-After` to 429s.
+pub struct RateLimitHeaders;
+
+impl Fairing for RateLimitHeaders
+{
+    fn info(&self) -> Info
+    {
+        Info {
+            name: "Rate limit headers",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response)
+    {
+        let status = match request.local_cache(|| RequestRateLimit(None)).0
+        {
+            Some(status) => status,
+            None => return,
+        };
+
+        response.set_header(Header::new("RateLimit-Limit", status.limit.to_string()));
+        response.set_header(Header::new("RateLimit-Remaining", status.remaining.to_string()));
+        response.set_header(Header::new("RateLimit-Reset", status.reset.to_string()));
+
+        if let (Some(retry_after), Status::TooManyRequests) = (status.retry_after, response.status())
+        {
+            response.set_header(Header::new("Retry-After", retry_after.to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{Bucket, RateLimit, RateLimitStatus, MemoryStore, take_from_table, prune_table};
+    use chrono::{NaiveDate, Duration};
+    use crate::test_helpers::connect;
+
+    const LIMIT: RateLimit = RateLimit { per_minute: 60, burst: 2 };
+
+    #[test]
+    fn bucket_empties_and_refills()
+    {
+        let start = NaiveDate::from_ymd(2021, 1, 1).and_hms(0, 0, 0);
+        let mut bucket = Bucket::full(&LIMIT, start);
+
+        assert_eq!(bucket.take(&LIMIT, start), RateLimitStatus { limit: 2, remaining: 1, reset: 1, retry_after: None });
+        assert_eq!(bucket.take(&LIMIT, start), RateLimitStatus { limit: 2, remaining: 0, reset: 2, retry_after: None });
+        assert_eq!(bucket.take(&LIMIT, start).retry_after, Some(1));
+
+        // Half a second refills half a request, not enough yet.
+        assert_eq!(bucket.take(&LIMIT, start + Duration::milliseconds(500)).retry_after, Some(1));
+        assert_eq!(bucket.take(&LIMIT, start + Duration::seconds(1)).retry_after, None);
+
+        // Never holds more than the burst.
+        let status = bucket.take(&LIMIT, start + Duration::hours(1));
+        assert_eq!(status.remaining, 1);
+        assert_eq!(status.reset, 1);
+    }
+
+    #[test]
+    fn memory_store_keeps_buckets_apart()
+    {
+        let now = NaiveDate::from_ymd(2021, 1, 1).and_hms(0, 0, 0);
+        let store = MemoryStore::new();
+
+        store.take_from_memory("api_key:a", &LIMIT, now);
+        store.take_from_memory("api_key:a", &LIMIT, now);
+
+        assert!(store.take_from_memory("api_key:a", &LIMIT, now).retry_after.is_some());
+        assert!(store.take_from_memory("api_key:b", &LIMIT, now).retry_after.is_none());
+    }
+
+    #[test]
+    #[ignore]
+    fn table_store()
+    {
+        let mut client = connect();
+        let mut db = client.transaction().unwrap();
+
+        let now = NaiveDate::from_ymd(2021, 1, 1).and_hms(0, 0, 0);
+
+        assert_eq!(take_from_table(&mut db, "user:test", &LIMIT, now).unwrap().remaining, 1);
+        assert_eq!(take_from_table(&mut db, "user:test", &LIMIT, now).unwrap().remaining, 0);
+        assert_eq!(take_from_table(&mut db, "user:test", &LIMIT, now).unwrap().retry_after, Some(1));
+        assert_eq!(take_from_table(&mut db, "user:test", &LIMIT, now + Duration::seconds(1)).unwrap().retry_after, None);
+    }
+
+    #[test]
+    #[ignore]
+    fn table_store_prunes_full_buckets()
+    {
+        let mut client = connect();
+        let mut db = client.transaction().unwrap();
+
+        let now = NaiveDate::from_ymd(2021, 1, 1).and_hms(0, 0, 0);
+
+        take_from_table(&mut db, "user:full", &LIMIT, now).unwrap();
+        take_from_table(&mut db, "user:empty", &LIMIT, now).unwrap();
+        take_from_table(&mut db, "user:empty", &LIMIT, now + Duration::seconds(1)).unwrap();
+        take_from_table(&mut db, "user:empty", &LIMIT, now + Duration::seconds(1)).unwrap();
+
+        // One request takes a second to refill, two take two seconds.
+        assert_eq!(prune_table(&mut db, now + Duration::seconds(2)).unwrap(), 1);
+        assert_eq!(prune_table(&mut db, now + Duration::seconds(3)).unwrap(), 1);
+    }
+}
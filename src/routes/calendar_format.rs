@@ -0,0 +1,210 @@
+use rocket::{Request, Data, Outcome};
+use rocket::request::{self, FromRequest};
+use rocket::data::{self, FromDataSimple};
+use rocket::http::{MediaType, ContentType, Status};
+use rocket_okapi::request::OpenApiFromData;
+use rocket_okapi::gen::OpenApiGenerator;
+use okapi::openapi3::{RequestBody, MediaType as OpenApiMediaType};
+use crate::event::EventPlain;
+use crate::ical::Component;
+use crate::ical::jcal::{self, JCalError};
+use crate::ical::xcal::{self, XCalError};
+use std::io::{self, Read};
+
+/// Default maximum size of event bodies, the same as Rocket's `Json`. Can be
+/// changed with the `json` limit (see Rocket's `limits` configuration).
+const DEFAULT_JSON_LIMIT: u64 = 1 << 20;
+
+/// jCal's media type (RFC 7265).
+pub fn jcal_media_type() -> MediaType
+{
+    MediaType::new("application", "calendar+json")
+}
+
+/// xCal's media type (RFC 6321).
+pub fn xcal_media_type() -> MediaType
+{
+    MediaType::new("application", "calendar+xml")
+}
+
+/// The format a request wants events in, given by its `Accept` header:
+/// jCal if its preferred media type is `application/calendar+json`, xCal
+/// if it's `application/calendar+xml`, JSON (event objects) otherwise.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CalendarFormat
+{
+    Json,
+    JCal,
+    XCal,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for CalendarFormat
+{
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error>
+    {
+        match request.accept()
+        {
+            Some(accept) if *accept.preferred().media_type() == jcal_media_type() => Outcome::Success(CalendarFormat::JCal),
+            Some(accept) if *accept.preferred().media_type() == xcal_media_type() => Outcome::Success(CalendarFormat::XCal),
+            _ => Outcome::Success(CalendarFormat::Json),
+        }
+    }
+}
+
+/// The format of a calendar file, given by its extension: `.ics` for
+/// iCalendar and `.xml` for xCal.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CalendarFileFormat
+{
+    ICalendar,
+    XCal,
+}
+
+impl CalendarFileFormat
+{
+    /// The format and the rest of the file name, or None if the
+    /// extension isn't one of a calendar file.
+    pub fn from_file_name(file_name: &str) -> Option<(CalendarFileFormat, &str)>
+    {
+        if let Some(name) = file_name.strip_suffix(".ics")
+        {
+            Some((CalendarFileFormat::ICalendar, name))
+        }
+        else if let Some(name) = file_name.strip_suffix(".xml")
+        {
+            Some((CalendarFileFormat::XCal, name))
+        }
+        else
+        {
+            None
+        }
+    }
+
+    pub fn content_type(&self) -> ContentType
+    {
+        match self
+        {
+            CalendarFileFormat::ICalendar => ContentType::with_params("text", "calendar", ("charset", "utf-8")),
+            CalendarFileFormat::XCal => ContentType(xcal_media_type()),
+        }
+    }
+
+    /// Serializes the VCALENDAR as a file of this format.
+    pub fn render(&self, vcalendar: &Component) -> String
+    {
+        match self
+        {
+            CalendarFileFormat::ICalendar => vcalendar.to_ics(),
+            CalendarFileFormat::XCal => xcal::to_xcal(std::slice::from_ref(vcalendar)),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum EventDataError
+{
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    JCal(#[from] JCalError),
+
+    #[error(transparent)]
+    XCal(#[from] XCalError),
+}
+
+/// The body of a request with an event: an event object or, if its `Content-Type`
+/// is `application/calendar+json` or `application/calendar+xml`, the components of
+/// a jCal or xCal document.
+pub enum EventData
+{
+    Plain(EventPlain),
+    Components(Vec<Component>),
+}
+
+impl FromDataSimple for EventData
+{
+    type Error = EventDataError;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error>
+    {
+        let limit = request.limits().get("json").unwrap_or(DEFAULT_JSON_LIMIT);
+
+        let mut body = String::new();
+
+        if let Err(e) = data.open().take(limit).read_to_string(&mut body)
+        {
+            return Outcome::Failure((Status::BadRequest, e.into()));
+        }
+
+        let media_type = request.content_type().map(|content_type| content_type.media_type().clone());
+
+        let event = if media_type == Some(jcal_media_type())
+        {
+            serde_json::from_str(&body)
+                .map_err(EventDataError::from)
+                .and_then(|jcal| Ok(EventData::Components(vec![jcal::from_jcal(&jcal)?])))
+        }
+        else if media_type == Some(xcal_media_type())
+        {
+            xcal::from_xcal(&body)
+                .map(EventData::Components)
+                .map_err(EventDataError::from)
+        }
+        else
+        {
+            serde_json::from_str(&body)
+                .map(EventData::Plain)
+                .map_err(EventDataError::from)
+        };
+
+        // Same as Rocket's `Json`.
+        match event
+        {
+            Ok(event) => Outcome::Success(event),
+            Err(e) => Outcome::Failure((Status::UnprocessableEntity, e)),
+        }
+    }
+}
+
+impl<'a> OpenApiFromData<'a> for EventData
+{
+    fn request_body(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<RequestBody>
+    {
+        let mut request_body = RequestBody {
+            required: true,
+            ..Default::default()
+        };
+
+        request_body.content.insert(
+            "application/json".to_owned(),
+            OpenApiMediaType {
+                schema: Some(gen.json_schema::<EventPlain>()),
+                ..Default::default()
+            }
+        );
+
+        request_body.content.insert(
+            jcal_media_type().to_string(),
+            OpenApiMediaType {
+                schema: Some(gen.json_schema::<serde_json::Value>()),
+                ..Default::default()
+            }
+        );
+
+        request_body.content.insert(
+            xcal_media_type().to_string(),
+            OpenApiMediaType {
+                schema: Some(gen.json_schema::<String>()),
+                ..Default::default()
+            }
+        );
+
+        Ok(request_body)
+    }
+}
@@ -0,0 +1,116 @@
+use rocket::{Request, request::Outcome};
+use rocket::request::FromRequest;
+use chrono::NaiveDateTime;
+
+pub(crate) const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// The version of a resource a response has, sent as the `ETag` and `Last-Modified`
+/// headers so that clients can ask for the resource only if it changed (RFC 7232).
+pub struct Validators
+{
+    /// Without quotes.
+    etag: String,
+    last_modified: NaiveDateTime,
+}
+
+impl Validators
+{
+    pub fn new(etag: impl Into<String>, last_modified: NaiveDateTime) -> Validators
+    {
+        Validators {
+            etag: etag.into(),
+            last_modified,
+        }
+    }
+
+    /// The value of the `ETag` header.
+    pub fn etag_header(&self) -> String
+    {
+        format!("\"{}\"", self.etag)
+    }
+
+    /// The value of the `Last-Modified` header.
+    pub fn last_modified_header(&self) -> String
+    {
+        self.last_modified.format(HTTP_DATE_FORMAT).to_string()
+    }
+}
+
+/// The `If-None-Match` and `If-Modified-Since` headers of a request.
+pub struct ConditionalRequest
+{
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+}
+
+impl ConditionalRequest
+{
+    /// Whether the client's copy of the resource is the version with the validators,
+    /// so that it can be answered with 304 Not Modified. If-Modified-Since is
+    /// ignored when there's an If-None-Match, as RFC 7232 says.
+    pub fn is_fresh(&self, validators: &Validators) -> bool
+    {
+        if let Some(if_none_match) = &self.if_none_match
+        {
+            return if_none_match
+                .split(',')
+                .map(|etag| etag.trim())
+                .any(|etag| etag == "*" || etag.trim_start_matches("W/") == validators.etag_header());
+        }
+
+        match self.if_modified_since.as_ref().and_then(|date| NaiveDateTime::parse_from_str(date, HTTP_DATE_FORMAT).ok())
+        {
+            // HTTP dates don't have fractions of a second.
+            Some(since) => validators.last_modified.timestamp() <= since.timestamp(),
+            None => false,
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ConditionalRequest
+{
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error>
+    {
+        Outcome::Success(
+            ConditionalRequest {
+                if_none_match: request.headers().get_one("If-None-Match").map(|value| value.to_owned()),
+                if_modified_since: request.headers().get_one("If-Modified-Since").map(|value| value.to_owned()),
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{ConditionalRequest, Validators};
+    use chrono::NaiveDate;
+
+    fn request(if_none_match: Option<&str>, if_modified_since: Option<&str>) -> ConditionalRequest
+    {
+        ConditionalRequest {
+            if_none_match: if_none_match.map(|value| value.to_owned()),
+            if_modified_since: if_modified_since.map(|value| value.to_owned()),
+        }
+    }
+
+    #[test]
+    fn checks_etags_before_dates()
+    {
+        let validators = Validators::new("42", NaiveDate::from_ymd(2020, 9, 1).and_hms_milli(10, 0, 0, 500));
+
+        assert_eq!(validators.etag_header(), "\"42\"");
+        assert_eq!(validators.last_modified_header(), "Tue, 01 Sep 2020 10:00:00 GMT");
+
+        assert!(request(Some("\"41\", W/\"42\""), None).is_fresh(&validators));
+        assert!(request(Some("*"), None).is_fresh(&validators));
+        assert!(!request(Some("\"41\""), Some("Tue, 01 Sep 2020 10:00:00 GMT")).is_fresh(&validators));
+
+        assert!(request(None, Some("Tue, 01 Sep 2020 10:00:00 GMT")).is_fresh(&validators));
+        assert!(!request(None, Some("Tue, 01 Sep 2020 09:59:59 GMT")).is_fresh(&validators));
+        assert!(!request(None, Some("yesterday")).is_fresh(&validators));
+        assert!(!request(None, None).is_fresh(&validators));
+    }
+}
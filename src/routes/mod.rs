@@ -2,10 +2,20 @@
 
 use rocket::Route;
 use rocket_okapi::routes_with_openapi;
+use crate::authentication::scopes::Scope;
 
 mod routes_calendar;
 mod routes_event;
+mod routes_api_key;
+mod routes_acl;
+mod routes_webhook;
+mod routes_ical;
+mod routes_feed;
+mod routes_scheduling;
 mod common_query_params;
+mod responders;
+pub(crate) mod conditional_request;
+pub(crate) mod calendar_format;
 
 /// All project routes go in here, main.rs
 /// uses this method to get all routes.
@@ -14,13 +24,222 @@ pub fn get_routes() -> Vec<Route>
     routes_with_openapi![
         routes_calendar::get_calendar,
         routes_calendar::insert_calendar,
+        routes_calendar::update_calendar,
+        routes_calendar::patch_calendar,
         routes_calendar::list_calendars,
+        routes_calendar::delete_calendar,
+        routes_calendar::check_for_calendar_changes,
+        routes_calendar::stream_calendar_changes,
 
         routes_event::get_event,
         routes_event::insert_event,
         routes_event::get_instances,
         routes_event::update_event,
+        routes_event::delete_event,
         routes_event::list_events,
         routes_event::check_for_changes,
+        routes_event::get_agenda,
+        routes_event::sync_events,
+
+        routes_api_key::create_api_key,
+        routes_api_key::list_api_keys,
+        routes_api_key::rotate_api_key,
+        routes_api_key::revoke_api_key,
+
+        routes_acl::list_grants,
+        routes_acl::set_grant,
+        routes_acl::delete_grant,
+
+        routes_webhook::create_webhook,
+        routes_webhook::list_webhooks,
+        routes_webhook::get_webhook,
+        routes_webhook::delete_webhook,
+        routes_webhook::list_webhook_deliveries,
+        routes_webhook::redeliver_webhook,
+
+        routes_ical::export_calendar,
+        routes_ical::export_event,
+        routes_ical::import_calendar,
+
+        routes_feed::create_feed,
+        routes_feed::list_feeds,
+        routes_feed::revoke_feed,
+        routes_feed::get_feed,
+
+        routes_scheduling::get_attendees,
+        routes_scheduling::set_attendees,
+        routes_scheduling::list_scheduling_messages,
+        routes_scheduling::process_reply,
     ]
-}
\ No newline at end of file
+}
+
+/// The scope each route's `ApiKey` guard requires (for `Principal` and
+/// `CalendarAccess` guards, the scope they require from API keys), by route
+/// (function) name. Only used to document the scopes in the OpenAPI spec, see
+/// `OpenApiSecurity`. `routes_reject_keys_without_their_scope` checks that it
+/// matches the guards.
+pub const ROUTE_SCOPES: &[(&str, Scope)] = &[
+    ("get_calendar", Scope::Read),
+    ("insert_calendar", Scope::Write),
+    ("update_calendar", Scope::Write),
+    ("patch_calendar", Scope::Write),
+    ("list_calendars", Scope::Read),
+    ("delete_calendar", Scope::Write),
+    ("check_for_calendar_changes", Scope::Read),
+    ("stream_calendar_changes", Scope::Read),
+
+    ("get_event", Scope::Read),
+    ("insert_event", Scope::Write),
+    ("get_instances", Scope::Read),
+    ("update_event", Scope::Write),
+    ("delete_event", Scope::Write),
+    ("list_events", Scope::Read),
+    ("check_for_changes", Scope::Read),
+    ("get_agenda", Scope::Read),
+    ("sync_events", Scope::Read),
+
+    ("create_api_key", Scope::Super),
+    ("list_api_keys", Scope::Super),
+    ("rotate_api_key", Scope::Super),
+    ("revoke_api_key", Scope::Super),
+
+    ("list_grants", Scope::Write),
+    ("set_grant", Scope::Write),
+    ("delete_grant", Scope::Write),
+
+    ("create_webhook", Scope::Webhooks),
+    ("list_webhooks", Scope::Webhooks),
+    ("get_webhook", Scope::Webhooks),
+    ("delete_webhook", Scope::Webhooks),
+    ("list_webhook_deliveries", Scope::Webhooks),
+    ("redeliver_webhook", Scope::Webhooks),
+
+    ("export_calendar", Scope::Read),
+    ("export_event", Scope::Read),
+    ("import_calendar", Scope::Write),
+
+    ("create_feed", Scope::Write),
+    ("list_feeds", Scope::Write),
+    ("revoke_feed", Scope::Write),
+
+    ("get_attendees", Scope::Read),
+    ("set_attendees", Scope::Write),
+    ("list_scheduling_messages", Scope::Read),
+    ("process_reply", Scope::Write),
+];
+
+/// Routes that don't require authentication, they're left out of `ROUTE_SCOPES`.
+pub const PUBLIC_ROUTES: &[&str] = &[
+    "get_feed",
+];
+
+#[cfg(test)]
+mod test
+{
+    use super::{get_routes, ROUTE_SCOPES, PUBLIC_ROUTES};
+    use crate::authentication::scopes::{Scope, Scopes};
+    use crate::test_helpers::{connect, insert_tenant, insert_calendar, insert_api_key, http_client};
+    use rocket::http::{Header, Status};
+    use rocket::Route;
+    use uuid::Uuid;
+
+    #[test]
+    fn every_route_has_a_scope()
+    {
+        for route in get_routes()
+        {
+            if let Some(name) = route.name
+            {
+                if PUBLIC_ROUTES.contains(&name)
+                {
+                    continue;
+                }
+
+                assert!(ROUTE_SCOPES.iter().any(|(n, _)| *n == name), "Route {} is missing from ROUTE_SCOPES.", name);
+            }
+        }
+    }
+
+    /// The URI of a request that reaches the route, with the given calendar and
+    /// event in it and random ids for everything else.
+    fn request_uri(route: &Route, calendar_id: &Uuid, event_id: &Uuid) -> String
+    {
+        let mut uri = String::from("/api");
+
+        for segment in route.uri.path().split('/').filter(|segment| !segment.is_empty())
+        {
+            let segment = match segment
+            {
+                "<calendar_id>" => calendar_id.to_string(),
+                "<event_id>" => event_id.to_string(),
+                "<calendar_file>" => format!("{}.ics", calendar_id),
+                "<event_file>" => format!("{}.ics", event_id),
+                s if s.starts_with('<') => Uuid::new_v4().to_string(),
+                s => s.to_owned(),
+            };
+
+            uri.push('/');
+            uri.push_str(&segment);
+        }
+
+        // Every other query parameter is optional.
+        if route.uri.query().is_some()
+        {
+            uri.push_str("?since=2021-01-01&until=2021-02-01");
+        }
+
+        uri
+    }
+
+    /// Every scope that doesn't imply the given one.
+    fn scopes_without(scope: Scope) -> Vec<&'static str>
+    {
+        Scope::ALL.iter()
+            .filter(|other| !Scopes::parse(&[other.as_str()]).unwrap().contains(scope))
+            .map(|other| other.as_str())
+            .collect()
+    }
+
+    #[test]
+    #[ignore]
+    fn routes_reject_keys_without_their_scope()
+    {
+        let mut db = connect();
+
+        let tenant_id = insert_tenant(&mut db);
+        let calendar_id = insert_calendar(&mut db, &tenant_id);
+        let event_id: Uuid = db.query_one(
+            "INSERT INTO events (calendar_id, start_date, end_date) VALUES ($1, '2021-01-01', '2021-01-01') RETURNING id;",
+            &[&calendar_id]
+        ).unwrap().get("id");
+
+        let client = http_client();
+
+        for route in get_routes()
+        {
+            let name = match route.name
+            {
+                Some(name) if !PUBLIC_ROUTES.contains(&name) => name,
+                _ => continue,
+            };
+
+            let scope = ROUTE_SCOPES.iter().find(|(n, _)| *n == name).unwrap().1;
+            let uri = request_uri(&route, &calendar_id, &event_id);
+
+            let wrong_key = insert_api_key(&mut db, &tenant_id, &scopes_without(scope), None);
+            let mut response = client.req(route.method, uri.as_str()).header(Header::new("Authorization", wrong_key)).dispatch();
+
+            assert_eq!(response.status(), Status::Forbidden, "{} {} without {}", route.method, uri, scope.as_str());
+            assert!(response.body_string().unwrap().contains("missing-scope"), "{} {} without {}", route.method, uri, scope.as_str());
+
+            // Only reading the body of 403s, other responses might be endless streams.
+            let right_key = insert_api_key(&mut db, &tenant_id, &[scope.as_str()], None);
+            let mut response = client.req(route.method, uri.as_str()).header(Header::new("Authorization", right_key)).dispatch();
+
+            if response.status() == Status::Forbidden
+            {
+                assert!(!response.body_string().unwrap().contains("missing-scope"), "{} {} with {}", route.method, uri, scope.as_str());
+            }
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use rocket::{Request, Response};
+use rocket::response::{self, Responder};
+use rocket::http::{Status, ContentType, Header};
+use rocket_okapi::response::OpenApiResponder;
+use rocket_okapi::gen::OpenApiGenerator;
+use okapi::openapi3::Responses;
+use rocket_route_result::RouteResult;
+use std::io::Cursor;
+use crate::change_stream::ChangeStream;
+use crate::routes::conditional_request::Validators;
+use crate::routes::calendar_format::{CalendarFileFormat, jcal_media_type, xcal_media_type};
+use serde_json::Value;
+
+/// Adds a `Sync-Token` header with the given token to the response
+/// of `R`. The header is omitted if the token is None.
+///
+/// The token should be taken before reading the events that go
+/// in the response, see `SyncToken::current`.
+pub struct WithSyncToken<R>(pub R, pub Option<String>);
+
+impl<'r, R: Responder<'r>> Responder<'r> for WithSyncToken<R>
+{
+    fn respond_to(self, request: &Request) -> response::Result<'r>
+    {
+        let mut response = self.0.respond_to(request)?;
+
+        if let Some(token) = self.1
+        {
+            response.set_header(Header::new("Sync-Token", token));
+        }
+
+        Ok(response)
+    }
+}
+
+impl<'r, R: OpenApiResponder<'r>> OpenApiResponder<'r> for WithSyncToken<R>
+{
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses>
+    {
+        R::responses(gen)
+    }
+}
+
+
+
+/// Adds `ETag` and `Last-Modified` headers with the validators to the
+/// response of `R`. The headers are omitted if the validators are None.
+pub struct WithValidators<R>(pub R, pub Option<Validators>);
+
+impl<'r, R: Responder<'r>> Responder<'r> for WithValidators<R>
+{
+    fn respond_to(self, request: &Request) -> response::Result<'r>
+    {
+        let mut response = self.0.respond_to(request)?;
+
+        if let Some(validators) = self.1
+        {
+            response.set_header(Header::new("ETag", validators.etag_header()));
+            response.set_header(Header::new("Last-Modified", validators.last_modified_header()));
+        }
+
+        Ok(response)
+    }
+}
+
+impl<'r, R: OpenApiResponder<'r>> OpenApiResponder<'r> for WithValidators<R>
+{
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses>
+    {
+        R::responses(gen)
+    }
+}
+
+
+
+/// Result of a sync request or of a request for the changes after a sync token.
+/// Same as a `RouteResult`, except for the `InvalidToken` case, which works like
+/// RFC 6578's `DAV:valid-sync-token` precondition: a 403 telling the client to
+/// discard what it synced and start over without a token.
+pub enum SyncResult<T>
+{
+    Result(RouteResult<T>),
+    InvalidToken,
+}
+
+impl<'r, T> Responder<'r> for SyncResult<T>
+    where RouteResult<T>: Responder<'r>
+{
+    fn respond_to(self, request: &Request) -> response::Result<'r>
+    {
+        match self
+        {
+            SyncResult::Result(result) => result.respond_to(request),
+            SyncResult::InvalidToken =>
+            {
+                let body = serde_json::json!({
+                    "error": "valid-sync-token",
+                    "message": "The sync token is invalid or has expired. Discard what you synced with it and start over without a token.",
+                });
+
+                Response::build()
+                    .status(Status::Forbidden)
+                    .header(ContentType::JSON)
+                    .sized_body(Cursor::new(body.to_string()))
+                    .ok()
+            },
+        }
+    }
+}
+
+impl<'r, T> OpenApiResponder<'r> for SyncResult<T>
+    where RouteResult<T>: OpenApiResponder<'r>
+{
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses>
+    {
+        <RouteResult<T>>::responses(gen)
+    }
+}
+
+
+
+/// Result of a change stream request, either the stream or
+/// a `RouteResult` with an error.
+pub enum StreamResult
+{
+    Stream(ChangeStream),
+
+    /// 503, there are as many streams open as there can be.
+    TooManyStreams,
+
+    Result(RouteResult<()>),
+}
+
+impl<'r> Responder<'r> for StreamResult
+{
+    fn respond_to(self, request: &Request) -> response::Result<'r>
+    {
+        match self
+        {
+            StreamResult::Stream(stream) =>
+            {
+                Response::build()
+                    .header(ContentType::new("text", "event-stream"))
+                    .raw_header("Cache-Control", "no-cache")
+                    // Tells nginx not to buffer the stream.
+                    .raw_header("X-Accel-Buffering", "no")
+                    .streamed_body(stream)
+                    .ok()
+            },
+            StreamResult::TooManyStreams =>
+            {
+                let body = serde_json::json!({
+                    "error": "too-many-streams",
+                    "message": "There are too many open streams, try again later.",
+                });
+
+                Response::build()
+                    .status(Status::ServiceUnavailable)
+                    .header(ContentType::JSON)
+                    .sized_body(Cursor::new(body.to_string()))
+                    .ok()
+            },
+            StreamResult::Result(result) => result.respond_to(request),
+        }
+    }
+}
+
+impl<'r> OpenApiResponder<'r> for StreamResult
+{
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses>
+    {
+        <RouteResult<()>>::responses(gen)
+    }
+}
+
+
+
+/// An iCalendar or xCal file, or a `RouteResult` with an error.
+pub enum CalendarFileResult
+{
+    File(String, CalendarFileFormat),
+
+    /// 304, the client's copy of the file is current (see `ConditionalRequest`).
+    NotModified,
+
+    Result(RouteResult<()>),
+}
+
+impl<'r> Responder<'r> for CalendarFileResult
+{
+    fn respond_to(self, request: &Request) -> response::Result<'r>
+    {
+        match self
+        {
+            CalendarFileResult::File(body, format) =>
+            {
+                Response::build()
+                    .header(format.content_type())
+                    .sized_body(Cursor::new(body))
+                    .ok()
+            },
+            CalendarFileResult::NotModified => Response::build().status(Status::NotModified).ok(),
+            CalendarFileResult::Result(result) => result.respond_to(request),
+        }
+    }
+}
+
+impl<'r> OpenApiResponder<'r> for CalendarFileResult
+{
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses>
+    {
+        <RouteResult<()>>::responses(gen)
+    }
+}
+
+
+
+/// Events, as JSON or as a jCal or xCal VCALENDAR (see `CalendarFormat`).
+/// `JCal` and `XCal` have the event's location if it was created, and then
+/// respond with 201.
+pub enum EventsResult<T>
+{
+    Plain(RouteResult<T>),
+    JCal(Value, Option<String>),
+    XCal(String, Option<String>),
+}
+
+impl<'r, T> Responder<'r> for EventsResult<T>
+    where RouteResult<T>: Responder<'r>
+{
+    fn respond_to(self, request: &Request) -> response::Result<'r>
+    {
+        let (content_type, body, location) = match self
+        {
+            EventsResult::Plain(result) => return result.respond_to(request),
+            EventsResult::JCal(vcalendar, location) => (ContentType(jcal_media_type()), vcalendar.to_string(), location),
+            EventsResult::XCal(vcalendar, location) => (ContentType(xcal_media_type()), vcalendar, location),
+        };
+
+        let mut response = Response::build();
+
+        response
+            .header(content_type)
+            .sized_body(Cursor::new(body));
+
+        if let Some(location) = location
+        {
+            response
+                .status(Status::Created)
+                .header(Header::new("Location", location));
+        }
+
+        response.ok()
+    }
+}
+
+impl<'r, T> OpenApiResponder<'r> for EventsResult<T>
+    where RouteResult<T>: OpenApiResponder<'r>
+{
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses>
+    {
+        <RouteResult<T>>::responses(gen)
+    }
+}
+
+
+
+/// Result of the API key routes. Same as a `RouteResult`, except for the
+/// `BeyondKey` case: a 403 for requests that would give (or take away) more
+/// access than the request's own API key has.
+pub enum ApiKeyResult<T>
+{
+    Result(RouteResult<T>),
+    BeyondKey,
+}
+
+impl<'r, T> Responder<'r> for ApiKeyResult<T>
+    where RouteResult<T>: Responder<'r>
+{
+    fn respond_to(self, request: &Request) -> response::Result<'r>
+    {
+        match self
+        {
+            ApiKeyResult::Result(result) => result.respond_to(request),
+            ApiKeyResult::BeyondKey =>
+            {
+                let body = serde_json::json!({
+                    "error": "beyond-api-key",
+                    "message": "New API keys can't reach other calendars or make more requests than the API key creating them, and only API keys that aren't restricted to some calendars can rotate or revoke keys.",
+                });
+
+                Response::build()
+                    .status(Status::Forbidden)
+                    .header(ContentType::JSON)
+                    .sized_body(Cursor::new(body.to_string()))
+                    .ok()
+            },
+        }
+    }
+}
+
+impl<'r, T> OpenApiResponder<'r> for ApiKeyResult<T>
+    where RouteResult<T>: OpenApiResponder<'r>
+{
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses>
+    {
+        <RouteResult<T>>::responses(gen)
+    }
+}
@@ -0,0 +1,77 @@
+use crate::connection_pool::PgsqlConn;
+use rocket_route_result::RouteResult;
+use rocket_contrib::json::Json;
+use crate::database_helpers::UuidParam;
+use crate::authentication::calendar_access::{CalendarAccess, Owner};
+use crate::acl::{self, Grant, NewGrant, PrincipalType};
+use uuid::Uuid;
+use std::str::FromStr;
+
+/// Lists the calendar's grants. Requires the owner role.
+///
+/// Response codes: 200, 403, 404, 500
+#[openapi]
+#[get("/calendars/<calendar_id>/acl")]
+pub fn list_grants(mut db: PgsqlConn, _access: CalendarAccess<Owner>, calendar_id: UuidParam) -> RouteResult<Vec<Grant>>
+{
+    RouteResult::Ok(
+        acl::list_grants(&mut **db, &calendar_id.into_inner())?
+    )
+}
+
+/// Grants a role on the calendar to a principal, replacing the principal's current
+/// grant if it has one. API key principals are referred to by the key's id, which
+/// must be of a key of the same tenant. Requires the owner role.
+///
+/// Response codes: 200, 400, 403, 404, 500
+#[openapi]
+#[put("/calendars/<calendar_id>/acl", data = "<grant>")]
+pub fn set_grant(mut db: PgsqlConn, access: CalendarAccess<Owner>, calendar_id: UuidParam, grant: Json<NewGrant>) -> RouteResult<Grant>
+{
+    let mut grant = grant.into_inner();
+
+    if grant.principal_id.is_empty() || grant.principal_id.len() > 255
+    {
+        return RouteResult::BadRequest(None);
+    }
+
+    if grant.principal_type == PrincipalType::ApiKey
+    {
+        let key_id = match Uuid::from_str(&grant.principal_id)
+        {
+            Ok(key_id) => key_id,
+            Err(_) => return RouteResult::BadRequest(None),
+        };
+
+        let rows = db.query("SELECT id FROM api_keys WHERE id = $1 AND tenant_id = $2;", &[&key_id, &access.get_tenant_id()])?;
+
+        if rows.is_empty()
+        {
+            return RouteResult::BadRequest(None);
+        }
+
+        // Stored in the same format as events.created_by.
+        grant.principal_id = key_id.to_string();
+    }
+
+    RouteResult::Ok(
+        acl::set_grant(&mut **db, &calendar_id.into_inner(), &grant)?
+    )
+}
+
+/// Removes a grant from the calendar. Requires the owner role.
+///
+/// Response codes: 200, 403, 404, 500
+#[openapi]
+#[delete("/calendars/<calendar_id>/acl/<grant_id>")]
+pub fn delete_grant(mut db: PgsqlConn, _access: CalendarAccess<Owner>, calendar_id: UuidParam, grant_id: UuidParam) -> RouteResult<()>
+{
+    if acl::delete_grant(&mut **db, &calendar_id.into_inner(), &grant_id.into_inner())?
+    {
+        RouteResult::Ok(())
+    }
+    else
+    {
+        RouteResult::NotFound
+    }
+}
@@ -0,0 +1,210 @@
+use crate::connection_pool::PgsqlConn;
+use rocket_route_result::RouteResult;
+use rocket_contrib::json::Json;
+use crate::database_helpers::UuidParam;
+use crate::routes::common_query_params::CommonQueryParams;
+use crate::authentication::auth_guard::ApiKey;
+use crate::authentication::scopes::Super;
+use crate::api_keys::{self, ApiKeyInfo, ApiKeyWithSecret, NewApiKey, NewApiKeyError, RotateApiKeyError};
+use crate::routes::responders::ApiKeyResult;
+use crate::rate_limit::RateLimiter;
+use rocket::State;
+
+/// Creates an API key in the tenant of the API key making the request. The response
+/// has the new key in its `key` property, this is the only time it's shown.
+///
+/// The new key can't reach calendars the request's key can't reach, and can't
+/// have a higher rate limit than the request's key.
+///
+/// Response codes: 201, 400, 403, 500
+#[openapi]
+#[post("/api-keys", data = "<new_key>")]
+pub fn create_api_key(
+    mut db: PgsqlConn,
+    api_key: ApiKey<Super>,
+    rate_limiter: State<RateLimiter>,
+    new_key: Json<NewApiKey>,
+) -> ApiKeyResult<ApiKeyWithSecret>
+{
+    if !new_key.is_within(api_key.get_calendar_ids(), api_key.get_rate_limit(), rate_limiter.get_default_limit())
+    {
+        return ApiKeyResult::BeyondKey;
+    }
+
+    let result = match api_keys::create(&mut **db, &api_key.get_tenant_id(), &new_key)
+    {
+        Ok(created) =>
+        {
+            //TODO: prepend host to url.
+            let location = format!("/api/api-keys/{}", created.info.id);
+            RouteResult::Created(created, location)
+        },
+        Err(NewApiKeyError::InvalidScopes) | Err(NewApiKeyError::UnknownCalendars) | Err(NewApiKeyError::InvalidRateLimit) => RouteResult::BadRequest(None),
+        Err(NewApiKeyError::Database(e)) => RouteResult::InternalError(Box::new(e)),
+        Err(NewApiKeyError::Random(e)) => RouteResult::InternalError(Box::new(e)),
+    };
+
+    ApiKeyResult::Result(result)
+}
+
+/// Lists the API keys of the tenant, including revoked and expired ones.
+/// The keys themselves are not included.
+///
+/// Response codes: 200, 500
+#[openapi]
+#[get("/api-keys")]
+pub fn list_api_keys(mut db: PgsqlConn, api_key: ApiKey<Super>, common_params: CommonQueryParams) -> RouteResult<Vec<ApiKeyInfo>>
+{
+    RouteResult::Ok(
+        api_keys::list(&mut **db, &api_key.get_tenant_id(), common_params.offset(), common_params.page_size())?
+    )
+}
+
+/// Replaces an API key's key with a new one, everything else stays the same.
+/// The old key stops working right away. The response has the new key in
+/// its `key` property, this is the only time it's shown.
+///
+/// Only keys that aren't restricted to some calendars can rotate keys.
+///
+/// Response codes: 200, 403, 404, 500
+#[openapi]
+#[post("/api-keys/<key_id>/rotate")]
+pub fn rotate_api_key(mut db: PgsqlConn, api_key: ApiKey<Super>, key_id: UuidParam) -> ApiKeyResult<ApiKeyWithSecret>
+{
+    if api_key.get_calendar_ids().is_some()
+    {
+        return ApiKeyResult::BeyondKey;
+    }
+
+    let result = match api_keys::rotate(&mut **db, &api_key.get_tenant_id(), &key_id.into_inner())
+    {
+        Ok(Some(rotated)) => RouteResult::Ok(rotated),
+        Ok(None) => RouteResult::NotFound,
+        Err(RotateApiKeyError::Database(e)) => RouteResult::InternalError(Box::new(e)),
+        Err(RotateApiKeyError::Random(e)) => RouteResult::InternalError(Box::new(e)),
+    };
+
+    ApiKeyResult::Result(result)
+}
+
+/// Revokes an API key. Revoked keys can't be used anymore, but are still listed.
+///
+/// Only keys that aren't restricted to some calendars can revoke keys.
+///
+/// Response codes: 200, 403, 404, 500
+#[openapi]
+#[delete("/api-keys/<key_id>")]
+pub fn revoke_api_key(mut db: PgsqlConn, api_key: ApiKey<Super>, key_id: UuidParam) -> ApiKeyResult<()>
+{
+    if api_key.get_calendar_ids().is_some()
+    {
+        return ApiKeyResult::BeyondKey;
+    }
+
+    let result = match api_keys::revoke(&mut **db, &api_key.get_tenant_id(), &key_id.into_inner())
+    {
+        Ok(true) => RouteResult::Ok(()),
+        Ok(false) => RouteResult::NotFound,
+        Err(e) => RouteResult::InternalError(Box::new(e)),
+    };
+
+    ApiKeyResult::Result(result)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::test_helpers::{connect, insert_tenant, insert_calendar, insert_api_key, http_client};
+    use crate::api_keys::{self, NewApiKey};
+    use crate::rate_limit::RateLimit;
+    use rocket::http::{Header, ContentType, Status};
+    use rocket::local::Client as HttpClient;
+
+    fn create_key(client: &HttpClient, key: &str, body: &str) -> Status
+    {
+        client.post("/api/api-keys")
+            .header(Header::new("Authorization", key.to_owned()))
+            .header(ContentType::JSON)
+            .body(body)
+            .dispatch()
+            .status()
+    }
+
+    #[test]
+    #[ignore]
+    fn restricted_keys_only_create_keys_for_their_calendars()
+    {
+        let mut db = connect();
+
+        let tenant_id = insert_tenant(&mut db);
+        let calendar_a = insert_calendar(&mut db, &tenant_id);
+        let calendar_b = insert_calendar(&mut db, &tenant_id);
+        let key = insert_api_key(&mut db, &tenant_id, &["SUPER"], Some(vec![calendar_a]));
+
+        let client = http_client();
+
+        assert_eq!(create_key(&client, &key, r#"{"scopes": ["READ"]}"#), Status::Forbidden);
+        assert_eq!(create_key(&client, &key, &format!(r#"{{"scopes": ["READ"], "calendar_ids": ["{}"]}}"#, calendar_b)), Status::Forbidden);
+        assert_eq!(create_key(&client, &key, &format!(r#"{{"scopes": ["READ"], "calendar_ids": ["{}", "{}"]}}"#, calendar_a, calendar_b)), Status::Forbidden);
+        assert_eq!(create_key(&client, &key, &format!(r#"{{"scopes": ["READ"], "calendar_ids": ["{}"]}}"#, calendar_a)), Status::Created);
+    }
+
+    #[test]
+    #[ignore]
+    fn limited_keys_only_create_keys_within_their_limit()
+    {
+        let mut db = connect();
+
+        let tenant_id = insert_tenant(&mut db);
+        let new_key = NewApiKey {
+            label: "test".to_owned(),
+            scopes: vec!["SUPER".to_owned()],
+            expires_at: None,
+            calendar_ids: None,
+            rate_limit: Some(RateLimit { per_minute: 60, burst: 10 }),
+        };
+        let key = api_keys::create(&mut db, &tenant_id, &new_key).unwrap().key;
+
+        let client = http_client();
+
+        assert_eq!(create_key(&client, &key, r#"{"scopes": ["READ"], "rate_limit": {"per_minute": 120, "burst": 10}}"#), Status::Forbidden);
+        assert_eq!(create_key(&client, &key, r#"{"scopes": ["READ"], "rate_limit": {"per_minute": 60, "burst": 20}}"#), Status::Forbidden);
+        assert_eq!(create_key(&client, &key, r#"{"scopes": ["READ"], "rate_limit": {"per_minute": 30, "burst": 5}}"#), Status::Created);
+    }
+
+    #[test]
+    #[ignore]
+    fn only_unrestricted_keys_rotate_and_revoke_keys()
+    {
+        let mut db = connect();
+
+        let tenant_id = insert_tenant(&mut db);
+        let calendar_id = insert_calendar(&mut db, &tenant_id);
+        let restricted_key = insert_api_key(&mut db, &tenant_id, &["SUPER"], Some(vec![calendar_id]));
+        let unrestricted_key = insert_api_key(&mut db, &tenant_id, &["SUPER"], None);
+        let other = api_keys::create(&mut db, &tenant_id, &NewApiKey {
+            label: "test".to_owned(),
+            scopes: vec!["READ".to_owned()],
+            expires_at: None,
+            calendar_ids: None,
+            rate_limit: None,
+        }).unwrap();
+
+        let client = http_client();
+
+        let rotate = |key: &str| client.post(format!("/api/api-keys/{}/rotate", other.info.id))
+            .header(Header::new("Authorization", key.to_owned()))
+            .dispatch()
+            .status();
+
+        let revoke = |key: &str| client.delete(format!("/api/api-keys/{}", other.info.id))
+            .header(Header::new("Authorization", key.to_owned()))
+            .dispatch()
+            .status();
+
+        assert_eq!(rotate(&restricted_key), Status::Forbidden);
+        assert_eq!(revoke(&restricted_key), Status::Forbidden);
+        assert_eq!(rotate(&unrestricted_key), Status::Ok);
+        assert_eq!(revoke(&unrestricted_key), Status::Ok);
+    }
+}
@@ -1,28 +1,36 @@
 use crate::connection_pool::PgsqlConn;
-use crate::calendar::{Calendar};
+use crate::calendar::{self, Calendar, CalendarPatch, CALENDAR_FIELDS};
 use rocket_route_result::RouteResult;
 use crate::database_helpers::{FromRow, UuidParam};
 use rocket_contrib::json::Json;
 use crate::database_error::{DatabaseError, DatabaseErrorKind};
 use crate::routes::common_query_params::CommonQueryParams;
-use crate::authentication::auth_guard::ApiKey;
+use crate::authentication::principal::Principal;
+use crate::authentication::calendar_access::{CalendarAccess, Reader, Editor, Owner};
+use crate::authentication::scopes::{Read, Write};
+use crate::acl::{self, CalendarRole, NewGrant, PrincipalType};
+use crate::change_feed::{self, ChangeType};
+use crate::routes::routes_event::{NaiveDateOrTime, ChangesSince};
+use crate::webhooks::{self, Change, WebhookEventType};
+use crate::change_stream::{self, ChangeBroadcaster, LastEventId, OpenStreamError};
+use crate::configs::Configs;
+use crate::routes::responders::{StreamResult, SyncResult, WithSyncToken};
+use crate::sync::SyncToken;
+use rocket::State;
+use uuid::Uuid;
+use postgres::types::ToSql;
+use std::time::Duration;
 
-/// Gets a calendar by id from the database.
+/// Gets a calendar by id.
 ///
 /// Response codes: 200, 404, 500
 #[openapi]
 #[get("/calendars/<calendar_id>")]
-pub fn get_calendar(mut db: PgsqlConn, _api_key: ApiKey, calendar_id: UuidParam) -> RouteResult<Calendar>
+pub fn get_calendar(mut db: PgsqlConn, access: CalendarAccess<Reader>, calendar_id: UuidParam) -> RouteResult<Calendar>
 {
-    let query = "SELECT * FROM calendars WHERE id = $1;";
-
-    let query = db.query(query, &[&calendar_id])?;
-
-    if let Some(row) = query.get(0)
+    if let Some(calendar) = calendar::get_calendar(&mut **db, &access.get_tenant_id(), &calendar_id.into_inner())?
     {
-        RouteResult::Ok(
-            Calendar::from_row(row)?
-        )
+        RouteResult::Ok(calendar)
     }
     else
     {
@@ -30,54 +38,370 @@ pub fn get_calendar(mut db: PgsqlConn, _api_key: ApiKey, calendar_id: UuidParam)
     }
 }
 
-/// Lists all calendars in the database.
+/// Lists the calendars the principal can see: all calendars of its tenant for
+/// API keys that are not restricted to some calendars, otherwise the ones it
+/// was given access to. The response has a `Sync-Token` header for
+/// `check_for_calendar_changes`.
 ///
 /// Response codes: 200, 500
 #[openapi]
 #[get("/calendars")]
-pub fn list_calendars(mut db: PgsqlConn, _api_key: ApiKey, shared_params: CommonQueryParams) -> RouteResult<Vec<Calendar>>
+pub fn list_calendars(mut db: PgsqlConn, principal: Principal<Read>, shared_params: CommonQueryParams) -> WithSyncToken<RouteResult<Vec<Calendar>>>
 {
-    let query = "SELECT * FROM calendars OFFSET $1 LIMIT $2;";
+    with_tenant_sync_token(&mut db, principal.get_tenant_id(), |db| {
+        let calendar_ids = principal.visible_calendar_ids(&mut ***db)?;
 
-    let rows = db.query(query, &[
-        &shared_params.offset(),
-        &shared_params.page_size(),
-    ])?;
+        RouteResult::Ok(
+            calendar::list_calendars(&mut ***db, &principal.get_tenant_id(), calendar_ids.as_ref(), shared_params.offset(), shared_params.page_size())?
+        )
+    })
+}
+
+
+/// Lists calendars the principal can see that changed after `sync_token`, or that
+/// were modified at or after `since`, ordered by when they changed. Deleted calendars
+/// are included with `deleted` set to true. Only one of `since` and `sync_token`
+/// can be set.
+///
+/// Prefer `sync_token` (e.g. the `Sync-Token` of a previous response), `since` can
+/// miss changes committed out of order by concurrent requests. Like
+/// `routes_event::sync_events`, returns 403 with a `valid-sync-token` error if the
+/// token is invalid or expired, the client should then list the calendars again.
+///
+/// Response codes: 200, 400, 403, 500
+#[openapi]
+#[get("/calendars/changes?<since>&<sync_token>")]
+pub fn check_for_calendar_changes(
+    mut db: PgsqlConn,
+    principal: Principal<Read>,
+    shared_params: CommonQueryParams,
+    since: Option<NaiveDateOrTime>,
+    sync_token: Option<String>,
+) -> WithSyncToken<SyncResult<Vec<Calendar>>>
+{
+    let since = match ChangesSince::from_params(since, sync_token)
+    {
+        Ok(since) => since,
+        Err(result) => return WithSyncToken(result, None),
+    };
+
+    // This doesn't read any calendars, so it can come before the current token is taken.
+    let after_seq = match since.after_seq(|token| token.seq_for_tenant(&mut **db, &principal.get_tenant_id()))
+    {
+        Ok(after_seq) => after_seq,
+        Err(result) => return WithSyncToken(result, None),
+    };
+
+    let WithSyncToken(result, token) = with_tenant_sync_token(&mut db, principal.get_tenant_id(), |db| {
+        let calendar_ids = principal.visible_calendar_ids(&mut ***db)?;
+
+        let query = "
+            SELECT * FROM calendars
+            WHERE
+                tenant_id = $5
+                AND ($6::UUID[] IS NULL OR id = ANY($6))
+                AND ($1::TIMESTAMP IS NULL OR last_modified >= $1::TIMESTAMP)
+                AND ($2::DATE IS NULL OR last_modified >= $2::DATE)
+                AND ($7::BIGINT IS NULL OR tenant_change_seq > $7::BIGINT)
+            ORDER BY tenant_change_seq
+            OFFSET $3
+            LIMIT $4;
+        ";
+
+        let rows = db.query(query, &[
+            &since.as_naive_date_time(),
+            &since.as_naive_date(),
+
+            &shared_params.offset(),
+            &shared_params.page_size(),
+
+            &principal.get_tenant_id(),
+            &calendar_ids,
+            &after_seq,
+        ])?;
+
+        RouteResult::Ok(
+            rows.into_iter()
+                .map(|row| Calendar::from_row(&row))
+                .collect::<Result<Vec<_>, _>>()?
+        )
+    });
+
+    WithSyncToken(SyncResult::Result(result), token)
+}
+
+/// Gets the sync token of the tenant's calendar list, then runs `f`
+/// and adds the token to its response in the `Sync-Token` header. Like
+/// `routes_event::with_sync_token`, the token is taken before `f` reads anything.
+fn with_tenant_sync_token<T>(db: &mut PgsqlConn, tenant_id: Uuid, f: impl FnOnce(&mut PgsqlConn) -> RouteResult<T>) -> WithSyncToken<RouteResult<T>>
+{
+    match SyncToken::current_for_tenant(&mut ***db, &tenant_id)
+    {
+        Ok(token) => WithSyncToken(f(db), token.map(|t| t.to_string())),
+        Err(e) => WithSyncToken(RouteResult::InternalError(Box::new(e)), None),
+    }
+}
+
+
+/// Streams the changes to the calendar and its events as Server-Sent Events, see
+/// docs/resources.md. Clients that reconnect with a `Last-Event-ID` get the changes
+/// they missed, unless there are more than the page size of them, in which case
+/// they get a `reset` event and should sync the calendar again. Streams are closed
+/// after `STREAM_MAX_DURATION`, so that access is checked again on reconnection.
+///
+/// Response codes: 200, 404, 500, 503
+#[openapi]
+#[get("/calendars/<calendar_id>/stream")]
+pub fn stream_calendar_changes(
+    mut db: PgsqlConn,
+    _access: CalendarAccess<Reader>,
+    calendar_id: UuidParam,
+    last_event_id: LastEventId,
+    broadcaster: State<ChangeBroadcaster>,
+    configs: State<Configs>,
+    common_params: CommonQueryParams,
+) -> StreamResult
+{
+    let max_duration = Duration::from_secs(configs.get_stream_max_duration());
 
-    RouteResult::Ok(
-        rows.into_iter()
-            .map(|row| Calendar::from_row(&row))
-            .collect::<Result<Vec<_>, _>>()?
-    )
+    match change_stream::open(&mut **db, &broadcaster, calendar_id.into_inner(), &last_event_id, common_params.page_size(), max_duration)
+    {
+        Ok(stream) => StreamResult::Stream(stream),
+        Err(OpenStreamError::UnknownCalendar) => StreamResult::Result(RouteResult::NotFound),
+        Err(OpenStreamError::TooManyStreams) => StreamResult::TooManyStreams,
+        Err(OpenStreamError::Database(e)) => StreamResult::Result(RouteResult::InternalError(Box::new(e))),
+    }
 }
 
 
-/// Inserts a calendar into the database and returns it.
+/// Inserts a calendar into the database and returns it. The calendar
+/// belongs to the principal's tenant. If the principal is an API key restricted
+/// to some calendars, the new calendar is added to them. If it's a user, the
+/// user is granted the owner role on the calendar.
 ///
-/// Response codes: 201, 500
+/// Response codes: 201, 400, 500
 #[openapi]
 #[post("/calendars", data = "<calendar>")]
-pub fn insert_calendar(mut db: PgsqlConn, _api_key: ApiKey, calendar: Json<Calendar>) -> RouteResult<Calendar>
+pub fn insert_calendar(mut db: PgsqlConn, principal: Principal<Write>, calendar: Json<Calendar>) -> RouteResult<Calendar>
 {
     let calendar = calendar.into_inner();
 
-    if !calendar.get_id().is_nil()
+    if !calendar.get_id().is_nil() || !calendar.validate()
     {
-        RouteResult::BadRequest(None)
+        return RouteResult::BadRequest(None);
+    }
+
+    let query = format!("
+        INSERT INTO calendars (name, description, timezone, color, owner_id, tenant_id)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING {};
+    ", CALENDAR_FIELDS);
+
+    let mut transaction = db.transaction()?;
+
+    let rows = transaction.query(query.as_str(), &[
+        &calendar.get_name(),
+        &calendar.get_description(),
+        &calendar.get_timezone(),
+        &calendar.get_color(),
+        &calendar.get_owner_id(),
+        &principal.get_tenant_id(),
+    ])?;
+
+    if let Some(row) = rows.get(0)
+    {
+        let calendar = Calendar::from_row(row)?;
+
+        change_feed::record_calendar_change(&mut transaction, &calendar.get_id(), ChangeType::Created)?;
+
+        match &principal
+        {
+            Principal::ApiKey(api_key) =>
+            {
+                transaction.execute(
+                    "UPDATE api_keys SET calendar_ids = array_append(calendar_ids, $1) WHERE id = $2 AND calendar_ids IS NOT NULL;",
+                    &[&calendar.get_id(), &api_key.get_id()]
+                )?;
+            },
+            Principal::User(user) =>
+            {
+                acl::set_grant(&mut transaction, &calendar.get_id(), &NewGrant {
+                    principal_type: PrincipalType::User,
+                    principal_id: user.get_id().to_owned(),
+                    role: CalendarRole::Owner,
+                })?;
+            },
+        }
+
+        transaction.commit()?;
+
+        //TODO: prepend host to url.
+        let location = format!("/api/calendars/{}", calendar.get_id());
+        RouteResult::Created(calendar, location)
     }
     else
     {
-        let query = "INSERT INTO calendars DEFAULT VALUES RETURNING *";
+        RouteResult::InternalError(Box::<DatabaseError>::new(DatabaseErrorKind::ReturningIsEmpty.into()))
+    }
+}
 
-        let rows = db.query(query, &[])?;
+/// Replaces all of the calendar's properties with the ones in the request's body.
+/// Properties that are not in the body are set to their defaults. If the body has
+/// an `id` it must be the same as `calendar_id`. Requires the editor role.
+///
+/// Response codes: 200, 400, 403, 404, 500
+#[openapi]
+#[put("/calendars/<calendar_id>", data = "<calendar>")]
+pub fn update_calendar(mut db: PgsqlConn, access: CalendarAccess<Editor>, calendar_id: UuidParam, calendar: Json<Calendar>) -> RouteResult<Calendar>
+{
+    let calendar_id = calendar_id.into_inner();
+    let calendar = calendar.into_inner();
 
-        if let Some(row) = rows.get(0)
+    if (!calendar.get_id().is_nil() && calendar.get_id() != calendar_id) || !calendar.validate()
+    {
+        return RouteResult::BadRequest(None);
+    }
+
+    let query = format!("
+        UPDATE calendars
+        SET name = $2, description = $3, timezone = $4, color = $5, owner_id = $6
+        WHERE id = $1 AND tenant_id = $7 AND deleted_at IS NULL
+        RETURNING {};
+    ", CALENDAR_FIELDS);
+
+    let mut transaction = db.transaction()?;
+
+    let rows = transaction.query(query.as_str(), &[
+        &calendar_id,
+        &calendar.get_name(),
+        &calendar.get_description(),
+        &calendar.get_timezone(),
+        &calendar.get_color(),
+        &calendar.get_owner_id(),
+        &access.get_tenant_id(),
+    ])?;
+
+    if let Some(row) = rows.get(0)
+    {
+        let calendar = Calendar::from_row(row)?;
+
+        change_feed::record_calendar_change(&mut transaction, &calendar_id, ChangeType::Updated)?;
+        webhooks::enqueue(&mut transaction, &[Change::calendar(WebhookEventType::CalendarUpdated, &calendar)])?;
+        transaction.commit()?;
+
+        RouteResult::Ok(calendar)
+    }
+    else
+    {
+        RouteResult::NotFound
+    }
+}
+
+/// Updates the calendar's properties that are in the request's body, the others
+/// are left unchanged. Setting `description`, `color` or `owner_id` to null
+/// clears them. Requires the editor role.
+///
+/// Response codes: 200, 400, 403, 404, 500
+#[openapi]
+#[patch("/calendars/<calendar_id>", data = "<patch>")]
+pub fn patch_calendar(mut db: PgsqlConn, access: CalendarAccess<Editor>, calendar_id: UuidParam, patch: Json<CalendarPatch>) -> RouteResult<Calendar>
+{
+    let calendar_id = calendar_id.into_inner();
+    let tenant_id = access.get_tenant_id();
+
+    if !patch.validate()
+    {
+        return RouteResult::BadRequest(None);
+    }
+
+    let fields: Vec<(&str, Option<&(dyn ToSql + Sync)>)> = vec![
+        ("name",        patch.name          .as_ref().map::<&(dyn ToSql + Sync), _>(|x| &*x)),
+        ("description", patch.description   .as_ref().map::<&(dyn ToSql + Sync), _>(|x| &*x)),
+        ("timezone",    patch.timezone      .as_ref().map::<&(dyn ToSql + Sync), _>(|x| &*x)),
+        ("color",       patch.color         .as_ref().map::<&(dyn ToSql + Sync), _>(|x| &*x)),
+        ("owner_id",    patch.owner_id      .as_ref().map::<&(dyn ToSql + Sync), _>(|x| &*x)),
+    ];
+
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&calendar_id, &tenant_id];
+    let mut assignments = vec![];
+
+    // $1 is the calendar's id and $2 is the tenant's id,
+    // each field that is set gets the next parameter.
+    for (field, value) in fields
+    {
+        if let Some(value) = value
         {
-            RouteResult::Ok(Calendar::from_row(row)?)
+            params.push(value);
+            assignments.push(format!("{} = ${}", field, params.len()));
         }
-        else
+    }
+
+    let mut transaction = db.transaction()?;
+
+    let rows = if assignments.is_empty()
+    {
+        let query = format!("SELECT {} FROM calendars WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NULL;", CALENDAR_FIELDS);
+        transaction.query(query.as_str(), &params)?
+    }
+    else
+    {
+        let query = format!(
+            "UPDATE calendars SET {} WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NULL RETURNING {};",
+            assignments.join(", "),
+            CALENDAR_FIELDS
+        );
+        transaction.query(query.as_str(), &params)?
+    };
+
+    if let Some(row) = rows.get(0)
+    {
+        let calendar = Calendar::from_row(row)?;
+
+        if !assignments.is_empty()
         {
-            RouteResult::InternalError(Box::<DatabaseError>::new(DatabaseErrorKind::ReturningIsEmpty.into()))
+            change_feed::record_calendar_change(&mut transaction, &calendar_id, ChangeType::Updated)?;
+            webhooks::enqueue(&mut transaction, &[Change::calendar(WebhookEventType::CalendarUpdated, &calendar)])?;
         }
+
+        transaction.commit()?;
+
+        RouteResult::Ok(calendar)
+    }
+    else
+    {
+        RouteResult::NotFound
     }
-}
\ No newline at end of file
+}
+
+/// Deletes a calendar and all of its events. They are kept as
+/// tombstones until the configured retention period expires, see `tombstones`.
+/// Requires the owner role.
+///
+/// Response codes: 200, 403, 404, 500
+#[openapi]
+#[delete("/calendars/<calendar_id>")]
+pub fn delete_calendar(mut db: PgsqlConn, access: CalendarAccess<Owner>, calendar_id: UuidParam) -> RouteResult<()>
+{
+    let calendar_id = calendar_id.into_inner();
+
+    let mut transaction = db.transaction()?;
+
+    let query = format!("SELECT {} FROM calendars WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NULL FOR UPDATE;", CALENDAR_FIELDS);
+    let rows = transaction.query(query.as_str(), &[&calendar_id, &access.get_tenant_id()])?;
+
+    let calendar = match rows.get(0)
+    {
+        Some(row) => Calendar::from_row(row)?,
+        None => return RouteResult::NotFound,
+    };
+
+    transaction.execute("UPDATE events SET deleted_at = NOW() WHERE calendar_id = $1 AND deleted_at IS NULL;", &[&calendar_id])?;
+
+    change_feed::record_calendar_change(&mut transaction, &calendar_id, ChangeType::Deleted)?;
+    transaction.execute("UPDATE calendars SET deleted_at = NOW() WHERE id = $1;", &[&calendar_id])?;
+    webhooks::enqueue(&mut transaction, &[Change::calendar(WebhookEventType::CalendarDeleted, &calendar)])?;
+
+    transaction.commit()?;
+
+    RouteResult::Ok(())
+}
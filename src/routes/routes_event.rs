@@ -1,13 +1,13 @@
 use crate::connection_pool::PgsqlConn;
 use rocket_route_result::RouteResult;
-use crate::event::{Event, EventPlain, ToPlain};
+use crate::event::{Event, EventPlain, ToPlain, RangeMode};
 use crate::database_helpers::{FromRow, get_cell_from_row, UuidParam};
 use rocket_contrib::json::Json;
 use crate::database_error::{DatabaseErrorKind, DatabaseError};
 use std::ops::Add;
 use rocket::request::{FromFormValue};
 use rocket::http::RawStr;
-use chrono::{NaiveDateTime, NaiveDate, NaiveTime};
+use chrono::{NaiveDateTime, NaiveDate, NaiveTime, Duration};
 use postgres::types::{ToSql};
 
 
@@ -17,8 +17,20 @@ use crate::routes::common_query_params::CommonQueryParams;
 use rocket_okapi::request::OpenApiFromFormValue;
 use rocket_okapi::gen::OpenApiGenerator;
 use okapi::openapi3::{Parameter, ParameterValue};
-use crate::authentication::auth_guard::{ApiKey};
+use crate::authentication::calendar_access::{CalendarAccess, Reader, SelfWriter};
 use uuid::Uuid;
+use crate::agenda::{self, AgendaPage, AgendaCursor};
+use crate::sync::{self, SyncToken, SyncOutcome, SyncResponse};
+use crate::routes::responders::{WithSyncToken, SyncResult, EventsResult};
+use crate::routes::calendar_format::{CalendarFormat, EventData};
+use crate::routes::routes_ical;
+use crate::calendar;
+use crate::ical::{jcal, xcal, import, Component};
+use crate::webhooks::{self, Change, WebhookEventType};
+use crate::scheduling;
+
+/// How many events `query_events` gets from the database at a time.
+const QUERY_BATCH_SIZE: i64 = 200;
 
 
 /// Store a NaiveDate, NaiveTime or NaiveDateTime without knowing
@@ -67,6 +79,30 @@ impl NaiveDateOrTime
             NaiveDateOrTime::DateTime(dt) => Some(dt),
         }
     }
+
+    /// Use this as the inclusive start of a time window. Dates
+    /// start at midnight. Returns None for times.
+    pub fn as_window_start(&self) -> Option<NaiveDateTime>
+    {
+        match self
+        {
+            NaiveDateOrTime::Date(d) => Some(d.and_hms(0, 0, 0)),
+            NaiveDateOrTime::Time(_) => None,
+            NaiveDateOrTime::DateTime(dt) => Some(*dt),
+        }
+    }
+
+    /// Use this as the exclusive end of a time window. Dates are
+    /// inclusive, so they end at the following midnight. Returns None for times.
+    pub fn as_window_end(&self) -> Option<NaiveDateTime>
+    {
+        match self
+        {
+            NaiveDateOrTime::Date(d) => Some((*d + Duration::days(1)).and_hms(0, 0, 0)),
+            NaiveDateOrTime::Time(_) => None,
+            NaiveDateOrTime::DateTime(dt) => Some(*dt),
+        }
+    }
 }
 
 impl FromStr for NaiveDateOrTime
@@ -124,6 +160,45 @@ impl OpenApiFromFormValue<'_> for NaiveDateOrTime
     }
 }
 
+impl<'v> FromFormValue<'v> for RangeMode
+{
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error>
+    {
+        match form_value.as_str()
+        {
+            "contained" => Ok(RangeMode::Contained),
+            "overlaps" => Ok(RangeMode::Overlaps),
+            _ => Err(form_value),
+        }
+    }
+}
+
+impl OpenApiFromFormValue<'_> for RangeMode
+{
+    fn query_parameter(gen: &mut OpenApiGenerator, name: String, required: bool) -> rocket_okapi::Result<Parameter> {
+        let schema = gen.json_schema::<RangeMode>();
+        Ok(Parameter {
+            name,
+            location: "query".to_owned(),
+            description: None,
+            required,
+            deprecated: false,
+            allow_empty_value: false,
+            value: ParameterValue::Schema {
+                style: None,
+                explode: None,
+                allow_reserved: false,
+                schema,
+                example: None,
+                examples: None,
+            },
+            extensions: Default::default(),
+        })
+    }
+}
+
 pub struct NaiveDateParam(NaiveDate);
 impl NaiveDateParam
 {
@@ -169,11 +244,19 @@ impl OpenApiFromFormValue<'_> for NaiveDateParam
 
 
 
-fn get_event_by_id(db: &mut PgsqlConn, calendar_id: UuidParam, event_id: UuidParam) -> Result<Option<Event>, DatabaseError>
+/// Gets an event of one of the tenant's calendars.
+fn get_event_by_id(db: &mut PgsqlConn, tenant_id: Uuid, calendar_id: UuidParam, event_id: UuidParam) -> Result<Option<Event>, DatabaseError>
 {
-    let query = "SELECT * FROM events WHERE calendar_id = $1 AND id = $2";
+    let query = "
+        SELECT * FROM events
+        WHERE
+            calendar_id = $1
+            AND id = $2
+            AND deleted_at IS NULL
+            AND calendar_id IN (SELECT id FROM calendars WHERE tenant_id = $3)
+    ";
 
-    let rows = db.query(query, &[&calendar_id, &event_id])?;
+    let rows = db.query(query, &[&calendar_id, &event_id, &tenant_id])?;
 
     if let Some(row) = rows.get(0)
     {
@@ -190,38 +273,88 @@ fn get_event_by_id(db: &mut PgsqlConn, calendar_id: UuidParam, event_id: UuidPar
 
 
 
+/// Gets an event. Responds with a jCal or xCal VCALENDAR (see docs/ical.md) if
+/// the request accepts `application/calendar+json` or `application/calendar+xml`.
+///
+/// Response codes: 200, 404, 500
 #[openapi]
 #[get("/calendars/<calendar_id>/events/<event_id>")]
-pub fn get_event(mut db: PgsqlConn, _api_key: ApiKey, calendar_id: UuidParam, event_id: UuidParam) -> RouteResult<EventPlain>
+pub fn get_event(mut db: PgsqlConn, access: CalendarAccess<Reader>, calendar_id: UuidParam, event_id: UuidParam, format: CalendarFormat) -> EventsResult<EventPlain>
 {
-    get_event_by_id(&mut db, calendar_id, event_id)
+    let result: RouteResult<EventPlain> = get_event_by_id(&mut db, access.get_tenant_id(), calendar_id, event_id)
         .map(|opt|
             opt.map(|event| event.into_plain())
         )
-        .into()
+        .into();
+
+    negotiate(&mut db, &access.get_calendar_id(), format, result, std::slice::from_ref)
 }
 
+/// Inserts an event into the calendar. Requires the self-writer role, self-writers
+/// can only override instances (i.e. set `parent_id`) of events they created.
+///
+/// The event can also be a jCal or xCal VCALENDAR (see docs/ical.md), sent with
+/// the `application/calendar+json` or `application/calendar+xml` content type.
+/// The response is a VCALENDAR in the format the request accepts.
+///
+/// Response codes: 201, 400, 403, 404, 422, 500
 #[openapi]
 #[post("/calendars/<calendar_id>/events", data = "<event>")]
-pub fn insert_event(mut db: PgsqlConn, _api_key: ApiKey, calendar_id: UuidParam, event: Json<EventPlain>) -> RouteResult<EventPlain>
+pub fn insert_event(mut db: PgsqlConn, access: CalendarAccess<SelfWriter>, calendar_id: UuidParam, event: EventData, format: CalendarFormat) -> EventsResult<EventPlain>
+{
+    let result = match event
+    {
+        EventData::Plain(event) => insert_plain(&mut db, &access, calendar_id, event),
+        EventData::Components(components) => insert_vcalendar(&mut db, &access, calendar_id, &components),
+    };
+
+    negotiate(&mut db, &access.get_calendar_id(), format, result, std::slice::from_ref)
+}
+
+fn insert_plain(db: &mut PgsqlConn, access: &CalendarAccess<SelfWriter>, calendar_id: UuidParam, event: EventPlain) -> RouteResult<EventPlain>
 {
     if !event.validate_non_patch() || event.id.is_some()
     {
         return RouteResult::BadRequest(None);
     }
 
+    let event = event.with_default_original_start_date();
+
+    let rows = db.query("SELECT id FROM calendars WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NULL;", &[&calendar_id, &access.get_tenant_id()])?;
+
+    if rows.is_empty()
+    {
+        return RouteResult::NotFound;
+    }
+
+    if let Some(parent_id) = &event.parent_id
+    {
+        let rows = db.query("SELECT created_by FROM events WHERE id = $1 AND calendar_id = $2 AND deleted_at IS NULL;", &[parent_id, &calendar_id])?;
+
+        match rows.get(0)
+        {
+            Some(row) if access.can_change_event(get_cell_from_row::<Option<String>>(row, "created_by")?.as_deref()) => {},
+            _ => return RouteResult::NotFound,
+        }
+    }
+
+    let created_by = access.get_principal().as_principal_ref().to_string();
+
     let query = "INSERT INTO events
     (
-        parent_event_id,
+        parent_event_id, original_start_date,
         start_date, start_time, end_date, end_time, rrule, exdates,
-        rdates, calendar_id
+        rdates, calendar_id, created_by
     )
 
-    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
     RETURNING *;";
 
-    let rows = db.query(query, &[
+    let mut transaction = db.transaction()?;
+
+    let rows = transaction.query(query, &[
         &event.parent_id,
+        &event.original_start_date,
         &event.start_date,
         &event.start_time,
         &event.end_date,
@@ -230,12 +363,19 @@ pub fn insert_event(mut db: PgsqlConn, _api_key: ApiKey, calendar_id: UuidParam,
         &event.recurrence.as_ref().map(|r| &r.exdates),
         &event.recurrence.as_ref().map(|r| &r.rdates),
         &calendar_id,
+        &created_by,
     ])?;
 
     if let Some(row) = rows.get(0)
     {
+        let event = Event::from_row(row)?.into_plain();
+
+        webhooks::enqueue(&mut transaction, &[Change::event(WebhookEventType::EventCreated, calendar_id.get_inner(), &event)])?;
+        scheduling::notify_change(&mut transaction, &event)?;
+        transaction.commit()?;
+
         RouteResult::Created(
-            Event::from_row(row)?.into_plain(),
+            event,
             //TODO: prepend host to url.
             format!("/api/calendars/{}/events/{}", calendar_id, get_cell_from_row::<Uuid>(row, "id")?)
         )
@@ -246,9 +386,103 @@ pub fn insert_event(mut db: PgsqlConn, _api_key: ApiKey, calendar_id: UuidParam,
     }
 }
 
+/// Inserts the event of a jCal or xCal document, which must be a VCALENDAR with
+/// one VEVENT (and the VTIMEZONEs it uses). It's imported like the VEVENTs of
+/// iCalendar files, so it overrides an instance of the recurring event with the
+/// same UID if it has a RECURRENCE-ID.
+fn insert_vcalendar(db: &mut PgsqlConn, access: &CalendarAccess<SelfWriter>, calendar_id: UuidParam, components: &[Component]) -> RouteResult<EventPlain>
+{
+    let vcalendar = match components
+    {
+        [vcalendar] if vcalendar.name == "VCALENDAR" => vcalendar,
+        _ => return RouteResult::BadRequest(None),
+    };
+
+    if vcalendar.components.iter().filter(|component| component.name == "VEVENT").count() != 1
+    {
+        return RouteResult::BadRequest(None);
+    }
+
+    let calendar = match calendar::get_calendar(&mut ***db, &access.get_tenant_id(), &calendar_id.into_inner())?
+    {
+        Some(calendar) => calendar,
+        None => return RouteResult::NotFound,
+    };
+
+    let created_by = access.get_principal().as_principal_ref().to_string();
+
+    let mut transaction = db.transaction()?;
+
+    let report = import::import_components(&mut transaction, &calendar, &created_by, components)?;
+
+    // The VEVENT was skipped or failed, e.g. because it's invalid or was already inserted.
+    let event_id = match report.imported.get(0)
+    {
+        Some(imported) => imported.event_id,
+        None => return RouteResult::BadRequest(None),
+    };
+
+    let row = transaction.query_one("SELECT * FROM events WHERE id = $1;", &[&event_id])?;
+    let event = Event::from_row(&row)?.into_plain();
+
+    if let Some(parent_id) = &event.parent_id
+    {
+        let row = transaction.query_one("SELECT created_by FROM events WHERE id = $1;", &[parent_id])?;
+
+        if !access.can_change_event(get_cell_from_row::<Option<String>>(&row, "created_by")?.as_deref())
+        {
+            return RouteResult::NotFound;
+        }
+    }
+
+    scheduling::notify_change(&mut transaction, &event)?;
+
+    transaction.commit()?;
+
+    RouteResult::Created(
+        event,
+        //TODO: prepend host to url.
+        format!("/api/calendars/{}/events/{}", calendar.get_id(), event_id)
+    )
+}
+
+/// Responds with the events in `result`, which are events of the calendar, as JSON,
+/// or as a jCal or xCal VCALENDAR if that's the format the request wants. `events`
+/// gets them from `result`'s value.
+fn negotiate<T>(db: &mut PgsqlConn, calendar_id: &Uuid, format: CalendarFormat, result: RouteResult<T>, events: impl Fn(&T) -> &[EventPlain]) -> EventsResult<T>
+{
+    if format == CalendarFormat::Json
+    {
+        return EventsResult::Plain(result);
+    }
+
+    let (value, location) = match result
+    {
+        RouteResult::Ok(value) => (value, None),
+        RouteResult::Created(value, location) => (value, Some(location)),
+        result => return EventsResult::Plain(result),
+    };
+
+    let vcalendar = match routes_ical::events_calendar(&mut ***db, calendar_id, events(&value))
+    {
+        Ok(vcalendar) => vcalendar,
+        Err(e) => return EventsResult::Plain(RouteResult::InternalError(Box::new(e))),
+    };
+
+    match format
+    {
+        CalendarFormat::XCal => EventsResult::XCal(xcal::to_xcal(&[vcalendar]), location),
+        _ => EventsResult::JCal(jcal::to_jcal(&vcalendar), location),
+    }
+}
+
+/// Updates the event's fields that are in the request's body. Requires the
+/// self-writer role, self-writers can only update events they created.
+///
+/// Response codes: 200, 403, 404, 500
 #[openapi]
 #[put("/calendars/<calendar_id>/events/<event_id>", data = "<event_data>")]
-pub fn update_event(mut db: PgsqlConn, _api_key: ApiKey, calendar_id: UuidParam, event_id: UuidParam, event_data: Json<EventPlain>) -> RouteResult<()>
+pub fn update_event(mut db: PgsqlConn, _access: CalendarAccess<SelfWriter>, calendar_id: UuidParam, event_id: UuidParam, event_data: Json<EventPlain>) -> RouteResult<()>
 {
     let mut query = "UPDATE events SET ".to_owned();
 
@@ -290,7 +524,7 @@ pub fn update_event(mut db: PgsqlConn, _api_key: ApiKey, calendar_id: UuidParam,
     // the character removed was not a comma.
     assert_eq!(query.remove(query.len() - 1), ',');
 
-    query = query.add(" WHERE calendar_id = $1 AND id = $2 RETURNING *;");
+    query = query.add(" WHERE calendar_id = $1 AND id = $2 AND deleted_at IS NULL RETURNING *;");
 
     if params.len() > 0
     {
@@ -298,59 +532,200 @@ pub fn update_event(mut db: PgsqlConn, _api_key: ApiKey, calendar_id: UuidParam,
         params.insert(1, &event_id);
 
 
-        db.execute(query.as_str(), &params)?;
+        let mut transaction = db.transaction()?;
+
+        let rows = transaction.query(query.as_str(), &params)?;
+
+        let events = rows
+            .iter()
+            .map(|row| Ok(Event::from_row(row)?.into_plain()))
+            .collect::<Result<Vec<EventPlain>, DatabaseError>>()?;
+
+        let changes: Vec<Change> = events
+            .iter()
+            .map(|event| Change::event(WebhookEventType::EventUpdated, calendar_id.get_inner(), event))
+            .collect();
+
+        webhooks::enqueue(&mut transaction, &changes)?;
+
+        for event in events.iter()
+        {
+            scheduling::notify_change(&mut transaction, event)?;
+        }
+
+        transaction.commit()?;
     }
 
     RouteResult::Ok(())
 }
 
+/// Deletes an event. It is kept as a tombstone until the configured
+/// retention period expires, see `tombstones`.
+///
+/// If the event is recurring, the events that override its instances (its children)
+/// are deleted as well, unless `detach_children` is true. Detached children
+/// become regular single events.
+///
+/// Requires the self-writer role, self-writers can only delete events they created.
+///
+/// Response codes: 200, 403, 404, 500
 #[openapi]
-#[get("/calendars/<calendar_id>/events/<event_id>/instances?<since>&<until>")]
+#[delete("/calendars/<calendar_id>/events/<event_id>?<detach_children>")]
+pub fn delete_event(mut db: PgsqlConn, _access: CalendarAccess<SelfWriter>, calendar_id: UuidParam, event_id: UuidParam, detach_children: Option<bool>) -> RouteResult<()>
+{
+    let mut transaction = db.transaction()?;
+
+    let rows = transaction.query("SELECT * FROM events WHERE calendar_id = $1 AND id = $2 AND deleted_at IS NULL FOR UPDATE;", &[&calendar_id, &event_id])?;
+
+    let event = match rows.get(0)
+    {
+        Some(row) => Event::from_row(row)?.into_plain(),
+        None => return RouteResult::NotFound,
+    };
+
+    let mut changes = vec![];
+
+    // Children are detached (an update) or deleted along with the event.
+    let (children_query, children_change) = if detach_children.unwrap_or(false)
+    {
+        (
+            "UPDATE events SET parent_event_id = NULL, original_start_date = NULL WHERE parent_event_id = $1 AND deleted_at IS NULL RETURNING *;",
+            WebhookEventType::EventUpdated,
+        )
+    }
+    else
+    {
+        (
+            "UPDATE events SET deleted_at = NOW() WHERE parent_event_id = $1 AND deleted_at IS NULL RETURNING *;",
+            WebhookEventType::EventDeleted,
+        )
+    };
+
+    for row in transaction.query(children_query, &[&event_id])?.iter()
+    {
+        let mut change = Change::event(children_change, calendar_id.get_inner(), &Event::from_row(row)?.into_plain());
+
+        // Detached children don't have a parent anymore, but subscriptions
+        // to the event's instances still have to learn about them.
+        change.parent_event_id = Some(event_id.get_inner());
+
+        changes.push(change);
+    }
+
+    transaction.execute("UPDATE events SET deleted_at = NOW() WHERE id = $1;", &[&event_id])?;
+
+    changes.push(Change::event(WebhookEventType::EventDeleted, calendar_id.get_inner(), &event));
+    webhooks::enqueue(&mut transaction, &changes)?;
+
+    // Attendees of the event get a CANCEL, those of its parent's series a REQUEST without it.
+    scheduling::notify_change(&mut transaction, &event)?;
+
+    transaction.commit()?;
+
+    RouteResult::Ok(())
+}
+
+/// Lists the instances of a recurring event.
+///
+/// If `with_overrides` is true, child events that override instances
+/// are merged into the result, ordered by the date they happen at, and each
+/// entry's `instance_origin` tells whether it was generated or overridden.
+///
+/// Response codes: 200, 404, 500
+#[openapi]
+#[get("/calendars/<calendar_id>/events/<event_id>/instances?<since>&<until>&<with_overrides>")]
 pub fn get_instances(
     mut db: PgsqlConn,
-    _api_key: ApiKey,
+    access: CalendarAccess<Reader>,
     calendar_id: UuidParam,
     event_id: UuidParam,
     since: Option<NaiveDateParam>,
     until: Option<NaiveDateParam>,
+    with_overrides: Option<bool>,
     common_params: CommonQueryParams,
-) -> RouteResult<Vec<EventPlain>>
+) -> WithSyncToken<RouteResult<Vec<EventPlain>>>
 {
-    if let Some(event) = get_event_by_id(&mut db, calendar_id, event_id)?
-    {
-        match event
+    let tenant_id = access.get_tenant_id();
+
+    with_sync_token(&mut db, calendar_id.get_inner(), |db| {
+        if let Some(event) = get_event_by_id(db, tenant_id, calendar_id, event_id)?
         {
-            Event::Recurring(event) => RouteResult::Ok(
-
-                event
-                    .generate_instances(
-                        since.map(|x| x.into_inner()),
-                        until.map(|x| x.into_inner()),
-                        common_params.offset() as usize,
-                        common_params.page_size() as usize
-                    )?
-                    .into_iter()
-                    .map(|e| e.into_plain())
-                    .collect()
-
-            ),
-            Event::Single(_) => RouteResult::NotFound,
+            match event
+            {
+                Event::Recurring(event) if with_overrides.unwrap_or(false) => RouteResult::Ok(
+
+                    event
+                        .expand_instances(
+                            db,
+                            since.map(|x| x.into_inner()),
+                            until.map(|x| x.into_inner()),
+                            common_params.offset() as usize,
+                            common_params.page_size() as usize
+                        )?
+                        .into_iter()
+                        .map(|e| e.into_plain())
+                        .collect()
+
+                ),
+                Event::Recurring(event) => RouteResult::Ok(
+
+                    event
+                        .generate_instances(
+                            since.map(|x| x.into_inner()),
+                            until.map(|x| x.into_inner()),
+                            common_params.offset() as usize,
+                            common_params.page_size() as usize
+                        )?
+                        .into_iter()
+                        .map(|e| e.into_plain())
+                        .collect()
+
+                ),
+                Event::Single(_) => RouteResult::NotFound,
+            }
         }
-    }
-    else
-    {
-        RouteResult::NotFound
-    }
+        else
+        {
+            RouteResult::NotFound
+        }
+    })
 }
 
+/// Lists the calendar's events. If `since` and/or `until` are set, only events
+/// that match the window between them according to `range` are returned
+/// (`contained` by default). Recurring events are returned if any of their
+/// instances matches the window.
+///
+/// Responds with a jCal or xCal VCALENDAR (see docs/ical.md) if the request
+/// accepts `application/calendar+json` or `application/calendar+xml`.
+///
+/// Response codes: 200, 400, 500
 #[openapi]
-#[get("/calendars/<calendar_id>/events?<since>&<until>")]
+#[get("/calendars/<calendar_id>/events?<since>&<until>&<range>")]
 pub fn list_events(
     mut db: PgsqlConn,
-    _api_key: ApiKey,
+    _access: CalendarAccess<Reader>,
     calendar_id: UuidParam,
     since: Option<NaiveDateOrTime>,
     until: Option<NaiveDateOrTime>,
+    range: Option<RangeMode>,
+    common_params: CommonQueryParams,
+    format: CalendarFormat,
+) -> WithSyncToken<EventsResult<Vec<EventPlain>>>
+{
+    let calendar_id = calendar_id.into_inner();
+
+    let WithSyncToken(result, token) = with_sync_token(&mut db, calendar_id, |db| query_events(db, calendar_id, since, until, range, common_params));
+
+    WithSyncToken(negotiate(&mut db, &calendar_id, format, result, |events| events.as_slice()), token)
+}
+
+fn query_events(
+    db: &mut PgsqlConn,
+    calendar_id: Uuid,
+    since: Option<NaiveDateOrTime>,
+    until: Option<NaiveDateOrTime>,
+    range: Option<RangeMode>,
     common_params: CommonQueryParams,
 ) -> RouteResult<Vec<EventPlain>>
 {
@@ -361,66 +736,223 @@ pub fn list_events(
         return RouteResult::BadRequest(None);
     }
 
-    // A query parameter can only have one type and we want to be able to
-    // use `since` and `until` as either a date or a date-time, so we have
-    // a pair of parameters for each variable, one for each type. If, for example,
-    // `since` is a date, `$2` will be `NULL`.
+    let range = range.unwrap_or(RangeMode::Contained);
+    let window_start = since.as_ref().and_then(|x| x.as_window_start());
+    let window_end = until.as_ref().and_then(|x| x.as_window_end());
+
+    // Events without a start_time (all-day events) start at the midnight of their
+    // start_date and end at the midnight after their end_date.
+    //
+    // We can't know whether a recurring event has instances in the window
+    // from SQL, so every recurring event that starts before the window ends
+    // is queried and then checked by generating its instances.
     let query = "
         SELECT * FROM events
         WHERE
             calendar_id = $1
-            AND ($2::TIMESTAMP IS NULL OR start_date + start_time >= $2::TIMESTAMP)
-            AND ($3::DATE IS NULL OR start_date >= $3::DATE)
-            AND ($4::TIMESTAMP IS NULL OR end_date + end_time <= $4::TIMESTAMP)
-            AND ($5::DATE IS NULL OR end_date <= $5::DATE)
-        OFFSET $6
-        LIMIT $7;
+            AND deleted_at IS NULL
+            AND (
+                (
+                    rrule IS NULL
+                    AND (
+                        (
+                            NOT $4::BOOLEAN
+                            AND ($2::TIMESTAMP IS NULL OR start_date + COALESCE(start_time, '00:00'::TIME) >= $2::TIMESTAMP)
+                            AND ($3::TIMESTAMP IS NULL OR COALESCE(end_date + end_time, (end_date + 1) + '00:00'::TIME) <= $3::TIMESTAMP)
+                        )
+                        OR
+                        (
+                            $4::BOOLEAN
+                            AND ($2::TIMESTAMP IS NULL OR COALESCE(end_date + end_time, (end_date + 1) + '00:00'::TIME) > $2::TIMESTAMP)
+                            AND ($3::TIMESTAMP IS NULL OR start_date + COALESCE(start_time, '00:00'::TIME) < $3::TIMESTAMP)
+                        )
+                    )
+                )
+                OR
+                (
+                    rrule IS NOT NULL
+                    AND ($3::TIMESTAMP IS NULL OR start_date + COALESCE(start_time, '00:00'::TIME) < $3::TIMESTAMP)
+                )
+            )
+        ORDER BY start_date, start_time, id
+        OFFSET $5
+        LIMIT $6;
     ";
 
-    let rows = db.query(query, &[
-        &calendar_id,
+    // Recurring events are only filtered after they're queried, so OFFSET and
+    // LIMIT can't be applied in SQL. Events are queried in batches instead,
+    // until there are enough matching ones to skip `offset` and fill the page.
+    let offset = common_params.offset() as usize;
+    let page_size = common_params.page_size() as usize;
 
-        &since.as_ref().and_then(|x| x.as_naive_date_time() .map(|dt| dt.clone())),
-        &since.as_ref().and_then(|x| x.as_naive_date()      .map(|d|   d.clone())),
+    let mut events = vec![];
+    let mut skipped = 0;
+    let mut batch_offset = 0;
 
-        &until.as_ref().and_then(|x| x.as_naive_date_time() .map(|dt| dt.clone())),
-        &until.as_ref().and_then(|x| x.as_naive_date()      .map(|d|   d.clone())),
+    loop
+    {
+        let rows = db.query(query, &[
+            &calendar_id,
 
-        &common_params.offset(),
-        &common_params.page_size(),
-    ]);
+            &window_start,
+            &window_end,
+            &(range == RangeMode::Overlaps),
 
-    RouteResult::Ok(
-        rows?
-            .into_iter()
-            .map::<Result<EventPlain, _>, _>(|r| Event::from_row(&r).map(|e| e.into_plain()))
-            .collect::<Result<Vec<EventPlain>, _>>()?
-    )
+            &batch_offset,
+            &QUERY_BATCH_SIZE,
+        ])?;
+
+        batch_offset += rows.len() as i64;
+
+        for row in rows.iter()
+        {
+            let event = Event::from_row(row)?;
+
+            let matches = match &event
+            {
+                Event::Recurring(e) => e.has_instance_in_range(range, window_start, window_end),
+                Event::Single(_) => true,
+            };
+
+            if !matches
+            {
+                continue;
+            }
+
+            if skipped < offset
+            {
+                skipped += 1;
+                continue;
+            }
+
+            events.push(event.into_plain());
+
+            if events.len() == page_size
+            {
+                return RouteResult::Ok(events);
+            }
+        }
+
+        if rows.len() < QUERY_BATCH_SIZE as usize
+        {
+            return RouteResult::Ok(events);
+        }
+    }
 }
 
+/// Where the changes listed by `check_for_changes` and `check_for_calendar_changes`
+/// start: after a sync token, or at a date/date-time compared to `last_modified`,
+/// which can miss changes committed out of order by concurrent requests.
+pub enum ChangesSince
+{
+    Token(SyncToken),
+    Timestamp(NaiveDateOrTime),
+}
+
+impl ChangesSince
+{
+    /// Exactly one of `since` (a date or date-time) and `sync_token` must be set.
+    /// Fails with a 400 otherwise, or with a `SyncResult::InvalidToken` if the
+    /// token can't be parsed.
+    pub fn from_params<T>(since: Option<NaiveDateOrTime>, sync_token: Option<String>) -> Result<ChangesSince, SyncResult<T>>
+    {
+        match (since, sync_token)
+        {
+            (Some(since), None) if since.as_naive_time().is_none() => Ok(ChangesSince::Timestamp(since)),
+            (None, Some(token)) => SyncToken::from_str(&token)
+                .map(ChangesSince::Token)
+                .map_err(|_| SyncResult::InvalidToken),
+            _ => Err(SyncResult::Result(RouteResult::BadRequest(None))),
+        }
+    }
+
+    /// The position in the change sequence the changes start after, for tokens, looked
+    /// up with `seq_for`. Fails with a `SyncResult::InvalidToken` if the token expired.
+    pub fn after_seq<T>(&self, seq_for: impl FnOnce(&SyncToken) -> Result<Option<i64>, DatabaseError>) -> Result<Option<i64>, SyncResult<T>>
+    {
+        match self
+        {
+            ChangesSince::Token(token) => match seq_for(token)
+            {
+                Ok(Some(seq)) => Ok(Some(seq)),
+                Ok(None) => Err(SyncResult::InvalidToken),
+                Err(e) => Err(SyncResult::Result(RouteResult::InternalError(Box::new(e)))),
+            },
+            ChangesSince::Timestamp(_) => Ok(None),
+        }
+    }
+
+    pub fn as_naive_date_time(&self) -> Option<&NaiveDateTime>
+    {
+        match self
+        {
+            ChangesSince::Timestamp(since) => since.as_naive_date_time(),
+            ChangesSince::Token(_) => None,
+        }
+    }
+
+    pub fn as_naive_date(&self) -> Option<&NaiveDate>
+    {
+        match self
+        {
+            ChangesSince::Timestamp(since) => since.as_naive_date(),
+            ChangesSince::Token(_) => None,
+        }
+    }
+}
+
+/// Lists events that changed after `sync_token`, or that were modified at or after
+/// `since`, ordered by when they changed. Deleted events are included with `deleted`
+/// set to true. Only one of `since` and `sync_token` can be set.
+///
+/// Prefer `sync_token` (e.g. the `Sync-Token` of a previous response), `since` can
+/// miss changes committed out of order by concurrent requests. Like `sync_events`,
+/// returns 403 with a `valid-sync-token` error if the token is invalid or expired,
+/// the client should then read the calendar again.
+///
+/// Response codes: 200, 400, 403, 500
 #[openapi]
-#[get("/calendars/<calendar_id>/events/changes?<since>")]
+#[get("/calendars/<calendar_id>/events/changes?<since>&<sync_token>")]
 pub fn check_for_changes(
     mut db: PgsqlConn,
-    _api_key: ApiKey,
+    _access: CalendarAccess<Reader>,
     common_params: CommonQueryParams,
     calendar_id: UuidParam,
-    since: NaiveDateOrTime,
-) -> RouteResult<Vec<EventPlain>>
+    since: Option<NaiveDateOrTime>,
+    sync_token: Option<String>,
+) -> WithSyncToken<SyncResult<Vec<EventPlain>>>
 {
-    if since.as_naive_time().is_some()
+    let calendar_id = calendar_id.into_inner();
+
+    let since = match ChangesSince::from_params(since, sync_token)
     {
-        return RouteResult::BadRequest(None);
-    }
+        Ok(since) => since,
+        Err(result) => return WithSyncToken(result, None),
+    };
 
+    // This doesn't read any events, so it can come before the current token is taken.
+    let after_seq = match since.after_seq(|token| token.seq_for_calendar(&mut **db, &calendar_id))
+    {
+        Ok(after_seq) => after_seq,
+        Err(result) => return WithSyncToken(result, None),
+    };
+
+    let WithSyncToken(result, token) = with_sync_token(&mut db, calendar_id, |db| query_changes(db, calendar_id, since, after_seq, common_params));
+    WithSyncToken(SyncResult::Result(result), token)
+}
+
+fn query_changes(db: &mut PgsqlConn, calendar_id: Uuid, since: ChangesSince, after_seq: Option<i64>, common_params: CommonQueryParams) -> RouteResult<Vec<EventPlain>>
+{
     let query = "
         SELECT * FROM events
         WHERE
             calendar_id = $1
             AND ($2::TIMESTAMP IS NULL OR last_modified >= $2::TIMESTAMP)
             AND ($3::DATE IS NULL OR last_modified >= $3::DATE)
-        OFFSET $4
-        LIMIT $5;
+            AND ($4::BIGINT IS NULL OR change_seq > $4::BIGINT)
+        ORDER BY change_seq
+        OFFSET $5
+        LIMIT $6;
     ";
 
     let rows = db.query(query, &[
@@ -428,7 +960,7 @@ pub fn check_for_changes(
 
         &since.as_naive_date_time(),
         &since.as_naive_date(),
-
+        &after_seq,
 
         &common_params.offset(),
         &common_params.page_size(),
@@ -437,7 +969,260 @@ pub fn check_for_changes(
     RouteResult::Ok (
         rows?
             .into_iter()
-            .map::<Result<EventPlain, _>, _>(|r| Event::from_row(&r).map(|e| e.into_plain()))
+            .map::<Result<EventPlain, _>, _>(|r| {
+                // Deleted events are tombstones, they still have all of the
+                // event's data but are marked as deleted.
+                let deleted_at: Option<NaiveDateTime> = get_cell_from_row(&r, "deleted_at")?;
+
+                Event::from_row(&r).map(|e| {
+                    let mut plain = e.into_plain();
+                    plain.deleted = deleted_at.is_some();
+                    plain
+                })
+            })
             .collect::<Result<Vec<EventPlain>, _>>()?
     )
-}
\ No newline at end of file
+}
+
+/// Lists every occurrence of the calendar's events that happens between `since`
+/// and `until` (both inclusive): single events, instances of recurring events and
+/// the events that override those instances, sorted by start.
+///
+/// This is paginated with a cursor instead of an offset, to get the next page pass the
+/// response's `next_cursor` as the `cursor` parameter.
+///
+/// Response codes: 200, 400, 500
+#[openapi]
+#[get("/calendars/<calendar_id>/agenda?<since>&<until>&<cursor>")]
+pub fn get_agenda(
+    mut db: PgsqlConn,
+    _access: CalendarAccess<Reader>,
+    calendar_id: UuidParam,
+    since: NaiveDateParam,
+    until: NaiveDateParam,
+    cursor: Option<String>,
+    common_params: CommonQueryParams,
+) -> WithSyncToken<RouteResult<AgendaPage>>
+{
+    let calendar_id = calendar_id.into_inner();
+    let since = since.into_inner();
+    let until = until.into_inner();
+
+    if since > until
+    {
+        return WithSyncToken(RouteResult::BadRequest(None), None);
+    }
+
+    let cursor = match cursor.map(|c| AgendaCursor::from_str(&c)).transpose()
+    {
+        Ok(cursor) => cursor,
+        Err(_) => return WithSyncToken(RouteResult::BadRequest(None), None),
+    };
+
+    with_sync_token(&mut db, calendar_id, |db| RouteResult::Ok(
+        agenda::get_agenda(
+            db,
+            calendar_id,
+            since,
+            until,
+            cursor,
+            common_params.page_size() as usize
+        )?
+    ))
+}
+
+
+/// Gets the calendar's events that changed since `token` was issued, ordered by when
+/// they changed. Deleted events are included with `deleted` set to true. Without a
+/// token, every event of the calendar is returned (except for deleted ones).
+///
+/// If `has_more` is true there are more changes, sync again right away with the returned
+/// `sync_token` to get them. `offset` is ignored.
+///
+/// If the token is invalid or too old (changes older than the tombstone retention
+/// period are forgotten) this responds with 403 and a `valid-sync-token` error,
+/// the client should then discard its copy of the calendar and sync again without a token.
+///
+/// Response codes: 200, 403, 404, 500
+#[openapi]
+#[get("/calendars/<calendar_id>/sync?<token>")]
+pub fn sync_events(
+    mut db: PgsqlConn,
+    _access: CalendarAccess<Reader>,
+    calendar_id: UuidParam,
+    token: Option<String>,
+    common_params: CommonQueryParams,
+) -> SyncResult<SyncResponse>
+{
+    let calendar_id = calendar_id.into_inner();
+
+    let token = match token.map(|t| SyncToken::from_str(&t)).transpose()
+    {
+        Ok(token) => token,
+        Err(_) => return SyncResult::InvalidToken,
+    };
+
+    match sync::get_changes(&mut **db, calendar_id, token, common_params.page_size() as usize)
+    {
+        Ok(SyncOutcome::Changes(response)) => SyncResult::Result(RouteResult::Ok(response)),
+        Ok(SyncOutcome::InvalidToken) => SyncResult::InvalidToken,
+        Ok(SyncOutcome::CalendarNotFound) => SyncResult::Result(RouteResult::NotFound),
+        Err(e) => SyncResult::Result(RouteResult::InternalError(Box::new(e))),
+    }
+}
+
+
+/// Gets the calendar's sync token, then runs `f` and adds the token
+/// to its response in the `Sync-Token` header.
+///
+/// The token is taken before `f` reads anything, so that changes committed
+/// in the meantime are sent again on the next sync instead of being lost.
+fn with_sync_token<T>(db: &mut PgsqlConn, calendar_id: Uuid, f: impl FnOnce(&mut PgsqlConn) -> RouteResult<T>) -> WithSyncToken<RouteResult<T>>
+{
+    match SyncToken::current(&mut ***db, &calendar_id)
+    {
+        Ok(token) => WithSyncToken(f(db), token.map(|t| t.to_string())),
+        Err(e) => WithSyncToken(RouteResult::InternalError(Box::new(e)), None),
+    }
+}
+
+/// These tests send requests to the server, see `test_helpers::http_client`.
+#[cfg(test)]
+mod test
+{
+    use crate::test_helpers::{connect, insert_tenant, insert_calendar, insert_api_key, http_client};
+    use rocket::http::{Header, ContentType, Status};
+    use postgres::Client;
+    use uuid::Uuid;
+
+    fn insert_recurring_event(db: &mut Client, calendar_id: &Uuid) -> Uuid
+    {
+        db.query_one(
+            "INSERT INTO events (calendar_id, start_date, end_date, rrule) VALUES ($1, '2021-01-01', '2021-01-01', 'FREQ=DAILY') RETURNING id;",
+            &[calendar_id]
+        ).unwrap().get("id")
+    }
+
+    #[test]
+    #[ignore]
+    fn tenants_cant_reach_each_others_events()
+    {
+        let mut db = connect();
+
+        let tenant_a = insert_tenant(&mut db);
+        let tenant_b = insert_tenant(&mut db);
+        let calendar_a = insert_calendar(&mut db, &tenant_a);
+        let calendar_b = insert_calendar(&mut db, &tenant_b);
+        let event_b = insert_recurring_event(&mut db, &calendar_b);
+        let key_a = insert_api_key(&mut db, &tenant_a, &["WRITE"], None);
+
+        let client = http_client();
+
+        for uri in &[
+            format!("/api/calendars/{}", calendar_b),
+            format!("/api/calendars/{}/events", calendar_b),
+            format!("/api/calendars/{}/events/{}", calendar_b, event_b),
+            format!("/api/calendars/{}/events/{}", calendar_a, event_b),
+            format!("/api/calendars/{}/events/{}/instances", calendar_a, event_b),
+        ]
+        {
+            let response = client.get(uri.as_str()).header(Header::new("Authorization", key_a.clone())).dispatch();
+            assert_eq!(response.status(), Status::NotFound, "GET {}", uri);
+        }
+
+        let mut response = client.get("/api/calendars").header(Header::new("Authorization", key_a.clone())).dispatch();
+        let body = response.body_string().unwrap();
+
+        assert!(body.contains(&calendar_a.to_string()));
+        assert!(!body.contains(&calendar_b.to_string()));
+    }
+
+    #[test]
+    #[ignore]
+    fn events_only_override_events_of_their_calendar()
+    {
+        let mut db = connect();
+
+        let tenant_a = insert_tenant(&mut db);
+        let tenant_b = insert_tenant(&mut db);
+        let calendar_a = insert_calendar(&mut db, &tenant_a);
+        let other_calendar_a = insert_calendar(&mut db, &tenant_a);
+        let calendar_b = insert_calendar(&mut db, &tenant_b);
+        let key_a = insert_api_key(&mut db, &tenant_a, &["WRITE"], None);
+
+        let client = http_client();
+
+        let insert_child = |parent_id: Uuid| client.post(format!("/api/calendars/{}/events", calendar_a))
+            .header(Header::new("Authorization", key_a.clone()))
+            .header(ContentType::JSON)
+            .body(format!(r#"{{"parent_id": "{}", "start_date": "2021-01-02", "end_date": "2021-01-02"}}"#, parent_id))
+            .dispatch()
+            .status();
+
+        assert_eq!(insert_child(insert_recurring_event(&mut db, &calendar_b)), Status::NotFound);
+        assert_eq!(insert_child(insert_recurring_event(&mut db, &other_calendar_a)), Status::NotFound);
+        assert_eq!(insert_child(insert_recurring_event(&mut db, &calendar_a)), Status::Created);
+    }
+
+    #[test]
+    #[ignore]
+    fn invalid_sync_tokens_get_the_same_response_everywhere()
+    {
+        let mut db = connect();
+
+        let tenant_id = insert_tenant(&mut db);
+        let calendar_id = insert_calendar(&mut db, &tenant_id);
+        let key = insert_api_key(&mut db, &tenant_id, &["READ"], None);
+
+        let client = http_client();
+
+        for uri in &[
+            format!("/api/calendars/{}/sync?token=garbage", calendar_id),
+            format!("/api/calendars/{}/events/changes?sync_token=garbage", calendar_id),
+            "/api/calendars/changes?sync_token=garbage".to_owned(),
+        ]
+        {
+            let mut response = client.get(uri.as_str()).header(Header::new("Authorization", key.clone())).dispatch();
+
+            assert_eq!(response.status(), Status::Forbidden, "GET {}", uri);
+            assert!(response.body_string().unwrap().contains("valid-sync-token"), "GET {}", uri);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn agenda_lists_overridden_instances_once()
+    {
+        let mut db = connect();
+
+        let tenant_id = insert_tenant(&mut db);
+        let calendar_id = insert_calendar(&mut db, &tenant_id);
+        let event_id = insert_recurring_event(&mut db, &calendar_id);
+        let key = insert_api_key(&mut db, &tenant_id, &["READ"], None);
+
+        // Overrides the instance of 2021-01-02 without adding it to the exdates.
+        let child_id: Uuid = db.query_one(
+            "INSERT INTO events (calendar_id, parent_event_id, original_start_date, start_date, end_date) VALUES ($1, $2, '2021-01-02', '2021-01-03', '2021-01-03') RETURNING id;",
+            &[&calendar_id, &event_id]
+        ).unwrap().get("id");
+
+        let client = http_client();
+
+        let mut response = client.get(format!("/api/calendars/{}/agenda?since=2021-01-01&until=2021-01-04", calendar_id))
+            .header(Header::new("Authorization", key))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let page: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let items = page["items"].as_array().unwrap();
+
+        let overridden = items.iter()
+            .filter(|item| item["original_start_date"] == "2021-01-02")
+            .collect::<Vec<_>>();
+
+        assert_eq!(items.len(), 4);
+        assert_eq!(overridden.len(), 1);
+        assert_eq!(overridden[0]["id"], child_id.to_string());
+    }
+}
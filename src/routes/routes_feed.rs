@@ -0,0 +1,131 @@
+use crate::connection_pool::PgsqlConn;
+use rocket_route_result::RouteResult;
+use rocket_contrib::json::Json;
+use crate::database_helpers::{UuidParam, get_cell_from_row};
+use crate::database_error::DatabaseError;
+use crate::authentication::calendar_access::{CalendarAccess, Owner};
+use crate::feeds::{self, Feed, FeedWithToken, NewFeed, NewFeedError};
+use crate::routes::conditional_request::{ConditionalRequest, Validators};
+use crate::routes::responders::{CalendarFileResult, WithValidators};
+use crate::routes::routes_ical::render_calendar;
+use crate::routes::calendar_format::CalendarFileFormat;
+use postgres::GenericClient;
+use uuid::Uuid;
+
+/// Creates a feed of the calendar, a URL anyone can get the calendar from as an
+/// iCalendar file, without authentication. The response has the feed's token,
+/// which can't be retrieved later. Requires the owner role.
+///
+/// Response codes: 201, 400, 403, 404, 500
+#[openapi]
+#[post("/calendars/<calendar_id>/feeds", data = "<new_feed>")]
+pub fn create_feed(mut db: PgsqlConn, _access: CalendarAccess<Owner>, calendar_id: UuidParam, new_feed: Json<NewFeed>) -> RouteResult<FeedWithToken>
+{
+    match feeds::create(&mut **db, &calendar_id.into_inner(), &new_feed)
+    {
+        Ok(feed) =>
+        {
+            let location = feed.path.clone();
+            RouteResult::Created(feed, location)
+        },
+        Err(NewFeedError::Database(e)) => RouteResult::InternalError(Box::new(e)),
+        Err(NewFeedError::Random(e)) => RouteResult::InternalError(Box::new(e)),
+    }
+}
+
+/// Lists the calendar's feeds, including revoked ones. Requires the owner role.
+///
+/// Response codes: 200, 403, 404, 500
+#[openapi]
+#[get("/calendars/<calendar_id>/feeds")]
+pub fn list_feeds(mut db: PgsqlConn, _access: CalendarAccess<Owner>, calendar_id: UuidParam) -> RouteResult<Vec<Feed>>
+{
+    RouteResult::Ok(
+        feeds::list(&mut **db, &calendar_id.into_inner())?
+    )
+}
+
+/// Revokes one of the calendar's feeds, its URL stops working right away.
+/// Requires the owner role.
+///
+/// Response codes: 200, 403, 404, 500
+#[openapi]
+#[delete("/calendars/<calendar_id>/feeds/<feed_id>")]
+pub fn revoke_feed(mut db: PgsqlConn, _access: CalendarAccess<Owner>, calendar_id: UuidParam, feed_id: UuidParam) -> RouteResult<()>
+{
+    if feeds::revoke(&mut **db, &calendar_id.into_inner(), &feed_id.into_inner())?
+    {
+        RouteResult::Ok(())
+    }
+    else
+    {
+        RouteResult::NotFound
+    }
+}
+
+/// The validators of a calendar's iCalendar file: its sequence number (see
+/// db_schema/07.sql), which changes with every change to the calendar or its
+/// events, and when the calendar or one of its events last changed.
+fn feed_validators(db: &mut impl GenericClient, calendar_id: &Uuid) -> Result<Option<Validators>, DatabaseError>
+{
+    let query = "
+        SELECT
+            change_seq,
+            GREATEST(last_modified, (SELECT MAX(last_modified) FROM events WHERE calendar_id = $1)) AS last_modified
+        FROM calendars
+        WHERE id = $1 AND deleted_at IS NULL;
+    ";
+
+    match db.query(query, &[calendar_id])?.get(0)
+    {
+        Some(row) => Ok(Some(Validators::new(
+            get_cell_from_row::<i64>(row, "change_seq")?.to_string(),
+            get_cell_from_row(row, "last_modified")?,
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Gets a feed's calendar as an iCalendar file. Doesn't require authentication, the
+/// feed's token is the path's last segment (followed by `.ics`). Supports `ETag`
+/// and `Last-Modified` validators, so apps can poll it cheaply.
+///
+/// Response codes: 200, 304, 404, 500
+#[openapi]
+#[get("/feeds/<feed_file>")]
+pub fn get_feed(mut db: PgsqlConn, feed_file: String, conditional: ConditionalRequest) -> WithValidators<CalendarFileResult>
+{
+    let token = match feed_file.strip_suffix(".ics")
+    {
+        Some(token) => token,
+        None => return WithValidators(CalendarFileResult::Result(RouteResult::NotFound), None),
+    };
+
+    let result = feeds::find_calendar(&mut **db, token)
+        .and_then(|calendar| match calendar
+        {
+            // Get the validators before the file so that changes committed
+            // in between make the next request get the file again.
+            Some((tenant_id, calendar_id)) => Ok(feed_validators(&mut **db, &calendar_id)?.map(|validators| (tenant_id, calendar_id, validators))),
+            None => Ok(None),
+        });
+
+    let (tenant_id, calendar_id, validators) = match result
+    {
+        Ok(Some(found)) => found,
+        Ok(None) => return WithValidators(CalendarFileResult::Result(RouteResult::NotFound), None),
+        Err(e) => return WithValidators(CalendarFileResult::Result(RouteResult::InternalError(Box::new(e))), None),
+    };
+
+    if conditional.is_fresh(&validators)
+    {
+        return WithValidators(CalendarFileResult::NotModified, Some(validators));
+    }
+
+    match render_calendar(&mut **db, &tenant_id, &calendar_id, CalendarFileFormat::ICalendar)
+    {
+        Ok(Some(ics)) => WithValidators(CalendarFileResult::File(ics, CalendarFileFormat::ICalendar), Some(validators)),
+        Ok(None) => WithValidators(CalendarFileResult::Result(RouteResult::NotFound), None),
+        Err(e) => WithValidators(CalendarFileResult::Result(RouteResult::InternalError(Box::new(e))), None),
+    }
+}
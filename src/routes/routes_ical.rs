@@ -0,0 +1,240 @@
+use crate::connection_pool::PgsqlConn;
+use rocket_route_result::RouteResult;
+use crate::database_helpers::{FromRow, UuidParam, CalendarFileParam, get_cell_from_row};
+use crate::database_error::DatabaseError;
+use crate::authentication::calendar_access::{CalendarAccess, Reader, Writer};
+use crate::routes::responders::CalendarFileResult;
+use crate::routes::calendar_format::{CalendarFileFormat, xcal_media_type};
+use crate::calendar;
+use crate::event::{Event, EventPlain, ToPlain};
+use crate::ical::{export, xcal, Component};
+use crate::ical::import::{self, ImportReport, ImportError};
+use postgres::GenericClient;
+use uuid::Uuid;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use rocket::{Request, Data, Outcome};
+use rocket::data::{self, FromDataSimple};
+use rocket::http::Status;
+use rocket_okapi::request::OpenApiFromData;
+use rocket_okapi::gen::OpenApiGenerator;
+use okapi::openapi3::{RequestBody, MediaType};
+
+/// Default maximum size of imported files, can be changed with the `ics`
+/// limit (see Rocket's `limits` configuration).
+const DEFAULT_ICS_LIMIT: u64 = 10 * 1024 * 1024;
+
+/// The body of an import request or a reply, an iCalendar file or, if its `Content-Type`
+/// is `application/calendar+xml`, an xCal file.
+pub struct CalendarFileData(pub(super) String, pub(super) CalendarFileFormat);
+
+impl FromDataSimple for CalendarFileData
+{
+    type Error = io::Error;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error>
+    {
+        let limit = request.limits().get("ics").unwrap_or(DEFAULT_ICS_LIMIT);
+
+        let mut file = String::new();
+
+        // Read one byte more than the limit to know if the file is over it.
+        if let Err(e) = data.open().take(limit + 1).read_to_string(&mut file)
+        {
+            return Outcome::Failure((Status::BadRequest, e));
+        }
+
+        if file.len() as u64 > limit
+        {
+            return Outcome::Failure((Status::PayloadTooLarge, io::Error::new(io::ErrorKind::Other, "The file is too large.")));
+        }
+
+        let format = match request.content_type()
+        {
+            Some(content_type) if *content_type.media_type() == xcal_media_type() => CalendarFileFormat::XCal,
+            _ => CalendarFileFormat::ICalendar,
+        };
+
+        Outcome::Success(CalendarFileData(file, format))
+    }
+}
+
+impl<'a> OpenApiFromData<'a> for CalendarFileData
+{
+    fn request_body(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<RequestBody>
+    {
+        let mut request_body = RequestBody {
+            required: true,
+            ..Default::default()
+        };
+
+        request_body.content.insert(
+            "text/calendar".to_owned(),
+            MediaType {
+                schema: Some(gen.json_schema::<String>()),
+                ..Default::default()
+            }
+        );
+
+        request_body.content.insert(
+            xcal_media_type().to_string(),
+            MediaType {
+                schema: Some(gen.json_schema::<String>()),
+                ..Default::default()
+            }
+        );
+
+        Ok(request_body)
+    }
+}
+
+/// Gets the calendar's events, parents before their children, and the UIDs of those
+/// that were imported. If `event_id` is Some only gets that event's series: the event,
+/// its parent and its parent's children.
+fn get_events(db: &mut impl GenericClient, calendar_id: &Uuid, event_id: Option<&Uuid>) -> Result<(Vec<EventPlain>, HashMap<Uuid, String>), DatabaseError>
+{
+    let query = "
+        SELECT * FROM events
+        WHERE
+            calendar_id = $1
+            AND deleted_at IS NULL
+            AND (
+                $2::UUID IS NULL
+                OR COALESCE(parent_event_id, id) = (SELECT COALESCE(parent_event_id, id) FROM events WHERE id = $2 AND calendar_id = $1 AND deleted_at IS NULL)
+            )
+        ORDER BY parent_event_id IS NOT NULL, start_date, id;
+    ";
+
+    let mut events = vec![];
+    let mut uids = HashMap::new();
+
+    for row in db.query(query, &[calendar_id, &event_id])?.iter()
+    {
+        if let Some(uid) = get_cell_from_row::<Option<String>>(row, "ical_uid")?
+        {
+            uids.insert(get_cell_from_row(row, "id")?, uid);
+        }
+
+        events.push(Event::from_row(row)?.into_plain());
+    }
+
+    Ok((events, uids))
+}
+
+/// The calendar and its events as a file of the given format. Returns None if the
+/// calendar doesn't exist.
+pub(super) fn render_calendar(db: &mut impl GenericClient, tenant_id: &Uuid, calendar_id: &Uuid, format: CalendarFileFormat) -> Result<Option<String>, DatabaseError>
+{
+    let calendar = match calendar::get_calendar(db, tenant_id, calendar_id)?
+    {
+        Some(calendar) => calendar,
+        None => return Ok(None),
+    };
+
+    let (events, uids) = get_events(db, calendar_id, None)?;
+
+    Ok(Some(format.render(&export::calendar_component(&calendar, &events, &uids))))
+}
+
+/// The events of the calendar as a VCALENDAR (see `export::events_component`), with
+/// the UIDs of their series and the parents of those that override instances.
+pub(super) fn events_calendar(db: &mut impl GenericClient, calendar_id: &Uuid, events: &[EventPlain]) -> Result<Component, DatabaseError>
+{
+    let series_ids: Vec<Uuid> = events
+        .iter()
+        .filter_map(|event| event.parent_id.or(event.id))
+        .collect();
+
+    let mut parents = vec![];
+    let mut uids = HashMap::new();
+
+    let query = "SELECT * FROM events WHERE id = ANY($1) AND calendar_id = $2 AND deleted_at IS NULL;";
+
+    for row in db.query(query, &[&series_ids, calendar_id])?.iter()
+    {
+        if let Some(uid) = get_cell_from_row::<Option<String>>(row, "ical_uid")?
+        {
+            uids.insert(get_cell_from_row(row, "id")?, uid);
+        }
+
+        parents.push(Event::from_row(row)?.into_plain());
+    }
+
+    Ok(export::events_component(events, &parents, &uids))
+}
+
+/// Exports the calendar and its events as an iCalendar file (RFC 5545), or as an
+/// xCal file (RFC 6321) if the path ends with `.xml`, see docs/ical.md.
+///
+/// Response codes: 200, 404, 500
+#[openapi]
+#[get("/calendars/<calendar_file>", rank = 2)]
+pub fn export_calendar(mut db: PgsqlConn, access: CalendarAccess<Reader>, calendar_file: CalendarFileParam) -> CalendarFileResult
+{
+    let format = calendar_file.format();
+
+    match render_calendar(&mut **db, &access.get_tenant_id(), &calendar_file.into_inner(), format)
+    {
+        Ok(Some(file)) => CalendarFileResult::File(file, format),
+        Ok(None) => CalendarFileResult::Result(RouteResult::NotFound),
+        Err(e) => CalendarFileResult::Result(RouteResult::InternalError(Box::new(e))),
+    }
+}
+
+/// Exports an event as an iCalendar or xCal file. Recurring events are exported with
+/// the events that override their instances, and those with their whole series.
+///
+/// Response codes: 200, 404, 500
+#[openapi]
+#[get("/calendars/<calendar_id>/events/<event_file>", rank = 2)]
+pub fn export_event(mut db: PgsqlConn, access: CalendarAccess<Reader>, calendar_id: UuidParam, event_file: CalendarFileParam) -> CalendarFileResult
+{
+    let calendar_id = calendar_id.into_inner();
+    let format = event_file.format();
+    let event_id = event_file.into_inner();
+
+    let result = calendar::get_calendar(&mut **db, &access.get_tenant_id(), &calendar_id)
+        .and_then(|calendar| Ok((calendar, get_events(&mut **db, &calendar_id, Some(&event_id))?)));
+
+    match result
+    {
+        Ok((Some(calendar), (events, uids))) if !events.is_empty() => CalendarFileResult::File(format.render(&export::calendar_component(&calendar, &events, &uids)), format),
+        Ok(_) => CalendarFileResult::Result(RouteResult::NotFound),
+        Err(e) => CalendarFileResult::Result(RouteResult::InternalError(Box::new(e))),
+    }
+}
+
+/// Imports the events of an iCalendar or xCal file (the request's body) into the
+/// calendar, see docs/ical.md. Events that can't be imported don't stop the others from being
+/// imported, the response reports what was imported, skipped and failed. Requires
+/// the writer role.
+///
+/// Response codes: 200, 400, 403, 404, 413, 500
+#[openapi]
+#[post("/calendars/<calendar_id>/import", data = "<file>")]
+pub fn import_calendar(mut db: PgsqlConn, access: CalendarAccess<Writer>, calendar_id: UuidParam, file: CalendarFileData) -> RouteResult<ImportReport>
+{
+    let calendar = match calendar::get_calendar(&mut **db, &access.get_tenant_id(), &calendar_id.into_inner())?
+    {
+        Some(calendar) => calendar,
+        None => return RouteResult::NotFound,
+    };
+
+    let created_by = access.get_principal().as_principal_ref().to_string();
+
+    if file.1 == CalendarFileFormat::XCal
+    {
+        return match xcal::from_xcal(&file.0)
+        {
+            Ok(components) => RouteResult::Ok(import::import_components(&mut **db, &calendar, &created_by, &components)?),
+            Err(_) => RouteResult::BadRequest(None),
+        };
+    }
+
+    match import::import(&mut **db, &calendar, &created_by, &file.0)
+    {
+        Ok(report) => RouteResult::Ok(report),
+        Err(ImportError::Parse(_)) => RouteResult::BadRequest(None),
+        Err(ImportError::Database(e)) => RouteResult::InternalError(Box::new(e)),
+    }
+}
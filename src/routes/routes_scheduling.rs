@@ -0,0 +1,111 @@
+use crate::connection_pool::PgsqlConn;
+use rocket_route_result::RouteResult;
+use rocket_contrib::json::Json;
+use crate::database_helpers::UuidParam;
+use crate::routes::common_query_params::CommonQueryParams;
+use crate::routes::calendar_format::CalendarFileFormat;
+use crate::routes::routes_ical::CalendarFileData;
+use crate::authentication::calendar_access::{CalendarAccess, Reader, SelfWriter, Writer};
+use crate::scheduling::{self, EventAttendees, Attendee, AttendeesError, ReplyOutcome};
+use crate::scheduling::itip;
+use crate::scheduling::outbox::{self, ScheduleMessage};
+use crate::ical::{parser, xcal};
+use uuid::Uuid;
+
+/// What was done with a reply.
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct ProcessedReply
+{
+    pub event_id: Uuid,
+
+    /// The attendee, with their participation status.
+    pub attendee: Attendee,
+
+    /// False if the reply was ignored because it's about an
+    /// older version (SEQUENCE) of the event.
+    pub applied: bool,
+}
+
+/// Gets the organizer and attendees of an event, see docs/scheduling.md. Only
+/// events that don't override an instance of another event have attendees.
+///
+/// Response codes: 200, 404, 500
+#[openapi]
+#[get("/calendars/<calendar_id>/events/<event_id>/attendees")]
+pub fn get_attendees(mut db: PgsqlConn, _access: CalendarAccess<Reader>, calendar_id: UuidParam, event_id: UuidParam) -> RouteResult<EventAttendees>
+{
+    match scheduling::get(&mut **db, &calendar_id.into_inner(), &event_id.into_inner())?
+    {
+        Some(attendees) => RouteResult::Ok(attendees),
+        None => RouteResult::NotFound,
+    }
+}
+
+/// Replaces the organizer and attendees of an event. New and remaining attendees
+/// are sent a REQUEST with the event, removed ones a CANCEL. Attendees that were
+/// already attending keep their participation status.
+///
+/// Requires the self-writer role, self-writers can only change events they created.
+///
+/// Response codes: 200, 400, 403, 404, 500
+#[openapi]
+#[put("/calendars/<calendar_id>/events/<event_id>/attendees", data = "<attendees>")]
+pub fn set_attendees(mut db: PgsqlConn, _access: CalendarAccess<SelfWriter>, calendar_id: UuidParam, event_id: UuidParam, attendees: Json<EventAttendees>) -> RouteResult<EventAttendees>
+{
+    match scheduling::set(&mut **db, &calendar_id.into_inner(), &event_id.into_inner(), &attendees)
+    {
+        Ok(Some(attendees)) => RouteResult::Ok(attendees),
+        Ok(None) => RouteResult::NotFound,
+        Err(AttendeesError::Database(e)) => RouteResult::InternalError(Box::new(e)),
+        Err(_) => RouteResult::BadRequest(None),
+    }
+}
+
+/// Lists the scheduling messages sent (or to be sent) about an event, newest
+/// first. Sent messages are kept for as long as tombstones, see the configs.
+///
+/// Response codes: 200, 404, 500
+#[openapi]
+#[get("/calendars/<calendar_id>/events/<event_id>/messages")]
+pub fn list_scheduling_messages(mut db: PgsqlConn, _access: CalendarAccess<Reader>, calendar_id: UuidParam, event_id: UuidParam, common_params: CommonQueryParams) -> RouteResult<Vec<ScheduleMessage>>
+{
+    let calendar_id = calendar_id.into_inner();
+    let event_id = event_id.into_inner();
+
+    // Deleted events are included, their attendees got a CANCEL.
+    if db.query("SELECT id FROM events WHERE calendar_id = $1 AND id = $2;", &[&calendar_id, &event_id])?.is_empty()
+    {
+        return RouteResult::NotFound;
+    }
+
+    RouteResult::Ok(outbox::list(&mut **db, &calendar_id, &event_id, common_params.offset(), common_params.page_size())?)
+}
+
+/// Applies an attendee's iTIP REPLY (an iCalendar or xCal file, the request's
+/// body) to the event of the calendar it's about, see docs/scheduling.md.
+/// Requires the writer role.
+///
+/// Response codes: 200, 400, 403, 404, 413, 500
+#[openapi]
+#[post("/calendars/<calendar_id>/replies", data = "<file>")]
+pub fn process_reply(mut db: PgsqlConn, _access: CalendarAccess<Writer>, calendar_id: UuidParam, file: CalendarFileData) -> RouteResult<ProcessedReply>
+{
+    let components = match file.1
+    {
+        CalendarFileFormat::XCal => xcal::from_xcal(&file.0).ok(),
+        CalendarFileFormat::ICalendar => parser::parse(&file.0).ok(),
+    };
+
+    let reply = match components.map(|components| itip::parse_reply(&components))
+    {
+        Some(Ok(reply)) => reply,
+        _ => return RouteResult::BadRequest(None),
+    };
+
+    match scheduling::process_reply(&mut **db, &calendar_id.into_inner(), &reply)?
+    {
+        ReplyOutcome::Applied(event_id, attendee) => RouteResult::Ok(ProcessedReply { event_id, attendee, applied: true }),
+        ReplyOutcome::Stale(event_id, attendee) => RouteResult::Ok(ProcessedReply { event_id, attendee, applied: false }),
+        ReplyOutcome::EventNotFound | ReplyOutcome::AttendeeNotFound => RouteResult::NotFound,
+    }
+}
@@ -0,0 +1,145 @@
+use crate::connection_pool::PgsqlConn;
+use rocket_route_result::RouteResult;
+use rocket_contrib::json::Json;
+use crate::database_helpers::UuidParam;
+use crate::routes::common_query_params::CommonQueryParams;
+use crate::authentication::auth_guard::ApiKey;
+use crate::authentication::principal::Principal;
+use crate::authentication::scopes::Webhooks;
+use crate::webhooks::{self, Subscription, SubscriptionWithSecret, NewSubscription, NewSubscriptionError};
+use crate::webhook_delivery::{self, Delivery};
+use crate::configs::Configs;
+use rocket::State;
+
+/// Subscribes to changes to a calendar, an event or an event's instances. The
+/// API key must be able to access the calendar. The response has the secret
+/// requests are signed with in its `secret` property, this is the only time it's shown.
+///
+/// Response codes: 201, 400, 500
+#[openapi]
+#[post("/webhooks", data = "<new_subscription>")]
+pub fn create_webhook(
+    mut db: PgsqlConn,
+    api_key: ApiKey<Webhooks>,
+    configs: State<Configs>,
+    new_subscription: Json<NewSubscription>,
+) -> RouteResult<SubscriptionWithSecret>
+{
+    let principal = Principal::ApiKey(api_key);
+
+    if principal.calendar_role(&mut **db, &new_subscription.calendar_id)?.is_none()
+    {
+        return RouteResult::BadRequest(None);
+    }
+
+    match webhooks::create(&mut **db, &principal.get_tenant_id(), &new_subscription, configs.get_webhook_allow_private_targets())
+    {
+        Ok(subscription) =>
+        {
+            //TODO: prepend host to url.
+            let location = format!("/api/webhooks/{}", subscription.info.id);
+            RouteResult::Created(subscription, location)
+        },
+        Err(NewSubscriptionError::Database(e)) => RouteResult::InternalError(Box::new(e)),
+        Err(NewSubscriptionError::Random(e)) => RouteResult::InternalError(Box::new(e)),
+        Err(_) => RouteResult::BadRequest(None),
+    }
+}
+
+/// Lists the subscriptions to the calendars the API key can access.
+///
+/// Response codes: 200, 500
+#[openapi]
+#[get("/webhooks")]
+pub fn list_webhooks(mut db: PgsqlConn, api_key: ApiKey<Webhooks>, common_params: CommonQueryParams) -> RouteResult<Vec<Subscription>>
+{
+    let principal = Principal::ApiKey(api_key);
+    let calendar_ids = principal.visible_calendar_ids(&mut **db)?;
+
+    RouteResult::Ok(
+        webhooks::list(&mut **db, &principal.get_tenant_id(), calendar_ids.as_ref(), common_params.offset(), common_params.page_size())?
+    )
+}
+
+/// Gets a subscription by id.
+///
+/// Response codes: 200, 404, 500
+#[openapi]
+#[get("/webhooks/<webhook_id>")]
+pub fn get_webhook(mut db: PgsqlConn, api_key: ApiKey<Webhooks>, webhook_id: UuidParam) -> RouteResult<Subscription>
+{
+    let principal = Principal::ApiKey(api_key);
+
+    match webhooks::get(&mut **db, &principal.get_tenant_id(), &webhook_id.into_inner())?
+    {
+        Some(subscription) if principal.calendar_role(&mut **db, &subscription.calendar_id)?.is_some() => RouteResult::Ok(subscription),
+        _ => RouteResult::NotFound,
+    }
+}
+
+/// Deletes a subscription, it stops getting changes right away.
+///
+/// Response codes: 200, 404, 500
+#[openapi]
+#[delete("/webhooks/<webhook_id>")]
+pub fn delete_webhook(mut db: PgsqlConn, api_key: ApiKey<Webhooks>, webhook_id: UuidParam) -> RouteResult<()>
+{
+    let principal = Principal::ApiKey(api_key);
+    let webhook_id = webhook_id.into_inner();
+
+    match webhooks::get(&mut **db, &principal.get_tenant_id(), &webhook_id)?
+    {
+        Some(subscription) if principal.calendar_role(&mut **db, &subscription.calendar_id)?.is_some() =>
+        {
+            webhooks::delete(&mut **db, &principal.get_tenant_id(), &webhook_id)?;
+            RouteResult::Ok(())
+        },
+        _ => RouteResult::NotFound,
+    }
+}
+
+/// Lists the subscription's deliveries, newest first. Finished deliveries
+/// are kept for as long as tombstones, see the configs.
+///
+/// Response codes: 200, 404, 500
+#[openapi]
+#[get("/webhooks/<webhook_id>/deliveries")]
+pub fn list_webhook_deliveries(mut db: PgsqlConn, api_key: ApiKey<Webhooks>, webhook_id: UuidParam, common_params: CommonQueryParams) -> RouteResult<Vec<Delivery>>
+{
+    let principal = Principal::ApiKey(api_key);
+    let webhook_id = webhook_id.into_inner();
+
+    match webhooks::get(&mut **db, &principal.get_tenant_id(), &webhook_id)?
+    {
+        Some(subscription) if principal.calendar_role(&mut **db, &subscription.calendar_id)?.is_some() =>
+        {
+            RouteResult::Ok(webhook_delivery::list(&mut **db, &webhook_id, common_params.offset(), common_params.page_size())?)
+        },
+        _ => RouteResult::NotFound,
+    }
+}
+
+/// Sends a delivery again, e.g. a dead one once the target is fixed. It gets a
+/// fresh set of attempts and is sent as soon as possible.
+///
+/// Response codes: 200, 404, 500
+#[openapi]
+#[post("/webhooks/<webhook_id>/deliveries/<delivery_id>/redeliver")]
+pub fn redeliver_webhook(mut db: PgsqlConn, api_key: ApiKey<Webhooks>, webhook_id: UuidParam, delivery_id: UuidParam) -> RouteResult<Delivery>
+{
+    let principal = Principal::ApiKey(api_key);
+    let webhook_id = webhook_id.into_inner();
+
+    match webhooks::get(&mut **db, &principal.get_tenant_id(), &webhook_id)?
+    {
+        Some(subscription) if principal.calendar_role(&mut **db, &subscription.calendar_id)?.is_some() =>
+        {
+            match webhook_delivery::redeliver(&mut **db, &webhook_id, &delivery_id.into_inner())?
+            {
+                Some(delivery) => RouteResult::Ok(delivery),
+                None => RouteResult::NotFound,
+            }
+        },
+        _ => RouteResult::NotFound,
+    }
+}
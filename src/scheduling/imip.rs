@@ -0,0 +1,379 @@
+//! Sends scheduling messages by email (iMIP, RFC 6047).
+//!
+//! Messages are handed to an SMTP server (`SMTP_SERVER`, see
+//! docs/configurations.md) over plain SMTP, without TLS or authentication, so
+//! it should be a relay on the same host or network that takes care of
+//! delivering them, e.g. Postfix or an email provider's relay.
+
+use super::itip::Method;
+use super::transport::{Message, Transport};
+use super::is_valid_address;
+use chrono::{DateTime, Utc};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// How long to wait for the SMTP server to accept the connection or respond.
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum length of the lines of the base64 encoded body (RFC 2045).
+const BASE64_LINE_LENGTH: usize = 76;
+
+#[derive(Clone, Debug)]
+pub struct SmtpSettings
+{
+    /// `host:port` of the SMTP server.
+    pub server: String,
+
+    /// Address messages are sent from. Replies go to the event's organizer.
+    pub from: String,
+
+    /// Name the server introduces itself with in EHLO.
+    pub hello_name: String,
+}
+
+pub struct SmtpTransport
+{
+    settings: SmtpSettings,
+}
+
+impl SmtpTransport
+{
+    pub fn new(settings: SmtpSettings) -> SmtpTransport
+    {
+        SmtpTransport { settings }
+    }
+}
+
+impl Transport for SmtpTransport
+{
+    fn send(&self, message: &Message) -> Result<(), String>
+    {
+        let data = format_message(&self.settings.from, message, Utc::now())?;
+
+        let mut session = Session::connect(&self.settings.server)?;
+        let result = session.send(&self.settings.hello_name, &self.settings.from, &message.recipient, &data);
+
+        // The message was sent (or not) already, whatever QUIT gets is irrelevant.
+        let _ = session.command("QUIT", 221);
+
+        result
+    }
+}
+
+fn subject(message: &Message) -> &'static str
+{
+    match message.method
+    {
+        Method::Request if message.sequence > 0 => "Updated invitation",
+        Method::Request => "Invitation",
+        Method::Reply => "Reply",
+        Method::Cancel => "Cancelled event",
+    }
+}
+
+/// The email with the message: a text/calendar part (RFC 6047 section 2.4)
+/// from `from`, whose replies go to the organizer.
+pub fn format_message(from: &str, message: &Message, date: DateTime<Utc>) -> Result<String, String>
+{
+    // Addresses end up in headers and SMTP commands, which must not be
+    // broken by line breaks or anything else.
+    for address in &[from, message.organizer.as_str(), message.recipient.as_str()]
+    {
+        if !is_valid_address(address)
+        {
+            return Err(format!("Invalid email address: {}", address));
+        }
+    }
+
+    let mut data = format!(
+        "From: {}\r\n\
+        Reply-To: {}\r\n\
+        To: {}\r\n\
+        Subject: {}\r\n\
+        Date: {}\r\n\
+        Message-ID: <{}@calendar-server>\r\n\
+        MIME-Version: 1.0\r\n\
+        Content-Type: text/calendar; method={}; charset=UTF-8\r\n\
+        Content-Transfer-Encoding: base64\r\n\
+        \r\n",
+        from,
+        message.organizer,
+        message.recipient,
+        subject(message),
+        date.to_rfc2822(),
+        message.id,
+        message.method.as_str(),
+    );
+
+    let body = base64::encode(&message.payload);
+
+    // base64 is ASCII, so this doesn't split characters.
+    for line in body.as_bytes().chunks(BASE64_LINE_LENGTH)
+    {
+        data.push_str(std::str::from_utf8(line).unwrap());
+        data.push_str("\r\n");
+    }
+
+    Ok(data)
+}
+
+/// A connection to the SMTP server (RFC 5321).
+struct Session
+{
+    reader: BufReader<TcpStream>,
+    stream: TcpStream,
+}
+
+impl Session
+{
+    fn connect(server: &str) -> Result<Session, String>
+    {
+        let address = server.to_socket_addrs()
+            .map_err(|e| format!("Failed to resolve the SMTP server: {}", e))?
+            .next()
+            .ok_or_else(|| "Failed to resolve the SMTP server.".to_owned())?;
+
+        let stream = TcpStream::connect_timeout(&address, SMTP_TIMEOUT)
+            .map_err(|e| format!("Failed to connect to the SMTP server: {}", e))?;
+
+        stream.set_read_timeout(Some(SMTP_TIMEOUT)).map_err(|e| e.to_string())?;
+        stream.set_write_timeout(Some(SMTP_TIMEOUT)).map_err(|e| e.to_string())?;
+
+        let mut session = Session {
+            reader: BufReader::new(stream.try_clone().map_err(|e| e.to_string())?),
+            stream,
+        };
+
+        session.expect("the greeting", 220)?;
+
+        Ok(session)
+    }
+
+    fn send(&mut self, hello_name: &str, from: &str, to: &str, data: &str) -> Result<(), String>
+    {
+        self.command(&format!("EHLO {}", hello_name), 250)?;
+        self.command(&format!("MAIL FROM:<{}>", from), 250)?;
+        self.command(&format!("RCPT TO:<{}>", to), 250)?;
+        self.command("DATA", 354)?;
+
+        // Lines starting with a dot get another one, so that they aren't
+        // taken for the end of the data.
+        let mut stuffed = String::with_capacity(data.len());
+
+        for line in data.split_terminator("\r\n")
+        {
+            if line.starts_with('.')
+            {
+                stuffed.push('.');
+            }
+
+            stuffed.push_str(line);
+            stuffed.push_str("\r\n");
+        }
+
+        stuffed.push_str(".\r\n");
+
+        self.stream.write_all(stuffed.as_bytes()).map_err(|e| format!("Failed to send the message: {}", e))?;
+        self.expect("the message", 250)
+    }
+
+    /// Sends a command, its reply must be in the same class (2xx, 3xx) as `expected`.
+    fn command(&mut self, command: &str, expected: u16) -> Result<(), String>
+    {
+        write!(self.stream, "{}\r\n", command).map_err(|e| format!("Failed to send {}: {}", command, e))?;
+
+        let verb = command.split(|c| c == ' ' || c == ':').next().unwrap_or(command);
+        self.expect(verb, expected)
+    }
+
+    /// Reads a reply, which may span several lines (`250-...` until `250 ...`).
+    fn expect(&mut self, what: &str, expected: u16) -> Result<(), String>
+    {
+        let mut text = vec![];
+        let mut line = String::new();
+
+        let code = loop
+        {
+            line.clear();
+
+            match self.reader.read_line(&mut line)
+            {
+                Ok(0) => return Err("The SMTP server closed the connection.".to_owned()),
+                Ok(_) => {},
+                Err(e) => return Err(format!("Failed to read the SMTP server's reply: {}", e)),
+            }
+
+            let line = line.trim_end();
+
+            let code: u16 = match line.get(..3).and_then(|code| code.parse().ok())
+            {
+                Some(code) => code,
+                None => return Err(format!("Invalid reply from the SMTP server: {}", line)),
+            };
+
+            text.push(line.get(4..).unwrap_or("").to_owned());
+
+            if line.as_bytes().get(3) != Some(&b'-')
+            {
+                break code;
+            }
+        };
+
+        if code / 100 == expected / 100
+        {
+            Ok(())
+        }
+        else
+        {
+            Err(format!("The SMTP server responded to {} with {} {}", what, code, text.join(" ")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{format_message, SmtpSettings, SmtpTransport};
+    use crate::scheduling::itip::Method;
+    use crate::scheduling::transport::{Message, Transport};
+    use chrono::{TimeZone, Utc};
+    use uuid::Uuid;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    fn message(recipient: &str) -> Message
+    {
+        Message {
+            id: Uuid::new_v4(),
+            method: Method::Request,
+            sequence: 1,
+            organizer: "org@example.com".to_owned(),
+            recipient: recipient.to_owned(),
+            payload: "BEGIN:VCALENDAR\r\nMETHOD:REQUEST\r\nEND:VCALENDAR\r\n".to_owned(),
+        }
+    }
+
+    /// Starts an SMTP server on localhost that takes one message and replies
+    /// to RCPT with `rcpt_reply`. Returns its address and the commands (and
+    /// data) it gets.
+    fn sink(rcpt_reply: &'static str) -> (String, Receiver<Vec<String>>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, received) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut lines = vec![];
+            let mut in_data = false;
+
+            write!(stream, "220 sink ESMTP\r\n").unwrap();
+
+            loop
+            {
+                let mut line = String::new();
+
+                if reader.read_line(&mut line).unwrap() == 0
+                {
+                    break;
+                }
+
+                let line = line.trim_end_matches("\r\n").to_owned();
+
+                let reply = if in_data
+                {
+                    in_data = line != ".";
+                    if in_data { "" } else { "250 Queued\r\n" }
+                }
+                else if line.starts_with("EHLO")
+                {
+                    "250-sink\r\n250 8BITMIME\r\n"
+                }
+                else if line.starts_with("RCPT")
+                {
+                    rcpt_reply
+                }
+                else if line == "DATA"
+                {
+                    in_data = true;
+                    "354 Go ahead\r\n"
+                }
+                else if line == "QUIT"
+                {
+                    lines.push(line);
+                    write!(stream, "221 Bye\r\n").unwrap();
+                    break;
+                }
+                else
+                {
+                    "250 OK\r\n"
+                };
+
+                lines.push(line);
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+
+            sender.send(lines).unwrap();
+        });
+
+        (address, received)
+    }
+
+    fn transport(server: String) -> SmtpTransport
+    {
+        SmtpTransport::new(SmtpSettings {
+            server,
+            from: "calendar@example.com".to_owned(),
+            hello_name: "calendar.example.com".to_owned(),
+        })
+    }
+
+    #[test]
+    fn formats_messages()
+    {
+        let message = message("ana@example.com");
+        let date = Utc.ymd(2020, 9, 15).and_hms(8, 0, 0);
+        let data = format_message("calendar@example.com", &message, date).unwrap();
+
+        assert!(data.starts_with("From: calendar@example.com\r\nReply-To: org@example.com\r\nTo: ana@example.com\r\n"));
+        assert!(data.contains("\r\nSubject: Updated invitation\r\n"));
+        assert!(data.contains("\r\nDate: Tue, 15 Sep 2020 08:00:00 +0000\r\n"));
+        assert!(data.contains(&format!("\r\nMessage-ID: <{}@calendar-server>\r\n", message.id)));
+        assert!(data.contains("\r\nContent-Type: text/calendar; method=REQUEST; charset=UTF-8\r\n"));
+
+        let body: String = data.split("\r\n\r\n").nth(1).unwrap().split("\r\n").collect();
+        assert_eq!(base64::decode(body).unwrap(), message.payload.as_bytes());
+
+        assert!(format_message("calendar@example.com", &self::message("ana@example.com\r\nBcc: x@example.com"), date).is_err());
+    }
+
+    #[test]
+    fn sends_messages_over_smtp()
+    {
+        let (server, received) = sink("250 OK\r\n");
+        let message = message("ana@example.com");
+
+        assert_eq!(transport(server).send(&message), Ok(()));
+
+        let lines = received.recv().unwrap();
+        assert_eq!(lines[0], "EHLO calendar.example.com");
+        assert_eq!(lines[1], "MAIL FROM:<calendar@example.com>");
+        assert_eq!(lines[2], "RCPT TO:<ana@example.com>");
+        assert_eq!(lines[3], "DATA");
+        assert!(lines.contains(&"To: ana@example.com".to_owned()));
+        assert_eq!(lines[lines.len() - 2], ".");
+        assert_eq!(lines[lines.len() - 1], "QUIT");
+
+        let (server, received) = sink("550 No such user\r\n");
+
+        assert_eq!(
+            transport(server).send(&message),
+            Err("The SMTP server responded to RCPT with 550 No such user".to_owned())
+        );
+        assert!(!received.recv().unwrap().contains(&"DATA".to_owned()));
+    }
+}
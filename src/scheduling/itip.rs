@@ -0,0 +1,345 @@
+//! Builds iTIP (RFC 5546) messages and parses the REPLYs attendees send.
+//!
+//! Messages are VCALENDARs with a METHOD whose VEVENTs are exported like any
+//! other (see `ical::export`), plus the ORGANIZER, ATTENDEEs and SEQUENCE.
+//! DTSTAMP is when the message was written, not when the event changed.
+
+use super::{Organizer, Attendee, ParticipationStatus, normalize_address, is_valid_address};
+use crate::event::EventPlain;
+use crate::ical::{export, Component, Property};
+use chrono::NaiveDateTime;
+
+/// The iTIP methods the server knows about. It sends REQUESTs and CANCELs
+/// and receives REPLYs.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Method
+{
+    Request,
+    Reply,
+    Cancel,
+}
+
+impl Method
+{
+    pub const ALL: [Method; 3] = [Method::Request, Method::Reply, Method::Cancel];
+
+    /// The method's name, as in the METHOD property and the `method`
+    /// column of `scheduling_messages`.
+    pub fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            Method::Request => "REQUEST",
+            Method::Reply => "REPLY",
+            Method::Cancel => "CANCEL",
+        }
+    }
+
+    /// Parses the method's name, in any case.
+    pub fn from_str(s: &str) -> Option<Method>
+    {
+        Method::ALL.iter().copied().find(|method| method.as_str().eq_ignore_ascii_case(s))
+    }
+}
+
+fn organizer_property(organizer: &Organizer) -> Property
+{
+    let property = Property::new("ORGANIZER", format!("mailto:{}", organizer.email));
+
+    match &organizer.name
+    {
+        Some(name) => property.param("CN", name.as_str()),
+        None => property,
+    }
+}
+
+fn attendee_property(attendee: &Attendee) -> Property
+{
+    let mut property = Property::new("ATTENDEE", format!("mailto:{}", attendee.email));
+
+    if let Some(name) = &attendee.name
+    {
+        property = property.param("CN", name.as_str());
+    }
+
+    property
+        .param("ROLE", attendee.role.as_str().to_ascii_uppercase())
+        .param("PARTSTAT", attendee.partstat.as_str().to_ascii_uppercase())
+        .param("RSVP", if attendee.rsvp { "TRUE" } else { "FALSE" })
+}
+
+/// Adds the scheduling properties to an exported VEVENT.
+fn add_scheduling_properties(component: &mut Component, organizer: &Organizer, attendees: &[&Attendee], sequence: i32, dtstamp: NaiveDateTime)
+{
+    component.properties.retain(|property| property.name != "DTSTAMP");
+    component.push(Property::new("DTSTAMP", dtstamp.format("%Y%m%dT%H%M%SZ").to_string()));
+    component.push(Property::new("SEQUENCE", sequence.to_string()));
+    component.push(organizer_property(organizer));
+
+    for attendee in attendees
+    {
+        component.push(attendee_property(attendee));
+    }
+}
+
+fn vcalendar(method: Method) -> Component
+{
+    let mut vcalendar = export::vcalendar();
+    vcalendar.push(Property::new("METHOD", method.as_str()));
+    vcalendar
+}
+
+/// A REQUEST with an event's series: the event first, then the children that
+/// override its instances, each with its SEQUENCE. `uid` is the series' UID.
+pub fn request(uid: &str, organizer: &Organizer, attendees: &[Attendee], events: &[(EventPlain, i32)], dtstamp: NaiveDateTime) -> Component
+{
+    let mut vcalendar = vcalendar(Method::Request);
+
+    let attendees: Vec<&Attendee> = attendees.iter().collect();
+    let parent = events.get(0).map(|(event, _)| event);
+
+    for (event, sequence) in events
+    {
+        let parent = if event.parent_id.is_some() { parent } else { None };
+
+        if let Some(mut component) = export::event_component(event, parent, uid)
+        {
+            add_scheduling_properties(&mut component, organizer, &attendees, *sequence, dtstamp);
+            vcalendar.components.push(component);
+        }
+    }
+
+    vcalendar
+}
+
+/// A CANCEL of a whole series for some of its attendees. `event` is the
+/// series' event (not a child) and `sequence` its SEQUENCE.
+pub fn cancel(uid: &str, organizer: &Organizer, attendees: &[&Attendee], event: &EventPlain, sequence: i32, dtstamp: NaiveDateTime) -> Component
+{
+    let mut vcalendar = vcalendar(Method::Cancel);
+
+    if let Some(mut component) = export::event_component(event, None, uid)
+    {
+        add_scheduling_properties(&mut component, organizer, attendees, sequence, dtstamp);
+        component.push(Property::new("STATUS", "CANCELLED"));
+        vcalendar.components.push(component);
+    }
+
+    vcalendar
+}
+
+/// An attendee's answer to a REQUEST.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Reply
+{
+    /// UID of the event's series.
+    pub uid: String,
+
+    /// The replying attendee's (normalized) email address.
+    pub attendee: String,
+
+    pub partstat: ParticipationStatus,
+
+    /// The SEQUENCE of the version of the event the attendee is answering.
+    pub sequence: i32,
+}
+
+#[derive(Error, Debug)]
+pub enum ReplyError
+{
+    #[error("The object isn't an iTIP REPLY to an event.")]
+    NotAReply,
+
+    #[error("Replies to single instances of an event aren't supported.")]
+    InstanceReply,
+
+    #[error("Property {0} is required but missing.")]
+    MissingProperty(&'static str),
+
+    #[error("Property {0} has an invalid value.")]
+    InvalidValue(&'static str),
+
+    #[error("Replies must have exactly one ATTENDEE, the one replying.")]
+    AttendeeCount,
+}
+
+/// Parses a REPLY, a VCALENDAR with METHOD:REPLY and a VEVENT with the
+/// replying attendee (RFC 5546 section 3.2.3).
+pub fn parse_reply(components: &[Component]) -> Result<Reply, ReplyError>
+{
+    let vcalendar = match components
+    {
+        [vcalendar] if vcalendar.name == "VCALENDAR" => vcalendar,
+        _ => return Err(ReplyError::NotAReply),
+    };
+
+    if vcalendar.property("METHOD").and_then(|method| Method::from_str(method.value())) != Some(Method::Reply)
+    {
+        return Err(ReplyError::NotAReply);
+    }
+
+    let events: Vec<&Component> = vcalendar.components
+        .iter()
+        .filter(|component| component.name == "VEVENT")
+        .collect();
+
+    let event = match events.iter().find(|event| event.property("RECURRENCE-ID").is_none())
+    {
+        Some(event) => event,
+        None if events.is_empty() => return Err(ReplyError::NotAReply),
+        None => return Err(ReplyError::InstanceReply),
+    };
+
+    let uid = event.property("UID")
+        .map(|uid| uid.value().to_owned())
+        .ok_or(ReplyError::MissingProperty("UID"))?;
+
+    let attendee = match event.properties.iter().filter(|property| property.name == "ATTENDEE").collect::<Vec<&Property>>()[..]
+    {
+        [attendee] => attendee,
+        [] => return Err(ReplyError::MissingProperty("ATTENDEE")),
+        _ => return Err(ReplyError::AttendeeCount),
+    };
+
+    let address = normalize_address(attendee.value());
+
+    if !is_valid_address(&address)
+    {
+        return Err(ReplyError::InvalidValue("ATTENDEE"));
+    }
+
+    let partstat = match attendee.get_param("PARTSTAT")
+    {
+        Some(partstat) => ParticipationStatus::from_str(partstat).ok_or(ReplyError::InvalidValue("ATTENDEE"))?,
+        None => ParticipationStatus::NeedsAction,
+    };
+
+    let sequence = match event.property("SEQUENCE")
+    {
+        Some(sequence) => sequence.value().trim().parse().map_err(|_| ReplyError::InvalidValue("SEQUENCE"))?,
+        None => 0,
+    };
+
+    Ok(
+        Reply {
+            uid,
+            attendee: address,
+            partstat,
+            sequence,
+        }
+    )
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{request, cancel, parse_reply, Reply, ReplyError};
+    use crate::scheduling::{Organizer, Attendee, AttendeeRole, ParticipationStatus};
+    use crate::event::EventPlain;
+    use crate::ical::parser;
+    use chrono::{NaiveDate, NaiveTime};
+    use uuid::Uuid;
+
+    fn event(id: Uuid, parent_id: Option<Uuid>) -> EventPlain
+    {
+        EventPlain {
+            id: Some(id),
+            parent_id,
+            original_start_date: parent_id.map(|_| NaiveDate::from_ymd(2020, 9, 8)),
+            start_date: Some(NaiveDate::from_ymd(2020, 9, 1)),
+            start_time: Some(NaiveTime::from_hms(10, 0, 0)),
+            end_date: Some(NaiveDate::from_ymd(2020, 9, 1)),
+            end_time: Some(NaiveTime::from_hms(11, 0, 0)),
+            recurrence: None,
+            last_modified: Some(NaiveDate::from_ymd(2020, 8, 20).and_hms(12, 0, 0)),
+            instance_origin: None,
+            deleted: false,
+        }
+    }
+
+    #[test]
+    fn writes_requests_and_cancels()
+    {
+        let organizer = Organizer { email: "org@example.com".to_owned(), name: Some("Org, Inc".to_owned()) };
+        let attendee = Attendee {
+            email: "ana@example.com".to_owned(),
+            name: None,
+            role: AttendeeRole::OptParticipant,
+            partstat: ParticipationStatus::Tentative,
+            rsvp: true,
+            replied_at: None,
+        };
+
+        let id = Uuid::new_v4();
+        let events = vec![(event(id, None), 2), (event(Uuid::new_v4(), Some(id)), 0)];
+        let dtstamp = NaiveDate::from_ymd(2020, 9, 1).and_hms(8, 0, 0);
+
+        let vcalendar = request("uid-1", &organizer, &[attendee.clone()], &events, dtstamp);
+        assert_eq!(vcalendar.property("METHOD").unwrap().value(), "REQUEST");
+        assert_eq!(vcalendar.components.len(), 2);
+
+        let ics = vcalendar.components[0].to_ics();
+        assert!(ics.contains("\r\nUID:uid-1\r\n"));
+        assert!(ics.contains("\r\nDTSTAMP:20200901T080000Z\r\n"));
+        assert!(!ics.contains("DTSTAMP:20200820"));
+        assert!(ics.contains("\r\nSEQUENCE:2\r\n"));
+        assert!(ics.contains("\r\nORGANIZER;CN=\"Org, Inc\":mailto:org@example.com\r\n"));
+
+        let attendee_property = vcalendar.components[0].property("ATTENDEE").unwrap();
+        assert_eq!(attendee_property.value(), "mailto:ana@example.com");
+        assert_eq!(attendee_property.get_param("ROLE"), Some("OPT-PARTICIPANT"));
+        assert_eq!(attendee_property.get_param("PARTSTAT"), Some("TENTATIVE"));
+        assert_eq!(attendee_property.get_param("RSVP"), Some("TRUE"));
+        assert_eq!(attendee_property.get_param("CN"), None);
+
+        let child = &vcalendar.components[1];
+        assert_eq!(child.property("SEQUENCE").unwrap().value(), "0");
+        assert_eq!(child.property("RECURRENCE-ID").unwrap().value(), "20200908T100000Z");
+
+        let vcalendar = cancel("uid-1", &organizer, &[&attendee], &events[0].0, 3, dtstamp);
+        assert_eq!(vcalendar.property("METHOD").unwrap().value(), "CANCEL");
+        assert_eq!(vcalendar.components.len(), 1);
+        assert_eq!(vcalendar.components[0].property("STATUS").unwrap().value(), "CANCELLED");
+        assert_eq!(vcalendar.components[0].property("SEQUENCE").unwrap().value(), "3");
+    }
+
+    #[test]
+    fn parses_replies()
+    {
+        let reply = |event: &str| parse_reply(&parser::parse(&format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//test//EN\r\nMETHOD:REPLY\r\n{}END:VCALENDAR\r\n",
+            event
+        )).unwrap());
+
+        assert_eq!(
+            reply("BEGIN:VEVENT\r\nUID:uid-1\r\nSEQUENCE:2\r\nATTENDEE;partstat=accepted;CN=Ana:MAILTO:Ana@Example.com\r\nEND:VEVENT\r\n").unwrap(),
+            Reply {
+                uid: "uid-1".to_owned(),
+                attendee: "ana@example.com".to_owned(),
+                partstat: ParticipationStatus::Accepted,
+                sequence: 2,
+            }
+        );
+
+        assert_eq!(reply("BEGIN:VEVENT\r\nUID:uid-1\r\nATTENDEE;PARTSTAT=DECLINED:mailto:ana@example.com\r\nEND:VEVENT\r\n").unwrap().sequence, 0);
+
+        assert!(matches!(reply(""), Err(ReplyError::NotAReply)));
+        assert!(matches!(
+            reply("BEGIN:VEVENT\r\nUID:uid-1\r\nRECURRENCE-ID:20200908T100000Z\r\nATTENDEE:mailto:ana@example.com\r\nEND:VEVENT\r\n"),
+            Err(ReplyError::InstanceReply)
+        ));
+        assert!(matches!(reply("BEGIN:VEVENT\r\nUID:uid-1\r\nEND:VEVENT\r\n"), Err(ReplyError::MissingProperty("ATTENDEE"))));
+        assert!(matches!(
+            reply("BEGIN:VEVENT\r\nUID:uid-1\r\nATTENDEE:mailto:ana@example.com\r\nATTENDEE:mailto:bob@example.com\r\nEND:VEVENT\r\n"),
+            Err(ReplyError::AttendeeCount)
+        ));
+        assert!(matches!(
+            reply("BEGIN:VEVENT\r\nUID:uid-1\r\nATTENDEE;PARTSTAT=MAYBE:mailto:ana@example.com\r\nEND:VEVENT\r\n"),
+            Err(ReplyError::InvalidValue("ATTENDEE"))
+        ));
+
+        let request = parse_reply(&parser::parse("BEGIN:VCALENDAR\r\nMETHOD:REQUEST\r\nEND:VCALENDAR\r\n").unwrap());
+        assert!(matches!(request, Err(ReplyError::NotAReply)));
+    }
+}
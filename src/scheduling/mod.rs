@@ -0,0 +1,731 @@
+//! Scheduling with iTIP (RFC 5546), see docs/scheduling.md.
+//!
+//! An event that isn't a child can have an organizer and attendees. Whenever
+//! the event or one of its children (the events that override its instances)
+//! changes, routes call `notify_change` in the same transaction as the change,
+//! which queues a REQUEST with the event's series for each attendee, or a
+//! CANCEL if the event was deleted. Attendees removed from the event get a
+//! CANCEL too. `itip` builds the messages, `outbox` queues them and sends them
+//! through a `Transport`, e.g. email (`imip`).
+//!
+//! Attendees answer with REPLYs, which `process_reply` applies to their
+//! participation status. Events have a SEQUENCE (see db_schema/20.sql) that's
+//! incremented when their dates or recurrence change, replies to older
+//! versions of an event are ignored.
+
+pub mod itip;
+pub mod transport;
+pub mod imip;
+pub mod outbox;
+
+use self::itip::{Method, Reply};
+use crate::database_error::{DatabaseError, DatabaseErrorKind};
+use crate::database_helpers::{FromRow, get_cell_from_row};
+use crate::event::{Event, EventPlain, ToPlain};
+use crate::ical::export;
+use postgres::{Row, GenericClient};
+use chrono::{NaiveDateTime, Utc};
+use uuid::Uuid;
+
+/// Maximum length of an email address (RFC 5321's limit on paths).
+pub const MAX_ADDRESS_LENGTH: usize = 254;
+
+const ATTENDEE_FIELDS: &str = "email, name, role, partstat, rsvp, replied_at";
+
+/// An attendee's participation status (RFC 5545's PARTSTAT).
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum ParticipationStatus
+{
+    NeedsAction,
+    Accepted,
+    Declined,
+    Tentative,
+    Delegated,
+}
+
+impl ParticipationStatus
+{
+    pub const ALL: [ParticipationStatus; 5] = [
+        ParticipationStatus::NeedsAction,
+        ParticipationStatus::Accepted,
+        ParticipationStatus::Declined,
+        ParticipationStatus::Tentative,
+        ParticipationStatus::Delegated,
+    ];
+
+    /// The status' name, as stored in the `partstat` column of `event_attendees`.
+    /// Its iCalendar name is the same in upper case.
+    pub fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            ParticipationStatus::NeedsAction => "needs-action",
+            ParticipationStatus::Accepted => "accepted",
+            ParticipationStatus::Declined => "declined",
+            ParticipationStatus::Tentative => "tentative",
+            ParticipationStatus::Delegated => "delegated",
+        }
+    }
+
+    /// Parses the status' name, in any case.
+    pub fn from_str(s: &str) -> Option<ParticipationStatus>
+    {
+        ParticipationStatus::ALL.iter().copied().find(|status| status.as_str().eq_ignore_ascii_case(s))
+    }
+}
+
+impl Default for ParticipationStatus
+{
+    fn default() -> Self
+    {
+        ParticipationStatus::NeedsAction
+    }
+}
+
+/// An attendee's role in the event (RFC 5545's ROLE).
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum AttendeeRole
+{
+    Chair,
+    ReqParticipant,
+    OptParticipant,
+    NonParticipant,
+}
+
+impl AttendeeRole
+{
+    pub const ALL: [AttendeeRole; 4] = [AttendeeRole::Chair, AttendeeRole::ReqParticipant, AttendeeRole::OptParticipant, AttendeeRole::NonParticipant];
+
+    /// The role's name, as stored in the `role` column of `event_attendees`.
+    /// Its iCalendar name is the same in upper case.
+    pub fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            AttendeeRole::Chair => "chair",
+            AttendeeRole::ReqParticipant => "req-participant",
+            AttendeeRole::OptParticipant => "opt-participant",
+            AttendeeRole::NonParticipant => "non-participant",
+        }
+    }
+
+    /// Parses the role's name, in any case.
+    pub fn from_str(s: &str) -> Option<AttendeeRole>
+    {
+        AttendeeRole::ALL.iter().copied().find(|role| role.as_str().eq_ignore_ascii_case(s))
+    }
+}
+
+impl Default for AttendeeRole
+{
+    fn default() -> Self
+    {
+        AttendeeRole::ReqParticipant
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Organizer
+{
+    /// Email address, without `mailto:`.
+    pub email: String,
+
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Attendee
+{
+    /// Email address, without `mailto:`.
+    pub email: String,
+
+    #[serde(default)]
+    pub name: Option<String>,
+
+    #[serde(default)]
+    pub role: AttendeeRole,
+
+    /// Only changed by the attendee's replies, ignored when setting attendees.
+    #[serde(default)]
+    pub partstat: ParticipationStatus,
+
+    /// Whether the attendee is asked to reply.
+    #[serde(default = "default_rsvp")]
+    pub rsvp: bool,
+
+    /// When the attendee last replied, None if they haven't replied to
+    /// the event's current SEQUENCE.
+    #[serde(default, with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub replied_at: Option<NaiveDateTime>,
+}
+
+fn default_rsvp() -> bool
+{
+    true
+}
+
+impl FromRow for Attendee
+{
+    type SelfType = Attendee;
+
+    fn from_row(row: &Row) -> Result<Self::SelfType, DatabaseError>
+    {
+        let role: String = get_cell_from_row(row, "role")?;
+        let partstat: String = get_cell_from_row(row, "partstat")?;
+
+        Ok(
+            Attendee {
+                email: get_cell_from_row(row, "email")?,
+                name: get_cell_from_row(row, "name")?,
+                role: AttendeeRole::from_str(&role)
+                    .ok_or_else(|| DatabaseErrorKind::FailedConstraint("role_values".to_owned()))?,
+                partstat: ParticipationStatus::from_str(&partstat)
+                    .ok_or_else(|| DatabaseErrorKind::FailedConstraint("partstat_values".to_owned()))?,
+                rsvp: get_cell_from_row(row, "rsvp")?,
+                replied_at: get_cell_from_row(row, "replied_at")?,
+            }
+        )
+    }
+}
+
+/// An event's organizer and attendees.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct EventAttendees
+{
+    /// Required if there are attendees.
+    pub organizer: Option<Organizer>,
+
+    pub attendees: Vec<Attendee>,
+
+    /// The event's SEQUENCE, ignored when setting attendees.
+    #[serde(default)]
+    pub sequence: i32,
+}
+
+#[derive(Error, Debug)]
+pub enum AttendeesError
+{
+    #[error("Invalid email address or name: {0}")]
+    InvalidAddress(String),
+
+    #[error("Events with attendees must have an organizer.")]
+    MissingOrganizer,
+
+    #[error("{0} is in the attendees more than once.")]
+    DuplicateAttendee(String),
+
+    #[error(transparent)]
+    Database(#[from] DatabaseError),
+}
+
+impl From<postgres::Error> for AttendeesError
+{
+    fn from(e: postgres::Error) -> Self
+    {
+        AttendeesError::Database(e.into())
+    }
+}
+
+/// Normalizes an email address (or a `mailto:` URI, as in iCalendar's
+/// CAL-ADDRESS values) to a lower case address without `mailto:`.
+pub fn normalize_address(address: &str) -> String
+{
+    let address = address.trim();
+
+    let address = match address.get(..7)
+    {
+        Some(scheme) if scheme.eq_ignore_ascii_case("mailto:") => &address[7..],
+        _ => address,
+    };
+
+    address.to_ascii_lowercase()
+}
+
+/// Checks that a (normalized) email address has a local part and a domain and
+/// only the characters RFC 5322 allows in unquoted addresses. Addresses end
+/// up in SMTP commands and email headers, so this must never let line breaks
+/// or angle brackets through.
+pub fn is_valid_address(address: &str) -> bool
+{
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(c);
+
+    match address.split_once('@')
+    {
+        Some((local, domain)) => !local.is_empty()
+            && !domain.is_empty()
+            && address.len() <= MAX_ADDRESS_LENGTH
+            && local.chars().all(allowed)
+            && domain.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.'),
+        None => false,
+    }
+}
+
+/// Names end up in iCalendar parameters, control characters would break them.
+fn is_valid_name(name: &Option<String>) -> bool
+{
+    name.as_ref().map_or(true, |name| !name.chars().any(|c| c.is_control()))
+}
+
+/// The organizer and attendees of an event of the calendar that isn't a child.
+/// Returns None if there's no such event.
+pub fn get(db: &mut impl GenericClient, calendar_id: &Uuid, event_id: &Uuid) -> Result<Option<EventAttendees>, DatabaseError>
+{
+    let query = "
+        SELECT organizer, organizer_name, sequence FROM events
+        WHERE calendar_id = $1 AND id = $2 AND parent_event_id IS NULL AND deleted_at IS NULL;
+    ";
+
+    let row = match db.query(query, &[calendar_id, event_id])?.into_iter().next()
+    {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    let organizer = match get_cell_from_row::<Option<String>>(&row, "organizer")?
+    {
+        Some(email) => Some(Organizer { email, name: get_cell_from_row(&row, "organizer_name")? }),
+        None => None,
+    };
+
+    Ok(
+        Some(EventAttendees {
+            organizer,
+            attendees: get_attendees(db, event_id)?,
+            sequence: get_cell_from_row(&row, "sequence")?,
+        })
+    )
+}
+
+fn get_attendees(db: &mut impl GenericClient, event_id: &Uuid) -> Result<Vec<Attendee>, DatabaseError>
+{
+    let query = format!("SELECT {} FROM event_attendees WHERE event_id = $1 ORDER BY email;", ATTENDEE_FIELDS);
+
+    db.query(query.as_str(), &[event_id])?
+        .iter()
+        .map(|row| Attendee::from_row(row))
+        .collect()
+}
+
+/// Replaces the organizer and attendees of an event of the calendar that isn't
+/// a child. Attendees that were already attending keep their participation
+/// status. Removed attendees get a CANCEL and the others a REQUEST. Doesn't
+/// check whether the caller can change the event, callers have to. Returns
+/// None if there's no such event.
+pub fn set(db: &mut impl GenericClient, calendar_id: &Uuid, event_id: &Uuid, new: &EventAttendees) -> Result<Option<EventAttendees>, AttendeesError>
+{
+    let organizer = new.organizer
+        .as_ref()
+        .map(|organizer| Organizer { email: normalize_address(&organizer.email), name: organizer.name.clone() });
+
+    let mut emails: Vec<String> = vec![];
+
+    for attendee in new.attendees.iter()
+    {
+        let email = normalize_address(&attendee.email);
+
+        if !is_valid_address(&email) || !is_valid_name(&attendee.name)
+        {
+            return Err(AttendeesError::InvalidAddress(attendee.email.clone()));
+        }
+
+        if emails.contains(&email)
+        {
+            return Err(AttendeesError::DuplicateAttendee(email));
+        }
+
+        emails.push(email);
+    }
+
+    match &organizer
+    {
+        Some(organizer) if !is_valid_address(&organizer.email) || !is_valid_name(&organizer.name) =>
+        {
+            return Err(AttendeesError::InvalidAddress(organizer.email.clone()));
+        },
+        None if !emails.is_empty() => return Err(AttendeesError::MissingOrganizer),
+        _ => {},
+    }
+
+    let mut transaction = db.transaction()?;
+
+    let query = "
+        SELECT id FROM events
+        WHERE calendar_id = $1 AND id = $2 AND parent_event_id IS NULL AND deleted_at IS NULL
+        FOR UPDATE;
+    ";
+
+    if transaction.query(query, &[calendar_id, event_id])?.is_empty()
+    {
+        return Ok(None);
+    }
+
+    // Removed attendees are told before the old organizer is replaced.
+    let removed: Vec<String> = get_attendees(&mut transaction, event_id)?
+        .into_iter()
+        .map(|attendee| attendee.email)
+        .filter(|email| !emails.contains(email))
+        .collect();
+
+    if !removed.is_empty()
+    {
+        if let Some(series) = Series::load(&mut transaction, event_id)?
+        {
+            series.queue(&mut transaction, Method::Cancel, &removed)?;
+        }
+
+        transaction.execute("DELETE FROM event_attendees WHERE event_id = $1 AND email = ANY($2);", &[event_id, &removed])?;
+    }
+
+    transaction.execute(
+        "UPDATE events SET organizer = $2, organizer_name = $3 WHERE id = $1;",
+        &[event_id, &organizer.as_ref().map(|o| &o.email), &organizer.as_ref().and_then(|o| o.name.as_ref())]
+    )?;
+
+    for (attendee, email) in new.attendees.iter().zip(emails.iter())
+    {
+        transaction.execute(
+            "
+            INSERT INTO event_attendees (event_id, email, name, role, rsvp) VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (event_id, email) DO UPDATE SET name = EXCLUDED.name, role = EXCLUDED.role, rsvp = EXCLUDED.rsvp;
+            ",
+            &[event_id, email, &attendee.name, &attendee.role.as_str(), &attendee.rsvp]
+        )?;
+    }
+
+    notify(&mut transaction, event_id)?;
+
+    let attendees = get(&mut transaction, calendar_id, event_id)?;
+
+    transaction.commit()?;
+
+    Ok(attendees)
+}
+
+/// Queues the messages about a change to an event, which are about its whole
+/// series, see `notify`.
+pub fn notify_change(db: &mut impl GenericClient, event: &EventPlain) -> Result<(), DatabaseError>
+{
+    match event.parent_id.or(event.id)
+    {
+        Some(series_id) => notify(db, &series_id),
+        None => Ok(()),
+    }
+}
+
+/// Queues a REQUEST with the series' current state for each of its attendees,
+/// or a CANCEL if its event was deleted. Does nothing if it has no attendees.
+/// Should be called in the same transaction as the change.
+pub fn notify(db: &mut impl GenericClient, series_id: &Uuid) -> Result<(), DatabaseError>
+{
+    let series = match Series::load(db, series_id)?
+    {
+        Some(series) => series,
+        None => return Ok(()),
+    };
+
+    let method = if series.deleted { Method::Cancel } else { Method::Request };
+
+    let recipients: Vec<String> = series.attendees
+        .iter()
+        .map(|attendee| attendee.email.clone())
+        .collect();
+
+    series.queue(db, method, &recipients)
+}
+
+/// An event that isn't a child, with what's needed to write messages about it.
+struct Series
+{
+    id: Uuid,
+    uid: String,
+    sequence: i32,
+    deleted: bool,
+    organizer: Organizer,
+    attendees: Vec<Attendee>,
+
+    /// The event first, then its children that weren't deleted,
+    /// with their SEQUENCEs.
+    events: Vec<(EventPlain, i32)>,
+}
+
+impl Series
+{
+    /// Loads the series, deleted or not. Returns None if it has no
+    /// organizer, i.e. no attendees, or there's no such series.
+    fn load(db: &mut impl GenericClient, series_id: &Uuid) -> Result<Option<Series>, DatabaseError>
+    {
+        let query = "
+            SELECT * FROM events
+            WHERE id = $1 OR (parent_event_id = $1 AND deleted_at IS NULL)
+            ORDER BY parent_event_id IS NOT NULL, start_date, id;
+        ";
+
+        let rows = db.query(query, &[series_id])?;
+
+        let row = match rows.get(0)
+        {
+            Some(row) if get_cell_from_row::<Uuid>(row, "id")? == *series_id && get_cell_from_row::<Option<Uuid>>(row, "parent_event_id")?.is_none() => row,
+            _ => return Ok(None),
+        };
+
+        let organizer = match get_cell_from_row::<Option<String>>(row, "organizer")?
+        {
+            Some(email) => Organizer { email, name: get_cell_from_row(row, "organizer_name")? },
+            None => return Ok(None),
+        };
+
+        let events = rows
+            .iter()
+            .map(|row| Ok((Event::from_row(row)?.into_plain(), get_cell_from_row(row, "sequence")?)))
+            .collect::<Result<Vec<(EventPlain, i32)>, DatabaseError>>()?;
+
+        Ok(
+            Some(Series {
+                id: *series_id,
+                uid: get_cell_from_row::<Option<String>>(row, "ical_uid")?.unwrap_or_else(|| export::event_uid(series_id)),
+                sequence: get_cell_from_row(row, "sequence")?,
+                deleted: get_cell_from_row::<Option<NaiveDateTime>>(row, "deleted_at")?.is_some(),
+                organizer,
+                attendees: get_attendees(db, series_id)?,
+                events,
+            })
+        )
+    }
+
+    /// Queues a message with the method for each of the recipients, except
+    /// the organizer, who doesn't have to be told about their own changes.
+    fn queue(&self, db: &mut impl GenericClient, method: Method, recipients: &[String]) -> Result<(), DatabaseError>
+    {
+        let recipients: Vec<String> = recipients
+            .iter()
+            .filter(|recipient| **recipient != self.organizer.email)
+            .cloned()
+            .collect();
+
+        if recipients.is_empty()
+        {
+            return Ok(());
+        }
+
+        let now = Utc::now().naive_utc();
+
+        let message = match method
+        {
+            Method::Request => itip::request(&self.uid, &self.organizer, &self.attendees, &self.events, now),
+            _ =>
+            {
+                let cancelled: Vec<&Attendee> = self.attendees
+                    .iter()
+                    .filter(|attendee| recipients.contains(&attendee.email))
+                    .collect();
+
+                itip::cancel(&self.uid, &self.organizer, &cancelled, &self.events[0].0, self.sequence, now)
+            },
+        };
+
+        outbox::enqueue(db, &self.id, method, self.sequence, &self.organizer.email, &recipients, &message.to_ics())
+    }
+}
+
+/// What `process_reply` did with a reply.
+pub enum ReplyOutcome
+{
+    /// The attendee's participation status was updated.
+    Applied(Uuid, Attendee),
+
+    /// The reply is about an older SEQUENCE of the event, so it was
+    /// ignored. Has the attendee as they are.
+    Stale(Uuid, Attendee),
+
+    /// The calendar has no event with the reply's UID.
+    EventNotFound,
+
+    /// The replying attendee isn't one of the event's attendees.
+    AttendeeNotFound,
+}
+
+/// Applies an attendee's REPLY to an event of the calendar: updates their
+/// participation status, unless the reply is about an older SEQUENCE.
+pub fn process_reply(db: &mut impl GenericClient, calendar_id: &Uuid, reply: &Reply) -> Result<ReplyOutcome, DatabaseError>
+{
+    let mut transaction = db.transaction()?;
+
+    // Locks the event so that it can't be rescheduled while the reply is applied.
+    let query = "
+        SELECT id, sequence FROM events
+        WHERE
+            calendar_id = $1
+            AND parent_event_id IS NULL
+            AND deleted_at IS NULL
+            AND (ical_uid = $2 OR id = $3)
+        LIMIT 1
+        FOR UPDATE;
+    ";
+
+    let (event_id, sequence): (Uuid, i32) = match transaction.query(query, &[calendar_id, &reply.uid, &export::event_id_from_uid(&reply.uid)])?.get(0)
+    {
+        Some(row) => (get_cell_from_row(row, "id")?, get_cell_from_row(row, "sequence")?),
+        None => return Ok(ReplyOutcome::EventNotFound),
+    };
+
+    let rows = if reply.sequence < sequence
+    {
+        let query = format!("SELECT {} FROM event_attendees WHERE event_id = $1 AND email = $2;", ATTENDEE_FIELDS);
+        transaction.query(query.as_str(), &[&event_id, &reply.attendee])?
+    }
+    else
+    {
+        let query = format!("UPDATE event_attendees SET partstat = $3, replied_at = NOW() WHERE event_id = $1 AND email = $2 RETURNING {};", ATTENDEE_FIELDS);
+        transaction.query(query.as_str(), &[&event_id, &reply.attendee, &reply.partstat.as_str()])?
+    };
+
+    let attendee = match rows.get(0)
+    {
+        Some(row) => Attendee::from_row(row)?,
+        None => return Ok(ReplyOutcome::AttendeeNotFound),
+    };
+
+    transaction.commit()?;
+
+    if reply.sequence < sequence
+    {
+        Ok(ReplyOutcome::Stale(event_id, attendee))
+    }
+    else
+    {
+        Ok(ReplyOutcome::Applied(event_id, attendee))
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{EventAttendees, Organizer, Attendee, AttendeeRole, ParticipationStatus, ReplyOutcome, AttendeesError, normalize_address, is_valid_address, set, get, process_reply};
+    use super::itip::{self, Reply};
+    use super::outbox;
+    use crate::ical::parser;
+    use crate::test_helpers::{connect, insert_tenant, insert_calendar};
+    use uuid::Uuid;
+
+    fn attendee(email: &str) -> Attendee
+    {
+        Attendee {
+            email: email.to_owned(),
+            name: None,
+            role: AttendeeRole::ReqParticipant,
+            partstat: ParticipationStatus::NeedsAction,
+            rsvp: true,
+            replied_at: None,
+        }
+    }
+
+    #[test]
+    fn validates_addresses()
+    {
+        assert_eq!(normalize_address(" MAILTO:Ana@Example.com "), "ana@example.com");
+        assert_eq!(normalize_address("bob@example.com"), "bob@example.com");
+
+        assert!(is_valid_address("ana.b+tag@mail.example.com"));
+        assert!(!is_valid_address("ana"));
+        assert!(!is_valid_address("@example.com"));
+        assert!(!is_valid_address("ana@"));
+        assert!(!is_valid_address("ana@example.com>\r\nRCPT TO:<eve@example.com"));
+        assert!(!is_valid_address("ana smith@example.com"));
+        assert!(!is_valid_address(&format!("{}@example.com", "a".repeat(250))));
+    }
+
+    #[test]
+    #[ignore]
+    fn sends_requests_and_cancels_and_applies_replies()
+    {
+        let mut client = connect();
+        let mut db = client.transaction().unwrap();
+
+        let tenant_id = insert_tenant(&mut db);
+        let calendar_id = insert_calendar(&mut db, &tenant_id);
+        let event_id: Uuid = db.query_one(
+            "INSERT INTO events (calendar_id, start_date, end_date, start_time, end_time) VALUES ($1, '2020-09-01', '2020-09-01', '10:00', '11:00') RETURNING id;",
+            &[&calendar_id]
+        ).unwrap().get("id");
+
+        let messages = |db: &mut postgres::Transaction| outbox::list(db, &calendar_id, &event_id, 0, 100).unwrap();
+
+        let mut attendees = EventAttendees {
+            organizer: Some(Organizer { email: "Org@example.com".to_owned(), name: Some("Org".to_owned()) }),
+            attendees: vec![attendee("ana@example.com"), attendee("mailto:Bob@example.com"), attendee("org@example.com")],
+            sequence: 0,
+        };
+
+        let stored = set(&mut db, &calendar_id, &event_id, &attendees).unwrap().unwrap();
+        assert_eq!(stored.organizer.unwrap().email, "org@example.com");
+        assert_eq!(stored.attendees.len(), 3);
+
+        // The organizer doesn't get their own requests.
+        let sent = messages(&mut db);
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|message| message.method == itip::Method::Request && message.sequence == 0));
+
+        attendees.attendees.push(attendee("ana@example.com"));
+        assert!(matches!(set(&mut db, &calendar_id, &event_id, &attendees), Err(AttendeesError::DuplicateAttendee(_))));
+        attendees.attendees.pop();
+
+        let reply = |partstat, sequence| Reply {
+            uid: format!("{}@calendar-server", event_id),
+            attendee: "ana@example.com".to_owned(),
+            partstat,
+            sequence,
+        };
+
+        match process_reply(&mut db, &calendar_id, &reply(ParticipationStatus::Accepted, 0)).unwrap()
+        {
+            ReplyOutcome::Applied(id, attendee) =>
+            {
+                assert_eq!(id, event_id);
+                assert_eq!(attendee.partstat, ParticipationStatus::Accepted);
+                assert!(attendee.replied_at.is_some());
+            },
+            _ => panic!("The reply wasn't applied."),
+        }
+
+        // Rescheduling increments the sequence, attendees have to reply again
+        // and replies to the old sequence are ignored.
+        db.execute("UPDATE events SET start_time = '12:00', end_time = '13:00' WHERE id = $1;", &[&event_id]).unwrap();
+        super::notify(&mut db, &event_id).unwrap();
+
+        let stored = get(&mut db, &calendar_id, &event_id).unwrap().unwrap();
+        assert_eq!(stored.sequence, 1);
+        assert!(stored.attendees.iter().all(|attendee| attendee.partstat == ParticipationStatus::NeedsAction));
+        assert!(messages(&mut db).iter().any(|message| message.sequence == 1));
+
+        assert!(matches!(process_reply(&mut db, &calendar_id, &reply(ParticipationStatus::Declined, 0)).unwrap(), ReplyOutcome::Stale(..)));
+        assert!(matches!(process_reply(&mut db, &calendar_id, &reply(ParticipationStatus::Declined, 1)).unwrap(), ReplyOutcome::Applied(..)));
+
+        let mut stranger = reply(ParticipationStatus::Accepted, 1);
+        stranger.attendee = "eve@example.com".to_owned();
+        assert!(matches!(process_reply(&mut db, &calendar_id, &stranger).unwrap(), ReplyOutcome::AttendeeNotFound));
+
+        // Removed attendees get a CANCEL.
+        attendees.attendees.remove(1);
+        set(&mut db, &calendar_id, &event_id, &attendees).unwrap().unwrap();
+
+        let cancel = messages(&mut db).into_iter().find(|message| message.method == itip::Method::Cancel).unwrap();
+        assert_eq!(cancel.recipient, "bob@example.com");
+
+        let payload: String = db.query_one("SELECT payload FROM scheduling_messages WHERE id = $1;", &[&cancel.id]).unwrap().get("payload");
+        let vcalendar = parser::parse(&payload).unwrap().remove(0);
+        assert_eq!(vcalendar.property("METHOD").unwrap().value(), "CANCEL");
+        assert_eq!(vcalendar.components[0].property("ATTENDEE").unwrap().value(), "mailto:bob@example.com");
+
+        // Deleting the event cancels it for everyone left.
+        db.execute("UPDATE events SET deleted_at = NOW() WHERE id = $1;", &[&event_id]).unwrap();
+        super::notify(&mut db, &event_id).unwrap();
+
+        let cancels = messages(&mut db).into_iter().filter(|message| message.method == itip::Method::Cancel).count();
+        assert_eq!(cancels, 2);
+        assert!(matches!(process_reply(&mut db, &calendar_id, &reply(ParticipationStatus::Accepted, 2)).unwrap(), ReplyOutcome::EventNotFound));
+    }
+}
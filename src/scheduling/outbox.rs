@@ -0,0 +1,266 @@
+//! Queues scheduling messages and sends them.
+//!
+//! Messages are rows of `scheduling_messages`, queued in the same transaction
+//! as the change they're about. The scheduling worker (see `outbox_worker`)
+//! claims the ones that are due, sends them through the configured `Transport`
+//! and records the outcome. Failed messages are retried with exponential
+//! backoff until they run out of attempts, then they're dead.
+
+use super::itip::Method;
+use super::transport::{Message, Transport};
+use crate::connection_pool::PgsqlPool;
+use crate::database_error::{DatabaseError, DatabaseErrorKind};
+use crate::database_helpers::{FromRow, get_cell_from_row};
+use crate::outbox_worker::{self, Outbox, DeliveryStatus};
+use postgres::{Row, GenericClient};
+use chrono::NaiveDateTime;
+use uuid::Uuid;
+
+const MESSAGE_FIELDS: &str = "id, event_id, method, sequence, recipient, status, attempts, next_attempt_at, last_attempt_at, last_error, created_at";
+
+/// A scheduling message to one of an event's attendees. Its payload isn't
+/// included, it's the iTIP VCALENDAR the attendee got.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct ScheduleMessage
+{
+    pub id: Uuid,
+    pub event_id: Uuid,
+    pub method: Method,
+
+    /// The event's SEQUENCE when the message was queued.
+    pub sequence: i32,
+    pub recipient: String,
+    pub status: DeliveryStatus,
+
+    /// How many times it was sent so far.
+    pub attempts: i32,
+
+    /// When it's sent next, if it's pending.
+    #[serde(with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub next_attempt_at: Option<NaiveDateTime>,
+
+    #[serde(with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub last_attempt_at: Option<NaiveDateTime>,
+
+    /// Why the last attempt failed, None if it didn't.
+    pub last_error: Option<String>,
+
+    #[serde(with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub created_at: Option<NaiveDateTime>,
+}
+
+impl FromRow for ScheduleMessage
+{
+    type SelfType = ScheduleMessage;
+
+    fn from_row(row: &Row) -> Result<Self::SelfType, DatabaseError>
+    {
+        let method: String = get_cell_from_row(row, "method")?;
+        let status: String = get_cell_from_row(row, "status")?;
+        let status = DeliveryStatus::from_str(&status)
+            .ok_or_else(|| DatabaseErrorKind::FailedConstraint("status_values".to_owned()))?;
+
+        Ok(
+            ScheduleMessage {
+                id: get_cell_from_row(row, "id")?,
+                event_id: get_cell_from_row(row, "event_id")?,
+                method: Method::from_str(&method)
+                    .ok_or_else(|| DatabaseErrorKind::FailedConstraint("method_values".to_owned()))?,
+                sequence: get_cell_from_row(row, "sequence")?,
+                recipient: get_cell_from_row(row, "recipient")?,
+                status,
+                attempts: get_cell_from_row(row, "attempts")?,
+                next_attempt_at: match status
+                {
+                    DeliveryStatus::Pending => Some(get_cell_from_row(row, "next_attempt_at")?),
+                    _ => None,
+                },
+                last_attempt_at: get_cell_from_row(row, "last_attempt_at")?,
+                last_error: get_cell_from_row(row, "last_error")?,
+                created_at: Some(get_cell_from_row(row, "created_at")?),
+            }
+        )
+    }
+}
+
+/// Queues a message with the payload about the event for each of the recipients.
+pub fn enqueue(db: &mut impl GenericClient, event_id: &Uuid, method: Method, sequence: i32, organizer: &str, recipients: &[String], payload: &str) -> Result<(), DatabaseError>
+{
+    let query = "
+        INSERT INTO scheduling_messages (calendar_id, event_id, method, sequence, organizer, recipient, payload)
+        SELECT events.calendar_id, events.id, $2, $3, $4, recipient, $6
+        FROM events, UNNEST($5::TEXT[]) AS recipient
+        WHERE events.id = $1;
+    ";
+
+    db.execute(query, &[event_id, &method.as_str(), &sequence, &organizer, &recipients, &payload])?;
+
+    Ok(())
+}
+
+/// Lists the messages about an event of the calendar, newest first.
+pub fn list(db: &mut impl GenericClient, calendar_id: &Uuid, event_id: &Uuid, offset: i64, limit: i64) -> Result<Vec<ScheduleMessage>, DatabaseError>
+{
+    let query = format!("
+        SELECT {} FROM scheduling_messages
+        WHERE calendar_id = $1 AND event_id = $2
+        ORDER BY created_at DESC, id
+        OFFSET $3 LIMIT $4;
+    ", MESSAGE_FIELDS);
+
+    db.query(query.as_str(), &[calendar_id, event_id, &offset, &limit])?
+        .iter()
+        .map(|row| ScheduleMessage::from_row(row))
+        .collect()
+}
+
+/// The `scheduling_messages` outbox, sent through `transport`.
+pub struct Messages
+{
+    pub transport: Box<dyn Transport>,
+}
+
+impl Outbox for Messages
+{
+    type Message = Message;
+
+    const TABLE: &'static str = "scheduling_messages";
+    const NAME: &'static str = "scheduling messages";
+
+    fn load(&self, db: &mut impl GenericClient, ids: &[Uuid]) -> Result<Vec<Message>, DatabaseError>
+    {
+        let query = "
+            SELECT id, method, sequence, organizer, recipient, payload
+            FROM scheduling_messages
+            WHERE id = ANY($1);
+        ";
+
+        db.query(query, &[&ids])?
+            .iter()
+            .map(|row| {
+                let method: String = get_cell_from_row(row, "method")?;
+
+                Ok(
+                    Message {
+                        id: get_cell_from_row(row, "id")?,
+                        method: Method::from_str(&method)
+                            .ok_or_else(|| DatabaseErrorKind::FailedConstraint("method_values".to_owned()))?,
+                        sequence: get_cell_from_row(row, "sequence")?,
+                        organizer: get_cell_from_row(row, "organizer")?,
+                        recipient: get_cell_from_row(row, "recipient")?,
+                        payload: get_cell_from_row(row, "payload")?,
+                    }
+                )
+            })
+            .collect()
+    }
+
+    fn id(message: &Message) -> Uuid
+    {
+        message.id
+    }
+
+    fn recipient(message: &Message) -> &str
+    {
+        &message.recipient
+    }
+
+    fn send(&self, message: &Message) -> Result<(), String>
+    {
+        self.transport.send(message)
+    }
+}
+
+/// Spawns a thread that sends due messages through `transport` every `poll_interval` seconds.
+pub fn spawn_worker(pool: PgsqlPool, transport: Box<dyn Transport>, poll_interval: u64, max_attempts: u32)
+{
+    outbox_worker::spawn_worker(pool, Messages { transport }, poll_interval, max_attempts);
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{enqueue, list, Messages};
+    use crate::scheduling::itip::Method;
+    use crate::scheduling::transport::{Message, Transport};
+    use crate::outbox_worker::{process_due, DeliveryStatus};
+    use crate::test_helpers::{connect, insert_tenant, insert_calendar};
+    use uuid::Uuid;
+    use std::sync::{Arc, Mutex};
+
+    /// Fails the first `failures` messages, keeps the ones it sends.
+    struct FakeTransport
+    {
+        failures: Mutex<u32>,
+        sent: Arc<Mutex<Vec<Message>>>,
+    }
+
+    impl Transport for FakeTransport
+    {
+        fn send(&self, message: &Message) -> Result<(), String>
+        {
+            let mut failures = self.failures.lock().unwrap();
+
+            if *failures > 0
+            {
+                *failures -= 1;
+                return Err("Mailbox unavailable.".to_owned());
+            }
+
+            self.sent.lock().unwrap().push(message.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn messages_are_retried_until_sent()
+    {
+        let mut client = connect();
+        let mut db = client.transaction().unwrap();
+
+        // Messages other tests left behind would be sent too.
+        db.execute("DELETE FROM scheduling_messages;", &[]).unwrap();
+
+        let tenant_id = insert_tenant(&mut db);
+        let calendar_id = insert_calendar(&mut db, &tenant_id);
+        let event_id: Uuid = db.query_one(
+            "INSERT INTO events (calendar_id, start_date, end_date) VALUES ($1, '2020-09-01', '2020-09-01') RETURNING id;",
+            &[&calendar_id]
+        ).unwrap().get("id");
+
+        let recipients = vec!["ana@example.com".to_owned(), "bob@example.com".to_owned()];
+        enqueue(&mut db, &event_id, Method::Request, 3, "org@example.com", &recipients, "BEGIN:VCALENDAR").unwrap();
+
+        let messages = list(&mut db, &calendar_id, &event_id, 0, 10).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|message| message.status == DeliveryStatus::Pending && message.sequence == 3));
+
+        let sent = Arc::new(Mutex::new(vec![]));
+        let outbox = Messages { transport: Box::new(FakeTransport { failures: Mutex::new(1), sent: sent.clone() }) };
+
+        // One fails and is retried later.
+        assert_eq!(process_due(&mut db, &outbox, 2).unwrap(), 2);
+        assert_eq!(sent.lock().unwrap().len(), 1);
+        assert_eq!(process_due(&mut db, &outbox, 2).unwrap(), 0);
+
+        let failed = list(&mut db, &calendar_id, &event_id, 0, 10).unwrap()
+            .into_iter()
+            .find(|message| message.status == DeliveryStatus::Pending)
+            .unwrap();
+
+        assert_eq!(failed.attempts, 1);
+        assert_eq!(failed.last_error.as_deref(), Some("Mailbox unavailable."));
+
+        db.execute("UPDATE scheduling_messages SET next_attempt_at = NOW();", &[]).unwrap();
+        assert_eq!(process_due(&mut db, &outbox, 2).unwrap(), 1);
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|message| message.organizer == "org@example.com" && message.payload == "BEGIN:VCALENDAR"));
+        assert!(list(&mut db, &calendar_id, &event_id, 0, 10).unwrap().iter().all(|message| message.status == DeliveryStatus::Delivered));
+    }
+}
@@ -0,0 +1,27 @@
+//! How scheduling messages leave the server.
+
+use super::itip::Method;
+use uuid::Uuid;
+
+/// A scheduling message claimed by the outbox worker, ready to be sent.
+#[derive(Clone, Debug)]
+pub struct Message
+{
+    pub id: Uuid,
+    pub method: Method,
+    pub sequence: i32,
+
+    /// The event's organizer, answers go to them.
+    pub organizer: String,
+    pub recipient: String,
+
+    /// The iTIP VCALENDAR.
+    pub payload: String,
+}
+
+/// Sends scheduling messages to their recipients, e.g. by email (see `imip`).
+/// Errors are a description of what went wrong, the message is retried later.
+pub trait Transport: Send + Sync
+{
+    fn send(&self, message: &Message) -> Result<(), String>;
+}
@@ -0,0 +1,283 @@
+//! Incremental synchronization of a calendar's events with sync tokens,
+//! modeled after RFC 6578 (WebDAV Collection Synchronization).
+//!
+//! Every change to an event gets the next change sequence number of its
+//! calendar (see db_schema/07.sql). A sync token is just a calendar id plus
+//! a sequence number, clients send it back to get every event that changed
+//! after it.
+//!
+//! Changes to the calendars of a tenant get the next number of the tenant's
+//! own sequence (see db_schema/21.sql), so there are tokens for a tenant's
+//! calendar list too, which are a tenant id plus a sequence number.
+
+use crate::database_error::DatabaseError;
+use crate::database_helpers::{FromRow, get_cell_from_row};
+use crate::event::{Event, EventPlain, ToPlain};
+use crate::encoding_helpers::{to_hex, from_hex};
+use postgres::GenericClient;
+use chrono::NaiveDateTime;
+use uuid::Uuid;
+use std::convert::TryInto;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// Clients get a sync token as an opaque string, they
+/// should not try to build or parse one.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SyncToken
+{
+    /// Id of the calendar, or of the tenant for tokens of calendar lists.
+    /// Tenants and calendars never have the same id, so a token of one
+    /// can't be taken for a token of the other.
+    calendar_id: Uuid,
+    seq: i64,
+}
+
+#[derive(Error, Debug)]
+#[error("Invalid sync token.")]
+pub struct InvalidSyncTokenError;
+
+impl SyncToken
+{
+    pub fn get_calendar_id(&self) -> Uuid { self.calendar_id }
+
+    /// Gets a token that points to the current state of the calendar. Returns None
+    /// if the calendar does not exist.
+    ///
+    /// Get the token *before* reading the calendar's events, so that changes
+    /// committed in between are not lost (they'll be sent again on the next sync).
+    pub fn current(db: &mut impl GenericClient, calendar_id: &Uuid) -> Result<Option<SyncToken>, DatabaseError>
+    {
+        let rows = db.query("SELECT change_seq FROM calendars WHERE id = $1 AND deleted_at IS NULL;", &[calendar_id])?;
+
+        if let Some(row) = rows.get(0)
+        {
+            Ok(Some(SyncToken { calendar_id: *calendar_id, seq: get_cell_from_row(row, "change_seq")? }))
+        }
+        else
+        {
+            Ok(None)
+        }
+    }
+
+    /// Same as `current`, for the calendar list of a tenant.
+    pub fn current_for_tenant(db: &mut impl GenericClient, tenant_id: &Uuid) -> Result<Option<SyncToken>, DatabaseError>
+    {
+        let rows = db.query("SELECT change_seq FROM tenants WHERE id = $1;", &[tenant_id])?;
+
+        if let Some(row) = rows.get(0)
+        {
+            Ok(Some(SyncToken { calendar_id: *tenant_id, seq: get_cell_from_row(row, "change_seq")? }))
+        }
+        else
+        {
+            Ok(None)
+        }
+    }
+
+    /// Gets the sequence number of the token if it's a valid token of the calendar:
+    /// it's not newer than the calendar's current state, nor older than the changes
+    /// that were purged. Returns None otherwise.
+    pub fn seq_for_calendar(&self, db: &mut impl GenericClient, calendar_id: &Uuid) -> Result<Option<i64>, DatabaseError>
+    {
+        self.seq_for("SELECT change_seq, purged_seq FROM calendars WHERE id = $1 AND deleted_at IS NULL;", db, calendar_id)
+    }
+
+    /// Same as `seq_for_calendar`, for the calendar list of a tenant.
+    pub fn seq_for_tenant(&self, db: &mut impl GenericClient, tenant_id: &Uuid) -> Result<Option<i64>, DatabaseError>
+    {
+        self.seq_for("SELECT change_seq, purged_seq FROM tenants WHERE id = $1;", db, tenant_id)
+    }
+
+    fn seq_for(&self, query: &str, db: &mut impl GenericClient, id: &Uuid) -> Result<Option<i64>, DatabaseError>
+    {
+        if self.calendar_id != *id
+        {
+            return Ok(None);
+        }
+
+        let rows = db.query(query, &[id])?;
+
+        if let Some(row) = rows.get(0)
+        {
+            let current_seq: i64 = get_cell_from_row(row, "change_seq")?;
+            let purged_seq: i64 = get_cell_from_row(row, "purged_seq")?;
+
+            if self.seq >= purged_seq && self.seq <= current_seq
+            {
+                return Ok(Some(self.seq));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Display for SyncToken
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result
+    {
+        let mut bytes = self.calendar_id.as_bytes().to_vec();
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+
+        f.write_str(&to_hex(&bytes))
+    }
+}
+
+impl FromStr for SyncToken
+{
+    type Err = InvalidSyncTokenError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        let bytes = from_hex(s).ok_or(InvalidSyncTokenError)?;
+
+        if bytes.len() != 24
+        {
+            return Err(InvalidSyncTokenError);
+        }
+
+        Ok(
+            SyncToken {
+                calendar_id: Uuid::from_slice(&bytes[..16]).map_err(|_| InvalidSyncTokenError)?,
+                seq: i64::from_be_bytes(bytes[16..].try_into().map_err(|_| InvalidSyncTokenError)?),
+            }
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct SyncResponse
+{
+    /// Events that changed since the sync token, ordered by when they
+    /// changed. Deleted events have `deleted` set to true.
+    pub events: Vec<EventPlain>,
+
+    /// Send this in the next sync request.
+    pub sync_token: String,
+
+    /// If true, there are more changes. Sync again right
+    /// away with `sync_token` to get them.
+    pub has_more: bool,
+}
+
+pub enum SyncOutcome
+{
+    Changes(SyncResponse),
+
+    /// The token is older than the oldest change we still remember, or is
+    /// newer than the calendar's current state. Clients should discard
+    /// their copy of the calendar and do a full sync (without a token).
+    InvalidToken,
+
+    CalendarNotFound,
+}
+
+/// Gets at most `max_results` events of the calendar that changed after `token`.
+/// If `token` is None, gets all events of the calendar (except for deleted ones).
+pub fn get_changes(db: &mut impl GenericClient, calendar_id: Uuid, token: Option<SyncToken>, max_results: usize) -> Result<SyncOutcome, DatabaseError>
+{
+    if token.is_some() && token.unwrap().calendar_id != calendar_id
+    {
+        return Ok(SyncOutcome::InvalidToken);
+    }
+
+    // Both queries have to see the same snapshot of the database, otherwise
+    // the new token could point past changes we didn't send.
+    let mut transaction = db.transaction()?;
+    transaction.batch_execute("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY;")?;
+
+    let rows = transaction.query("SELECT change_seq, purged_seq FROM calendars WHERE id = $1 AND deleted_at IS NULL;", &[&calendar_id])?;
+
+    let (current_seq, purged_seq): (i64, i64) = match rows.get(0)
+    {
+        Some(row) => (get_cell_from_row(row, "change_seq")?, get_cell_from_row(row, "purged_seq")?),
+        None => return Ok(SyncOutcome::CalendarNotFound),
+    };
+
+    if let Some(token) = token
+    {
+        if token.seq < purged_seq || token.seq > current_seq
+        {
+            return Ok(SyncOutcome::InvalidToken);
+        }
+    }
+
+    let query = "
+        SELECT * FROM events
+        WHERE
+            calendar_id = $1
+            AND change_seq > $2
+            AND ($3 OR deleted_at IS NULL)
+        ORDER BY change_seq
+        LIMIT $4;
+    ";
+
+    // Get one more event than we need to know whether there are more changes.
+    let rows = transaction.query(query, &[
+        &calendar_id,
+        &token.map(|t| t.seq).unwrap_or(0),
+        &token.is_some(),
+        &(max_results as i64 + 1),
+    ])?;
+
+    let has_more = rows.len() > max_results;
+
+    let mut events = vec![];
+    let mut last_seq = current_seq;
+
+    for row in rows.iter().take(max_results)
+    {
+        let deleted_at: Option<NaiveDateTime> = get_cell_from_row(row, "deleted_at")?;
+
+        let mut plain = Event::from_row(row)?.into_plain();
+        plain.deleted = deleted_at.is_some();
+
+        events.push(plain);
+        last_seq = get_cell_from_row(row, "change_seq")?;
+    }
+
+    let new_token = SyncToken {
+        calendar_id,
+        seq: if has_more { last_seq } else { current_seq },
+    };
+
+    transaction.commit()?;
+
+    Ok(
+        SyncOutcome::Changes(
+            SyncResponse {
+                events,
+                sync_token: new_token.to_string(),
+                has_more,
+            }
+        )
+    )
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::SyncToken;
+    use uuid::Uuid;
+    use std::str::FromStr;
+
+    #[test]
+    fn token_round_trip()
+    {
+        let token = SyncToken {
+            calendar_id: Uuid::from_str("bf10b852-bbcc-43be-93c8-3c236e764247").unwrap(),
+            seq: 1234567,
+        };
+
+        assert_eq!(SyncToken::from_str(&token.to_string()).unwrap(), token);
+    }
+
+    #[test]
+    fn token_rejects_garbage()
+    {
+        assert!(SyncToken::from_str("").is_err());
+        assert!(SyncToken::from_str("not a token").is_err());
+        assert!(SyncToken::from_str("bf10b852bbcc43be93c83c236e764247").is_err());
+    }
+}
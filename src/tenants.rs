@@ -0,0 +1,100 @@
+//! Tenants isolate customers hosted on the same instance from each other.
+//!
+//! Every API key and calendar belongs to a tenant, and a key can only
+//! access calendars of its own tenant. Events don't have a tenant of
+//! their own, they belong to the tenant of their calendar, so every
+//! route that works on a calendar's events has to check the calendar
+//! with `owns_calendar` first (the `CalendarAccess` guard does this).
+
+use crate::database_error::DatabaseError;
+use crate::database_helpers::get_cell_from_row;
+use postgres::GenericClient;
+use uuid::Uuid;
+
+/// Gets the id of the tenant with the name, None if there's no such tenant.
+/// Names are unique, see db_schema/09.sql.
+pub fn get_id_by_name(db: &mut impl GenericClient, name: &str) -> Result<Option<Uuid>, DatabaseError>
+{
+    db.query_opt("SELECT id FROM tenants WHERE name = $1;", &[&name])?
+        .map(|row| get_cell_from_row(&row, "id"))
+        .transpose()
+}
+
+/// Checks if the calendar exists and belongs to the tenant. Deleted
+/// calendars (tombstones) still belong to their tenant.
+pub fn owns_calendar(db: &mut impl GenericClient, tenant_id: &Uuid, calendar_id: &Uuid) -> Result<bool, DatabaseError>
+{
+    let rows = db.query("SELECT id FROM calendars WHERE id = $1 AND tenant_id = $2;", &[calendar_id, tenant_id])?;
+
+    Ok(!rows.is_empty())
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{owns_calendar, get_id_by_name};
+    use crate::calendar::{get_calendar, list_calendars};
+    use crate::test_helpers::{connect, insert_tenant, insert_calendar};
+    use uuid::Uuid;
+
+    #[test]
+    #[ignore]
+    fn tenants_only_see_their_calendars()
+    {
+        let mut client = connect();
+        let mut db = client.transaction().unwrap();
+
+        let tenant_a = insert_tenant(&mut db);
+        let tenant_b = insert_tenant(&mut db);
+        let calendar_a = insert_calendar(&mut db, &tenant_a);
+        let calendar_b = insert_calendar(&mut db, &tenant_b);
+
+        assert!(owns_calendar(&mut db, &tenant_a, &calendar_a).unwrap());
+        assert!(!owns_calendar(&mut db, &tenant_a, &calendar_b).unwrap());
+        assert!(!owns_calendar(&mut db, &tenant_b, &calendar_a).unwrap());
+
+        assert!(get_calendar(&mut db, &tenant_a, &calendar_a).unwrap().is_some());
+        assert!(get_calendar(&mut db, &tenant_a, &calendar_b).unwrap().is_none());
+
+        let listed: Vec<Uuid> = list_calendars(&mut db, &tenant_b, None, 0, 100)
+            .unwrap()
+            .iter()
+            .map(|c| c.get_id())
+            .collect();
+
+        assert_eq!(listed, vec![calendar_b]);
+    }
+
+    #[test]
+    #[ignore]
+    fn tenants_are_found_by_name()
+    {
+        let mut client = connect();
+        let mut db = client.transaction().unwrap();
+
+        let tenant_id = insert_tenant(&mut db);
+        let name: String = db.query_one("SELECT name FROM tenants WHERE id = $1;", &[&tenant_id]).unwrap().get("name");
+
+        assert_eq!(get_id_by_name(&mut db, &name).unwrap(), Some(tenant_id));
+        assert_eq!(get_id_by_name(&mut db, "no-such-tenant").unwrap(), None);
+        assert!(db.execute("INSERT INTO tenants (name) VALUES ($1);", &[&name]).is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn deleted_calendars_keep_their_tenant()
+    {
+        let mut client = connect();
+        let mut db = client.transaction().unwrap();
+
+        let tenant_a = insert_tenant(&mut db);
+        let tenant_b = insert_tenant(&mut db);
+        let calendar_a = insert_calendar(&mut db, &tenant_a);
+
+        db.execute("UPDATE calendars SET deleted_at = NOW() WHERE id = $1;", &[&calendar_a]).unwrap();
+
+        assert!(owns_calendar(&mut db, &tenant_a, &calendar_a).unwrap());
+        assert!(!owns_calendar(&mut db, &tenant_b, &calendar_a).unwrap());
+        assert!(get_calendar(&mut db, &tenant_a, &calendar_a).unwrap().is_none());
+    }
+}
@@ -0,0 +1,72 @@
+//! Fixtures shared by the tests that need a database.
+//!
+//! See docs/dev/testing.md for how to run them.
+
+use postgres::{Client, NoTls, GenericClient};
+use rocket::local::Client as HttpClient;
+use crate::connection_pool::PgsqlPool;
+use crate::configs::Configs;
+use crate::rate_limit::{RateLimiter, MemoryStore};
+use crate::api_keys::{self, NewApiKey};
+use uuid::Uuid;
+
+pub fn database_url() -> String
+{
+    std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set to run database tests.")
+}
+
+pub fn connect() -> Client
+{
+    Client::connect(&database_url(), NoTls).expect("Failed to connect to the test database.")
+}
+
+pub fn insert_tenant(db: &mut impl GenericClient) -> Uuid
+{
+    // Tenant names are unique.
+    db.query_one("INSERT INTO tenants (name) VALUES ('test-' || gen_random_uuid()) RETURNING id;", &[])
+        .unwrap()
+        .get("id")
+}
+
+pub fn insert_calendar(db: &mut impl GenericClient, tenant_id: &Uuid) -> Uuid
+{
+    db.query_one("INSERT INTO calendars (tenant_id, name) VALUES ($1, 'test') RETURNING id;", &[tenant_id])
+        .unwrap()
+        .get("id")
+}
+
+/// Creates an API key in the tenant, returns the key to send in the `Authorization` header.
+pub fn insert_api_key(db: &mut impl GenericClient, tenant_id: &Uuid, scopes: &[&str], calendar_ids: Option<Vec<Uuid>>) -> String
+{
+    let new_key = NewApiKey {
+        label: "test".to_owned(),
+        scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+        expires_at: None,
+        calendar_ids,
+        rate_limit: None,
+    };
+
+    api_keys::create(db, tenant_id, &new_key).unwrap().key
+}
+
+/// A client for the server, connected to the test database, without JWT
+/// authentication. Requests aren't made inside a transaction, so tests that
+/// use it commit what they insert and should only use tenants of their own.
+pub fn http_client() -> HttpClient
+{
+    let configs = Configs::get_configs();
+    let rate_limiter = RateLimiter::new(Box::new(MemoryStore::new()), configs.get_rate_limit());
+    let rocket = rocket::ignite();
+    let change_broadcaster = crate::get_change_broadcaster(&configs, &rocket);
+
+    let rocket = crate::build_rocket(
+        rocket,
+        PgsqlPool::new(&database_url()),
+        configs,
+        None,
+        rate_limiter,
+        change_broadcaster,
+    );
+
+    HttpClient::new(rocket).expect("Failed to build the server.")
+}
@@ -0,0 +1,118 @@
+//! Deleted calendars and events are not removed from the database right away,
+//! they're kept as tombstones (rows with a `deleted_at`) so that clients syncing
+//! changes can learn about the deletion. This module purges tombstones once
+//! they're older than the configured retention period.
+
+use crate::connection_pool::PgsqlPool;
+use crate::database_error::DatabaseError;
+use postgres::GenericClient;
+use std::thread;
+use std::time::Duration;
+use log::{info, error};
+
+/// Permanently deletes tombstones (and change feed entries, finished webhook
+/// deliveries and scheduling messages) older than `retention_days`. Returns
+/// the amount of events purged.
+pub fn purge_expired(db: &mut impl GenericClient, retention_days: u32) -> Result<u64, DatabaseError>
+{
+    let mut transaction = db.transaction()?;
+
+    // Events of purged calendars have to go regardless of when they were deleted
+    // since they reference the calendar. Children go first since they reference
+    // their parents.
+    let condition = "
+        (deleted_at IS NOT NULL AND deleted_at < NOW() - make_interval(days => $1))
+        OR calendar_id IN (SELECT id FROM calendars WHERE deleted_at < NOW() - make_interval(days => $1))
+    ";
+
+    let purged_children = transaction.execute(
+        format!("DELETE FROM events WHERE parent_event_id IS NOT NULL AND ({});", condition).as_str(),
+        &[&(retention_days as i32)]
+    )?;
+
+    // Children of purged parents that weren't deleted themselves (which shouldn't
+    // happen, but just in case) are detached instead.
+    transaction.execute(
+        format!("UPDATE events SET parent_event_id = NULL, original_start_date = NULL WHERE parent_event_id IN (SELECT id FROM events WHERE {});", condition).as_str(),
+        &[&(retention_days as i32)]
+    )?;
+
+    let purged_events = transaction.execute(
+        format!("DELETE FROM events WHERE {};", condition).as_str(),
+        &[&(retention_days as i32)]
+    )?;
+
+    // Same as below, for the tokens of the tenants' calendar lists.
+    transaction.execute(
+        "
+        UPDATE tenants SET purged_seq = GREATEST(tenants.purged_seq, purged.seq)
+        FROM (
+            SELECT tenant_id, MAX(tenant_change_seq) AS seq FROM calendars
+            WHERE deleted_at < NOW() - make_interval(days => $1)
+            GROUP BY tenant_id
+        ) AS purged
+        WHERE tenants.id = purged.tenant_id;
+        ",
+        &[&(retention_days as i32)]
+    )?;
+
+    transaction.execute(
+        "DELETE FROM calendars WHERE deleted_at < NOW() - make_interval(days => $1);",
+        &[&(retention_days as i32)]
+    )?;
+
+    // Sync tokens from before the purged changes are not valid anymore,
+    // since the client would never learn about purged deletions.
+    transaction.execute(
+        "
+        UPDATE calendars SET purged_seq = GREATEST(calendars.purged_seq, purged.seq)
+        FROM (
+            SELECT calendar_id, MAX(seq) AS seq FROM change_feed
+            WHERE changed_at < NOW() - make_interval(days => $1)
+            GROUP BY calendar_id
+        ) AS purged
+        WHERE calendars.id = purged.calendar_id;
+        ",
+        &[&(retention_days as i32)]
+    )?;
+
+    transaction.execute(
+        "DELETE FROM change_feed WHERE changed_at < NOW() - make_interval(days => $1);",
+        &[&(retention_days as i32)]
+    )?;
+
+    transaction.execute(
+        "DELETE FROM webhook_deliveries WHERE status <> 'pending' AND created_at < NOW() - make_interval(days => $1);",
+        &[&(retention_days as i32)]
+    )?;
+
+    transaction.execute(
+        "DELETE FROM scheduling_messages WHERE status <> 'pending' AND created_at < NOW() - make_interval(days => $1);",
+        &[&(retention_days as i32)]
+    )?;
+
+    transaction.commit()?;
+
+    Ok(purged_children + purged_events)
+}
+
+/// Spawns a thread that calls `purge_expired` every `interval` seconds.
+pub fn spawn_purge_job(pool: PgsqlPool, retention_days: u32, interval: u64)
+{
+    thread::spawn(move || {
+        loop
+        {
+            match pool.get_conn()
+            {
+                Ok(mut conn) => match purge_expired(&mut **conn, retention_days)
+                {
+                    Ok(purged) => if purged > 0 { info!("Purged {} expired event tombstones.", purged) },
+                    Err(e) => error!("Failed to purge expired tombstones: {}", e),
+                },
+                Err(e) => error!("Failed to purge expired tombstones: {}", e),
+            }
+
+            thread::sleep(Duration::from_secs(interval));
+        }
+    });
+}
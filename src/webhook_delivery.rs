@@ -0,0 +1,407 @@
+//! Sends the webhook deliveries queued by `webhooks::enqueue`, see docs/webhooks.md.
+//!
+//! Deliveries are rows of `webhook_deliveries` (an outbox). The delivery worker
+//! (see `outbox_worker`) claims the ones that are due, POSTs them to their
+//! subscription's target URL and records the outcome. Failed deliveries are
+//! retried with exponential backoff until they run out of attempts, then
+//! they're dead until someone redelivers them.
+
+use crate::connection_pool::PgsqlPool;
+use crate::database_error::{DatabaseError, DatabaseErrorKind};
+use crate::database_helpers::{FromRow, get_cell_from_row};
+use crate::encoding_helpers::to_hex;
+use crate::webhooks::WebhookEventType;
+use crate::network_helpers;
+use crate::outbox_worker::{self, Outbox, DeliveryStatus};
+use ring::hmac;
+use postgres::{Row, GenericClient};
+use chrono::{NaiveDateTime, Utc};
+use uuid::Uuid;
+use std::time::Duration;
+
+const DELIVERY_FIELDS: &str = "id, subscription_id, event_type, status, attempts, next_attempt_at, last_attempt_at, last_error, created_at";
+
+/// How long to wait for the target to respond.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Name of the header with the request's signature.
+pub const SIGNATURE_HEADER: &str = "Webhook-Signature";
+
+/// Name of the header with the delivery's id.
+pub const ID_HEADER: &str = "Webhook-Id";
+
+/// A delivery of a change to a subscription. Its id is the `id` of the payload.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct Delivery
+{
+    pub id: Uuid,
+    pub subscription_id: Uuid,
+    pub event_type: WebhookEventType,
+    pub status: DeliveryStatus,
+
+    /// How many times it was sent so far.
+    pub attempts: i32,
+
+    /// When it's sent next, if it's pending.
+    #[serde(with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub next_attempt_at: Option<NaiveDateTime>,
+
+    #[serde(with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub last_attempt_at: Option<NaiveDateTime>,
+
+    /// Why the last attempt failed, None if it didn't.
+    pub last_error: Option<String>,
+
+    #[serde(with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub created_at: Option<NaiveDateTime>,
+}
+
+impl FromRow for Delivery
+{
+    type SelfType = Delivery;
+
+    fn from_row(row: &Row) -> Result<Self::SelfType, DatabaseError>
+    {
+        let event_type: String = get_cell_from_row(row, "event_type")?;
+        let status: String = get_cell_from_row(row, "status")?;
+        let status = DeliveryStatus::from_str(&status)
+            .ok_or_else(|| DatabaseErrorKind::FailedConstraint("status_values".to_owned()))?;
+
+        Ok(
+            Delivery {
+                id: get_cell_from_row(row, "id")?,
+                subscription_id: get_cell_from_row(row, "subscription_id")?,
+                event_type: WebhookEventType::from_str(&event_type)
+                    .ok_or_else(|| DatabaseErrorKind::FailedConstraint("event_type".to_owned()))?,
+                status,
+                attempts: get_cell_from_row(row, "attempts")?,
+                next_attempt_at: match status
+                {
+                    DeliveryStatus::Pending => Some(get_cell_from_row(row, "next_attempt_at")?),
+                    _ => None,
+                },
+                last_attempt_at: get_cell_from_row(row, "last_attempt_at")?,
+                last_error: get_cell_from_row(row, "last_error")?,
+                created_at: Some(get_cell_from_row(row, "created_at")?),
+            }
+        )
+    }
+}
+
+/// Lists the subscription's deliveries, newest first.
+pub fn list(db: &mut impl GenericClient, subscription_id: &Uuid, offset: i64, limit: i64) -> Result<Vec<Delivery>, DatabaseError>
+{
+    let query = format!("
+        SELECT {} FROM webhook_deliveries
+        WHERE subscription_id = $1
+        ORDER BY created_at DESC, id
+        OFFSET $2 LIMIT $3;
+    ", DELIVERY_FIELDS);
+
+    db.query(query.as_str(), &[subscription_id, &offset, &limit])?
+        .iter()
+        .map(|row| Delivery::from_row(row))
+        .collect()
+}
+
+/// Sends a delivery again (whatever its status) as soon as the worker gets to it,
+/// with a fresh set of attempts. Returns None if the subscription has no such delivery.
+pub fn redeliver(db: &mut impl GenericClient, subscription_id: &Uuid, delivery_id: &Uuid) -> Result<Option<Delivery>, DatabaseError>
+{
+    let query = format!("
+        UPDATE webhook_deliveries
+        SET status = 'pending', attempts = 0, next_attempt_at = NOW(), last_error = NULL
+        WHERE id = $1 AND subscription_id = $2
+        RETURNING {};
+    ", DELIVERY_FIELDS);
+
+    db.query(query.as_str(), &[delivery_id, subscription_id])?
+        .get(0)
+        .map(|row| Delivery::from_row(row))
+        .transpose()
+}
+
+fn hmac_sha256_hex(key: &[u8], data: &[u8]) -> String
+{
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    to_hex(hmac::sign(&key, data).as_ref())
+}
+
+/// The value of the signature header: `t=<timestamp>,v1=<signature>`, where the
+/// signature is the hex encoded HMAC-SHA256 of `<timestamp>.<body>` keyed
+/// with the subscription's secret.
+pub fn signature(secret: &str, timestamp: i64, body: &str) -> String
+{
+    let signed = format!("{}.{}", timestamp, body);
+    format!("t={},v1={}", timestamp, hmac_sha256_hex(secret.as_bytes(), signed.as_bytes()))
+}
+
+/// POSTs a delivery's payload to the target, signed at `timestamp`. Any response
+/// other than a 2xx is an error, redirects aren't followed. Unless
+/// `allow_private_targets` is set, targets that resolve to addresses that
+/// aren't public are errors too.
+pub fn send(target_url: &str, secret: &str, delivery_id: &Uuid, body: &str, timestamp: i64, allow_private_targets: bool) -> Result<(), String>
+{
+    let result = network_helpers::outbound_agent(DELIVERY_TIMEOUT, allow_private_targets)
+        .post(target_url)
+        .set("Content-Type", "application/json")
+        .set(ID_HEADER, &delivery_id.to_string())
+        .set(SIGNATURE_HEADER, &signature(secret, timestamp, body))
+        .send_string(body);
+
+    match result
+    {
+        Ok(response) if response.status() >= 300 => Err(format!("The target responded with {}.", response.status())),
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(status, _)) => Err(format!("The target responded with {}.", status)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// A delivery claimed by the worker, with what's needed to send it.
+pub struct DueDelivery
+{
+    id: Uuid,
+    payload: String,
+    target_url: String,
+    secret: String,
+}
+
+/// The `webhook_deliveries` outbox. Unless `allow_private_targets` is set,
+/// deliveries to targets that aren't public fail, see `send`.
+pub struct Deliveries
+{
+    pub allow_private_targets: bool,
+}
+
+impl Outbox for Deliveries
+{
+    type Message = DueDelivery;
+
+    const TABLE: &'static str = "webhook_deliveries";
+    const NAME: &'static str = "webhook deliveries";
+
+    fn load(&self, db: &mut impl GenericClient, ids: &[Uuid]) -> Result<Vec<DueDelivery>, DatabaseError>
+    {
+        let query = "
+            SELECT deliveries.id, deliveries.payload, subscriptions.target_url, subscriptions.secret
+            FROM webhook_deliveries AS deliveries
+            INNER JOIN webhook_subscriptions AS subscriptions ON subscriptions.id = deliveries.subscription_id
+            WHERE deliveries.id = ANY($1);
+        ";
+
+        db.query(query, &[&ids])?
+            .iter()
+            .map(|row| Ok(
+                DueDelivery {
+                    id: get_cell_from_row(row, "id")?,
+                    payload: get_cell_from_row(row, "payload")?,
+                    target_url: get_cell_from_row(row, "target_url")?,
+                    secret: get_cell_from_row(row, "secret")?,
+                }
+            ))
+            .collect()
+    }
+
+    fn id(delivery: &DueDelivery) -> Uuid
+    {
+        delivery.id
+    }
+
+    fn recipient(delivery: &DueDelivery) -> &str
+    {
+        &delivery.target_url
+    }
+
+    fn send(&self, delivery: &DueDelivery) -> Result<(), String>
+    {
+        send(&delivery.target_url, &delivery.secret, &delivery.id, &delivery.payload, Utc::now().timestamp(), self.allow_private_targets)
+    }
+}
+
+/// Spawns a thread that sends due deliveries every `poll_interval` seconds.
+pub fn spawn_delivery_worker(pool: PgsqlPool, poll_interval: u64, max_attempts: u32, allow_private_targets: bool)
+{
+    outbox_worker::spawn_worker(pool, Deliveries { allow_private_targets }, poll_interval, max_attempts);
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{hmac_sha256_hex, signature, send, redeliver, list, Deliveries, SIGNATURE_HEADER, ID_HEADER};
+    use crate::outbox_worker::{process_due, DeliveryStatus};
+    use crate::webhooks::{self, NewSubscription, WebhookScope, WebhookEventType, Change};
+    use crate::test_helpers::{connect, insert_tenant, insert_calendar};
+    use uuid::Uuid;
+    use std::io::{Read, Write, BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+    use std::time::Duration;
+
+    /// A request received by `receiver`.
+    struct Received
+    {
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Received
+    {
+        fn header(&self, name: &str) -> Option<&str>
+        {
+            self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+        }
+    }
+
+    /// Starts an HTTP server on localhost that responds to each request
+    /// with the next status in `statuses`. Returns its URL and the requests it gets.
+    fn receiver(statuses: Vec<u16>) -> (String, Receiver<Received>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, received) = mpsc::channel();
+
+        thread::spawn(move || {
+            for status in statuses
+            {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut headers = vec![];
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+
+                loop
+                {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+
+                    match line.trim_end().split_once(':')
+                    {
+                        Some((name, value)) => headers.push((name.to_owned(), value.trim().to_owned())),
+                        None => break,
+                    }
+                }
+
+                let length = headers.iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .map_or(0, |(_, value)| value.parse().unwrap());
+
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+
+                write!(stream, "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+
+                sender.send(Received { headers, body: String::from_utf8(body).unwrap() }).unwrap();
+            }
+        });
+
+        (url, received)
+    }
+
+    #[test]
+    fn hmac_sha256()
+    {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            hmac_sha256_hex(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn sends_signed_requests()
+    {
+        let (url, received) = receiver(vec![204, 500]);
+        let id = Uuid::new_v4();
+        let body = r#"{"type":"event.updated"}"#;
+
+        assert_eq!(send(&url, "secret", &id, body, 1600000000, true), Ok(()));
+
+        let request = received.recv().unwrap();
+        assert_eq!(request.body, body);
+        assert_eq!(request.header(ID_HEADER), Some(id.to_string().as_str()));
+        assert_eq!(request.header(SIGNATURE_HEADER), Some(signature("secret", 1600000000, body).as_str()));
+        assert!(request.header(SIGNATURE_HEADER).unwrap().starts_with("t=1600000000,v1="));
+
+        assert_eq!(send(&url, "secret", &id, body, 1600000000, true), Err("The target responded with 500.".to_owned()));
+    }
+
+    #[test]
+    fn doesnt_send_to_private_addresses_or_follow_redirects()
+    {
+        let (url, received) = receiver(vec![302]);
+        let id = Uuid::new_v4();
+
+        assert!(send(&url, "secret", &id, "{}", 1600000000, false).is_err());
+        assert!(received.recv_timeout(Duration::from_millis(100)).is_err());
+
+        assert_eq!(send(&url, "secret", &id, "{}", 1600000000, true), Err("The target responded with 302.".to_owned()));
+    }
+
+    #[test]
+    #[ignore]
+    fn deliveries_are_retried_until_dead_and_can_be_redelivered()
+    {
+        let mut client = connect();
+        let mut db = client.transaction().unwrap();
+
+        // Deliveries other tests left behind would be sent too.
+        db.execute("DELETE FROM webhook_deliveries;", &[]).unwrap();
+
+        let tenant_id = insert_tenant(&mut db);
+        let calendar_id = insert_calendar(&mut db, &tenant_id);
+
+        let (target_url, received) = receiver(vec![500, 500, 200]);
+
+        let subscription = webhooks::create(&mut db, &tenant_id, &NewSubscription {
+            calendar_id,
+            event_id: None,
+            scope: WebhookScope::Calendar,
+            target_url,
+            event_types: vec![WebhookEventType::CalendarUpdated],
+        }, true).unwrap();
+
+        let change = Change {
+            event_type: WebhookEventType::CalendarUpdated,
+            calendar_id,
+            event_id: None,
+            parent_event_id: None,
+            data: serde_json::Value::Null,
+        };
+
+        webhooks::enqueue(&mut db, &[change]).unwrap();
+
+        let delivery = |db: &mut postgres::Transaction| list(db, &subscription.info.id, 0, 10).unwrap().remove(0);
+        let outbox = Deliveries { allow_private_targets: true };
+
+        // Fails and is retried later.
+        assert_eq!(process_due(&mut db, &outbox, 2).unwrap(), 1);
+        let request = received.recv().unwrap();
+        assert_eq!(request.header(ID_HEADER), Some(delivery(&mut db).id.to_string().as_str()));
+        assert_eq!(delivery(&mut db).status, DeliveryStatus::Pending);
+        assert_eq!(delivery(&mut db).attempts, 1);
+        assert_eq!(process_due(&mut db, &outbox, 2).unwrap(), 0);
+
+        // Fails again and runs out of attempts.
+        db.execute("UPDATE webhook_deliveries SET next_attempt_at = NOW();", &[]).unwrap();
+        assert_eq!(process_due(&mut db, &outbox, 2).unwrap(), 1);
+        received.recv().unwrap();
+        assert_eq!(delivery(&mut db).status, DeliveryStatus::Dead);
+        assert_eq!(delivery(&mut db).last_error.as_deref(), Some("The target responded with 500."));
+
+        let id = delivery(&mut db).id;
+        assert!(redeliver(&mut db, &Uuid::new_v4(), &id).unwrap().is_none());
+        assert_eq!(redeliver(&mut db, &subscription.info.id, &id).unwrap().unwrap().status, DeliveryStatus::Pending);
+
+        assert_eq!(process_due(&mut db, &outbox, 2).unwrap(), 1);
+        let request = received.recv().unwrap();
+        assert_eq!(request.header(SIGNATURE_HEADER).map(|s| s.contains(",v1=")), Some(true));
+        assert_eq!(delivery(&mut db).status, DeliveryStatus::Delivered);
+        assert_eq!(delivery(&mut db).attempts, 1);
+    }
+}
@@ -0,0 +1,523 @@
+//! Webhook subscriptions, see docs/webhooks.md.
+//!
+//! A subscription watches a calendar, an event or an event's instances and
+//! gets a POST with a `WebhookPayload` at its target URL for each change it's
+//! interested in. Routes that change calendars or events call `enqueue` in the
+//! same transaction as the change, which queues a delivery for each interested
+//! subscription. `webhook_delivery` sends them.
+
+use crate::database_error::{DatabaseError, DatabaseErrorKind};
+use crate::database_helpers::{FromRow, get_cell_from_row};
+use crate::event::EventPlain;
+use crate::calendar::Calendar;
+use crate::authentication::key_hashing::RandomError;
+use crate::encoding_helpers::to_hex;
+use crate::network_helpers;
+use ring::rand::{SecureRandom, SystemRandom};
+use postgres::{Row, GenericClient};
+use chrono::{NaiveDateTime, Utc};
+use uuid::Uuid;
+
+const SUBSCRIPTION_FIELDS: &str = "id, calendar_id, event_id, scope, target_url, event_types, created_at";
+
+pub const MAX_URL_LENGTH: usize = 2048;
+
+const SECRET_BYTES: usize = 32;
+
+/// What a subscription watches.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookScope
+{
+    /// The calendar itself and all of its events.
+    Calendar,
+
+    /// A single event.
+    Event,
+
+    /// An event and the events that override its instances (its children).
+    EventInstances,
+}
+
+impl WebhookScope
+{
+    pub const ALL: [WebhookScope; 3] = [WebhookScope::Calendar, WebhookScope::Event, WebhookScope::EventInstances];
+
+    /// The scope's name, as stored in the `scope` column of `webhook_subscriptions`.
+    pub fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            WebhookScope::Calendar => "calendar",
+            WebhookScope::Event => "event",
+            WebhookScope::EventInstances => "event_instances",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<WebhookScope>
+    {
+        WebhookScope::ALL.iter().copied().find(|scope| scope.as_str() == s)
+    }
+}
+
+/// The kinds of changes subscriptions can be interested in.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum WebhookEventType
+{
+    #[serde(rename = "calendar.updated")]
+    CalendarUpdated,
+
+    #[serde(rename = "calendar.deleted")]
+    CalendarDeleted,
+
+    #[serde(rename = "event.created")]
+    EventCreated,
+
+    #[serde(rename = "event.updated")]
+    EventUpdated,
+
+    #[serde(rename = "event.deleted")]
+    EventDeleted,
+}
+
+impl WebhookEventType
+{
+    pub const ALL: [WebhookEventType; 5] = [
+        WebhookEventType::CalendarUpdated,
+        WebhookEventType::CalendarDeleted,
+        WebhookEventType::EventCreated,
+        WebhookEventType::EventUpdated,
+        WebhookEventType::EventDeleted,
+    ];
+
+    /// The type's name, as stored in the `event_types` column of `webhook_subscriptions`.
+    pub fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            WebhookEventType::CalendarUpdated => "calendar.updated",
+            WebhookEventType::CalendarDeleted => "calendar.deleted",
+            WebhookEventType::EventCreated => "event.created",
+            WebhookEventType::EventUpdated => "event.updated",
+            WebhookEventType::EventDeleted => "event.deleted",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<WebhookEventType>
+    {
+        WebhookEventType::ALL.iter().copied().find(|t| t.as_str() == s)
+    }
+
+    fn is_calendar_change(&self) -> bool
+    {
+        match self
+        {
+            WebhookEventType::CalendarUpdated | WebhookEventType::CalendarDeleted => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct Subscription
+{
+    pub id: Uuid,
+    pub calendar_id: Uuid,
+
+    /// The watched event, None if `scope` is `calendar`.
+    pub event_id: Option<Uuid>,
+
+    pub scope: WebhookScope,
+
+    /// Where changes are POSTed to.
+    pub target_url: String,
+
+    pub event_types: Vec<WebhookEventType>,
+
+    #[serde(with = "crate::event::event_plain_serde::date_time_option")]
+    #[schemars(with = "Option<NaiveDateTime>")]
+    pub created_at: Option<NaiveDateTime>,
+}
+
+impl FromRow for Subscription
+{
+    type SelfType = Subscription;
+
+    fn from_row(row: &Row) -> Result<Self::SelfType, DatabaseError>
+    {
+        let scope: String = get_cell_from_row(row, "scope")?;
+        let event_types: Vec<String> = get_cell_from_row(row, "event_types")?;
+
+        Ok(
+            Subscription {
+                id: get_cell_from_row(row, "id")?,
+                calendar_id: get_cell_from_row(row, "calendar_id")?,
+                event_id: get_cell_from_row(row, "event_id")?,
+                scope: WebhookScope::from_str(&scope)
+                    .ok_or_else(|| DatabaseErrorKind::FailedConstraint("scope_values".to_owned()))?,
+                target_url: get_cell_from_row(row, "target_url")?,
+                // Unknown types are left out rather than failing, there's no constraint on them.
+                event_types: event_types.iter().filter_map(|t| WebhookEventType::from_str(t)).collect(),
+                created_at: Some(get_cell_from_row(row, "created_at")?),
+            }
+        )
+    }
+}
+
+/// A subscription together with its secret. Only returned when
+/// the subscription is created.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct SubscriptionWithSecret
+{
+    #[serde(flatten)]
+    pub info: Subscription,
+
+    /// Key of the HMAC-SHA256 signatures of the requests the subscription
+    /// gets. This is the only time it's shown, it can't be retrieved later.
+    pub secret: String,
+}
+
+/// Request body for creating a subscription.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct NewSubscription
+{
+    pub calendar_id: Uuid,
+
+    /// Required if `scope` is `event` or `event_instances`,
+    /// must not be set if it's `calendar`.
+    #[serde(default)]
+    pub event_id: Option<Uuid>,
+
+    pub scope: WebhookScope,
+    pub target_url: String,
+    pub event_types: Vec<WebhookEventType>,
+}
+
+#[derive(Error, Debug)]
+pub enum NewSubscriptionError
+{
+    #[error("The target URL must be an http or https URL.")]
+    InvalidUrl,
+
+    #[error("The target URL's host must resolve to public addresses.")]
+    PrivateUrl,
+
+    #[error("event_id doesn't match the scope.")]
+    InvalidScope,
+
+    #[error("Invalid event types.")]
+    InvalidEventTypes,
+
+    #[error("The event doesn't exist.")]
+    UnknownEvent,
+
+    #[error(transparent)]
+    Database(#[from] DatabaseError),
+
+    #[error(transparent)]
+    Random(#[from] RandomError),
+}
+
+/// Creates a subscription for the tenant. Doesn't check whether the tenant
+/// owns the calendar, callers have to. Unless `allow_private_targets` is set
+/// the target URL must resolve to public addresses, deliveries are checked
+/// again when they're sent since that might change.
+pub fn create(db: &mut impl GenericClient, tenant_id: &Uuid, new_subscription: &NewSubscription, allow_private_targets: bool) -> Result<SubscriptionWithSecret, NewSubscriptionError>
+{
+    let url = &new_subscription.target_url;
+
+    if !(url.starts_with("http://") || url.starts_with("https://")) || url.len() > MAX_URL_LENGTH
+    {
+        return Err(NewSubscriptionError::InvalidUrl);
+    }
+
+    if !allow_private_targets && !network_helpers::is_public_url(url)
+    {
+        return Err(NewSubscriptionError::PrivateUrl);
+    }
+
+    if (new_subscription.scope == WebhookScope::Calendar) != new_subscription.event_id.is_none()
+    {
+        return Err(NewSubscriptionError::InvalidScope);
+    }
+
+    // Event subscriptions never get calendar changes.
+    let only_calendar_changes = new_subscription.event_types.iter().all(|t| t.is_calendar_change());
+    if new_subscription.event_types.is_empty() || (new_subscription.scope != WebhookScope::Calendar && only_calendar_changes)
+    {
+        return Err(NewSubscriptionError::InvalidEventTypes);
+    }
+
+    if let Some(event_id) = &new_subscription.event_id
+    {
+        let rows = db.query(
+            "SELECT id FROM events WHERE id = $1 AND calendar_id = $2 AND deleted_at IS NULL;",
+            &[event_id, &new_subscription.calendar_id]
+        ).map_err(DatabaseError::from)?;
+
+        if rows.is_empty()
+        {
+            return Err(NewSubscriptionError::UnknownEvent);
+        }
+    }
+
+    let mut event_types: Vec<&str> = new_subscription.event_types.iter().map(|t| t.as_str()).collect();
+    event_types.sort();
+    event_types.dedup();
+
+    let mut secret = vec![0; SECRET_BYTES];
+    SystemRandom::new().fill(&mut secret).map_err(|_| RandomError)?;
+    let secret = to_hex(&secret);
+
+    let query = format!("
+        INSERT INTO webhook_subscriptions (tenant_id, calendar_id, event_id, scope, target_url, event_types, secret)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING {};
+    ", SUBSCRIPTION_FIELDS);
+
+    let row = db.query_one(query.as_str(), &[
+        tenant_id,
+        &new_subscription.calendar_id,
+        &new_subscription.event_id,
+        &new_subscription.scope.as_str(),
+        url,
+        &event_types,
+        &secret,
+    ]).map_err(DatabaseError::from)?;
+
+    Ok(
+        SubscriptionWithSecret {
+            info: Subscription::from_row(&row)?,
+            secret,
+        }
+    )
+}
+
+/// Lists the tenant's subscriptions. If `calendar_ids` is set, only
+/// subscriptions to those calendars are listed.
+pub fn list(db: &mut impl GenericClient, tenant_id: &Uuid, calendar_ids: Option<&Vec<Uuid>>, offset: i64, limit: i64) -> Result<Vec<Subscription>, DatabaseError>
+{
+    let query = format!("
+        SELECT {} FROM webhook_subscriptions
+        WHERE tenant_id = $1 AND ($2::UUID[] IS NULL OR calendar_id = ANY($2))
+        ORDER BY created_at, id
+        OFFSET $3 LIMIT $4;
+    ", SUBSCRIPTION_FIELDS);
+
+    db.query(query.as_str(), &[tenant_id, &calendar_ids, &offset, &limit])?
+        .iter()
+        .map(|row| Subscription::from_row(row))
+        .collect()
+}
+
+/// Gets one of the tenant's subscriptions.
+pub fn get(db: &mut impl GenericClient, tenant_id: &Uuid, id: &Uuid) -> Result<Option<Subscription>, DatabaseError>
+{
+    let query = format!("SELECT {} FROM webhook_subscriptions WHERE id = $1 AND tenant_id = $2;", SUBSCRIPTION_FIELDS);
+
+    db.query(query.as_str(), &[id, tenant_id])?
+        .get(0)
+        .map(|row| Subscription::from_row(row))
+        .transpose()
+}
+
+/// Deletes one of the tenant's subscriptions. Returns false if it doesn't exist.
+pub fn delete(db: &mut impl GenericClient, tenant_id: &Uuid, id: &Uuid) -> Result<bool, DatabaseError>
+{
+    let deleted = db.execute("DELETE FROM webhook_subscriptions WHERE id = $1 AND tenant_id = $2;", &[id, tenant_id])?;
+
+    Ok(deleted > 0)
+}
+
+/// A change to a calendar or event that subscriptions might be interested in.
+pub struct Change
+{
+    pub event_type: WebhookEventType,
+    pub calendar_id: Uuid,
+
+    /// None for changes to the calendar itself.
+    pub event_id: Option<Uuid>,
+    pub parent_event_id: Option<Uuid>,
+
+    /// The changed calendar or event, as returned by the API.
+    pub data: serde_json::Value,
+}
+
+impl Change
+{
+    /// A change to an event, `event` is the event after the change
+    /// (or before it, if it was deleted).
+    pub fn event(event_type: WebhookEventType, calendar_id: Uuid, event: &EventPlain) -> Change
+    {
+        Change {
+            event_type,
+            calendar_id,
+            event_id: event.id,
+            parent_event_id: event.parent_id,
+            data: serde_json::to_value(event).unwrap_or_default(),
+        }
+    }
+
+    pub fn calendar(event_type: WebhookEventType, calendar: &Calendar) -> Change
+    {
+        Change {
+            event_type,
+            calendar_id: calendar.get_id(),
+            event_id: None,
+            parent_event_id: None,
+            data: serde_json::to_value(calendar).unwrap_or_default(),
+        }
+    }
+}
+
+/// The body of the POST subscriptions get.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebhookPayload
+{
+    /// The delivery's id, the same in every attempt to deliver it.
+    pub id: Uuid,
+
+    #[serde(rename = "type")]
+    pub event_type: WebhookEventType,
+
+    pub subscription_id: Uuid,
+    pub calendar_id: Uuid,
+
+    #[serde(with = "crate::event::event_plain_serde::date_time_option")]
+    pub occurred_at: Option<NaiveDateTime>,
+
+    /// The changed `EventPlain` or `Calendar`.
+    pub data: serde_json::Value,
+}
+
+/// The subscriptions interested in the change.
+pub fn matching_subscriptions(db: &mut impl GenericClient, change: &Change) -> Result<Vec<Subscription>, DatabaseError>
+{
+    let query = format!("
+        SELECT {} FROM webhook_subscriptions
+        WHERE
+            calendar_id = $1
+            AND $2 = ANY(event_types)
+            AND (
+                scope = 'calendar'
+                OR (scope = 'event' AND event_id = $3)
+                OR (scope = 'event_instances' AND (event_id = $3 OR event_id = $4))
+            );
+    ", SUBSCRIPTION_FIELDS);
+
+    db.query(query.as_str(), &[&change.calendar_id, &change.event_type.as_str(), &change.event_id, &change.parent_event_id])?
+        .iter()
+        .map(|row| Subscription::from_row(row))
+        .collect()
+}
+
+/// Queues a delivery of each change for each subscription interested in it.
+/// Should always be called in the same transaction as the changes, so that
+/// deliveries are only queued for committed changes and never lost.
+pub fn enqueue(db: &mut impl GenericClient, changes: &[Change]) -> Result<(), DatabaseError>
+{
+    let occurred_at = Utc::now().naive_utc();
+
+    for change in changes
+    {
+        for subscription in matching_subscriptions(db, change)?
+        {
+            let payload = WebhookPayload {
+                id: Uuid::new_v4(),
+                event_type: change.event_type,
+                subscription_id: subscription.id,
+                calendar_id: change.calendar_id,
+                occurred_at: Some(occurred_at),
+                data: change.data.clone(),
+            };
+
+            let body = serde_json::to_string(&payload)
+                .map_err(|e| DatabaseError::from(DatabaseErrorKind::Other(Box::new(e))))?;
+
+            db.execute(
+                "INSERT INTO webhook_deliveries (id, subscription_id, event_type, payload) VALUES ($1, $2, $3, $4);",
+                &[&payload.id, &subscription.id, &change.event_type.as_str(), &body]
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{WebhookScope, WebhookEventType, NewSubscription, NewSubscriptionError, Change, create, matching_subscriptions};
+    use crate::test_helpers::{connect, insert_tenant, insert_calendar};
+    use uuid::Uuid;
+
+    #[test]
+    fn event_type_names()
+    {
+        for event_type in WebhookEventType::ALL.iter()
+        {
+            assert_eq!(WebhookEventType::from_str(event_type.as_str()), Some(*event_type));
+            assert_eq!(serde_json::to_value(event_type).unwrap(), event_type.as_str());
+        }
+
+        assert_eq!(WebhookScope::from_str("event_instances"), Some(WebhookScope::EventInstances));
+    }
+
+    #[test]
+    #[ignore]
+    fn subscriptions_match_their_scope()
+    {
+        let mut client = connect();
+        let mut db = client.transaction().unwrap();
+
+        let tenant_id = insert_tenant(&mut db);
+        let calendar_id = insert_calendar(&mut db, &tenant_id);
+        let event_id: Uuid = db.query_one(
+            "INSERT INTO events (calendar_id, start_date, end_date) VALUES ($1, '2021-01-01', '2021-01-01') RETURNING id;",
+            &[&calendar_id]
+        ).unwrap().get("id");
+
+        let new_subscription = |scope, event_id, event_types: &[WebhookEventType]| NewSubscription {
+            calendar_id,
+            event_id,
+            scope,
+            target_url: "http://localhost/hook".to_owned(),
+            event_types: event_types.to_vec(),
+        };
+
+        let calendar_hook = create(&mut db, &tenant_id, &new_subscription(WebhookScope::Calendar, None, &WebhookEventType::ALL), true).unwrap().info;
+        let event_hook = create(&mut db, &tenant_id, &new_subscription(WebhookScope::Event, Some(event_id), &[WebhookEventType::EventUpdated]), true).unwrap().info;
+        let instances_hook = create(&mut db, &tenant_id, &new_subscription(WebhookScope::EventInstances, Some(event_id), &[WebhookEventType::EventUpdated]), true).unwrap().info;
+
+        match create(&mut db, &tenant_id, &new_subscription(WebhookScope::Event, None, &WebhookEventType::ALL), true)
+        {
+            Err(NewSubscriptionError::InvalidScope) => {},
+            other => panic!("Expected InvalidScope, got {:?}", other),
+        }
+
+        match create(&mut db, &tenant_id, &new_subscription(WebhookScope::Calendar, None, &WebhookEventType::ALL), false)
+        {
+            Err(NewSubscriptionError::PrivateUrl) => {},
+            other => panic!("Expected PrivateUrl, got {:?}", other),
+        }
+
+        let matching = |db: &mut postgres::Transaction, event_type, event_id, parent_event_id| {
+            let change = Change { event_type, calendar_id, event_id, parent_event_id, data: serde_json::Value::Null };
+            let mut ids: Vec<Uuid> = matching_subscriptions(db, &change).unwrap().iter().map(|s| s.id).collect();
+            ids.sort();
+            ids
+        };
+
+        let mut all = vec![calendar_hook.id, event_hook.id, instances_hook.id];
+        all.sort();
+
+        assert_eq!(matching(&mut db, WebhookEventType::EventUpdated, Some(event_id), None), all);
+        assert_eq!(matching(&mut db, WebhookEventType::EventDeleted, Some(event_id), None), vec![calendar_hook.id]);
+        assert_eq!(matching(&mut db, WebhookEventType::CalendarUpdated, None, None), vec![calendar_hook.id]);
+
+        // A child of the event, i.e. an override of one of its instances.
+        let mut children_hooks = vec![calendar_hook.id, instances_hook.id];
+        children_hooks.sort();
+        assert_eq!(matching(&mut db, WebhookEventType::EventUpdated, Some(Uuid::new_v4()), Some(event_id)), children_hooks);
+    }
+}